@@ -0,0 +1,206 @@
+//! Structured records of security-relevant events, for shipping to an audit pipeline - see
+//! [`AuditSink`] and [`set_sink`].
+//!
+//! Unlike [`crate::events`], which are per service and meant for UIs, audit records go to a
+//! single sink for the whole process, and cover what auditors care about - connections accepted
+//! by servers along with who made them, services being started, stale sockets being removed and
+//! connections being denied. Nothing is recorded until a sink is set, and peer credentials are
+//! only read for accepted connections then.
+//!
+//! Records are handed to the sink synchronously, from whatever task or thread the event happened
+//! on, so sinks that do slow I/O should queue records rather than block.
+
+use std::{
+    fmt::{Debug, Display},
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use tracing::info;
+
+use crate::{PeerCredentials, ServiceSocket};
+
+/// The sink records go to, if any.
+static SINK: RwLock<Option<Arc<dyn AuditSink>>> = RwLock::new(None);
+
+/// Something security-relevant that happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuditEvent {
+    /// A server accepted a connection, from a client with the given credentials - if they could
+    /// be read.
+    ConnectionAccepted { peer: Option<PeerCredentials> },
+    /// A client is starting the service.
+    StartAttempted,
+    /// A socket nothing answered on was removed, to start or bind the service anew.
+    StaleSocketRemoved,
+    /// A server denied a connection, from a client with the given credentials - if they could be
+    /// read - for the given reason. See [`crate::authorization`] and
+    /// [`crate::serve::ServeOptions::with_credential_policy`].
+    AuthorizationDenied {
+        peer: Option<PeerCredentials>,
+        reason: String,
+    },
+}
+
+impl Display for AuditEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuditEvent::ConnectionAccepted { peer: Some(peer) } => {
+                write!(f, "connection accepted from {peer}")
+            }
+            AuditEvent::ConnectionAccepted { peer: None } => {
+                write!(f, "connection accepted from unknown peer")
+            }
+            AuditEvent::StartAttempted => write!(f, "start attempted"),
+            AuditEvent::StaleSocketRemoved => write!(f, "stale socket removed"),
+            AuditEvent::AuthorizationDenied {
+                peer: Some(peer),
+                reason,
+            } => write!(f, "connection from {peer} denied - {reason}"),
+            AuditEvent::AuthorizationDenied { peer: None, reason } => {
+                write!(f, "connection from unknown peer denied - {reason}")
+            }
+        }
+    }
+}
+
+/// Record of an [`AuditEvent`], with where and when it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// When the event happened.
+    pub time: SystemTime,
+    /// Process ID of the process recording the event.
+    pub pid: u32,
+    /// Socket of the service the event is about - `None` for connections accepted by servers
+    /// that weren't told their service socket, see [`crate::serve::ServeOptions::for_service`].
+    pub socket: Option<ServiceSocket>,
+    pub event: AuditEvent,
+}
+
+impl Display for AuditRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = humantime::format_rfc3339_millis(self.time);
+        match &self.socket {
+            Some(socket) => write!(f, "{time} [{}] @ {socket}: {}", self.pid, self.event),
+            None => write!(f, "{time} [{}]: {}", self.pid, self.event),
+        }
+    }
+}
+
+/// Where audit records go - see [`set_sink`].
+pub trait AuditSink: Debug + Send + Sync {
+    /// Take in a record. This shouldn't block for long.
+    fn record(&self, record: &AuditRecord);
+}
+
+/// Logs records with [`tracing`], at the info level and with the `suss::audit` target - for
+/// pipelines that already collect the logs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TracingSink;
+
+impl AuditSink for TracingSink {
+    fn record(&self, record: &AuditRecord) {
+        info!(target: "suss::audit", "{}", record);
+    }
+}
+
+/// Send the records of this process to the given sink from now on - or nowhere, for `None` -
+/// producing the sink they went to before.
+pub fn set_sink(sink: Option<Arc<dyn AuditSink>>) -> Option<Arc<dyn AuditSink>> {
+    let mut current = SINK
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    std::mem::replace(&mut *current, sink)
+}
+
+/// Whether a sink is set, so records are worth putting together.
+pub(crate) fn is_enabled() -> bool {
+    SINK.read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .is_some()
+}
+
+/// Hand a record of the event to the sink, if there is one.
+pub(crate) fn record(socket: Option<&ServiceSocket>, event: AuditEvent) {
+    // Don't hold the lock while the sink runs, so it can set another sink.
+    let Some(sink) = SINK
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+    else {
+        return;
+    };
+    sink.record(&AuditRecord {
+        time: SystemTime::now(),
+        pid: std::process::id(),
+        socket: socket.cloned(),
+        event,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        ffi::OsStr,
+        os::unix::net::UnixListener,
+        sync::{Arc, Mutex},
+    };
+
+    use futures_lite::future::block_on;
+
+    use super::{set_sink, AuditEvent, AuditRecord, AuditSink};
+    use crate::{remove_stale_socket, socket_shims::StdThreadpoolUSocks, ServiceSocket};
+
+    #[derive(Debug, Default)]
+    struct CollectingSink(Mutex<Vec<AuditRecord>>);
+
+    impl AuditSink for CollectingSink {
+        fn record(&self, record: &AuditRecord) {
+            self.0.lock().unwrap().push(record.clone());
+        }
+    }
+
+    #[test]
+    pub fn audit_sink_test() {
+        let socket_name = format!("audit-test-{}.sock", std::process::id());
+        let socket = ServiceSocket::new(OsStr::new(&socket_name), &temp_dir());
+        // Leave a stale socket behind.
+        drop(UnixListener::bind(&socket.path).unwrap());
+
+        let sink = Arc::new(CollectingSink::default());
+        set_sink(Some(sink.clone()));
+        let removed = block_on(remove_stale_socket::<StdThreadpoolUSocks>(&socket));
+        set_sink(None);
+        assert!(removed.unwrap().is_none());
+        let _ = std::fs::remove_file(socket.lock_path());
+
+        // Other tests may record events about their own sockets meanwhile.
+        let records = sink.0.lock().unwrap();
+        let ours: Vec<_> = records
+            .iter()
+            .filter(|record| record.socket.as_ref() == Some(&socket))
+            .collect();
+        assert_eq!(ours.len(), 1);
+        assert_eq!(ours[0].event, AuditEvent::StaleSocketRemoved);
+        assert_eq!(ours[0].pid, std::process::id());
+        assert!(ours[0].to_string().ends_with(": stale socket removed"));
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,375 @@
+//! Hooks deciding whether a connection to a service is let through - see [`Authorizer`] and
+//! [`crate::Service::authorizer`].
+//!
+//! Services with an authorizer authorize every connection once the [`crate::handshake`], if
+//! any, is done. The client calls [`Authorizer::on_connect`] to fill in a [`Handshake`] - for
+//! instance with a token - and sends it. The server reads it, and calls [`Authorizer::on_accept`]
+//! with it and the [`PeerCredentials`] of the client, which decides whether the connection is
+//! let through. The [`Decision`] is sent back, so a denied client fails with
+//! [`crate::Error::Unauthorized`] just like the server does, instead of finding the connection
+//! closed under it.
+//!
+//! The same authorizer can be shared between all the services of an application, to check
+//! tokens or allow users uniformly. [`CredentialPolicy`] is an authorizer allowing users and
+//! groups, and [`SharedToken`] one checking a secret known to clients and servers.
+//!
+//! Both sides read the handshake and the decision through a duplicate of the socket, without
+//! reading ahead - so the connection is left as it was, and can be written to straight away even
+//! with [`crate::socket_shims::StdThreadpoolUSocks`].
+//!
+//! Clients and servers of a service must agree on whether it has an authorizer. The handshake is
+//! encoded as the length of the token as a big-endian `u32` followed by the token itself, and the
+//! decision as a zero byte when allowed, or a one byte followed by the length of the reason as a
+//! big-endian `u16` and the reason when denied.
+
+use std::{
+    fmt::Debug,
+    io::{self, ErrorKind},
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    audit, fdpass::read_exact_through_duplicate, CredentialPolicy, Error, PeerCredentials,
+    ServiceSocket, UnixSocketInterface,
+};
+
+/// Largest token accepted from clients, to avoid allocating huge buffers for garbage lengths.
+pub const MAX_TOKEN_LENGTH: usize = 64 * 1024;
+
+/// What a client presents to the server of a service to be authorized - see [`Authorizer`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    socket: ServiceSocket,
+    token: Vec<u8>,
+}
+
+impl Handshake {
+    /// Empty handshake for a connection to the given service socket.
+    pub fn new(socket: ServiceSocket) -> Self {
+        Self {
+            socket,
+            token: Vec::new(),
+        }
+    }
+
+    /// The socket of the service the connection is to.
+    pub fn socket(&self) -> &ServiceSocket {
+        &self.socket
+    }
+
+    /// The token presented by the client - empty unless one was set.
+    pub fn token(&self) -> &[u8] {
+        &self.token
+    }
+
+    /// Set the token the client presents.
+    pub fn set_token(&mut self, token: impl Into<Vec<u8>>) {
+        self.token = token.into();
+    }
+}
+
+/// Whether an [`Authorizer`] lets a connection through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// Let the connection through.
+    Allow,
+    /// Refuse the connection, telling the client why.
+    Deny(String),
+}
+
+/// Middleware authorizing the connections to a service - on both the client and the server side.
+pub trait Authorizer: Debug + Send + Sync {
+    /// Called by clients on every new connection, to fill in the handshake they present. By
+    /// default it is left empty.
+    fn on_connect(&self, handshake: &mut Handshake) {
+        let _ = handshake;
+    }
+
+    /// Called by servers on every accepted connection, with the credentials of the client and the
+    /// handshake it presented, to decide whether to let it through.
+    fn on_accept(&self, peer: &PeerCredentials, handshake: &mut Handshake) -> Decision;
+}
+
+/// Lets through clients running as the allowed users or groups, whatever their handshake.
+impl Authorizer for CredentialPolicy {
+    fn on_accept(&self, peer: &PeerCredentials, _handshake: &mut Handshake) -> Decision {
+        match self.allows(peer) {
+            true => Decision::Allow,
+            false => Decision::Deny(format!("Connections from {peer} are not allowed")),
+        }
+    }
+}
+
+/// Lets through clients presenting the same secret token as the server has.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SharedToken {
+    token: Vec<u8>,
+}
+
+impl Debug for SharedToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedToken").finish_non_exhaustive()
+    }
+}
+
+impl SharedToken {
+    pub fn new(token: impl Into<Vec<u8>>) -> Self {
+        Self {
+            token: token.into(),
+        }
+    }
+}
+
+impl Authorizer for SharedToken {
+    fn on_connect(&self, handshake: &mut Handshake) {
+        handshake.set_token(self.token.clone());
+    }
+
+    fn on_accept(&self, _peer: &PeerCredentials, handshake: &mut Handshake) -> Decision {
+        // Compare every byte, so how long the comparison takes doesn't give the token away.
+        let presented = handshake.token();
+        let differences = self
+            .token
+            .iter()
+            .zip(presented)
+            .fold(0, |differences, (ours, theirs)| {
+                differences | (ours ^ theirs)
+            });
+        match differences == 0 && presented.len() == self.token.len() {
+            true => Decision::Allow,
+            false => Decision::Deny("Invalid token".to_owned()),
+        }
+    }
+}
+
+/// Present the handshake filled in by the authorizer over a fresh client connection, and wait for
+/// the server to decide whether to let it through.
+pub(crate) async fn authorize_outgoing<U: UnixSocketInterface>(
+    socket: &ServiceSocket,
+    authorizer: &dyn Authorizer,
+    unix_stream: &mut U::UnixStream,
+) -> crate::error::Result<()> {
+    let handshake_failed = |source| Error::HandshakeFailed {
+        socket: socket.clone(),
+        source,
+    };
+    let mut handshake = Handshake::new(socket.clone());
+    authorizer.on_connect(&mut handshake);
+    let token_length = u32::try_from(handshake.token.len())
+        .ok()
+        .filter(|length| *length as usize <= MAX_TOKEN_LENGTH)
+        .ok_or_else(|| {
+            handshake_failed(io::Error::new(
+                ErrorKind::InvalidInput,
+                "Authorization token too long",
+            ))
+        })?;
+    let mut request = Vec::with_capacity(4 + handshake.token.len());
+    request.extend_from_slice(&token_length.to_be_bytes());
+    request.extend_from_slice(&handshake.token);
+    U::unix_stream_write_all(unix_stream, &request)
+        .await
+        .map_err(handshake_failed)?;
+
+    let mut decision = [0u8];
+    read_exact::<U>(unix_stream, &mut decision)
+        .await
+        .map_err(handshake_failed)?;
+    match decision {
+        [0] => Ok(()),
+        [1] => {
+            let mut reason_length = [0u8; 2];
+            read_exact::<U>(unix_stream, &mut reason_length)
+                .await
+                .map_err(handshake_failed)?;
+            let mut reason = vec![0u8; u16::from_be_bytes(reason_length).into()];
+            read_exact::<U>(unix_stream, &mut reason)
+                .await
+                .map_err(handshake_failed)?;
+            Err(Error::Unauthorized {
+                socket: socket.clone(),
+                reason: String::from_utf8_lossy(&reason).into_owned(),
+            })
+        }
+        [other] => Err(handshake_failed(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unknown authorization decision {other}"),
+        ))),
+    }
+}
+
+/// Read the handshake presented over a connection accepted by a server, let the authorizer decide
+/// whether to let it through, and tell the client.
+pub(crate) async fn authorize_incoming<U: UnixSocketInterface>(
+    socket: &ServiceSocket,
+    authorizer: &dyn Authorizer,
+    unix_stream: &mut U::UnixStream,
+) -> crate::error::Result<()> {
+    let handshake_failed = |source| Error::HandshakeFailed {
+        socket: socket.clone(),
+        source,
+    };
+    let peer = U::unix_stream_peer_credentials(unix_stream)
+        .await
+        .map_err(|source| Error::PeerCheckFailed {
+            socket: socket.clone(),
+            source,
+        })?;
+    let mut token_length = [0u8; 4];
+    read_exact::<U>(unix_stream, &mut token_length)
+        .await
+        .map_err(handshake_failed)?;
+    let token_length = u32::from_be_bytes(token_length) as usize;
+    if token_length > MAX_TOKEN_LENGTH {
+        return Err(handshake_failed(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Authorization token of {} bytes exceeds the maximum of {}",
+                token_length, MAX_TOKEN_LENGTH
+            ),
+        )));
+    }
+    let mut handshake = Handshake::new(socket.clone());
+    handshake.token = vec![0u8; token_length];
+    read_exact::<U>(unix_stream, &mut handshake.token)
+        .await
+        .map_err(handshake_failed)?;
+
+    match authorizer.on_accept(&peer, &mut handshake) {
+        Decision::Allow => {
+            debug!("Authorized connection from {} @ {}", peer, socket);
+            U::unix_stream_write_all(unix_stream, &[0])
+                .await
+                .map_err(handshake_failed)
+        }
+        Decision::Deny(reason) => {
+            warn!("Denied connection from {} @ {} - {}", peer, socket, reason);
+            audit::record(
+                Some(socket),
+                audit::AuditEvent::AuthorizationDenied {
+                    peer: Some(peer),
+                    reason: reason.clone(),
+                },
+            );
+            let reason_bytes = &reason.as_bytes()[..reason.len().min(u16::MAX.into())];
+            let mut denial = Vec::with_capacity(3 + reason_bytes.len());
+            denial.push(1);
+            denial.extend_from_slice(&(reason_bytes.len() as u16).to_be_bytes());
+            denial.extend_from_slice(reason_bytes);
+            // The connection is refused either way, so failing to say why doesn't matter.
+            if let Err(e) = U::unix_stream_write_all(unix_stream, &denial).await {
+                debug!("Failed to tell denied client @ {} why - {}", socket, e);
+            }
+            Err(Error::Unauthorized {
+                socket: socket.clone(),
+                reason,
+            })
+        }
+    }
+}
+
+/// Read from the stream without leaving a read in flight - see the [module docs](self).
+async fn read_exact<U: UnixSocketInterface>(
+    unix_stream: &mut U::UnixStream,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    let socket = U::unix_stream_duplicate_fd(unix_stream).await?;
+    read_exact_through_duplicate(socket, buf).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env::temp_dir, ffi::OsStr, os::unix::net::UnixStream};
+
+    use blocking::Unblock;
+    use futures_lite::future::{block_on, zip};
+
+    use super::{authorize_incoming, authorize_outgoing, Authorizer, SharedToken};
+    use crate::{
+        credentials::current_uid, socket_shims::StdThreadpoolUSocks, CredentialPolicy, Error,
+        ServiceSocket, UnixSocketInterface,
+    };
+
+    #[test]
+    pub fn authorization_test() {
+        let socket = ServiceSocket::new(OsStr::new("authorized.sock"), &temp_dir());
+        let authorize = |client: &SharedToken, server: &dyn Authorizer| {
+            let (a, b) = UnixStream::pair().unwrap();
+            let (mut a, mut b) = (Unblock::new(a), Unblock::new(b));
+            block_on(zip(
+                authorize_outgoing::<StdThreadpoolUSocks>(&socket, client, &mut a),
+                authorize_incoming::<StdThreadpoolUSocks>(&socket, server, &mut b),
+            ))
+        };
+
+        let token = SharedToken::new("secret");
+        let (client, server) = authorize(&token, &token);
+        client.unwrap();
+        server.unwrap();
+
+        // Authorized connections can be written to and read from by both sides afterwards.
+        let (a, b) = UnixStream::pair().unwrap();
+        let (mut a, mut b) = (Unblock::new(a), Unblock::new(b));
+        let (reply, request) = block_on(zip(
+            async {
+                authorize_outgoing::<StdThreadpoolUSocks>(&socket, &token, &mut a)
+                    .await
+                    .unwrap();
+                StdThreadpoolUSocks::unix_stream_write_all(&mut a, b"ping")
+                    .await
+                    .unwrap();
+                let mut reply = [0u8; 4];
+                StdThreadpoolUSocks::unix_stream_read_exact(&mut a, &mut reply)
+                    .await
+                    .unwrap();
+                reply
+            },
+            async {
+                authorize_incoming::<StdThreadpoolUSocks>(&socket, &token, &mut b)
+                    .await
+                    .unwrap();
+                StdThreadpoolUSocks::unix_stream_write_all(&mut b, b"pong")
+                    .await
+                    .unwrap();
+                let mut request = [0u8; 4];
+                StdThreadpoolUSocks::unix_stream_read_exact(&mut b, &mut request)
+                    .await
+                    .unwrap();
+                request
+            },
+        ));
+        assert_eq!(&reply, b"pong");
+        assert_eq!(&request, b"ping");
+
+        let (client, server) = authorize(&SharedToken::new("guess"), &token);
+        assert!(
+            matches!(client, Err(Error::Unauthorized { reason, .. }) if reason == "Invalid token")
+        );
+        assert!(matches!(server, Err(Error::Unauthorized { .. })));
+
+        let (client, server) = authorize(&token, &CredentialPolicy::new().with_uid(current_uid()));
+        client.unwrap();
+        server.unwrap();
+
+        let (client, server) = authorize(&token, &CredentialPolicy::new());
+        assert!(matches!(client, Err(Error::Unauthorized { .. })));
+        assert!(matches!(server, Err(Error::Unauthorized { .. })));
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
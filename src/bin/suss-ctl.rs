@@ -0,0 +1,237 @@
+//! `suss-ctl` - manage the services in a base context directory from the command line, without
+//! writing any Rust. Requires the `cli` feature.
+//!
+//! * `suss-ctl list <context-dir>` lists the services in a context directory, and whether they
+//!   are running.
+//! * `suss-ctl status <socket>` shows whether the service on a socket is running, along with its
+//!   state file. The exit code is 0 if it is running, and 3 if it isn't.
+//! * `suss-ctl stop <socket>` asks the service on a socket to stop over its control channel.
+//! * `suss-ctl gc <context-dir>` removes stale sockets and orphaned files from a context directory.
+//! * `suss-ctl tail-state <socket>` prints the state file of a service whenever it changes, until
+//!   interrupted.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, SystemTime},
+};
+
+use clap::{value_parser, Arg, ArgMatches, Command};
+use futures_lite::future::block_on;
+use suss::{
+    control, gc_context, list_services,
+    status::{self, ServiceState},
+    timefut, ServiceSocket, ServiceStatus,
+};
+
+/// How often the state file is checked for changes by `tail-state`.
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Exit code of `status` for services that aren't running, as for LSB init scripts.
+const NOT_RUNNING_EXIT_CODE: u8 = 3;
+
+fn command() -> Command {
+    let context_dir = || {
+        Arg::new("context-dir")
+            .help("Base context directory the services put their sockets in")
+            .required(true)
+            .value_parser(value_parser!(PathBuf))
+    };
+    let socket = || {
+        Arg::new("socket")
+            .help("Path of the service socket")
+            .required(true)
+            .value_parser(value_parser!(PathBuf))
+    };
+    Command::new("suss-ctl")
+        .about("Manage the services in a suss base context directory")
+        .version(env!("CARGO_PKG_VERSION"))
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("list")
+                .about("List the services in a context directory")
+                .arg(context_dir()),
+        )
+        .subcommand(
+            Command::new("status")
+                .about("Show whether the service on a socket is running")
+                .arg(socket()),
+        )
+        .subcommand(
+            Command::new("stop")
+                .about("Ask the service on a socket to stop over its control channel")
+                .arg(socket())
+                .arg(
+                    Arg::new("timeout")
+                        .long("timeout")
+                        .help("How long to wait for the service to stop, like `10s`")
+                        .default_value("10s")
+                        .value_parser(|timeout: &str| humantime::parse_duration(timeout)),
+                ),
+        )
+        .subcommand(
+            Command::new("gc")
+                .about("Remove stale sockets and orphaned files from a context directory")
+                .arg(context_dir()),
+        )
+        .subcommand(
+            Command::new("tail-state")
+                .about("Print the state file of a service whenever it changes")
+                .arg(socket()),
+        )
+}
+
+fn main() -> ExitCode {
+    let matches = command().get_matches();
+    let (subcommand, arguments) = matches.subcommand().expect("subcommand is required");
+    match block_on(run(subcommand, arguments)) {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            eprintln!("suss-ctl: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(
+    subcommand: &str,
+    arguments: &ArgMatches,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let path = |name| {
+        arguments
+            .get_one::<PathBuf>(name)
+            .expect("argument is required")
+    };
+    match subcommand {
+        "list" => {
+            for service in list_services(path("context-dir")).await? {
+                println!(
+                    "{}\t{}\t{}",
+                    service.socket.name.to_string_lossy(),
+                    service.status,
+                    service
+                        .state
+                        .as_ref()
+                        .and_then(|state| state.service_version.as_deref())
+                        .unwrap_or("-")
+                );
+            }
+        }
+        "status" => {
+            let service_socket = service_socket(path("socket"))?;
+            let status = status::socket_status(&service_socket).await;
+            println!("{}: {}", service_socket, status);
+            if let Some(state) = status::read_state(&service_socket) {
+                print_state(&state);
+            }
+            if !matches!(status, ServiceStatus::Running { .. }) {
+                return Ok(ExitCode::from(NOT_RUNNING_EXIT_CODE));
+            }
+        }
+        "stop" => {
+            let service_socket = service_socket(path("socket"))?;
+            let timeout = *arguments
+                .get_one::<Duration>("timeout")
+                .expect("timeout has a default");
+            control::stop_service(&service_socket, timeout).await?;
+            println!("{}: stopped", service_socket);
+        }
+        "gc" => {
+            let report = gc_context(path("context-dir")).await?;
+            for socket in &report.stale_sockets {
+                println!("removed stale socket {}", socket.display());
+            }
+            for file in &report.orphaned_files {
+                println!("removed orphaned file {}", file.display());
+            }
+            println!(
+                "kept {} live sockets, removed {} stale sockets and {} orphaned files",
+                report.live_sockets.len(),
+                report.stale_sockets.len(),
+                report.orphaned_files.len()
+            );
+        }
+        "tail-state" => {
+            let service_socket = service_socket(path("socket"))?;
+            let mut last = None;
+            loop {
+                let state = Some(status::read_state(&service_socket));
+                if state != last {
+                    match state.as_ref().and_then(Option::as_ref) {
+                        Some(state) => print_state(state),
+                        None => {
+                            println!("no state file @ {}", service_socket.state_path().display())
+                        }
+                    }
+                    last = state;
+                }
+                timefut::sleep(TAIL_POLL_INTERVAL).await;
+            }
+        }
+        other => unreachable!("unknown subcommand {other}"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Identify a service socket by its path.
+fn service_socket(path: &Path) -> Result<ServiceSocket, String> {
+    let name: OsString = path
+        .file_name()
+        .ok_or_else(|| format!("{} is not a socket path", path.display()))?
+        .to_owned();
+    let context_dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok(ServiceSocket::new(&name, context_dir))
+}
+
+fn print_state(state: &ServiceState) {
+    let uptime = SystemTime::now()
+        .duration_since(state.started_at)
+        .unwrap_or_default();
+    println!("  pid:               {}", state.pid);
+    println!(
+        "  started at:        {} ({} ago)",
+        humantime::format_rfc3339_seconds(state.started_at),
+        humantime::format_duration(Duration::from_secs(uptime.as_secs()))
+    );
+    println!("  suss version:      {}", state.suss_version);
+    println!(
+        "  service version:   {}",
+        state.service_version.as_deref().unwrap_or("-")
+    );
+    match &state.protocol_versions {
+        Some(versions) => println!(
+            "  protocol versions: {}..={}",
+            versions.start(),
+            versions.end()
+        ),
+        None => println!("  protocol versions: -"),
+    }
+    match &state.heartbeat {
+        Some(heartbeat) => println!(
+            "  heartbeat:         every {} (tolerance {})",
+            humantime::format_duration(heartbeat.interval()),
+            humantime::format_duration(heartbeat.tolerance())
+        ),
+        None => println!("  heartbeat:         -"),
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
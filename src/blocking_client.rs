@@ -0,0 +1,515 @@
+//! Blocking implementation of connecting to - and starting - services, for programs without an
+//! async runtime. See [`crate::ServiceExt::connect_to_service_blocking`].
+//!
+//! This uses std unix sockets directly, with non-blocking accepts and read timeouts for the
+//! liveness check, and follows the same protocol as the async implementation.
+
+use std::{
+    ffi::OsStr,
+    fmt::Debug,
+    io::{BufRead, BufReader, ErrorKind, Read},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use blocking::Unblock;
+use futures_lite::future::block_on;
+use tracing::{error, info, warn};
+
+use crate::{
+    audit, authorization, check_liveness_status,
+    child::{self, ServiceProcessHandle},
+    cleanable_path::CleanablePathBuf,
+    credentials, events, get_random_sockpath, launch_service,
+    launcher::{Launched, ServiceLauncher},
+    liveness::{self, LivenessTransport},
+    lock_service_socket, remove_stale_socket_locked, seqpacket,
+    socket_shims::{SocketKind, StdThreadpoolUSocks},
+    start_dedup::{self, StartClaim},
+    verify_handshake, Error, Service, ServiceEvent, ServiceSocket, ServiceStartable,
+    UnixSocketInterface, CHILD_EXIT_POLL_INTERVAL,
+};
+
+/// Connect to the socket of an already running service, performing the handshake if the service
+/// has one.
+pub(crate) fn connect_to_running_service_blocking<U, S>(
+    service: &S,
+    base_context_directory: &Path,
+) -> crate::error::Result<UnixStream>
+where
+    U: UnixSocketInterface,
+    S: Service<U> + ?Sized,
+{
+    let service_socket = ServiceSocket::new(&service.socket_name(), base_context_directory);
+    let stream = connect_raw::<U, S>(service, &service_socket)?;
+    let stream = handshake::<U, S>(service, &service_socket, stream)?;
+    events::emit(ServiceEvent::Connected {
+        socket: service_socket,
+    });
+    Ok(stream)
+}
+
+/// Connect to a service, starting it if it isn't running.
+pub(crate) fn connect_to_service_blocking<U, S>(
+    service: &S,
+    executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
+    base_context_directory: &Path,
+    liveness_timeout: Duration,
+) -> crate::error::Result<UnixStream>
+where
+    U: UnixSocketInterface,
+    S: ServiceStartable<U> + ?Sized,
+{
+    let service_socket = ServiceSocket::new(&service.socket_name(), base_context_directory);
+    let stream = match connect_raw::<U, S>(service, &service_socket) {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!(
+                "Error connecting to existing service - {} - attempting on-demand service start",
+                e
+            );
+            start_service(
+                service,
+                executor_commandline_prefix,
+                base_context_directory,
+                &service_socket,
+                liveness_timeout,
+            )?
+        }
+    };
+    let stream = handshake::<U, S>(service, &service_socket, stream)?;
+    events::emit(ServiceEvent::Connected {
+        socket: service_socket,
+    });
+    Ok(stream)
+}
+
+fn start_service<U, S>(
+    service: &S,
+    executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
+    base_context_directory: &Path,
+    service_socket: &ServiceSocket,
+    liveness_timeout: Duration,
+) -> crate::error::Result<UnixStream>
+where
+    U: UnixSocketInterface,
+    S: ServiceStartable<U> + ?Sized,
+{
+    let _start_guard = match start_dedup::claim_start(&service_socket.path) {
+        StartClaim::Starter(guard) => guard,
+        StartClaim::Waiter(finished) => {
+            info!(
+                "Service @ {} is already being started in this process - waiting for that",
+                service_socket
+            );
+            block_on(finished);
+            return connect_raw::<U, S>(service, service_socket);
+        }
+    };
+    let _start_lock = block_on(lock_service_socket(service_socket, liveness_timeout))?;
+    // Another start - in this process or another - may have finished between our connection
+    // attempt and taking the lock.
+    let connect_error = match connect_raw::<U, S>(service, service_socket) {
+        Ok(stream) => return Ok(stream),
+        Err(e) => e,
+    };
+    if matches!(connect_error, Error::StaleSocket { .. }) {
+        let maybe_live_stream = block_on(remove_stale_socket_locked::<StdThreadpoolUSocks>(
+            service_socket,
+        ))
+        .map_err(|e| Error::StaleSocket {
+            socket: service_socket.clone(),
+            source: e,
+        })?;
+        if let Some(live_stream) = maybe_live_stream {
+            return Ok(block_on(live_stream.into_inner()));
+        }
+    }
+
+    events::emit(ServiceEvent::Starting {
+        socket: service_socket.clone(),
+    });
+    audit::record(Some(service_socket), audit::AuditEvent::StartAttempted);
+    let started: crate::error::Result<()> = (|| {
+        let liveness_failed = |e| Error::LivenessSocketFailed {
+            socket: service_socket.clone(),
+            source: e,
+        };
+        let service_launcher = service.launcher();
+        // In-process servers can't be handed anything but a path to ping.
+        let liveness_transport = match service_launcher {
+            ServiceLauncher::Command => service.liveness_transport(),
+            ServiceLauncher::InProcess(_) => LivenessTransport::TempSocket,
+        };
+        let (pending_liveness, liveness_path) = match liveness_transport {
+            transport @ (LivenessTransport::TempSocket
+            | LivenessTransport::AuthenticatedTempSocket) => {
+                let ephemeral_socket_path =
+                    CleanablePathBuf::new(get_random_sockpath(&liveness::default_ephemeral_dir()));
+                info!(
+                    "Creating ephemeral liveness socket @ {}",
+                    ephemeral_socket_path.as_ref().display()
+                );
+                let ephemeral_listener =
+                    UnixListener::bind(ephemeral_socket_path.as_ref()).map_err(liveness_failed)?;
+                ephemeral_listener
+                    .set_nonblocking(true)
+                    .map_err(liveness_failed)?;
+                let liveness_path = ephemeral_socket_path.as_ref().to_owned();
+                let liveness_token = (transport == LivenessTransport::AuthenticatedTempSocket)
+                    .then(liveness::generate_liveness_token);
+                (
+                    PendingLiveness::TempSocket(
+                        ephemeral_listener,
+                        ephemeral_socket_path,
+                        liveness_token,
+                    ),
+                    liveness_path,
+                )
+            }
+            LivenessTransport::InheritedFd => {
+                let (ours, theirs) = liveness::inheritable_pair().map_err(liveness_failed)?;
+                let liveness_path = liveness::inherited_fd_path(theirs.as_raw_fd());
+                (PendingLiveness::InheritedFd(ours, theirs), liveness_path)
+            }
+            LivenessTransport::InheritedListener => {
+                info!("Binding socket @ {} for the service", service_socket);
+                let listener =
+                    liveness::inheritable_listener(&service_socket.path, service.socket_kind())
+                        .map_err(|e| Error::BindFailed {
+                            socket: service_socket.clone(),
+                            source: e,
+                        })?;
+                let liveness_path = liveness::inherited_fd_path(listener.as_raw_fd());
+                (
+                    PendingLiveness::InheritedListener(
+                        service_socket.path.clone().into(),
+                        listener,
+                    ),
+                    liveness_path,
+                )
+            }
+        };
+
+        let liveness_token = match &pending_liveness {
+            PendingLiveness::TempSocket(_, _, token) => token.clone(),
+            PendingLiveness::InheritedFd(..) | PendingLiveness::InheritedListener(..) => None,
+        };
+        let mut launched = launch_service::<U, S>(
+            service,
+            &service_launcher,
+            executor_commandline_prefix,
+            base_context_directory,
+            service_socket,
+            &liveness_path,
+            liveness_token.as_deref(),
+        )?;
+
+        wait_for_liveness(
+            service_socket,
+            pending_liveness,
+            &mut launched,
+            service.verify_liveness_peer(),
+            liveness_timeout,
+        )?;
+        events::emit(ServiceEvent::LivenessReceived {
+            socket: service_socket.clone(),
+        });
+
+        let child_proc = match launched {
+            Launched::Process(child_proc) => child_proc,
+            // Nothing to look after - the server keeps running on its own.
+            Launched::InProcess(_) => return Ok(()),
+        };
+        match service.child_strategy() {
+            child::ChildStrategy::Custom => {
+                block_on(service.after_post_liveness_subprocess(child_proc)).map_err(|e| {
+                    Error::PostLivenessFailed {
+                        socket: service_socket.clone(),
+                        source: e,
+                    }
+                })?
+            }
+            strategy => child::apply_strategy(&strategy, child_proc, None),
+        }
+        Ok(())
+    })();
+    if let Err(e) = &started {
+        events::emit(ServiceEvent::StartFailed {
+            socket: service_socket.clone(),
+            reason: e.to_string(),
+        });
+    }
+    started?;
+    info!("Successfully received ephemeral liveness ping - trying to connect to service again.");
+    connect_raw::<U, S>(service, service_socket)
+}
+
+/// Where the liveness report of a service process that is being started will arrive - see
+/// [`LivenessTransport`].
+enum PendingLiveness {
+    /// Ephemeral listener socket, its self-cleaning path, and the liveness token the service has
+    /// to send back, if any.
+    TempSocket(UnixListener, CleanablePathBuf, Option<String>),
+    /// Our end of the socketpair, and the end the service process inherits.
+    InheritedFd(UnixStream, OwnedFd),
+    /// The service socket - cleaned up unless the service process is spawned - and its listener,
+    /// which the service process inherits.
+    InheritedListener(CleanablePathBuf, OwnedFd),
+}
+
+/// Wait for the liveness ping and status of a started service, failing early if the service
+/// exits unsuccessfully first. Optionally, pings over an ephemeral socket from processes other
+/// than the child and its descendants are ignored.
+fn wait_for_liveness(
+    service_socket: &ServiceSocket,
+    pending_liveness: PendingLiveness,
+    launched: &mut Launched<impl ServiceProcessHandle>,
+    verify_liveness_peer: bool,
+    liveness_timeout: Duration,
+) -> crate::error::Result<()> {
+    let liveness_failed = |e| Error::LivenessSocketFailed {
+        socket: service_socket.clone(),
+        source: e,
+    };
+    let timed_out = || {
+        error!(
+            "Timed out waiting for liveness ping for service @ {} after {}",
+            service_socket,
+            humantime::format_duration(liveness_timeout)
+        );
+        Error::LivenessTimeout {
+            socket: service_socket.clone(),
+            timeout: liveness_timeout,
+        }
+    };
+    let deadline = Instant::now() + liveness_timeout;
+
+    let (ephemeral_listener, _ephemeral_socket_path, liveness_token) = match pending_liveness {
+        PendingLiveness::TempSocket(listener, path, token) => (listener, path, token),
+        PendingLiveness::InheritedFd(ours, theirs) => {
+            // The service process has its own copy by now, and closes it if it crashes.
+            drop(theirs);
+            let status = read_liveness_status(service_socket, ours, deadline, 1)?;
+            // Closing the inherited end is what happens when the service crashes, so only a whole
+            // line counts as a report.
+            if !status.ends_with(b"\n") {
+                error!(
+                    "Service @ {} closed its liveness file descriptor without reporting liveness",
+                    service_socket
+                );
+                return Err(liveness_failed(ErrorKind::UnexpectedEof.into()));
+            }
+            return check_liveness_status(service_socket, &status);
+        }
+        PendingLiveness::InheritedListener(socket_path, listener) => {
+            // The service process owns the socket now - it is connectable straight away.
+            drop(listener);
+            socket_path.keep();
+            return Ok(());
+        }
+    };
+    loop {
+        match ephemeral_listener.accept() {
+            Ok((ping, _addr)) => {
+                if let Some(child_pid) = launched.pid().filter(|_| verify_liveness_peer) {
+                    if !ping_from_child(service_socket, &ping, child_pid)? {
+                        continue;
+                    }
+                }
+                // The token comes first, on a line of its own.
+                let status_lines = if liveness_token.is_some() { 2 } else { 1 };
+                let mut status =
+                    read_liveness_status(service_socket, ping, deadline, status_lines)?;
+                let Some(token) = &liveness_token else {
+                    return check_liveness_status(service_socket, &status);
+                };
+                let token_line_length = status
+                    .iter()
+                    .position(|b| *b == b'\n')
+                    .map_or(status.len(), |i| i + 1);
+                if liveness::liveness_token_matches(token, &status[..token_line_length]) {
+                    status.drain(..token_line_length);
+                    return check_liveness_status(service_socket, &status);
+                }
+                warn!(
+                    "Ignoring liveness ping without the liveness token for service @ {}",
+                    service_socket
+                );
+                continue;
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(liveness_failed(e)),
+        }
+        if let Some(e) = launched.failure(service_socket) {
+            error!("Service exited before becoming live - {}", e);
+            return Err(e);
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(timed_out());
+        }
+        std::thread::sleep(CHILD_EXIT_POLL_INTERVAL.min(remaining));
+    }
+}
+
+/// Whether a liveness ping came from the child process or one of its descendants.
+fn ping_from_child(
+    service_socket: &ServiceSocket,
+    ping: &UnixStream,
+    child_pid: u32,
+) -> crate::error::Result<bool> {
+    let peer = credentials::peer_credentials(ping).map_err(|e| Error::LivenessSocketFailed {
+        socket: service_socket.clone(),
+        source: e,
+    })?;
+    let from_child = credentials::is_descendant_of(peer.pid, child_pid).map_err(|e| {
+        Error::LivenessSocketFailed {
+            socket: service_socket.clone(),
+            source: e,
+        }
+    })?;
+    if !from_child {
+        warn!(
+            "Ignoring liveness ping from {}, which isn't child process {} or a descendant",
+            peer, child_pid
+        );
+    }
+    Ok(from_child)
+}
+
+/// Read the status lines a service sends over its liveness connection - everything up to the
+/// given number of line breaks or until it is closed - before the deadline.
+fn read_liveness_status(
+    service_socket: &ServiceSocket,
+    stream: UnixStream,
+    deadline: Instant,
+    lines: usize,
+) -> crate::error::Result<Vec<u8>> {
+    let liveness_failed = |e| Error::LivenessSocketFailed {
+        socket: service_socket.clone(),
+        source: e,
+    };
+    let timed_out = || {
+        error!(
+            "Timed out waiting for liveness status of service @ {}",
+            service_socket
+        );
+        Error::LivenessTimeout {
+            socket: service_socket.clone(),
+            timeout: deadline.saturating_duration_since(Instant::now()),
+        }
+    };
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining.is_zero() {
+        return Err(timed_out());
+    }
+    stream.set_nonblocking(false).map_err(liveness_failed)?;
+    stream
+        .set_read_timeout(Some(remaining))
+        .map_err(liveness_failed)?;
+    let mut reader = BufReader::new(stream.take(liveness::MAX_LIVENESS_STATUS_LENGTH as u64));
+    let mut status = Vec::new();
+    for _ in 0..lines {
+        match reader.read_until(b'\n', &mut status) {
+            Ok(_) if !status.ends_with(b"\n") => break,
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(timed_out())
+            }
+            Err(e) => return Err(liveness_failed(e)),
+        }
+    }
+    Ok(status)
+}
+
+fn connect_raw<U, S>(
+    service: &S,
+    service_socket: &ServiceSocket,
+) -> crate::error::Result<UnixStream>
+where
+    U: UnixSocketInterface,
+    S: Service<U> + ?Sized,
+{
+    service.security_policy().check_socket(service_socket)?;
+    info!("Attempting connection to service @ {}", service_socket);
+    let connected = match service.transport() {
+        Some(transport) => transport.connect(service_socket).map(UnixStream::from),
+        None => match service.socket_kind() {
+            SocketKind::Stream => UnixStream::connect(&service_socket.path),
+            SocketKind::SeqPacket => seqpacket::connect_std(&service_socket.path),
+        },
+    };
+    connected.map_err(|e| {
+        error!("Failed to connect to service @ {}", service_socket);
+        if e.kind() == ErrorKind::ConnectionRefused && service_socket.path.exists() {
+            Error::StaleSocket {
+                socket: service_socket.clone(),
+                source: e,
+            }
+        } else {
+            Error::ConnectFailed {
+                socket: service_socket.clone(),
+                source: e,
+            }
+        }
+    })
+}
+
+/// Check the owner of the service if it asks for that, and perform the handshake and
+/// authorization if it has them.
+fn handshake<U, S>(
+    service: &S,
+    service_socket: &ServiceSocket,
+    stream: UnixStream,
+) -> crate::error::Result<UnixStream>
+where
+    U: UnixSocketInterface,
+    S: Service<U> + ?Sized,
+{
+    if let Some(expected_uid) = service.trusted_owner_uid() {
+        let peer = credentials::peer_credentials(&stream);
+        credentials::verify_owner(service_socket, expected_uid, peer)?;
+    }
+    let protocol_versions = service.handshake_protocol_versions();
+    let authorizer = service.authorizer();
+    if protocol_versions.is_none() && authorizer.is_none() {
+        return Ok(stream);
+    }
+    let mut stream = Unblock::new(stream);
+    if let Some(protocol_versions) = protocol_versions {
+        block_on(verify_handshake::<StdThreadpoolUSocks>(
+            service_socket,
+            protocol_versions,
+            &mut stream,
+        ))?;
+    }
+    if let Some(authorizer) = authorizer {
+        block_on(authorization::authorize_outgoing::<StdThreadpoolUSocks>(
+            service_socket,
+            &*authorizer,
+            &mut stream,
+        ))?;
+    }
+    Ok(block_on(stream.into_inner()))
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
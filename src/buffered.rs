@@ -0,0 +1,278 @@
+//! Buffered connections - see [`BufferedConnection`].
+//!
+//! Reading a stream a few bytes at a time, or writing many small pieces to it, means a lot of
+//! trips through the socket interface. A [`BufferedConnection`] pairs a read buffer and a write
+//! buffer around the stream, like a [`std::io::BufReader`] and [`std::io::BufWriter`] rolled into
+//! one. This is what the `buffered` method of [`crate::declare_service`] wraps streams in.
+
+use std::{fmt::Debug, io};
+
+use crate::{DefaultUnixSocks, IoResult, UnixSocketInterface};
+
+/// Stream wrapped with a read buffer and a write buffer.
+///
+/// Written data is only sent once the write buffer fills up, or on [`Self::flush`] - remember to
+/// flush before waiting for a reply, or both sides will wait forever. Dropping the connection
+/// discards anything still in the write buffer, as it can't be flushed without waiting.
+pub struct BufferedConnection<U: UnixSocketInterface = DefaultUnixSocks> {
+    stream: U::UnixStream,
+    /// Received bytes, of which `read_buffer[read_start..read_end]` haven't been read yet.
+    read_buffer: Box<[u8]>,
+    read_start: usize,
+    read_end: usize,
+    /// Bytes written but not yet sent.
+    write_buffer: Vec<u8>,
+}
+
+impl<U: UnixSocketInterface> Debug for BufferedConnection<U>
+where
+    U::UnixStream: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BufferedConnection")
+            .field("stream", &self.stream)
+            .field("capacity", &self.capacity())
+            .field("buffered_read", &(self.read_end - self.read_start))
+            .field("buffered_write", &self.write_buffer.len())
+            .finish()
+    }
+}
+
+impl<U: UnixSocketInterface> BufferedConnection<U> {
+    /// Capacity of each of the buffers of a connection made with [`Self::new`].
+    pub const DEFAULT_CAPACITY: usize = 8 * 1024;
+
+    /// Wrap a bare stream, with buffers of [`Self::DEFAULT_CAPACITY`].
+    pub fn new(stream: U::UnixStream) -> Self {
+        Self::with_capacity(Self::DEFAULT_CAPACITY, stream)
+    }
+
+    /// Wrap a bare stream, with buffers of the given capacity - a capacity of 0 is treated as 1.
+    pub fn with_capacity(capacity: usize, stream: U::UnixStream) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            stream,
+            read_buffer: vec![0; capacity].into_boxed_slice(),
+            read_start: 0,
+            read_end: 0,
+            write_buffer: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Capacity of each of the buffers.
+    pub fn capacity(&self) -> usize {
+        self.read_buffer.len()
+    }
+
+    /// The bare stream. Reading from or writing to it directly skips the buffers.
+    pub fn get_ref(&self) -> &U::UnixStream {
+        &self.stream
+    }
+
+    /// The bare stream, mutably. Reading from or writing to it directly skips the buffers.
+    pub fn get_mut(&mut self) -> &mut U::UnixStream {
+        &mut self.stream
+    }
+
+    /// Received bytes that haven't been read yet.
+    pub fn read_buffer(&self) -> &[u8] {
+        &self.read_buffer[self.read_start..self.read_end]
+    }
+
+    /// Flush the write buffer, then take back the bare stream. Anything received but not yet
+    /// read is lost.
+    pub async fn into_inner(mut self) -> IoResult<U::UnixStream> {
+        self.flush().await?;
+        Ok(self.stream)
+    }
+
+    /// The unread part of the read buffer, receiving more first if it is empty. An empty result
+    /// means the other side closed the connection.
+    pub async fn fill_buf(&mut self) -> IoResult<&[u8]> {
+        if self.read_start == self.read_end {
+            self.read_end = U::unix_stream_read(&mut self.stream, &mut self.read_buffer).await?;
+            self.read_start = 0;
+        }
+        Ok(self.read_buffer())
+    }
+
+    /// Mark `amount` bytes from [`Self::fill_buf`] as read.
+    pub fn consume(&mut self, amount: usize) {
+        self.read_start = (self.read_start + amount).min(self.read_end);
+    }
+
+    /// Read some bytes into `buf`, returning how many. Ok(0) means the other side closed the
+    /// connection.
+    pub async fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        // Reads at least as big as the buffer may as well skip it.
+        if self.read_start == self.read_end && buf.len() >= self.capacity() {
+            return U::unix_stream_read(&mut self.stream, buf).await;
+        }
+        let available = self.fill_buf().await?;
+        let amount = available.len().min(buf.len());
+        buf[..amount].copy_from_slice(&available[..amount]);
+        self.consume(amount);
+        Ok(amount)
+    }
+
+    /// Fill all of `buf`. The connection being closed first is an
+    /// [`io::ErrorKind::UnexpectedEof`] error.
+    pub async fn read_exact(&mut self, mut buf: &mut [u8]) -> IoResult<()> {
+        while !buf.is_empty() {
+            match self.read(buf).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => buf = &mut buf[n..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Read bytes up to and including `delimiter` onto the end of `buf`, returning how many. This
+    /// stops short of the delimiter if the other side closes the connection, and Ok(0) means it
+    /// was closed before anything was read.
+    pub async fn read_until(&mut self, delimiter: u8, buf: &mut Vec<u8>) -> IoResult<usize> {
+        let mut total = 0;
+        loop {
+            let (found, used) = {
+                let available = self.fill_buf().await?;
+                match available.iter().position(|b| *b == delimiter) {
+                    Some(position) => {
+                        buf.extend_from_slice(&available[..=position]);
+                        (true, position + 1)
+                    }
+                    None => {
+                        buf.extend_from_slice(available);
+                        (available.is_empty(), available.len())
+                    }
+                }
+            };
+            self.consume(used);
+            total += used;
+            if found {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Read a line, including the newline, onto the end of `buf`, returning how many bytes were
+    /// read - see [`Self::read_until`]. Invalid UTF-8 is an [`io::ErrorKind::InvalidData`] error,
+    /// in which case `buf` is left alone.
+    pub async fn read_line(&mut self, buf: &mut String) -> IoResult<usize> {
+        let mut line = Vec::new();
+        let amount = self.read_until(b'\n', &mut line).await?;
+        let line =
+            String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        buf.push_str(&line);
+        Ok(amount)
+    }
+
+    /// Write some bytes from `buf`, returning how many. These may only be buffered.
+    pub async fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if self.write_buffer.len() + buf.len() > self.capacity() {
+            self.flush().await?;
+        }
+        // Writes at least as big as the buffer may as well skip it.
+        if buf.len() >= self.capacity() {
+            return U::unix_stream_write(&mut self.stream, buf).await;
+        }
+        self.write_buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    /// Write all of `buf`, some of which may only be buffered.
+    pub async fn write_all(&mut self, buf: &[u8]) -> IoResult<()> {
+        if self.write_buffer.len() + buf.len() > self.capacity() {
+            self.flush().await?;
+        }
+        if buf.len() >= self.capacity() {
+            return U::unix_stream_write_all(&mut self.stream, buf).await;
+        }
+        self.write_buffer.extend_from_slice(buf);
+        Ok(())
+    }
+
+    /// Send everything in the write buffer.
+    pub async fn flush(&mut self) -> IoResult<()> {
+        if !self.write_buffer.is_empty() {
+            U::unix_stream_write_all(&mut self.stream, &self.write_buffer).await?;
+            self.write_buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Flush the write buffer, then shut the stream down - see
+    /// [`UnixSocketInterface::unix_stream_shutdown`].
+    pub async fn shutdown(&mut self) -> IoResult<()> {
+        self.flush().await?;
+        U::unix_stream_shutdown(&mut self.stream).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+    };
+
+    use blocking::Unblock;
+    use futures_lite::future::block_on;
+
+    use super::*;
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn lines_are_read_across_fills() {
+        let (mut raw_client, b) = UnixStream::pair().unwrap();
+        let mut server =
+            BufferedConnection::<StdThreadpoolUSocks>::with_capacity(4, Unblock::new(b));
+        raw_client.write_all(b"hello\nworld\nrest").unwrap();
+        drop(raw_client);
+        block_on(async {
+            let mut line = String::new();
+            assert_eq!(server.read_line(&mut line).await.unwrap(), 6);
+            assert_eq!(server.read_line(&mut line).await.unwrap(), 6);
+            assert_eq!(line, "hello\nworld\n");
+            let mut rest = Vec::new();
+            assert_eq!(server.read_until(b'\n', &mut rest).await.unwrap(), 4);
+            assert_eq!(rest, b"rest");
+            assert_eq!(server.read_until(b'\n', &mut rest).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    pub fn writes_are_sent_on_flush() {
+        let (a, mut raw_server) = UnixStream::pair().unwrap();
+        let mut client = BufferedConnection::<StdThreadpoolUSocks>::new(Unblock::new(a));
+        raw_server.set_nonblocking(true).unwrap();
+        let mut received = [0u8; 5];
+        block_on(async {
+            client.write_all(b"ping").await.unwrap();
+            client.write_all(b"\n").await.unwrap();
+        });
+        assert_eq!(
+            raw_server.read(&mut received).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        block_on(client.flush()).unwrap();
+        raw_server.set_nonblocking(false).unwrap();
+        raw_server.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping\n");
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,204 @@
+//! Operations on all the services of a bundle at once - the `start_all`, `health_check_all` and
+//! `stop_all` methods generated by [`crate::declare_service_bundle`] - and the [`BundleManifest`]
+//! produced by its `describe` method.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    future::Future,
+    ops::RangeInclusive,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{Service, UnixSocketInterface};
+
+/// Description of the services of a bundle and how they fit together, for tooling and
+/// documentation generators that want to know the service topology without connecting to
+/// anything. Produced by the `describe` method generated by [`crate::declare_service_bundle`].
+///
+/// With the `manifest` feature, this can be serialized and deserialized with serde.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "manifest", derive(serde::Serialize, serde::Deserialize))]
+pub struct BundleManifest {
+    /// Name of the bundle type.
+    pub bundle: String,
+    /// The services of the bundle, in the order they were declared.
+    pub services: Vec<ServiceManifest>,
+}
+
+impl BundleManifest {
+    /// The service with the given name, if it is part of the bundle.
+    pub fn service(&self, name: &str) -> Option<&ServiceManifest> {
+        self.services.iter().find(|service| service.name == name)
+    }
+}
+
+/// Description of a single service of a [`BundleManifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "manifest", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServiceManifest {
+    /// Name of the service type.
+    pub name: String,
+    /// Name of the service socket in the base context directory - see [`Service::socket_name`].
+    /// Names that aren't UTF-8 are converted lossily.
+    pub socket_name: String,
+    /// Command line that starts the service, without any executor prefix - see
+    /// [`Service::command_line`]. `None` for services that aren't started by a command. Parts
+    /// that aren't UTF-8 are converted lossily.
+    pub command_line: Option<Vec<String>>,
+    /// Names of the services of the bundle this one requires.
+    pub dependencies: Vec<String>,
+    /// See [`Service::handshake_protocol_versions`].
+    pub protocol_versions: Option<RangeInclusive<u32>>,
+    /// See [`Service::service_version`].
+    pub service_version: Option<String>,
+}
+
+impl ServiceManifest {
+    /// Describe a service, with the given name and dependencies.
+    pub fn describe<S: Service<U>, U: UnixSocketInterface>(
+        name: &str,
+        service: &S,
+        dependencies: &[&str],
+    ) -> Self {
+        let lossy = |part: &OsStr| part.to_string_lossy().into_owned();
+        Self {
+            name: name.to_owned(),
+            socket_name: lossy(&service.socket_name()),
+            command_line: service
+                .command_line()
+                .map(|command_line| command_line.iter().map(|part| lossy(part)).collect()),
+            dependencies: dependencies.iter().map(|&name| name.to_owned()).collect(),
+            protocol_versions: service.handshake_protocol_versions(),
+            service_version: service.service_version().map(str::to_owned),
+        }
+    }
+}
+
+/// Future of an operation on a single service of a bundle.
+pub type BundleOperation<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// Run the operations on the services of a bundle concurrently, with at most `parallelism` of
+/// them in progress at once (and at least one), producing the result for each key - usually the
+/// service name.
+///
+/// Operations are started in order, and run on the current task - no runtime is needed.
+pub async fn run_bounded<'a, K: Ord, T>(
+    operations: Vec<(K, BundleOperation<'a, T>)>,
+    parallelism: usize,
+) -> BTreeMap<K, T> {
+    BoundedJoin {
+        pending: operations.into_iter(),
+        running: Vec::new(),
+        results: BTreeMap::new(),
+        parallelism: parallelism.max(1),
+    }
+    .await
+}
+
+/// Future behind [`run_bounded`].
+struct BoundedJoin<'a, K, T> {
+    pending: std::vec::IntoIter<(K, BundleOperation<'a, T>)>,
+    running: Vec<(K, BundleOperation<'a, T>)>,
+    results: BTreeMap<K, T>,
+    parallelism: usize,
+}
+
+// The operations are boxed, and nothing else is ever pinned in place.
+impl<K, T> Unpin for BoundedJoin<'_, K, T> {}
+
+impl<K: Ord, T> Future for BoundedJoin<'_, K, T> {
+    type Output = BTreeMap<K, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            while this.running.len() < this.parallelism {
+                match this.pending.next() {
+                    Some(operation) => this.running.push(operation),
+                    None => break,
+                }
+            }
+            if this.running.is_empty() {
+                return Poll::Ready(std::mem::take(&mut this.results));
+            }
+            let mut finished_any = false;
+            let mut index = 0;
+            while index < this.running.len() {
+                match this.running[index].1.as_mut().poll(cx) {
+                    Poll::Ready(result) => {
+                        let (name, _) = this.running.swap_remove(index);
+                        this.results.insert(name, result);
+                        finished_any = true;
+                    }
+                    Poll::Pending => index += 1,
+                }
+            }
+            // If any finished, go round again to start the next operations in the freed up slots.
+            if !finished_any {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, time::Duration};
+
+    use futures_lite::future::block_on;
+
+    use super::{run_bounded, BundleOperation};
+    use crate::timefut;
+
+    #[test]
+    pub fn run_bounded_test() {
+        let in_progress = Cell::new(0);
+        let max_in_progress = Cell::new(0);
+        let operation = |value: u32| -> BundleOperation<'_, u32> {
+            let in_progress = &in_progress;
+            let max_in_progress = &max_in_progress;
+            Box::pin(async move {
+                in_progress.set(in_progress.get() + 1);
+                max_in_progress.set(max_in_progress.get().max(in_progress.get()));
+                timefut::sleep(Duration::from_millis(10)).await;
+                in_progress.set(in_progress.get() - 1);
+                value * 2
+            })
+        };
+        let results = block_on(run_bounded(
+            vec![
+                ("a", operation(1)),
+                ("b", operation(2)),
+                ("c", operation(3)),
+                ("d", operation(4)),
+                ("e", operation(5)),
+            ],
+            2,
+        ));
+        assert_eq!(
+            results.into_iter().collect::<Vec<_>>(),
+            vec![("a", 2), ("b", 4), ("c", 6), ("d", 8), ("e", 10)]
+        );
+        assert_eq!(max_in_progress.get(), 2);
+
+        assert!(block_on(run_bounded::<&str, ()>(Vec::new(), 0)).is_empty());
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
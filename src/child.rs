@@ -0,0 +1,314 @@
+//! Handles on the processes of services - see [`ServiceProcessHandle`] - and ready-made ways of
+//! dealing with them once they are started - see [`ChildStrategy`] and
+//! [`crate::ServiceStartable::child_strategy`].
+
+use std::{
+    fmt::Debug,
+    io::{self, Read},
+    process::{Child, ExitStatus},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use tracing::{info, warn};
+
+use crate::{task, TaskSpawner};
+
+/// Handle on a started service process, as returned by
+/// [`crate::ServiceStartable::run_service_command_raw`]. This is usually a [`Child`], but
+/// services launched some other way - by forking, in a container, or on another machine - can
+/// hand out whatever tracks them.
+pub trait ServiceProcessHandle: Debug + Send + 'static {
+    /// Process id of the service, if it is a process on this machine. Liveness pings are checked
+    /// against it with [`crate::ServiceStartable::verify_liveness_peer`], and it is what gets
+    /// signalled to terminate the service.
+    fn pid(&self) -> Option<u32>;
+
+    /// The exit status of the service, if it has exited - without waiting for it.
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>>;
+
+    /// Kill the service straight away.
+    fn kill(&mut self) -> io::Result<()>;
+
+    /// Ask the service to shut down cleanly. The default sends `SIGTERM` to [`Self::pid`], or
+    /// kills the service if it has none.
+    fn terminate(&mut self) -> io::Result<()> {
+        match self.pid().and_then(|pid| i32::try_from(pid).ok()) {
+            Some(pid) => kill(Pid::from_raw(pid), Signal::SIGTERM).map_err(io::Error::from),
+            None => self.kill(),
+        }
+    }
+
+    /// Block until the service exits. The default polls [`Self::try_wait`].
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(status);
+            }
+            std::thread::sleep(Duration::from_millis(25));
+        }
+    }
+
+    /// Take the piped standard output of the service, to forward it as tracing events - see
+    /// [`crate::spawn::OutputTarget::Tracing`]. The default has none.
+    fn take_stdout(&mut self) -> Option<Box<dyn Read + Send>> {
+        None
+    }
+
+    /// Take the piped standard error of the service, like [`Self::take_stdout`].
+    fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>> {
+        None
+    }
+}
+
+impl ServiceProcessHandle for Child {
+    fn pid(&self) -> Option<u32> {
+        Some(self.id())
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        Child::try_wait(self)
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        Child::kill(self)
+    }
+
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        Child::wait(self)
+    }
+
+    fn take_stdout(&mut self) -> Option<Box<dyn Read + Send>> {
+        self.stdout.take().map(|stdout| Box::new(stdout) as _)
+    }
+
+    fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>> {
+        self.stderr.take().map(|stderr| Box::new(stderr) as _)
+    }
+}
+
+impl ServiceProcessHandle for Box<dyn ServiceProcessHandle> {
+    fn pid(&self) -> Option<u32> {
+        (**self).pid()
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        (**self).try_wait()
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        (**self).kill()
+    }
+
+    fn terminate(&mut self) -> io::Result<()> {
+        (**self).terminate()
+    }
+
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        (**self).wait()
+    }
+
+    fn take_stdout(&mut self) -> Option<Box<dyn Read + Send>> {
+        (**self).take_stdout()
+    }
+
+    fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>> {
+        (**self).take_stderr()
+    }
+}
+
+/// Description of a service process for logs - its pid, if it has one.
+pub(crate) fn describe(handle: &impl ServiceProcessHandle) -> String {
+    handle
+        .pid()
+        .map_or_else(|| "without a pid".to_owned(), |pid| pid.to_string())
+}
+
+/// What to do with the process of a service once it has started and passed its liveness check.
+#[derive(Debug, Clone, Default)]
+pub enum ChildStrategy {
+    /// Hand it to [`crate::ServiceStartable::after_post_liveness_subprocess`].
+    #[default]
+    Custom,
+    /// Leave it running as an orphan, without waiting on it - it lingers as a zombie if it exits
+    /// before the process that started it.
+    Detach,
+    /// Wait on it in a task spawned with the task spawner, so it is reaped once it exits.
+    Reap(Arc<dyn TaskSpawner>),
+    /// Keep it in a [`ChildGuard`] owned by the [`crate::ReifiedService`] that started it, so it
+    /// is terminated once that is dropped. Services started without a [`crate::ReifiedService`]
+    /// to own them are detached instead.
+    KillOnDrop,
+}
+
+/// Holds on to the process of a service, terminating it when dropped - first with
+/// [`ServiceProcessHandle::terminate`], so the server can shut down cleanly, then with
+/// [`ServiceProcessHandle::kill`] if it hasn't exited within [`ChildGuard::GRACE_PERIOD`].
+/// Dropping this blocks until the process is gone.
+#[derive(Debug)]
+pub struct ChildGuard(Option<Box<dyn ServiceProcessHandle>>);
+
+impl ChildGuard {
+    /// How long to wait for the process to exit after `SIGTERM`, before sending `SIGKILL`.
+    pub const GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+    /// Guard this process.
+    pub fn new(child: impl ServiceProcessHandle) -> Self {
+        Self(Some(Box::new(child)))
+    }
+
+    /// Process id of the guarded process, if it has one.
+    pub fn pid(&self) -> Option<u32> {
+        self.0.as_ref().and_then(|child| child.pid())
+    }
+
+    /// The exit status of the guarded process, if it has exited - without waiting for it.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.0
+            .as_mut()
+            .expect("only taken when consumed")
+            .try_wait()
+    }
+
+    /// Stop guarding the process, leaving it running.
+    pub fn into_inner(mut self) -> Box<dyn ServiceProcessHandle> {
+        self.0.take().expect("only taken when consumed")
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let Some(mut child) = self.0.take() else {
+            return;
+        };
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        info!("Terminating service process {}", describe(&child));
+        let _ = child.terminate();
+        let started = Instant::now();
+        while started.elapsed() < Self::GRACE_PERIOD {
+            match child.try_wait() {
+                Ok(None) => std::thread::sleep(Duration::from_millis(10)),
+                _ => return,
+            }
+        }
+        warn!(
+            "Service process {} didn't exit after SIGTERM - killing it",
+            describe(&child)
+        );
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Deal with the process of a started service according to the strategy - except for
+/// [`ChildStrategy::Custom`], which is up to the caller. Guards for [`ChildStrategy::KillOnDrop`]
+/// are pushed onto `owner`, if there is one.
+pub(crate) fn apply_strategy(
+    strategy: &ChildStrategy,
+    child: impl ServiceProcessHandle,
+    owner: Option<&std::sync::Mutex<Vec<ChildGuard>>>,
+) {
+    match (strategy, owner) {
+        (ChildStrategy::Custom | ChildStrategy::Detach, _) => drop(child),
+        (ChildStrategy::Reap(spawner), _) => task::reap_child(&**spawner, child),
+        (ChildStrategy::KillOnDrop, Some(owner)) => owner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(ChildGuard::new(child)),
+        (ChildStrategy::KillOnDrop, None) => {
+            warn!(
+                "Nothing to tie service process {} to - leaving it running",
+                describe(&child)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        process::{Child, Command, ExitStatus},
+        time::{Duration, Instant},
+    };
+
+    use super::{ChildGuard, ServiceProcessHandle};
+
+    /// Handle relying on the default methods of [`ServiceProcessHandle`].
+    #[derive(Debug)]
+    struct MinimalHandle(Child);
+
+    impl ServiceProcessHandle for MinimalHandle {
+        fn pid(&self) -> Option<u32> {
+            Some(self.0.id())
+        }
+
+        fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+            self.0.try_wait()
+        }
+
+        fn kill(&mut self) -> io::Result<()> {
+            self.0.kill()
+        }
+    }
+
+    #[test]
+    pub fn default_handle_methods_test() {
+        let mut handle = MinimalHandle(Command::new("sleep").arg("30").spawn().unwrap());
+        assert_eq!(handle.try_wait().unwrap(), None);
+        handle.terminate().unwrap();
+        assert!(!handle.wait().unwrap().success());
+        assert!(handle.take_stdout().is_none());
+    }
+
+    #[test]
+    pub fn child_guard_test() {
+        // Terminated straight away by SIGTERM.
+        let guard = ChildGuard::new(Command::new("sleep").arg("30").spawn().unwrap());
+        let started = Instant::now();
+        drop(guard);
+        assert!(started.elapsed() < ChildGuard::GRACE_PERIOD);
+
+        // Killed once the grace period is up.
+        let guard = ChildGuard::new(
+            Command::new("sh")
+                .args(["-c", "trap '' TERM; exec sleep 30"])
+                .spawn()
+                .unwrap(),
+        );
+        // Give the shell time to ignore SIGTERM.
+        std::thread::sleep(Duration::from_millis(200));
+        let started = Instant::now();
+        drop(guard);
+        assert!(started.elapsed() >= ChildGuard::GRACE_PERIOD);
+
+        // Left alone when no longer guarded.
+        let mut child =
+            ChildGuard::new(Command::new("sleep").arg("30").spawn().unwrap()).into_inner();
+        assert_eq!(child.try_wait().unwrap(), None);
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
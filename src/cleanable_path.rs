@@ -17,6 +17,12 @@ impl CleanablePathBuf {
     pub fn new(p: PathBuf) -> Self {
         Self(p)
     }
+
+    /// Stop cleaning the path up, handing it back.
+    pub fn keep(mut self) -> PathBuf {
+        // Drop still runs, but removing an empty path does nothing.
+        std::mem::take(&mut self.0)
+    }
 }
 
 impl From<PathBuf> for CleanablePathBuf {
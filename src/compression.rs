@@ -0,0 +1,329 @@
+//! Compressed connections - requires the `compression-zstd` or `compression-lz4` feature. See
+//! [`CompressedConnection`].
+//!
+//! Services shuttling large blobs between processes - source code, images - can trade some CPU
+//! for less copying through the kernel. When a connection is wrapped, both sides send the
+//! [`Compression`]s they were built with, and each picks the best one they have in common - so
+//! clients and servers built with different features still agree, falling back to
+//! [`Compression::None`]. This is what the `compressed` method of [`crate::declare_service`]
+//! wraps streams in.
+//!
+//! Written data is sent in blocks of up to [`MAX_BLOCK_SIZE`] bytes, each compressed on its own.
+//! Every block starts with a header of the [`Compression`] used for it, its length once
+//! decompressed and its length as sent, both as big-endian `u32`s. Blocks that don't get smaller
+//! are sent as they are.
+
+use std::{fmt::Debug, io};
+
+use crate::{
+    split::{self, ReadHalf, WriteHalf},
+    DefaultUnixSocks, IoResult, UnixSocketInterface,
+};
+
+/// Largest amount of data compressed as one block.
+pub const MAX_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Length of a block header.
+const HEADER_LEN: usize = 9;
+
+/// Way a connection is compressed, in order of preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum Compression {
+    /// Sent as is.
+    None = 0,
+    /// lz4 - fast, but doesn't compress as well. Needs the `compression-lz4` feature.
+    Lz4 = 1,
+    /// zstd - compresses better than lz4, at some cost in speed. Needs the `compression-zstd`
+    /// feature.
+    Zstd = 2,
+}
+
+impl Compression {
+    /// The compressions this was built with, best first.
+    pub fn available() -> Vec<Compression> {
+        let mut available = Vec::new();
+        if cfg!(feature = "compression-zstd") {
+            available.push(Compression::Zstd);
+        }
+        if cfg!(feature = "compression-lz4") {
+            available.push(Compression::Lz4);
+        }
+        available.push(Compression::None);
+        available
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        [Compression::None, Compression::Lz4, Compression::Zstd]
+            .into_iter()
+            .find(|compression| *compression as u8 == byte)
+    }
+
+    fn compress(self, data: &[u8]) -> IoResult<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "compression-lz4")]
+            Compression::Lz4 => Ok(lz4_flex::block::compress(data)),
+            #[cfg(feature = "compression-zstd")]
+            Compression::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL),
+            #[allow(unreachable_patterns)]
+            unavailable => Err(unavailable.not_built_in()),
+        }
+    }
+
+    fn decompress(self, data: &[u8], decompressed_len: usize) -> IoResult<Vec<u8>> {
+        let decompressed = match self {
+            Compression::None => data.to_vec(),
+            #[cfg(feature = "compression-lz4")]
+            Compression::Lz4 => lz4_flex::block::decompress(data, decompressed_len)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            #[cfg(feature = "compression-zstd")]
+            Compression::Zstd => zstd::bulk::decompress(data, decompressed_len)?,
+            #[allow(unreachable_patterns)]
+            unavailable => return Err(unavailable.not_built_in()),
+        };
+        if decompressed.len() != decompressed_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Block decompressed to {} bytes rather than {}",
+                    decompressed.len(),
+                    decompressed_len
+                ),
+            ));
+        }
+        Ok(decompressed)
+    }
+
+    #[allow(dead_code)]
+    fn not_built_in(self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} compression isn't built in", self),
+        )
+    }
+}
+
+/// Connection compressing what is written to it, and decompressing what is read from it.
+pub struct CompressedConnection<U: UnixSocketInterface = DefaultUnixSocks> {
+    // Split, so writing after the negotiation doesn't wait for a read of the socket interface.
+    read_half: ReadHalf<U>,
+    write_half: WriteHalf<U>,
+    compression: Compression,
+    /// Decompressed data not read yet, from `read_position` on.
+    read_buffer: Vec<u8>,
+    read_position: usize,
+}
+
+impl<U: UnixSocketInterface> Debug for CompressedConnection<U>
+where
+    U::UnixStream: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompressedConnection")
+            .field("read_half", &self.read_half)
+            .field("write_half", &self.write_half)
+            .field("compression", &self.compression)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<U: UnixSocketInterface> CompressedConnection<U> {
+    /// Agree on a compression with the other side - which must be doing the same - and wrap the
+    /// stream with it.
+    pub async fn negotiate(stream: U::UnixStream) -> IoResult<Self> {
+        let (mut read_half, mut write_half) = split::split::<U>(stream).await?;
+        let ours = Compression::available();
+        let mut offer = vec![ours.len() as u8];
+        offer.extend(ours.iter().map(|compression| *compression as u8));
+        write_half.write_all(&offer).await?;
+
+        let mut count = [0u8];
+        read_half.read_exact(&mut count).await?;
+        let mut theirs = vec![0u8; count[0].into()];
+        read_half.read_exact(&mut theirs).await?;
+        // Both sides pick the same way, so they agree without another round trip.
+        let compression = ours
+            .into_iter()
+            .find(|compression| theirs.contains(&(*compression as u8)))
+            .unwrap_or(Compression::None);
+        Ok(Self::from_halves(compression, read_half, write_half))
+    }
+
+    /// Wrap a stream whose other side is known to use the given compression, without
+    /// negotiating.
+    pub async fn with_compression(
+        compression: Compression,
+        stream: U::UnixStream,
+    ) -> IoResult<Self> {
+        let (read_half, write_half) = split::split::<U>(stream).await?;
+        Ok(Self::from_halves(compression, read_half, write_half))
+    }
+
+    fn from_halves(
+        compression: Compression,
+        read_half: ReadHalf<U>,
+        write_half: WriteHalf<U>,
+    ) -> Self {
+        Self {
+            read_half,
+            write_half,
+            compression,
+            read_buffer: Vec::new(),
+            read_position: 0,
+        }
+    }
+
+    /// The compression used for what is written.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Write all of `buf`, compressed.
+    pub async fn write_all(&mut self, buf: &[u8]) -> IoResult<()> {
+        for data in buf.chunks(MAX_BLOCK_SIZE) {
+            let compressed = self.compression.compress(data)?;
+            let (compression, payload) = match compressed.len() < data.len() {
+                true => (self.compression, &compressed[..]),
+                false => (Compression::None, data),
+            };
+            let mut block = Vec::with_capacity(HEADER_LEN + payload.len());
+            block.push(compression as u8);
+            block.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            block.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            block.extend_from_slice(payload);
+            self.write_half.write_all(&block).await?;
+        }
+        Ok(())
+    }
+
+    /// Read some decompressed bytes into `buf`, returning how many. Ok(0) means the other side
+    /// closed the connection.
+    pub async fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.read_position == self.read_buffer.len() {
+            match self.read_block().await? {
+                Some(block) => {
+                    self.read_buffer = block;
+                    self.read_position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let available = &self.read_buffer[self.read_position..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.read_position += read;
+        Ok(read)
+    }
+
+    /// Fill all of `buf` with decompressed bytes.
+    pub async fn read_exact(&mut self, mut buf: &mut [u8]) -> IoResult<()> {
+        while !buf.is_empty() {
+            match self.read(buf).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                read => buf = &mut buf[read..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the other side nothing more is coming - see [`WriteHalf::shutdown`].
+    pub async fn shutdown(&mut self) -> IoResult<()> {
+        self.write_half.shutdown().await
+    }
+
+    /// The next decompressed block, or `None` if the other side closed the connection between
+    /// blocks.
+    async fn read_block(&mut self) -> IoResult<Option<Vec<u8>>> {
+        let mut header = [0u8; HEADER_LEN];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.read_half.read(&mut header[filled..]).await? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => filled += n,
+            }
+        }
+        let [compression, d0, d1, d2, d3, p0, p1, p2, p3] = header;
+        let compression = Compression::from_byte(compression).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown compression {}", compression),
+            )
+        })?;
+        let decompressed_len = u32::from_be_bytes([d0, d1, d2, d3]) as usize;
+        let payload_len = u32::from_be_bytes([p0, p1, p2, p3]) as usize;
+        // Compressed blocks are only sent when they got smaller.
+        if decompressed_len > MAX_BLOCK_SIZE || payload_len > decompressed_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Malformed block of {} bytes decompressing to {}",
+                    payload_len, decompressed_len
+                ),
+            ));
+        }
+        let mut payload = vec![0u8; payload_len];
+        self.read_half.read_exact(&mut payload).await?;
+        compression.decompress(&payload, decompressed_len).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use blocking::Unblock;
+    use futures_lite::future::{block_on, zip};
+
+    use super::{CompressedConnection, Compression};
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn compressed_round_trip_test() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (client, server) = block_on(zip(
+            CompressedConnection::<StdThreadpoolUSocks>::negotiate(Unblock::new(a)),
+            CompressedConnection::<StdThreadpoolUSocks>::negotiate(Unblock::new(b)),
+        ));
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        assert_eq!(client.compression(), Compression::available()[0]);
+        assert_eq!(server.compression(), client.compression());
+
+        // Compressible and bigger than a block, then too small to shrink.
+        let blob = "source code ".repeat(20_000).into_bytes();
+        block_on(zip(
+            async {
+                client.write_all(&blob).await.unwrap();
+                client.write_all(b"!").await.unwrap();
+                client.shutdown().await.unwrap();
+            },
+            async {
+                let mut received = vec![0; blob.len() + 1];
+                server.read_exact(&mut received).await.unwrap();
+                assert_eq!(received[..blob.len()], blob);
+                assert_eq!(received[blob.len()], b'!');
+                assert_eq!(server.read(&mut [0; 1]).await.unwrap(), 0);
+            },
+        ));
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,284 @@
+//! Options for connecting to services in flaky environments, where a single attempt at starting a
+//! service might not be enough - see [`crate::ReifiedService::connect_with_options`].
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{Error, TaskSpawner};
+
+/// How to connect to a service - how long to wait for it to become live when starting it, how
+/// long to wait for a connection to be set up, and how to retry failed attempts with exponential
+/// backoff.
+///
+/// By default, no retries are made, which is the behaviour of [`crate::ReifiedService::connect`].
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    liveness_timeout: Duration,
+    connect_timeout: Option<Duration>,
+    retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    deadline: Option<Duration>,
+    task_spawner: Option<Arc<dyn TaskSpawner>>,
+}
+
+impl PartialEq for ConnectOptions {
+    fn eq(&self, other: &Self) -> bool {
+        let same_spawner = match (&self.task_spawner, &other.task_spawner) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        };
+        self.liveness_timeout == other.liveness_timeout
+            && self.connect_timeout == other.connect_timeout
+            && self.retries == other.retries
+            && self.initial_backoff == other.initial_backoff
+            && self.max_backoff == other.max_backoff
+            && self.jitter == other.jitter
+            && self.deadline == other.deadline
+            && same_spawner
+    }
+}
+
+impl ConnectOptions {
+    /// Default time to wait for a service to become live when starting it - see
+    /// [`crate::ServiceStartable::liveness_timeout`].
+    pub const DEFAULT_LIVENESS_TIMEOUT: Duration = Duration::from_secs(5);
+    /// Default delay before the first retry.
+    pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+    /// Default cap on the delay between retries.
+    pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+    /// Connect with the given liveness timeout for each attempt at starting the service, and no
+    /// retries.
+    pub fn new(liveness_timeout: Duration) -> Self {
+        Self {
+            liveness_timeout,
+            connect_timeout: None,
+            retries: 0,
+            initial_backoff: Self::DEFAULT_INITIAL_BACKOFF,
+            max_backoff: Self::DEFAULT_MAX_BACKOFF,
+            jitter: 0.0,
+            deadline: None,
+            task_spawner: None,
+        }
+    }
+
+    /// Wait this long for a service to become live when starting it, instead.
+    pub fn with_liveness_timeout(mut self, liveness_timeout: Duration) -> Self {
+        self.liveness_timeout = liveness_timeout;
+        self
+    }
+
+    /// Give up on setting up a connection - the handshake and wrapping of the stream - after this
+    /// long, in case the server accepts connections but never gets round to them.
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Retry failed connection attempts up to this many times.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Wait this long before the first retry. The delay doubles with every retry after that.
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Never wait longer than this between retries.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Randomly shorten every delay between retries by up to this fraction of it (clamped to
+    /// `0.0..=1.0`), so processes that failed at the same time don't all retry in lockstep.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Give up after this long in total. No attempt is started after the deadline, and the
+    /// liveness timeout of each attempt is cut short so it doesn't run past the deadline.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Wait on the processes of services started while connecting in tasks spawned with this, so
+    /// they are reaped once they exit rather than lingering as zombies. This takes the place of
+    /// the [`crate::ServiceStartable::child_strategy`] of the service, and of
+    /// [`crate::ServiceStartable::after_post_liveness_subprocess`], which isn't called.
+    pub fn with_task_spawner(mut self, task_spawner: Arc<dyn TaskSpawner>) -> Self {
+        self.task_spawner = Some(task_spawner);
+        self
+    }
+
+    /// Liveness timeout for each attempt at starting the service.
+    pub fn liveness_timeout(&self) -> Duration {
+        self.liveness_timeout
+    }
+
+    /// Time limit on setting up each connection, if any.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.connect_timeout
+    }
+
+    /// Maximum number of retries after the first attempt.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Total deadline for connecting, if any.
+    pub fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+
+    /// Spawner for the background tasks of connecting, if any.
+    pub fn task_spawner(&self) -> Option<&Arc<dyn TaskSpawner>> {
+        self.task_spawner.as_ref()
+    }
+
+    /// Delay before the given retry (counting from 0), without jitter applied.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        exponential_backoff(self.initial_backoff, self.max_backoff, retry)
+    }
+
+    /// Delay before the given retry (counting from 0), with jitter applied.
+    pub(crate) fn jittered_backoff(&self, retry: u32) -> Duration {
+        use nanorand::rand::{chacha::ChaCha20, Rng};
+        let backoff = self.backoff(retry);
+        if self.jitter == 0.0 {
+            return backoff;
+        }
+        let unit = ChaCha20::new().generate::<u32>() as f64 / u32::MAX as f64;
+        backoff.mul_f64(1.0 - self.jitter * unit)
+    }
+}
+
+/// Delay before the given retry (counting from 0), doubling from `initial_backoff` with every
+/// retry up to `max_backoff`.
+pub(crate) fn exponential_backoff(
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry: u32,
+) -> Duration {
+    initial_backoff
+        .checked_mul(1u32.checked_shl(retry).unwrap_or(u32::MAX))
+        .unwrap_or(Duration::MAX)
+        .min(max_backoff)
+}
+
+/// Parse a duration written like `5s` or `500ms` in [`crate::declare_service`] - a whole number
+/// followed by one of the units `ns`, `us`, `ms`, `s`, `m` (or `min`), `h` or `d`.
+///
+/// The macro evaluates this as a constant, so a mistake in the service declaration - like `5x` -
+/// fails to compile, rather than panicking whenever the service is connected to.
+#[doc(hidden)]
+pub const fn declared_duration(declared: &str) -> Duration {
+    let bytes = declared.as_bytes();
+    let mut count: u64 = 0;
+    let mut digits = 0;
+    while digits < bytes.len() && bytes[digits].is_ascii_digit() {
+        let digit = (bytes[digits] - b'0') as u64;
+        count = match count.checked_mul(10) {
+            Some(tens) if tens <= u64::MAX - digit => tens + digit,
+            _ => panic!("Declared duration is too long"),
+        };
+        digits += 1;
+    }
+    if digits == 0 {
+        panic!("Declared durations start with a whole number, like 5s or 500ms");
+    }
+    let seconds_per_unit = match bytes.split_at(digits).1 {
+        b"ns" => return Duration::from_nanos(count),
+        b"us" => return Duration::from_micros(count),
+        b"ms" => return Duration::from_millis(count),
+        b"s" => 1,
+        b"m" | b"min" => 60,
+        b"h" => 60 * 60,
+        b"d" => 24 * 60 * 60,
+        _ => panic!("Declared durations end with one of the units ns, us, ms, s, m, min, h or d"),
+    };
+    match count.checked_mul(seconds_per_unit) {
+        Some(seconds) => Duration::from_secs(seconds),
+        None => panic!("Declared duration is too long"),
+    }
+}
+
+/// Whether a failed connection attempt is worth retrying. Connecting to the wrong service, to an
+/// incompatible version of it, to one run by an untrusted user or that doesn't authorize us, or
+/// over an insecure path won't fix itself - and neither will a tripped circuit breaker any time soon.
+pub(crate) fn is_retryable(error: &Error) -> bool {
+    !matches!(
+        error,
+        Error::WrongService { .. }
+            | Error::VersionMismatch { .. }
+            | Error::UntrustedPeer { .. }
+            | Error::Unauthorized { .. }
+            | Error::InsecureSocketPath { .. }
+            | Error::CircuitOpen { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{declared_duration, ConnectOptions};
+
+    #[test]
+    pub fn backoff_test() {
+        let options = ConnectOptions::new(Duration::from_secs(1))
+            .with_initial_backoff(Duration::from_millis(100))
+            .with_max_backoff(Duration::from_secs(1));
+        assert_eq!(options.backoff(0), Duration::from_millis(100));
+        assert_eq!(options.backoff(1), Duration::from_millis(200));
+        assert_eq!(options.backoff(3), Duration::from_millis(800));
+        assert_eq!(options.backoff(4), Duration::from_secs(1));
+        assert_eq!(options.backoff(100), Duration::from_secs(1));
+        assert_eq!(options.jittered_backoff(2), Duration::from_millis(400));
+
+        let jittered = options.with_jitter(0.5);
+        for retry in 0..10 {
+            let backoff = jittered.jittered_backoff(retry);
+            assert!(backoff <= jittered.backoff(retry));
+            assert!(backoff >= jittered.backoff(retry) / 2);
+        }
+    }
+
+    #[test]
+    pub fn declared_duration_test() {
+        const DECLARED: Duration = declared_duration("20s");
+        assert_eq!(DECLARED, Duration::from_secs(20));
+        assert_eq!(declared_duration("1500ms"), Duration::from_millis(1500));
+        assert_eq!(declared_duration("250us"), Duration::from_micros(250));
+        assert_eq!(declared_duration("2m"), Duration::from_secs(120));
+        assert_eq!(declared_duration("2min"), Duration::from_secs(120));
+        assert_eq!(declared_duration("1h"), Duration::from_secs(3600));
+        assert_eq!(declared_duration("1d"), Duration::from_secs(86400));
+        for invalid in ["5x", "s", "5", "5 s", "-5s", "99999999999999999999s"] {
+            assert!(std::panic::catch_unwind(|| declared_duration(invalid)).is_err());
+        }
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,119 @@
+//! A single connection to a service, shared by everything in a process that talks to it - see
+//! [`crate::ReifiedService::connection`].
+//!
+//! Before the cached connection is handed out again, its socket is checked for having been hung
+//! up - by peeking at it without blocking, which reads nothing the connection itself would see.
+//! Broken connections are replaced by a fresh one, starting the service again if need be.
+
+use std::{
+    ops::{Deref, DerefMut},
+    os::fd::OwnedFd,
+};
+
+use async_lock::MutexGuard;
+use rustix::{io::Errno, net::RecvFlags};
+
+/// A connection held on to for reuse, along with a duplicate of its socket to check on it with.
+#[derive(Debug)]
+pub(crate) struct Cached<C> {
+    pub(crate) connection: C,
+    /// Missing if the [`crate::UnixSocketInterface`] can't duplicate streams, in which case the
+    /// connection is assumed to be healthy.
+    pub(crate) socket: Option<OwnedFd>,
+}
+
+impl<C> Cached<C> {
+    /// Whether the connection is still usable, as far as its socket can tell.
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.socket
+            .as_ref()
+            .is_none_or(|socket| !is_hung_up(socket))
+    }
+}
+
+/// Whether the other end of the socket has gone away. Data waiting to be read doesn't count - the
+/// connection is still up, whatever is done with it.
+pub(crate) fn is_hung_up(socket: &OwnedFd) -> bool {
+    let mut byte = [0u8];
+    match rustix::net::recv(socket, &mut byte, RecvFlags::PEEK | RecvFlags::DONTWAIT) {
+        Ok((0, _)) => true,
+        Ok(_) => false,
+        Err(e) if e == Errno::AGAIN || e == Errno::INTR => false,
+        Err(_) => true,
+    }
+}
+
+/// Exclusive access to the cached connection of a [`crate::ReifiedService`], for as long as this
+/// is held. Other users of the connection wait until it is dropped.
+#[derive(Debug)]
+pub struct CachedConnection<'a, C> {
+    guard: MutexGuard<'a, Option<Cached<C>>>,
+}
+
+impl<'a, C> CachedConnection<'a, C> {
+    /// The guard must hold a connection.
+    pub(crate) fn new(guard: MutexGuard<'a, Option<Cached<C>>>) -> Self {
+        assert!(guard.is_some(), "cached connection must be established");
+        Self { guard }
+    }
+
+    /// Throw the connection away - for instance because something went wrong in the middle of an
+    /// exchange, leaving it in an unknown state - so the next user establishes a fresh one.
+    pub fn invalidate(mut self) {
+        *self.guard = None;
+    }
+}
+
+impl<C> Deref for CachedConnection<'_, C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.guard.as_ref().expect("checked on creation").connection
+    }
+}
+
+impl<C> DerefMut for CachedConnection<'_, C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.guard.as_mut().expect("checked on creation").connection
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, os::fd::OwnedFd, os::unix::net::UnixStream};
+
+    use super::is_hung_up;
+
+    #[test]
+    pub fn hung_up_test() {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let socket = OwnedFd::from(ours.try_clone().unwrap());
+        assert!(!is_hung_up(&socket));
+
+        // Pending data is left for the connection to read.
+        theirs.write_all(b"x").unwrap();
+        assert!(!is_hung_up(&socket));
+        assert!(!is_hung_up(&socket));
+
+        drop(theirs);
+        let mut buf = [0u8];
+        std::io::Read::read_exact(&mut &ours, &mut buf).unwrap();
+        assert!(is_hung_up(&socket));
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,332 @@
+//! Starting services in containers, with the command line of an OCI runtime like `podman` or
+//! `docker` - see [`ContainerLauncher`].
+//!
+//! The base context directory is bind-mounted into the container at the same path, as is the
+//! ephemeral liveness socket, so the containerised server binds its socket where clients look for
+//! it and reports liveness like any other service. The liveness environment - and
+//! [`crate::environment::CONTEXT_DIR_ENV_VAR`] - is passed through too. As the container doesn't
+//! inherit file descriptors from the process starting it, services launched this way should use
+//! one of the temporary socket [`crate::liveness::LivenessTransport`]s.
+//!
+//! Implement [`crate::ServiceStartable`] by hand to use it:
+//! ```rust,compile_fail
+//! impl ServiceStartable for CacheService {
+//!     type ProcessHandle = suss::container::ContainerProcess;
+//!
+//!     fn run_service_command_raw(
+//!         &self,
+//!         executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Debug]>,
+//!         liveness_path: Option<&Path>,
+//!         liveness_token: Option<&str>,
+//!         prepared_spawn: suss::spawn::PreparedSpawn,
+//!     ) -> std::io::Result<Self::ProcessHandle> {
+//!         ContainerLauncher::new("localhost/cache:latest")
+//!             .with_command(["cache-server", "--serve"])
+//!             .launch(executor_commandline_prefix, liveness_path, liveness_token, prepared_spawn)
+//!     }
+//! }
+//! ```
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Debug,
+    io::{self, Read},
+    path::Path,
+    process::{Child, Command, ExitStatus},
+};
+
+use nanorand::rand::{chacha::ChaCha20, Rng};
+use tracing::{info, warn};
+
+use crate::{
+    child::ServiceProcessHandle,
+    environment::CONTEXT_DIR_ENV_VAR,
+    liveness::{self, LIVENESS_ENV_VAR, LIVENESS_TOKEN_ENV_VAR},
+    spawn::PreparedSpawn,
+};
+
+/// How to run the container of a service - see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContainerLauncher {
+    runtime: OsString,
+    image: OsString,
+    command: Vec<OsString>,
+    run_options: Vec<OsString>,
+}
+
+impl ContainerLauncher {
+    /// Runtime used unless [`Self::with_runtime`] says otherwise.
+    pub const DEFAULT_RUNTIME: &'static str = "podman";
+
+    /// Run the given image with its default command, using [`Self::DEFAULT_RUNTIME`].
+    pub fn new(image: impl AsRef<OsStr>) -> Self {
+        Self {
+            runtime: Self::DEFAULT_RUNTIME.into(),
+            image: image.as_ref().to_owned(),
+            command: Vec::new(),
+            run_options: Vec::new(),
+        }
+    }
+
+    /// Use another runtime with a podman-compatible `run` command, like `docker`.
+    pub fn with_runtime(mut self, runtime: impl AsRef<OsStr>) -> Self {
+        self.runtime = runtime.as_ref().to_owned();
+        self
+    }
+
+    /// Run this command in the container, rather than the default command of the image.
+    pub fn with_command(mut self, command: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Self {
+        self.command = command
+            .into_iter()
+            .map(|part| part.as_ref().to_owned())
+            .collect();
+        self
+    }
+
+    /// Pass an extra option to `run`, before the image - for instance `--network=none`, or
+    /// another volume.
+    pub fn with_run_option(mut self, option: impl AsRef<OsStr>) -> Self {
+        self.run_options.push(option.as_ref().to_owned());
+        self
+    }
+
+    /// Start the container, with the arguments of
+    /// [`crate::ServiceStartable::run_service_command_raw`]. The prepared spawn - which must know
+    /// the base context directory - applies to the runtime process, whose standard output and
+    /// error are those of the container.
+    pub fn launch(
+        &self,
+        executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
+        liveness_path: Option<&Path>,
+        liveness_token: Option<&str>,
+        prepared_spawn: PreparedSpawn,
+    ) -> io::Result<ContainerProcess> {
+        let name = format!("suss-{:016x}", ChaCha20::new().generate::<u64>());
+        let mut command = self.run_command(
+            executor_commandline_prefix,
+            &name,
+            liveness_path,
+            liveness_token,
+            &prepared_spawn,
+        )?;
+        prepared_spawn.apply(&mut command);
+        info!("Starting container {} from image {:?}", name, self.image);
+        Ok(ContainerProcess {
+            runtime: self.runtime.clone(),
+            name,
+            child: command.spawn()?,
+        })
+    }
+
+    /// The command running the container with the given name.
+    fn run_command(
+        &self,
+        executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
+        name: &str,
+        liveness_path: Option<&Path>,
+        liveness_token: Option<&str>,
+        prepared_spawn: &PreparedSpawn,
+    ) -> io::Result<Command> {
+        let context_dir = prepared_spawn.context_dir().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "containers need to know the base context directory to mount it",
+            )
+        })?;
+        let mut command_line: Vec<OsString> = executor_commandline_prefix
+            .into_iter()
+            .flatten()
+            .map(|part| part.as_ref().to_owned())
+            .collect();
+        command_line.push(self.runtime.clone());
+        let mut command = Command::new(command_line.remove(0));
+        command.args(command_line);
+        command.args(["run", "--rm", "--name", name]);
+        command.arg("--volume").arg(bind_mount(context_dir));
+        if let Some(liveness_path) = liveness_path {
+            if liveness::inherited_liveness_fd(liveness_path).is_some() {
+                warn!(
+                    "Containers can't inherit liveness file descriptors - use a temporary socket"
+                );
+            } else {
+                command.arg("--volume").arg(bind_mount(liveness_path));
+            }
+        }
+        // Pass the values through the environment of the runtime, so the liveness token doesn't
+        // show up in its command line.
+        liveness::set_liveness_environment(&mut command, liveness_path, liveness_token);
+        command.env(CONTEXT_DIR_ENV_VAR, context_dir);
+        command.args(["--env", LIVENESS_ENV_VAR, "--env", CONTEXT_DIR_ENV_VAR]);
+        if liveness_token.is_some() {
+            command.args(["--env", LIVENESS_TOKEN_ENV_VAR]);
+        }
+        command.args(&self.run_options);
+        command.arg(&self.image);
+        command.args(&self.command);
+        Ok(command)
+    }
+}
+
+/// `--volume` argument mounting the path at the same place in the container.
+fn bind_mount(path: &Path) -> OsString {
+    let mut mount = path.as_os_str().to_owned();
+    mount.push(":");
+    mount.push(path);
+    mount
+}
+
+/// A service container started by [`ContainerLauncher::launch`], tracked through the runtime
+/// process running it in the foreground.
+///
+/// The processes in the container are no descendants of this one, so there is no pid to check
+/// liveness pings against. Terminating and killing the service go through the runtime.
+#[derive(Debug)]
+pub struct ContainerProcess {
+    runtime: OsString,
+    name: String,
+    child: Child,
+}
+
+impl ContainerProcess {
+    /// Name of the container.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Send the container a signal with `<runtime> kill`.
+    fn signal(&self, signal: &str) -> io::Result<()> {
+        let status = Command::new(&self.runtime)
+            .args(["kill", "--signal", signal, &self.name])
+            .status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "couldn't signal container {} - {}",
+                self.name, status
+            )))
+        }
+    }
+}
+
+impl ServiceProcessHandle for ContainerProcess {
+    fn pid(&self) -> Option<u32> {
+        None
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.child.try_wait()
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        let signalled = self.signal("KILL");
+        // Without the runtime process, nothing is left tracking the container.
+        self.child.kill().and(signalled)
+    }
+
+    fn terminate(&mut self) -> io::Result<()> {
+        self.signal("TERM")
+    }
+
+    fn wait(&mut self) -> io::Result<ExitStatus> {
+        self.child.wait()
+    }
+
+    fn take_stdout(&mut self) -> Option<Box<dyn Read + Send>> {
+        self.child.take_stdout()
+    }
+
+    fn take_stderr(&mut self) -> Option<Box<dyn Read + Send>> {
+        self.child.take_stderr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, path::Path};
+
+    use super::ContainerLauncher;
+    use crate::{
+        environment::CONTEXT_DIR_ENV_VAR,
+        liveness::{LIVENESS_ENV_VAR, LIVENESS_TOKEN_ENV_VAR},
+        spawn::{PreparedSpawn, SpawnOptions},
+        ServiceSocket,
+    };
+
+    #[test]
+    pub fn container_command_test() {
+        let launcher = ContainerLauncher::new("localhost/echo:latest")
+            .with_runtime("docker")
+            .with_command(["echo-server", "--serve"])
+            .with_run_option("--network=none");
+        let socket = ServiceSocket::new("echo.sock".as_ref(), "/run/suss-container-test".as_ref());
+        let prepared_spawn = SpawnOptions::new().prepare(&socket).unwrap();
+        let command = launcher
+            .run_command(
+                Some(&["env"][..]),
+                "suss-test",
+                Some(Path::new("/tmp/temp-liveness.sock")),
+                Some("token"),
+                &prepared_spawn,
+            )
+            .unwrap();
+        assert_eq!(command.get_program(), "env");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "docker",
+                "run",
+                "--rm",
+                "--name",
+                "suss-test",
+                "--volume",
+                "/run/suss-container-test:/run/suss-container-test",
+                "--volume",
+                "/tmp/temp-liveness.sock:/tmp/temp-liveness.sock",
+                "--env",
+                LIVENESS_ENV_VAR,
+                "--env",
+                CONTEXT_DIR_ENV_VAR,
+                "--env",
+                LIVENESS_TOKEN_ENV_VAR,
+                "--network=none",
+                "localhost/echo:latest",
+                "echo-server",
+                "--serve",
+            ]
+            .map(OsStr::new)
+        );
+        let token = command
+            .get_envs()
+            .find(|(name, _)| *name == LIVENESS_TOKEN_ENV_VAR)
+            .and_then(|(_, value)| value);
+        assert_eq!(token, Some(OsStr::new("token")));
+
+        // The base context directory has to be known to mount it.
+        assert!(launcher
+            .run_command(
+                None::<&[&str]>,
+                "suss-test",
+                None,
+                None,
+                &PreparedSpawn::inherit()
+            )
+            .is_err());
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,159 @@
+//! Base context directories - the directories that services put their sockets in.
+
+use std::{
+    ffi::OsStr,
+    fs::DirBuilder,
+    io,
+    ops::Deref,
+    os::unix::fs::DirBuilderExt,
+    path::{Component, Path, PathBuf},
+};
+
+use tracing::{debug, instrument};
+
+use crate::IoResult;
+
+/// Environment variable holding the per-user runtime directory, as per the XDG base directory
+/// specification.
+pub const XDG_RUNTIME_DIR_ENV_VAR: &str = "XDG_RUNTIME_DIR";
+
+/// Validated, existing, canonical base context directory.
+///
+/// This dereferences to a [`Path`], so a `&ContextDir` can be passed anywhere a base context
+/// directory is taken as `&Path` - for instance to [`crate::ServiceExt::reify`] or
+/// [`crate::ServiceBundle::new`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ContextDir {
+    path: PathBuf,
+}
+
+impl ContextDir {
+    /// Use the given directory, creating it - and any missing parents - with `0700` permissions
+    /// if it doesn't exist yet. The stored path is canonicalized.
+    #[instrument(skip_all, fields(path = %path.as_ref().display()))]
+    pub fn new(path: impl AsRef<Path>) -> IoResult<Self> {
+        let path = path.as_ref();
+        DirBuilder::new().recursive(true).mode(0o700).create(path)?;
+        let path = path.canonicalize()?;
+        if !path.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Context directory {} is not a directory", path.display()),
+            ));
+        }
+        debug!("Using context directory {}", path.display());
+        Ok(Self { path })
+    }
+
+    /// Directory named `app_name` within the user's runtime directory
+    /// ([`XDG_RUNTIME_DIR_ENV_VAR`]), which is the conventional place for per-user sockets.
+    ///
+    /// `app_name` must be a single, plain path component.
+    pub fn xdg_runtime(app_name: impl AsRef<OsStr>) -> IoResult<Self> {
+        let app_name = Path::new(app_name.as_ref());
+        let mut components = app_name.components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Application name {} is not a single path component",
+                    app_name.display()
+                ),
+            ));
+        }
+        Self::new(Self::var_path(XDG_RUNTIME_DIR_ENV_VAR)?.join(app_name))
+    }
+
+    /// Directory named by the given environment variable.
+    ///
+    /// Fails with [`io::ErrorKind::NotFound`] if the variable is unset or empty.
+    pub fn from_env(var: &str) -> IoResult<Self> {
+        Self::new(Self::var_path(var)?)
+    }
+
+    /// Fresh directory with a random name inside [`std::env::temp_dir`], for use in tests.
+    ///
+    /// It is not removed automatically.
+    pub fn temp_for_tests() -> IoResult<Self> {
+        use nanorand::rand::{chacha::ChaCha20, Rng};
+        let mut gen = ChaCha20::new();
+        Self::new(std::env::temp_dir().join(format!("suss-test-{:016x}", gen.generate::<u64>())))
+    }
+
+    /// The canonical path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Take the canonical path of the directory.
+    pub fn into_path_buf(self) -> PathBuf {
+        self.path
+    }
+
+    fn var_path(var: &str) -> IoResult<PathBuf> {
+        std::env::var_os(var)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Context directory environment variable {} is not set", var),
+                )
+            })
+    }
+}
+
+impl Deref for ContextDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for ContextDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    pub fn context_dir_is_created_private_and_canonical() {
+        let base = ContextDir::temp_for_tests().unwrap();
+        let nested = ContextDir::new(base.join("a/../b")).unwrap();
+        assert_eq!(nested.path(), base.join("b"));
+        let mode = std::fs::metadata(&nested).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        assert_eq!(
+            ContextDir::xdg_runtime("../escape").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
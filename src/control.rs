@@ -0,0 +1,544 @@
+//! Opt-in control channel for servers, letting other processes stop or reload a running service
+//! and ask for its status - see [`crate::ServerExt::start_and_run_server_with_control`] and
+//! [`crate::ReifiedService::stop`].
+//!
+//! The control channel is a secondary socket next to the service socket - see
+//! [`ServiceSocket::control_path`]. A client connects, sends a single [`ControlCommand`] as a line,
+//! and gets back a single line in response - `OK`, optionally followed by a space and some text,
+//! or `ERR` followed by a space and an error message - after which the connection is closed.
+//!
+//! This always uses std unix sockets on the blocking threadpool rather than a
+//! [`crate::UnixSocketInterface`], so it works the same whatever runtime the server uses.
+//!
+//! The control socket gets the same [`SocketPermissions`] as the service socket, and commands are
+//! only taken from peers the [`CredentialPolicy`] of the channel allows - by default, processes of
+//! the user the server runs as. Others get an `ERR` response.
+//!
+//! ## Handoff
+//! Long-lived services can be upgraded without refusing a single client. The new instance sends
+//! [`ControlCommand::Handoff`] - see [`request_handoff`] and [`crate::ServerExt::take_over`] - and
+//! the old one responds with the file descriptor of its listener attached to the `OK` line, as
+//! `SCM_RIGHTS`. Then the old instance shuts down like for [`ControlCommand::Stop`], but leaves
+//! the service socket and its state file in place for the new one, which accepts on the very same
+//! listener - clients queued on it in between are served by the new instance.
+
+use std::{
+    fmt::Display,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    os::{
+        fd::{AsFd, OwnedFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use blocking::unblock;
+use rustix::net::RecvFlags;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    audit, cleanable_path::CleanablePathBuf, credentials, events, fdpass, timefut,
+    CredentialPolicy, Error, IoResult, ServiceEvent, ServiceSocket, SocketPermissions,
+    CHILD_EXIT_POLL_INTERVAL,
+};
+
+/// How often the control socket is checked for new connections.
+const CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(25);
+
+/// How long a control connection may take to send a command or receive a response.
+const CONTROL_IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum length of a line sent over the control channel.
+const MAX_CONTROL_LINE_LENGTH: u64 = 4096;
+
+/// Command sent over the control channel of a service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    /// Shut the server down gracefully. The response is sent before it starts shutting down.
+    Stop,
+    /// Reload the configuration of the server, or whatever else reloading means to it.
+    Reload,
+    /// Check that the server is alive. The response contains its process ID.
+    Status,
+    /// Hand the listener of the server over to the sender, and shut down gracefully - see
+    /// [Handoff](self#handoff). Servers without a listener to hand over respond with `ERR`.
+    Handoff,
+}
+
+impl ControlCommand {
+    /// The command as sent over the control channel.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ControlCommand::Stop => "STOP",
+            ControlCommand::Reload => "RELOAD",
+            ControlCommand::Status => "STATUS",
+            ControlCommand::Handoff => "HANDOFF",
+        }
+    }
+
+    /// Parse a command sent over the control channel.
+    pub fn parse(command: &str) -> Option<Self> {
+        match command {
+            "STOP" => Some(ControlCommand::Stop),
+            "RELOAD" => Some(ControlCommand::Reload),
+            "STATUS" => Some(ControlCommand::Status),
+            "HANDOFF" => Some(ControlCommand::Handoff),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ControlCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Server side of the control channel of a service. The control socket is removed when this is
+/// dropped - unless the listener was handed off, in which case the new instance may have bound its
+/// own there already.
+#[derive(Debug)]
+pub struct ControlChannel {
+    listener: UnixListener,
+    path: CleanablePathBuf,
+    service_socket: ServiceSocket,
+    credential_policy: CredentialPolicy,
+    handoff_listener: Option<OwnedFd>,
+    handed_off: AtomicBool,
+}
+
+impl ControlChannel {
+    /// Bind the control socket of a service, with the given permissions - usually those of the
+    /// service socket, see [`crate::Server::socket_permissions`].
+    ///
+    /// This should only be done while holding the service socket, as any control socket left
+    /// behind by a previous server is removed first.
+    pub fn bind(service_socket: &ServiceSocket, permissions: &SocketPermissions) -> IoResult<Self> {
+        let path = service_socket.control_path();
+        match std::fs::remove_file(&path) {
+            Ok(()) => debug!("Removed leftover control socket @ {}", path.display()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let listener = permissions.bind_std_listener(&path)?;
+        let path = CleanablePathBuf::new(path);
+        listener.set_nonblocking(true)?;
+        info!(
+            "Listening for control commands @ {}",
+            path.as_ref().display()
+        );
+        Ok(Self {
+            listener,
+            path,
+            service_socket: service_socket.clone(),
+            credential_policy: CredentialPolicy::current_user(),
+            handoff_listener: None,
+            handed_off: AtomicBool::new(false),
+        })
+    }
+
+    /// Take commands from the peers the policy allows, rather than only from processes of the
+    /// user the server runs as.
+    pub fn with_credential_policy(mut self, credential_policy: CredentialPolicy) -> Self {
+        self.credential_policy = credential_policy;
+        self
+    }
+
+    /// Hand the given listener - a duplicate of the one the server accepts on - over to the new
+    /// instance that sends [`ControlCommand::Handoff`].
+    pub fn with_handoff_listener(mut self, listener: OwnedFd) -> Self {
+        self.handoff_listener = Some(listener);
+        self
+    }
+
+    /// Path of the control socket.
+    pub fn path(&self) -> &Path {
+        self.path.as_ref()
+    }
+
+    /// Whether [`Self::run`] returned because the listener was handed off, rather than because
+    /// the server was asked to stop. If so, the service socket and state file belong to the new
+    /// instance now, and must be left in place.
+    pub fn handed_off(&self) -> bool {
+        self.handed_off.load(Ordering::Acquire)
+    }
+
+    /// Answer control commands until [`ControlCommand::Stop`] is received, or the listener is
+    /// handed off with [`ControlCommand::Handoff`]. `on_reload` is called for every
+    /// [`ControlCommand::Reload`], and any error it produces is sent back to the client.
+    pub async fn run(&self, mut on_reload: impl FnMut() -> IoResult<()>) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _addr)) => stream,
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        warn!(
+                            "Failed to accept control connection @ {} - {}",
+                            self.path().display(),
+                            e
+                        );
+                    }
+                    timefut::sleep(CONTROL_POLL_INTERVAL).await;
+                    continue;
+                }
+            };
+            if let Err(reason) = self.check_peer(&stream) {
+                let response = format!("ERR {}", single_line(&reason));
+                if let Err(e) = send_response(stream, response).await {
+                    debug!("Failed to tell rejected control peer why - {}", e);
+                }
+                continue;
+            }
+            match handle_control_connection(stream, &mut on_reload, self.handoff_listener.as_ref())
+                .await
+            {
+                Ok(Some(ControlCommand::Handoff)) => {
+                    info!("Handed off listener @ {}", self.path().display());
+                    self.handed_off.store(true, Ordering::Release);
+                    return;
+                }
+                Ok(Some(_)) => {
+                    info!("Received stop command @ {}", self.path().display());
+                    return;
+                }
+                Ok(None) => {}
+                Err(e) => warn!(
+                    "Failed to handle control connection @ {} - {}",
+                    self.path().display(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+impl ControlChannel {
+    /// Check the peer of a control connection against the credential policy, producing why it
+    /// isn't allowed if it isn't.
+    fn check_peer(&self, stream: &UnixStream) -> Result<(), String> {
+        let (peer, reason) = match credentials::peer_credentials(stream) {
+            Ok(peer) if self.credential_policy.allows(&peer) => return Ok(()),
+            Ok(peer) => (
+                Some(peer),
+                format!("Control commands from {peer} are not allowed"),
+            ),
+            Err(e) => (
+                None,
+                format!("Couldn't read control peer credentials - {e}"),
+            ),
+        };
+        warn!(
+            "Rejecting control connection @ {} - {}",
+            self.path().display(),
+            reason
+        );
+        audit::record(
+            Some(&self.service_socket),
+            audit::AuditEvent::AuthorizationDenied {
+                peer,
+                reason: reason.clone(),
+            },
+        );
+        Err(reason)
+    }
+}
+
+impl Drop for ControlChannel {
+    fn drop(&mut self) {
+        if self.handed_off() {
+            std::mem::replace(&mut self.path, CleanablePathBuf::new(PathBuf::new())).keep();
+        }
+    }
+}
+
+/// Answer a single control connection, producing the command if it asked us to stop - either
+/// [`ControlCommand::Stop`], or [`ControlCommand::Handoff`] once the listener was sent.
+async fn handle_control_connection(
+    stream: UnixStream,
+    on_reload: &mut impl FnMut() -> IoResult<()>,
+    handoff_listener: Option<&OwnedFd>,
+) -> IoResult<Option<ControlCommand>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CONTROL_IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONTROL_IO_TIMEOUT))?;
+    let (mut stream, line) = unblock(move || {
+        let mut stream = stream;
+        let line = read_control_line(&mut stream);
+        (stream, line)
+    })
+    .await;
+    let line = line?;
+    debug!("Received control command {:?}", line);
+    let command = ControlCommand::parse(&line);
+    let (response, stop) = match command {
+        Some(ControlCommand::Stop) => ("OK".to_owned(), command),
+        Some(ControlCommand::Reload) => match on_reload() {
+            Ok(()) => ("OK".to_owned(), None),
+            Err(e) => (format!("ERR {}", single_line(&e.to_string())), None),
+        },
+        Some(ControlCommand::Status) => (format!("OK {}", std::process::id()), None),
+        Some(ControlCommand::Handoff) => match handoff_listener {
+            Some(listener) => {
+                // Only stop once the listener is on its way, so a failed handoff leaves this
+                // server running.
+                let listener = listener.try_clone()?;
+                unblock(move || send_line_with_fd(&mut stream, "OK", &listener)).await?;
+                return Ok(command);
+            }
+            None => ("ERR no listener to hand off".to_owned(), None),
+        },
+        None => (
+            format!("ERR unknown control command {}", single_line(&line)),
+            None,
+        ),
+    };
+    send_response(stream, response).await?;
+    Ok(stop)
+}
+
+/// Write a response line, closing the control connection.
+async fn send_response(mut stream: UnixStream, response: String) -> IoResult<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(CONTROL_IO_TIMEOUT))?;
+    unblock(move || stream.write_all(format!("{response}\n").as_bytes())).await
+}
+
+/// Write a response line with the file descriptor attached to its first byte.
+fn send_line_with_fd(stream: &mut UnixStream, response: &str, fd: &OwnedFd) -> IoResult<()> {
+    let line = format!("{response}\n");
+    let sent = fdpass::send_with_fds(stream.as_fd(), line.as_bytes(), &[fd.as_fd()])?;
+    stream.write_all(&line.as_bytes()[sent..])
+}
+
+/// Send a command over the control channel of a service, producing the text of an `OK`
+/// response. An `ERR` response is turned into an error.
+pub async fn send_control_command(
+    service_socket: &ServiceSocket,
+    command: ControlCommand,
+    timeout: Duration,
+) -> IoResult<String> {
+    let path = service_socket.control_path();
+    // Zero timeouts are rejected by the socket options.
+    let timeout = timeout.max(Duration::from_millis(1));
+    unblock(move || {
+        let mut stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.write_all(format!("{command}\n").as_bytes())?;
+        parse_control_response(&read_control_line(&mut stream)?)
+    })
+    .await
+}
+
+/// Ask the service on a socket to hand its listener over with [`ControlCommand::Handoff`],
+/// producing the listener. The service shuts down once it has sent it, leaving the service socket
+/// in place - so the listener should be accepted on promptly, as clients queue up on it meanwhile.
+pub async fn request_handoff(
+    service_socket: &ServiceSocket,
+    timeout: Duration,
+) -> IoResult<OwnedFd> {
+    let path = service_socket.control_path();
+    // Zero timeouts are rejected by the socket options.
+    let timeout = timeout.max(Duration::from_millis(1));
+    unblock(move || {
+        let mut stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.write_all(format!("{}\n", ControlCommand::Handoff).as_bytes())?;
+        // The listener comes attached to the response, so it can't go through a buffered reader.
+        let mut fds = Vec::new();
+        let mut response = Vec::new();
+        let mut buf = [0u8; 256];
+        while !response.ends_with(b"\n") {
+            if response.len() as u64 >= MAX_CONTROL_LINE_LENGTH {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Control response too long",
+                ));
+            }
+            match fdpass::recv_with_fds(stream.as_fd(), &mut buf, &mut fds, RecvFlags::empty())? {
+                0 => return Err(ErrorKind::UnexpectedEof.into()),
+                read => response.extend_from_slice(&buf[..read]),
+            }
+        }
+        response.pop();
+        let response = String::from_utf8(response)
+            .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+        parse_control_response(&response)?;
+        fds.into_iter().next().ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidData, "No listener came with the handoff")
+        })
+    })
+    .await
+}
+
+/// Produce the text of an `OK` control response, turning an `ERR` one into an error.
+fn parse_control_response(response: &str) -> IoResult<String> {
+    match response.split_once(' ') {
+        _ if response == "OK" => Ok(String::new()),
+        Some(("OK", text)) => Ok(text.to_owned()),
+        Some(("ERR", message)) => Err(std::io::Error::other(message.to_owned())),
+        _ => Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid control response {response:?}"),
+        )),
+    }
+}
+
+/// Ask the service on a socket to stop with [`ControlCommand::Stop`], and wait until its socket is
+/// gone - which happens before it finishes draining in-flight work. This is
+/// [`crate::ReifiedService::stop`] for when only the socket is known.
+pub async fn stop_service(
+    service_socket: &ServiceSocket,
+    timeout: Duration,
+) -> crate::error::Result<()> {
+    let started = std::time::Instant::now();
+    send_control_command(service_socket, ControlCommand::Stop, timeout)
+        .await
+        .map_err(|e| Error::ControlFailed {
+            socket: service_socket.clone(),
+            source: e,
+        })?;
+    while service_socket.path.exists() {
+        if started.elapsed() >= timeout {
+            error!("Service @ {} didn't stop in time", service_socket);
+            return Err(Error::StopTimeout {
+                socket: service_socket.clone(),
+                timeout,
+            });
+        }
+        timefut::sleep(CHILD_EXIT_POLL_INTERVAL).await;
+    }
+    info!("Service @ {} stopped", service_socket);
+    events::emit(ServiceEvent::Stopped {
+        socket: service_socket.clone(),
+    });
+    Ok(())
+}
+
+/// Read a single line from a control connection, without the newline.
+fn read_control_line(stream: &mut UnixStream) -> IoResult<String> {
+    let mut line = Vec::new();
+    BufReader::new(stream.take(MAX_CONTROL_LINE_LENGTH)).read_until(b'\n', &mut line)?;
+    if line.pop() != Some(b'\n') {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(line).map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Collapse a message onto a single line, to fit into a control response.
+fn single_line(message: &str) -> String {
+    message.lines().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsStr,
+        os::unix::{fs::PermissionsExt, net::UnixStream},
+        time::Duration,
+    };
+
+    use futures_lite::future::{self, block_on};
+
+    use super::{request_handoff, send_control_command, ControlChannel, ControlCommand};
+    use crate::{ContextDir, CredentialPolicy, ServiceSocket, SocketPermissions};
+
+    #[test]
+    pub fn control_command_test() {
+        for command in [
+            ControlCommand::Stop,
+            ControlCommand::Reload,
+            ControlCommand::Status,
+            ControlCommand::Handoff,
+        ] {
+            assert_eq!(ControlCommand::parse(command.as_str()), Some(command));
+        }
+        assert_eq!(ControlCommand::parse("stop"), None);
+    }
+
+    #[test]
+    pub fn control_channel_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("control-test.sock"), &context);
+        let control = ControlChannel::bind(&service_socket, &SocketPermissions::new()).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let mut reloads = 0;
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                block_on(control.run(|| {
+                    reloads += 1;
+                    match reloads {
+                        1 => Ok(()),
+                        _ => Err(std::io::Error::other("bad\nconfig")),
+                    }
+                }))
+            });
+            let send = |command| block_on(send_control_command(&service_socket, command, timeout));
+            assert_eq!(
+                send(ControlCommand::Status).unwrap(),
+                std::process::id().to_string()
+            );
+            assert_eq!(send(ControlCommand::Reload).unwrap(), "");
+            assert_eq!(
+                send(ControlCommand::Reload).unwrap_err().to_string(),
+                "bad config"
+            );
+            assert_eq!(send(ControlCommand::Stop).unwrap(), "");
+        });
+        assert_eq!(reloads, 2);
+
+        drop(control);
+        assert!(!service_socket.control_path().exists());
+
+        // The control socket gets the permissions it is bound with, and peers the policy doesn't
+        // allow are turned away - without ever being handed the listener.
+        let permissions = SocketPermissions::new().with_mode(0o600);
+        let (handed_off, _) = UnixStream::pair().unwrap();
+        let control = ControlChannel::bind(&service_socket, &permissions)
+            .unwrap()
+            .with_credential_policy(CredentialPolicy::new())
+            .with_handoff_listener(handed_off.into());
+        let mode = std::fs::metadata(service_socket.control_path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Nothing can stop the channel either, so it is only run until the commands are answered.
+        let (handoff, stop) = block_on(future::or(
+            async {
+                control.run(|| Ok(())).await;
+                unreachable!("Control channel stopped by a rejected peer")
+            },
+            async {
+                let handoff = request_handoff(&service_socket, timeout).await;
+                let stop =
+                    send_control_command(&service_socket, ControlCommand::Stop, timeout).await;
+                (handoff, stop)
+            },
+        ));
+        assert!(handoff.unwrap_err().to_string().contains("not allowed"));
+        assert!(stop.unwrap_err().to_string().contains("not allowed"));
+        assert!(!control.handed_off());
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
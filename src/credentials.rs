@@ -0,0 +1,297 @@
+//! Credentials of the process on the other end of a unix socket connection, for servers to only
+//! let in the users they trust - see [`CredentialPolicy`] and
+//! [`crate::serve::serve_connections_with_credentials`] - and for clients to only talk to servers
+//! run by the user they expect - see [`crate::Service::trusted_owner_uid`].
+//!
+//! Clients can also check that the liveness ping of a service they started comes from the process
+//! they spawned, or one of its descendants - see [`crate::ServiceStartable::verify_liveness_peer`]
+//! and [`is_descendant_of`].
+//!
+//! Credentials are read with `SO_PEERCRED`, so they are those of the peer at the time it
+//! connected, as vouched for by the kernel. This is only available on Linux and Android - on
+//! other platforms, reading credentials fails with [`std::io::ErrorKind::Unsupported`].
+
+use std::{
+    fmt::Display,
+    io::{self, ErrorKind},
+    os::{fd::AsFd, unix::fs::MetadataExt},
+};
+
+use tracing::{error, warn};
+
+use crate::{Error, IoResult, ServiceSocket, UnixSocketInterface};
+
+/// Credentials of the peer of a unix socket connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    /// Process ID of the peer.
+    pub pid: u32,
+    /// Effective user ID of the peer.
+    pub uid: u32,
+    /// Effective group ID of the peer.
+    pub gid: u32,
+}
+
+impl Display for PeerCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "pid {} (uid {}, gid {})", self.pid, self.uid, self.gid)
+    }
+}
+
+/// Which peers a server accepts connections from, by their credentials. A peer is accepted if
+/// either its user or its group is allowed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CredentialPolicy {
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+}
+
+impl CredentialPolicy {
+    /// Accept nobody until users or groups are allowed.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept only the user this process runs as - the usual choice for per-user services.
+    pub fn current_user() -> Self {
+        Self::new().with_uid(current_uid())
+    }
+
+    /// Also accept peers running as this user.
+    pub fn with_uid(mut self, uid: u32) -> Self {
+        self.uids.push(uid);
+        self
+    }
+
+    /// Also accept peers running as this group.
+    pub fn with_gid(mut self, gid: u32) -> Self {
+        self.gids.push(gid);
+        self
+    }
+
+    /// Whether a peer with the given credentials is accepted.
+    pub fn allows(&self, credentials: &PeerCredentials) -> bool {
+        self.uids.contains(&credentials.uid) || self.gids.contains(&credentials.gid)
+    }
+}
+
+/// Effective user ID of this process.
+pub fn current_uid() -> u32 {
+    rustix::process::geteuid().as_raw()
+}
+
+/// Read the credentials of the peer of a std or runtime unix stream.
+pub fn peer_credentials(stream: &impl AsFd) -> IoResult<PeerCredentials> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let credentials = rustix::net::sockopt::socket_peercred(stream)?;
+        Ok(PeerCredentials {
+            pid: credentials.pid.as_raw_nonzero().get().unsigned_abs(),
+            uid: credentials.uid.as_raw(),
+            gid: credentials.gid.as_raw(),
+        })
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = stream;
+        Err(ErrorKind::Unsupported.into())
+    }
+}
+
+/// Deepest process tree walked by [`is_descendant_of`] before giving up.
+const MAX_PROCESS_TREE_DEPTH: usize = 256;
+
+/// Whether the process with the given ID is the ancestor process or one of its descendants,
+/// found by walking up the parent process IDs in `/proc`. Processes that are gone - or were
+/// reparented after their parent exited - don't count as descendants.
+pub fn is_descendant_of(pid: u32, ancestor: u32) -> IoResult<bool> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mut current = pid;
+        for _ in 0..MAX_PROCESS_TREE_DEPTH {
+            if current == ancestor {
+                return Ok(true);
+            }
+            if current <= 1 {
+                return Ok(false);
+            }
+            current = match parent_pid(current) {
+                Ok(parent) => parent,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(false)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (pid, ancestor);
+        Err(ErrorKind::Unsupported.into())
+    }
+}
+
+/// Parent process ID of a process, from `/proc/<pid>/stat`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parent_pid(pid: u32) -> IoResult<u32> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat"))?;
+    // The command name in brackets may contain anything, so only look after it - the state comes
+    // first, then the parent process ID.
+    stat.rsplit_once(')')
+        .and_then(|(_, fields)| fields.split_whitespace().nth(1))
+        .and_then(|ppid| ppid.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unrecognised /proc/{pid}/stat format"),
+            )
+        })
+}
+
+/// Read the credentials of the peer of a stream accepted by a server, and check them against the
+/// policy - failing with [`ErrorKind::PermissionDenied`] if the peer isn't allowed.
+pub async fn check_peer<U: UnixSocketInterface>(
+    stream: &mut U::UnixStream,
+    policy: &CredentialPolicy,
+) -> IoResult<PeerCredentials> {
+    let credentials = U::unix_stream_peer_credentials(stream).await?;
+    if !policy.allows(&credentials) {
+        warn!("Rejecting connection from {}", credentials);
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Connections from {credentials} are not allowed"),
+        ));
+    }
+    Ok(credentials)
+}
+
+/// Check - on the client side - that both the socket file of a service and the server on the
+/// other end of a connection to it belong to the expected user, so a socket squatted by someone
+/// else in a shared directory isn't trusted.
+pub(crate) fn verify_owner(
+    service_socket: &ServiceSocket,
+    expected_uid: u32,
+    peer: IoResult<PeerCredentials>,
+) -> crate::error::Result<()> {
+    let check_failed = |source| Error::PeerCheckFailed {
+        socket: service_socket.clone(),
+        source,
+    };
+    let socket_uid = service_socket
+        .path
+        .symlink_metadata()
+        .map_err(check_failed)?
+        .uid();
+    let peer_uid = peer.map_err(check_failed)?.uid;
+    for found_uid in [socket_uid, peer_uid] {
+        if found_uid != expected_uid {
+            error!(
+                "Service @ {} belongs to uid {}, but uid {} was expected",
+                service_socket, found_uid, expected_uid
+            );
+            return Err(Error::UntrustedPeer {
+                socket: service_socket.clone(),
+                expected_uid,
+                found_uid,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        ffi::OsStr,
+        os::unix::net::{UnixListener, UnixStream},
+    };
+
+    use super::{
+        current_uid, is_descendant_of, peer_credentials, verify_owner, CredentialPolicy,
+        PeerCredentials,
+    };
+    use crate::{Error, ServiceSocket};
+
+    #[test]
+    pub fn credential_policy_test() {
+        let credentials = PeerCredentials {
+            pid: 1,
+            uid: 1000,
+            gid: 100,
+        };
+        assert!(!CredentialPolicy::new().allows(&credentials));
+        assert!(CredentialPolicy::new().with_uid(1000).allows(&credentials));
+        assert!(CredentialPolicy::new().with_gid(100).allows(&credentials));
+        assert!(!CredentialPolicy::new()
+            .with_uid(0)
+            .with_gid(0)
+            .allows(&credentials));
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn peer_credentials_test() {
+        let (ours, _theirs) = UnixStream::pair().unwrap();
+        let credentials = peer_credentials(&ours).unwrap();
+        assert_eq!(credentials.pid, std::process::id());
+        assert!(CredentialPolicy::current_user().allows(&credentials));
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn is_descendant_of_test() {
+        let this_process = std::process::id();
+        assert!(is_descendant_of(this_process, this_process).unwrap());
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        assert!(is_descendant_of(child.id(), this_process).unwrap());
+        assert!(!is_descendant_of(this_process, child.id()).unwrap());
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn verify_owner_test() {
+        let service_socket = ServiceSocket::new(
+            OsStr::new(&format!("verify-owner-test-{}.sock", std::process::id())),
+            &temp_dir(),
+        );
+        let _ = std::fs::remove_file(&service_socket.path);
+        let _listener = UnixListener::bind(&service_socket.path).unwrap();
+        let stream = UnixStream::connect(&service_socket.path).unwrap();
+
+        verify_owner(&service_socket, current_uid(), peer_credentials(&stream)).unwrap();
+        assert!(matches!(
+            verify_owner(
+                &service_socket,
+                current_uid().wrapping_add(1),
+                peer_credentials(&stream)
+            ),
+            Err(Error::UntrustedPeer { found_uid, .. }) if found_uid == current_uid()
+        ));
+        std::fs::remove_file(&service_socket.path).unwrap();
+        assert!(matches!(
+            verify_owner(&service_socket, current_uid(), peer_credentials(&stream)),
+            Err(Error::PeerCheckFailed { .. })
+        ));
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,487 @@
+//! Fire-and-forget services over `SOCK_DGRAM` unix sockets - like log sinks or metrics
+//! collectors - where clients send self-contained datagrams and never wait for a reply. See
+//! [`DatagramService`] and [`ServerDatagramService`].
+//!
+//! Datagram services live in a base context directory like stream services do: their socket is at
+//! the same [`ServiceSocket`] path, is bound with the same [`SocketPermissions`] and
+//! [`SecurityPolicy`], is removed when stale under the same lock file, and is cleaned up along
+//! with the state file once the server finishes. Servers report liveness over the liveness socket
+//! they are given, so they can be started by anything that starts stream services - though
+//! clients don't start them on demand, as there is no connection to wait for.
+//!
+//! As sending and receiving a datagram are single system calls on a socket without connections,
+//! they are run on the [`blocking`] thread pool rather than through a [`UnixSocketInterface`].
+
+use std::{
+    borrow::Cow,
+    ffi::OsStr,
+    fmt::Debug,
+    io::{self, ErrorKind},
+    os::unix::net::UnixDatagram,
+    path::Path,
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use blocking::unblock;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    audit, cleanable_path::CleanablePathBuf, events, liveness, lockfile::LockFile,
+    notify_liveness_socket, socket_shims::StdThreadpoolUSocks, spawn, status, Error, IoResult,
+    SecurityPolicy, ServiceEvent, ServiceSocket, SocketPermissions,
+};
+
+/// Largest datagram received by default - see [`DatagramService::max_datagram_size`].
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// A service that receives datagrams on a unix socket in a base context directory, rather than
+/// accepting connections - the datagram counterpart of [`crate::Service`].
+pub trait DatagramService: Debug {
+    /// Name of the socket in the base context directory - see [`crate::Service::socket_name`].
+    fn socket_name(&self) -> Cow<'_, OsStr>;
+
+    /// Checks made on the socket path - see [`crate::Service::security_policy`]. The default
+    /// makes no checks.
+    fn security_policy(&self) -> SecurityPolicy {
+        SecurityPolicy::default()
+    }
+
+    /// Largest datagram clients send and servers receive. The default is
+    /// [`DEFAULT_MAX_DATAGRAM_SIZE`].
+    fn max_datagram_size(&self) -> usize {
+        DEFAULT_MAX_DATAGRAM_SIZE
+    }
+}
+
+/// Client-side functionality for every [`DatagramService`].
+pub trait DatagramServiceExt: DatagramService {
+    /// Connect a datagram socket to the running server of the service. Like
+    /// [`crate::ServiceExt::connect_to_running_service`], a socket file without a server produces
+    /// [`Error::StaleSocket`].
+    #[instrument]
+    fn connect_datagram(
+        &self,
+        base_context_directory: &Path,
+    ) -> crate::error::Result<DatagramSender> {
+        let service_socket = ServiceSocket::new(&self.socket_name(), base_context_directory);
+        self.security_policy().check_socket(&service_socket)?;
+        info!("Connecting datagram socket to service @ {}", service_socket);
+        let socket = UnixDatagram::unbound()
+            .and_then(|socket| socket.connect(&service_socket.path).map(|()| socket))
+            .map_err(|e| {
+                error!("Failed to connect to service @ {}", service_socket);
+                if e.kind() == ErrorKind::ConnectionRefused && service_socket.path.exists() {
+                    Error::StaleSocket {
+                        socket: service_socket.clone(),
+                        source: e,
+                    }
+                } else {
+                    Error::ConnectFailed {
+                        socket: service_socket.clone(),
+                        source: e,
+                    }
+                }
+            })?;
+        Ok(DatagramSender {
+            service_socket,
+            socket: Arc::new(socket),
+            max_datagram_size: self.max_datagram_size(),
+        })
+    }
+}
+
+impl<S: DatagramService + ?Sized> DatagramServiceExt for S {}
+
+/// Datagram socket connected to the server of a [`DatagramService`]. Cloning it produces another
+/// handle to the same socket.
+#[derive(Debug, Clone)]
+pub struct DatagramSender {
+    service_socket: ServiceSocket,
+    socket: Arc<UnixDatagram>,
+    max_datagram_size: usize,
+}
+
+impl DatagramSender {
+    /// The socket of the service this sends to.
+    pub fn service_socket(&self) -> &ServiceSocket {
+        &self.service_socket
+    }
+
+    /// Send a datagram to the server, waiting for room in its receive queue if it is full.
+    /// Datagrams bigger than [`DatagramService::max_datagram_size`] are
+    /// [`io::ErrorKind::InvalidInput`] errors.
+    pub async fn send(&self, datagram: &[u8]) -> IoResult<()> {
+        check_size(datagram.len(), self.max_datagram_size)?;
+        let socket = self.socket.clone();
+        let datagram = datagram.to_vec();
+        unblock(move || send_whole(&socket, &datagram)).await
+    }
+
+    /// [`Self::send`], blocking the current thread.
+    pub fn send_blocking(&self, datagram: &[u8]) -> IoResult<()> {
+        check_size(datagram.len(), self.max_datagram_size)?;
+        send_whole(&self.socket, datagram)
+    }
+}
+
+fn check_size(size: usize, max_datagram_size: usize) -> IoResult<()> {
+    if size > max_datagram_size {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "datagram of {} bytes is bigger than the maximum of {}",
+                size, max_datagram_size
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn send_whole(socket: &UnixDatagram, datagram: &[u8]) -> IoResult<()> {
+    let sent = socket.send(datagram)?;
+    if sent != datagram.len() {
+        return Err(io::Error::new(
+            ErrorKind::WriteZero,
+            format!(
+                "only sent {} bytes of a {} byte datagram",
+                sent,
+                datagram.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The bound socket of a [`DatagramService`], handed to [`ServerDatagramService::run_server`].
+#[derive(Debug, Clone)]
+pub struct DatagramReceiver {
+    socket: Arc<UnixDatagram>,
+    max_datagram_size: usize,
+}
+
+impl DatagramReceiver {
+    /// Receive the next datagram. Datagrams bigger than [`DatagramService::max_datagram_size`]
+    /// are [`io::ErrorKind::InvalidData`] errors, as the rest of them is lost.
+    ///
+    /// The datagram is received on the thread pool, and still taken off the socket if this is
+    /// dropped before it completes - so don't race it against other futures while datagrams
+    /// matter.
+    pub async fn recv(&self) -> IoResult<Vec<u8>> {
+        let socket = self.socket.clone();
+        let max_datagram_size = self.max_datagram_size;
+        unblock(move || recv_whole(&socket, max_datagram_size)).await
+    }
+
+    /// [`Self::recv`], blocking the current thread.
+    pub fn recv_blocking(&self) -> IoResult<Vec<u8>> {
+        recv_whole(&self.socket, self.max_datagram_size)
+    }
+
+    /// The bare socket - for instance to take it over with the datagram socket of an async
+    /// runtime, after duplicating it with [`UnixDatagram::try_clone`].
+    pub fn get_ref(&self) -> &UnixDatagram {
+        &self.socket
+    }
+}
+
+fn recv_whole(socket: &UnixDatagram, max_datagram_size: usize) -> IoResult<Vec<u8>> {
+    // One byte more than allowed, to tell datagrams that fit from those that were cut short.
+    let mut datagram = vec![0; max_datagram_size + 1];
+    let received = socket.recv(&mut datagram)?;
+    if received > max_datagram_size {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "received a datagram bigger than {} bytes",
+                max_datagram_size
+            ),
+        ));
+    }
+    datagram.truncate(received);
+    Ok(datagram)
+}
+
+/// Server implementation for a [`DatagramService`] - the datagram counterpart of
+/// [`crate::Server`].
+///
+/// Like [`crate::Server`], this always uses [`macro@async_trait`], as server futures are required
+/// to be `Send`.
+#[async_trait]
+pub trait ServerDatagramService<S: DatagramService>: Debug {
+    type FinalOutput;
+
+    /// File mode and group to give the service socket - see
+    /// [`crate::Server::socket_permissions`]. Sending a datagram needs write permission on the
+    /// socket, like connecting does.
+    fn socket_permissions(&self) -> SocketPermissions {
+        SocketPermissions::default()
+    }
+
+    /// Run the server, receiving datagrams from the socket. Cleaning up the socket path is
+    /// handled by [`ServerDatagramServiceExt`].
+    async fn run_server(
+        &self,
+        service: &S,
+        socket: DatagramReceiver,
+    ) -> IoResult<Self::FinalOutput>;
+}
+
+/// Extension trait that runs [`ServerDatagramService`]s - the datagram counterpart of
+/// [`crate::ServerExt`].
+#[allow(async_fn_in_trait)]
+pub trait ServerDatagramServiceExt<S: DatagramService>: ServerDatagramService<S> {
+    /// Bind the service socket, notify the liveness socket, run the server, and clean the socket
+    /// and state file up once it finishes - see [`crate::ServerExt::start_and_run_server`].
+    ///
+    /// Liveness is reported over the liveness socket path as for stream services, but datagram
+    /// sockets can't be bound by the client for the server, so the path must not be an inherited
+    /// listener - see [`liveness::LivenessTransport::InheritedListener`].
+    #[instrument]
+    async fn start_and_run_server(
+        &self,
+        service: &S,
+        context_base_path: &Path,
+        liveness_socket_path: Option<&Path>,
+    ) -> crate::error::Result<Self::FinalOutput> {
+        let service_socket = ServiceSocket::new(&service.socket_name(), context_base_path);
+        info!("Obtaining datagram socket @ {}", service_socket);
+        let bound = bind_datagram_socket(
+            &service_socket,
+            &self.socket_permissions(),
+            &service.security_policy(),
+        )
+        .await;
+        let socket = match bound {
+            Ok(socket) => socket,
+            Err(e) => {
+                if let Some(p) = liveness_socket_path {
+                    let _ =
+                        liveness::report_liveness_failure::<StdThreadpoolUSocks>(p, &e.to_string())
+                            .await;
+                }
+                return Err(e);
+            }
+        };
+        // Only clean up the socket once it is actually ours.
+        let socket_path: CleanablePathBuf = service_socket.path.clone().into();
+        info!("Successfully bound @ {}", service_socket);
+        let state = status::ServiceState::for_this_process(None, None);
+        let state_file = status::write_state_file(&service_socket, &state)
+            .map_err(|e| warn!("Couldn't write state file for {} - {}", service_socket, e))
+            .ok();
+        spawn::watch_parent();
+        let _ = match liveness_socket_path {
+            Some(p) => notify_liveness_socket::<StdThreadpoolUSocks>(p).await,
+            None => {
+                info!("No liveness socket path provided, assuming autonomous.");
+                Ok(())
+            }
+        };
+
+        info!("Starting datagram service @ {}", service_socket);
+        let receiver = DatagramReceiver {
+            socket: Arc::new(socket),
+            max_datagram_size: service.max_datagram_size(),
+        };
+        let res = self
+            .run_server(service, receiver)
+            .await
+            .map_err(|e| Error::ServerFailed {
+                socket: service_socket.clone(),
+                source: e,
+            })?;
+        info!("Cleaning up socket @ {}", service_socket);
+        drop(state_file);
+        drop(socket_path);
+        events::emit(ServiceEvent::Stopped {
+            socket: service_socket,
+        });
+        Ok(res)
+    }
+}
+
+impl<S: DatagramService, T: ServerDatagramService<S>> ServerDatagramServiceExt<S> for T {}
+
+/// Check whether a server is bound to the datagram socket at the path, by connecting to it.
+pub(crate) fn probe(socket_path: &Path) -> IoResult<()> {
+    UnixDatagram::unbound()?.connect(socket_path)
+}
+
+/// Remove the datagram socket of a service if it is stale, producing whether a live server is
+/// bound to it - the datagram counterpart of [`crate::remove_stale_socket`].
+pub(crate) async fn remove_stale_socket(service_socket: &ServiceSocket) -> IoResult<bool> {
+    let lock = LockFile::acquire(&service_socket.lock_path()).await?;
+    debug!("Acquired lock file @ {}", lock.path().display());
+    match probe(&service_socket.path) {
+        Ok(()) => {
+            info!("Socket @ {} is in use by a live server", service_socket);
+            Ok(true)
+        }
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            warn!("Removing stale socket @ {}", service_socket);
+            match std::fs::remove_file(&service_socket.path) {
+                Ok(()) => {
+                    audit::record(Some(service_socket), audit::AuditEvent::StaleSocketRemoved);
+                    Ok(false)
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Bind the datagram socket of a service - see [`crate::bind_service_socket`].
+async fn bind_datagram_socket(
+    service_socket: &ServiceSocket,
+    permissions: &SocketPermissions,
+    security: &SecurityPolicy,
+) -> crate::error::Result<UnixDatagram> {
+    let bind_failed = |e| Error::BindFailed {
+        socket: service_socket.clone(),
+        source: e,
+    };
+    security.check_socket(service_socket)?;
+    permissions
+        .prepare_context_dir(&service_socket.path)
+        .map_err(bind_failed)?;
+    match permissions.bind_datagram(&service_socket.path) {
+        Ok(socket) => return Ok(socket),
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            debug!(
+                "Socket @ {} already exists - checking if it is stale",
+                service_socket
+            );
+        }
+        Err(e) => return Err(bind_failed(e)),
+    }
+
+    // Whatever is in the way may have been swapped out since the first check.
+    security.check_socket(service_socket)?;
+    if remove_stale_socket(service_socket)
+        .await
+        .map_err(bind_failed)?
+    {
+        error!("Socket @ {} is in use by a live server", service_socket);
+        return Err(bind_failed(ErrorKind::AddrInUse.into()));
+    }
+    permissions
+        .bind_datagram(&service_socket.path)
+        .map_err(bind_failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, ffi::OsStr, io::ErrorKind, os::unix::net::UnixDatagram};
+
+    use futures_lite::future::block_on;
+
+    use super::{
+        DatagramReceiver, DatagramService, DatagramServiceExt, ServerDatagramService,
+        ServerDatagramServiceExt,
+    };
+    use crate::{error::Error, status, ContextDir, IoResult, ServiceSocket, ServiceStatus};
+
+    #[derive(Debug)]
+    struct MetricsService;
+
+    impl DatagramService for MetricsService {
+        fn socket_name(&self) -> Cow<'_, OsStr> {
+            OsStr::new("metrics.sock").into()
+        }
+
+        fn max_datagram_size(&self) -> usize {
+            16
+        }
+    }
+
+    /// Collects datagrams until it receives `stop`.
+    #[derive(Debug)]
+    struct MetricsServer;
+
+    #[crate::async_trait]
+    impl ServerDatagramService<MetricsService> for MetricsServer {
+        type FinalOutput = Vec<Vec<u8>>;
+
+        async fn run_server(
+            &self,
+            _service: &MetricsService,
+            socket: DatagramReceiver,
+        ) -> IoResult<Self::FinalOutput> {
+            let mut received = Vec::new();
+            loop {
+                let datagram = socket.recv().await?;
+                if datagram == b"stop" {
+                    return Ok(received);
+                }
+                received.push(datagram);
+            }
+        }
+    }
+
+    #[test]
+    pub fn datagram_service_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(&MetricsService.socket_name(), &context);
+        // Leave a stale socket behind for the server to replace.
+        drop(UnixDatagram::bind(&service_socket.path).unwrap());
+        assert!(matches!(
+            MetricsService.connect_datagram(&context),
+            Err(Error::StaleSocket { .. })
+        ));
+        assert_eq!(
+            block_on(status::socket_status(&service_socket)),
+            ServiceStatus::StaleSocket
+        );
+
+        let server_context = context.to_path_buf();
+        let server = std::thread::spawn(move || {
+            block_on(MetricsServer.start_and_run_server(&MetricsService, &server_context, None))
+        });
+        let sender = loop {
+            if let ServiceStatus::Running { .. } = block_on(status::socket_status(&service_socket))
+            {
+                break MetricsService.connect_datagram(&context).unwrap();
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        };
+        block_on(sender.send(b"cpu 0.5")).unwrap();
+        sender.send_blocking(b"mem 1024").unwrap();
+        assert_eq!(
+            sender
+                .send_blocking(b"far too long for the server")
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+        sender.send_blocking(b"stop").unwrap();
+
+        let received = server.join().unwrap().unwrap();
+        assert_eq!(received, [b"cpu 0.5".to_vec(), b"mem 1024".to_vec()]);
+        assert!(!service_socket.path.exists());
+        assert_eq!(
+            block_on(status::socket_status(&service_socket)),
+            ServiceStatus::NotRunning
+        );
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,174 @@
+//! Dependencies between the services of a [`crate::ServiceBundle`].
+//!
+//! Services declared in [`crate::declare_service_bundle`] can state that they require other
+//! services of the same bundle. When connecting to such a service via [`crate::ReifiedService::connect`]
+//! and it needs to be started, its dependencies are started first - transitively, and in
+//! topological order.
+
+use std::{fmt::Display, future::Future, pin::Pin, time::Duration};
+
+use async_trait::async_trait;
+
+use crate::{error, ReifiedService, Service, ServiceBundle, ServiceStartable, UnixSocketInterface};
+
+/// Future produced when starting the dependencies of a service.
+pub type DependencyStartFuture<'a> = Pin<Box<dyn Future<Output = error::Result<()>> + 'a>>;
+
+/// Function attached to a [`ReifiedService`] that starts all the dependencies of the service, with
+/// the given liveness timeout for each of them.
+pub type DependencyStarter<'a> = Box<dyn Fn(Duration) -> DependencyStartFuture<'a> + 'a>;
+
+/// Implemented on every service in a bundle generated by [`crate::declare_service_bundle`],
+/// describing how to start the services it depends on within that bundle.
+///
+/// This always uses [`macro@async_trait`], as starting dependencies recurses through the services
+/// of the bundle, which needs boxed futures.
+#[async_trait(?Send)]
+pub trait BundleDependencies<Bundle: ServiceBundle, U: UnixSocketInterface>: Service<U> {
+    /// Start all the (transitive) dependencies of this service within the bundle, dependencies
+    /// first. Services with names in `already_started` are skipped, and services started by this
+    /// function get added to it.
+    async fn start_dependencies(
+        &self,
+        bundle: &Bundle,
+        liveness_timeout: Duration,
+        already_started: &mut Vec<&'static str>,
+    ) -> error::Result<()>;
+}
+
+/// Make sure a dependency is running - starting it on-demand if necessary - without wrapping a
+/// connection to it. Unlike [`ReifiedService::ensure_started`], dependencies of the dependency are
+/// left to the caller.
+pub async fn ensure_dependency_started<S, U, ExecutorPrefixComponent>(
+    dependency: &ReifiedService<'_, S, U, ExecutorPrefixComponent>,
+    liveness_timeout: Duration,
+) -> error::Result<()>
+where
+    S: ServiceStartable<U>,
+    U: UnixSocketInterface,
+    ExecutorPrefixComponent: AsRef<std::ffi::OsStr> + Sized + std::fmt::Debug,
+{
+    dependency
+        .ensure_started_without_dependencies(liveness_timeout)
+        .await
+}
+
+/// Static graph of the dependencies between the services of a bundle, keyed by the service type
+/// names.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DependencyGraph {
+    services: &'static [(&'static str, &'static [&'static str])],
+}
+
+impl DependencyGraph {
+    /// Create a dependency graph from a list of services and the names of the services they
+    /// directly depend on.
+    pub const fn new(services: &'static [(&'static str, &'static [&'static str])]) -> Self {
+        Self { services }
+    }
+
+    /// Names of the services that the given service directly depends on. Unknown services have no
+    /// dependencies.
+    pub fn dependencies_of(&self, service: &str) -> &'static [&'static str] {
+        self.services
+            .iter()
+            .find(|(name, _)| *name == service)
+            .map(|(_, dependencies)| *dependencies)
+            .unwrap_or(&[])
+    }
+
+    /// Check that there are no cycles in the graph, returning one of the cycles if there are.
+    pub fn check_acyclic(&self) -> Result<(), DependencyCycle> {
+        let mut finished = Vec::new();
+        for (service, _) in self.services {
+            self.visit(service, &mut Vec::new(), &mut finished)?;
+        }
+        Ok(())
+    }
+
+    /// All the (transitive) dependencies of a service, in the order they should be started.
+    pub fn start_order(&self, service: &'static str) -> Result<Vec<&'static str>, DependencyCycle> {
+        let mut order = Vec::new();
+        self.visit(service, &mut Vec::new(), &mut order)?;
+        // The service itself is always visited last.
+        order.pop();
+        Ok(order)
+    }
+
+    /// Depth-first post-order traversal, tracking the current path to detect cycles.
+    fn visit(
+        &self,
+        service: &'static str,
+        path: &mut Vec<&'static str>,
+        finished: &mut Vec<&'static str>,
+    ) -> Result<(), DependencyCycle> {
+        if finished.contains(&service) {
+            return Ok(());
+        }
+        if let Some(cycle_start) = path.iter().position(|s| *s == service) {
+            let mut cycle = path[cycle_start..].to_vec();
+            cycle.push(service);
+            return Err(DependencyCycle(cycle));
+        }
+        path.push(service);
+        for dependency in self.dependencies_of(service) {
+            self.visit(dependency, path, finished)?;
+        }
+        path.pop();
+        finished.push(service);
+        Ok(())
+    }
+}
+
+/// A cycle in a [`DependencyGraph`], listed as the services along the cycle with the first
+/// service repeated at the end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyCycle(pub Vec<&'static str>);
+
+impl Display for DependencyCycle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cyclic service dependencies: {}", self.0.join(" -> "))
+    }
+}
+
+impl std::error::Error for DependencyCycle {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn start_order_is_topological() {
+        let graph =
+            DependencyGraph::new(&[("A", &["B", "C"]), ("B", &["C"]), ("C", &[]), ("D", &["A"])]);
+        assert_eq!(graph.check_acyclic(), Ok(()));
+        assert_eq!(graph.start_order("A"), Ok(vec!["C", "B"]));
+        assert_eq!(graph.start_order("D"), Ok(vec!["C", "B", "A"]));
+        assert_eq!(graph.start_order("C"), Ok(vec![]));
+    }
+
+    #[test]
+    pub fn cycles_are_detected() {
+        let graph = DependencyGraph::new(&[("A", &["B"]), ("B", &["C"]), ("C", &["A"])]);
+        assert_eq!(
+            graph.check_acyclic(),
+            Err(DependencyCycle(vec!["A", "B", "C", "A"]))
+        );
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,409 @@
+//! Type-erased services, for picking services by name at runtime - see [`ServiceRegistry`].
+//!
+//! [`Service`] has associated types and generic methods, so there can't be a `dyn Service`.
+//! [`DynService`] is an object-safe facade over it, implemented for every service whose client
+//! connections are a [`ReadWriteStream`] - which the bare streams of every socket interface are,
+//! as are the connections of the `buffered`, `tokio`, `async_io` and `smol` methods of
+//! [`crate::declare_service`]. Connections are handed out boxed, as `Box<dyn ReadWriteStream>`.
+//! [`DynServiceStartable`] does the same for [`ServiceStartable`], so services can be started on
+//! demand too.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    fmt::Debug,
+    future::Future,
+    io,
+    path::Path,
+    pin::Pin,
+    time::Duration,
+};
+
+use crate::{
+    buffered::BufferedConnection, error, DefaultUnixSocks, IoResult, Service, ServiceExt,
+    ServiceStartable, UnixSocketInterface,
+};
+
+/// Future of an operation on a [`ReadWriteStream`].
+pub type StreamFuture<'a, T> = Pin<Box<dyn Future<Output = IoResult<T>> + 'a>>;
+
+/// Future of a connection to a [`DynService`].
+pub type DynConnectFuture<'a> =
+    Pin<Box<dyn Future<Output = error::Result<Box<dyn ReadWriteStream>>> + 'a>>;
+
+/// Object-safe stream of bytes, that connections to a [`DynService`] are handed out as.
+pub trait ReadWriteStream {
+    /// Read some bytes into `buf`, returning how many. Ok(0) means the other side closed the
+    /// connection.
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> StreamFuture<'a, usize>;
+
+    /// Write some bytes from `buf`, returning how many.
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> StreamFuture<'a, usize>;
+
+    /// Send anything written but still buffered.
+    fn flush(&mut self) -> StreamFuture<'_, ()>;
+
+    /// Shut the stream down, as much as it can be.
+    fn shutdown(&mut self) -> StreamFuture<'_, ()>;
+
+    /// Fill all of `buf`. The connection being closed first is an
+    /// [`io::ErrorKind::UnexpectedEof`] error.
+    fn read_exact<'a>(&'a mut self, mut buf: &'a mut [u8]) -> StreamFuture<'a, ()> {
+        Box::pin(async move {
+            while !buf.is_empty() {
+                match self.read(buf).await? {
+                    0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    n => buf = &mut buf[n..],
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Write all of `buf`.
+    fn write_all<'a>(&'a mut self, mut buf: &'a [u8]) -> StreamFuture<'a, ()> {
+        Box::pin(async move {
+            while !buf.is_empty() {
+                match self.write(buf).await? {
+                    0 => return Err(io::ErrorKind::WriteZero.into()),
+                    n => buf = &buf[n..],
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Implement [`ReadWriteStream`] for streams implementing the `futures` io traits.
+macro_rules! futures_io_read_write_stream {
+    ($($(#[$stream_meta:meta])* $stream:ty),* $(,)?) => {$(
+        $(#[$stream_meta])*
+        impl ReadWriteStream for $stream {
+            fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> StreamFuture<'a, usize> {
+                Box::pin(futures_lite::AsyncReadExt::read(self, buf))
+            }
+
+            fn write<'a>(&'a mut self, buf: &'a [u8]) -> StreamFuture<'a, usize> {
+                Box::pin(futures_lite::AsyncWriteExt::write(self, buf))
+            }
+
+            fn flush(&mut self) -> StreamFuture<'_, ()> {
+                Box::pin(futures_lite::AsyncWriteExt::flush(self))
+            }
+
+            fn shutdown(&mut self) -> StreamFuture<'_, ()> {
+                Box::pin(futures_lite::AsyncWriteExt::close(self))
+            }
+        }
+    )*};
+}
+
+futures_io_read_write_stream! {
+    blocking::Unblock<std::os::unix::net::UnixStream>,
+    #[cfg(feature = "async-std")]
+    async_std::os::unix::net::UnixStream,
+    #[cfg(feature = "async-io")]
+    async_io::Async<std::os::unix::net::UnixStream>,
+    #[cfg(feature = "smol")]
+    async_net::unix::UnixStream,
+}
+
+#[cfg(feature = "tokio")]
+impl ReadWriteStream for tokio::net::UnixStream {
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> StreamFuture<'a, usize> {
+        Box::pin(tokio::io::AsyncReadExt::read(self, buf))
+    }
+
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> StreamFuture<'a, usize> {
+        Box::pin(tokio::io::AsyncWriteExt::write(self, buf))
+    }
+
+    fn flush(&mut self) -> StreamFuture<'_, ()> {
+        Box::pin(tokio::io::AsyncWriteExt::flush(self))
+    }
+
+    fn shutdown(&mut self) -> StreamFuture<'_, ()> {
+        Box::pin(tokio::io::AsyncWriteExt::shutdown(self))
+    }
+}
+
+impl<U: UnixSocketInterface> ReadWriteStream for BufferedConnection<U> {
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> StreamFuture<'a, usize> {
+        Box::pin(BufferedConnection::read(self, buf))
+    }
+
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> StreamFuture<'a, usize> {
+        Box::pin(BufferedConnection::write(self, buf))
+    }
+
+    fn flush(&mut self) -> StreamFuture<'_, ()> {
+        Box::pin(BufferedConnection::flush(self))
+    }
+
+    fn shutdown(&mut self) -> StreamFuture<'_, ()> {
+        Box::pin(BufferedConnection::shutdown(self))
+    }
+}
+
+/// Object-safe facade over a [`Service`], implemented for every service whose client connections
+/// are a [`ReadWriteStream`].
+pub trait DynService<U: UnixSocketInterface = DefaultUnixSocks>: Debug {
+    /// See [`Service::socket_name`].
+    fn socket_name(&self) -> Cow<'_, OsStr>;
+
+    /// Connect to the service if it is running in the given base context directory, without
+    /// starting it - see [`ServiceExt::connect_to_running_service`].
+    fn connect_to_running<'a>(&'a self, base_context_directory: &'a Path) -> DynConnectFuture<'a>;
+}
+
+impl<U, S> DynService<U> for S
+where
+    U: UnixSocketInterface + 'static,
+    S: Service<U>,
+    S::ServiceClientConnection: ReadWriteStream + 'static,
+{
+    fn socket_name(&self) -> Cow<'_, OsStr> {
+        Service::<U>::socket_name(self)
+    }
+
+    fn connect_to_running<'a>(&'a self, base_context_directory: &'a Path) -> DynConnectFuture<'a> {
+        Box::pin(async move {
+            let connection =
+                ServiceExt::<U>::connect_to_running_service(self, base_context_directory).await?;
+            Ok(Box::new(connection) as Box<dyn ReadWriteStream>)
+        })
+    }
+}
+
+/// Object-safe facade over a [`ServiceStartable`], implemented for every startable service whose
+/// client connections are a [`ReadWriteStream`].
+pub trait DynServiceStartable<U: UnixSocketInterface = DefaultUnixSocks>: DynService<U> {
+    /// Connect to the service in the given base context directory, starting it if it isn't
+    /// running - see [`ServiceExt::connect_to_service`].
+    fn connect<'a>(
+        &'a self,
+        base_context_directory: &'a Path,
+        liveness_timeout: Duration,
+    ) -> DynConnectFuture<'a>;
+
+    /// This service, as a [`DynService`].
+    fn as_dyn_service(&self) -> &dyn DynService<U>;
+}
+
+impl<U, S> DynServiceStartable<U> for S
+where
+    U: UnixSocketInterface + 'static,
+    S: ServiceStartable<U>,
+    S::ServiceClientConnection: ReadWriteStream + 'static,
+{
+    fn connect<'a>(
+        &'a self,
+        base_context_directory: &'a Path,
+        liveness_timeout: Duration,
+    ) -> DynConnectFuture<'a> {
+        Box::pin(async move {
+            let connection = ServiceExt::<U>::connect_to_service(
+                self,
+                None::<&[OsString]>,
+                base_context_directory,
+                liveness_timeout,
+            )
+            .await?;
+            Ok(Box::new(connection) as Box<dyn ReadWriteStream>)
+        })
+    }
+
+    fn as_dyn_service(&self) -> &dyn DynService<U> {
+        self
+    }
+}
+
+/// A registered service, startable or not.
+enum Registered<U: UnixSocketInterface> {
+    Running(Box<dyn DynService<U>>),
+    Startable(Box<dyn DynServiceStartable<U>>),
+}
+
+impl<U: UnixSocketInterface> Debug for Registered<U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Running(service) => service.fmt(f),
+            Self::Startable(service) => service.fmt(f),
+        }
+    }
+}
+
+/// Services registered by name at runtime, so which service to use can be picked - for instance
+/// from the command line - without knowing its type.
+pub struct ServiceRegistry<U: UnixSocketInterface = DefaultUnixSocks> {
+    services: BTreeMap<String, Registered<U>>,
+}
+
+impl<U: UnixSocketInterface> Debug for ServiceRegistry<U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.services.iter()).finish()
+    }
+}
+
+impl<U: UnixSocketInterface> Default for ServiceRegistry<U> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<U: UnixSocketInterface> ServiceRegistry<U> {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self {
+            services: BTreeMap::new(),
+        }
+    }
+
+    /// Register a service that is only connected to, never started, under the given name. Returns
+    /// whether this replaced a service already registered under that name.
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        service: impl DynService<U> + 'static,
+    ) -> bool {
+        self.services
+            .insert(name.into(), Registered::Running(Box::new(service)))
+            .is_some()
+    }
+
+    /// Register a service that is started on demand under the given name. Returns whether this
+    /// replaced a service already registered under that name.
+    pub fn register_startable(
+        &mut self,
+        name: impl Into<String>,
+        service: impl DynServiceStartable<U> + 'static,
+    ) -> bool {
+        self.services
+            .insert(name.into(), Registered::Startable(Box::new(service)))
+            .is_some()
+    }
+
+    /// Remove the service registered under the given name, returning whether there was one.
+    pub fn unregister(&mut self, name: &str) -> bool {
+        self.services.remove(name).is_some()
+    }
+
+    /// The service registered under the given name, startable or not.
+    pub fn get(&self, name: &str) -> Option<&dyn DynService<U>> {
+        match self.services.get(name)? {
+            Registered::Running(service) => Some(service.as_ref()),
+            Registered::Startable(service) => Some(service.as_dyn_service()),
+        }
+    }
+
+    /// The service registered under the given name, if it was registered as startable.
+    pub fn get_startable(&self, name: &str) -> Option<&dyn DynServiceStartable<U>> {
+        match self.services.get(name)? {
+            Registered::Running(_) => None,
+            Registered::Startable(service) => Some(service.as_ref()),
+        }
+    }
+
+    /// Names of the registered services, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> + '_ {
+        self.services.keys().map(String::as_str)
+    }
+
+    /// Whether no services are registered.
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    /// Number of registered services.
+    pub fn len(&self) -> usize {
+        self.services.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use futures_lite::future::block_on;
+
+    use super::*;
+    use crate::{
+        context_dir::ContextDir, declare_service, error::Error, socket_shims::StdThreadpoolUSocks,
+    };
+
+    declare_service! {
+        /// Service that is only ever connected to
+        pub RegisteredService <U> = {
+            @ "registered-service-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                Ok(unix_socket)
+            }
+        } impl {U: UnixSocketInterface}
+    }
+
+    declare_service! {
+        /// Service whose process always fails straight away
+        pub RegisteredCrashingService <U> = {
+            "false" @ "registered-crashing-service-test.sock" as buffered
+        } impl {U: UnixSocketInterface}
+    }
+
+    #[test]
+    pub fn registered_services_are_connected_to_by_name() {
+        let mut registry = ServiceRegistry::<StdThreadpoolUSocks>::new();
+        assert!(!registry.register("registered", RegisteredService));
+        assert!(!registry.register_startable("crashing", RegisteredCrashingService));
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            ["crashing", "registered"]
+        );
+        assert!(registry.get_startable("registered").is_none());
+        assert!(registry.get("missing").is_none());
+
+        let context = ContextDir::temp_for_tests().unwrap();
+        let listener =
+            std::os::unix::net::UnixListener::bind(context.join("registered-service-test.sock"))
+                .unwrap();
+        let service = registry.get("registered").unwrap();
+        assert_eq!(
+            service.socket_name(),
+            OsStr::new("registered-service-test.sock")
+        );
+        block_on(async {
+            let mut connection = service.connect_to_running(&context).await.unwrap();
+            connection.write_all(b"ping").await.unwrap();
+            connection.shutdown().await.unwrap();
+        });
+        let mut received = Vec::new();
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_to_end(&mut received)
+            .unwrap();
+        assert_eq!(received, b"ping");
+
+        let crashing = registry.get_startable("crashing").unwrap();
+        assert!(matches!(
+            block_on(crashing.connect(&context, Duration::from_secs(30))),
+            Err(Error::SpawnExited { .. })
+        ));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,331 @@
+//! Services defined at runtime from descriptors in configuration files - requires the `dynamic`
+//! feature, and the `dynamic-toml` feature for TOML files.
+//!
+//! Deployment tooling can then define the services a program uses - what to run to start them,
+//! and where their sockets are - without the program being recompiled. A definitions file lists
+//! [`ServiceDescriptor`]s by name, in JSON or TOML:
+//! ```toml
+//! [services.cache]
+//! command = "/usr/lib/app/cache-server"
+//! args = ["--liveness", "--shards", "4"]
+//! socket = "cache.sock"
+//! liveness_arg = 1
+//! ```
+//! [`ServiceDefinitions::load`] reads such a file, and hands out [`DynamicService`]s that can be
+//! reified and connected to like any other service. Their connections are bare streams.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    ffi::OsStr,
+    fmt::Debug,
+    io,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{liveness, spawn, IoResult, Service, ServiceStartable, UnixSocketInterface};
+
+/// How to start a service and where to find it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceDescriptor {
+    /// Program to run to start the service.
+    pub command: PathBuf,
+    /// Arguments to run the program with.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Name of the socket file in the base context directory.
+    pub socket: String,
+    /// Position in [`Self::args`] to insert the liveness socket path at, for programs that take it
+    /// as an argument rather than reading [`liveness::LIVENESS_ENV_VAR`] - which is set either
+    /// way. Services without a liveness path, like those inheriting their listener, get no
+    /// argument inserted.
+    #[serde(default)]
+    pub liveness_arg: Option<usize>,
+}
+
+impl ServiceDescriptor {
+    /// Check that the socket name is a plain file name, and that the liveness argument position
+    /// is within the arguments.
+    pub fn validate(&self) -> IoResult<()> {
+        let socket = Path::new(&self.socket);
+        if socket.file_name() != Some(socket.as_os_str()) {
+            return Err(invalid_definitions(format!(
+                "Socket name {:?} is not a plain file name",
+                self.socket
+            )));
+        }
+        match self.liveness_arg {
+            Some(position) if position > self.args.len() => Err(invalid_definitions(format!(
+                "Liveness argument position {} is past the {} arguments of {}",
+                position,
+                self.args.len(),
+                self.command.display()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Service defined by a [`ServiceDescriptor`] at runtime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DynamicService {
+    name: String,
+    descriptor: ServiceDescriptor,
+}
+
+impl DynamicService {
+    /// Create a service with the given name from a descriptor, failing with
+    /// [`io::ErrorKind::InvalidData`] if it isn't valid - see [`ServiceDescriptor::validate`].
+    pub fn new(name: impl Into<String>, descriptor: ServiceDescriptor) -> IoResult<Self> {
+        descriptor.validate()?;
+        Ok(Self {
+            name: name.into(),
+            descriptor,
+        })
+    }
+
+    /// Name of the service in its definitions.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Descriptor the service was created from.
+    pub fn descriptor(&self) -> &ServiceDescriptor {
+        &self.descriptor
+    }
+}
+
+#[cfg_attr(feature = "async-trait-compat", async_trait::async_trait(?Send))]
+impl<U: UnixSocketInterface> Service<U> for DynamicService {
+    type ServiceClientConnection = U::UnixStream;
+    type ServiceServerConnection = U::UnixStream;
+
+    fn socket_name(&self) -> Cow<'_, OsStr> {
+        Cow::Borrowed(OsStr::new(&self.descriptor.socket))
+    }
+
+    async fn wrap_connection(&self, bare_stream: U::UnixStream) -> IoResult<U::UnixStream> {
+        Ok(bare_stream)
+    }
+
+    async fn wrap_incoming(&self, bare_stream: U::UnixStream) -> IoResult<U::UnixStream> {
+        Ok(bare_stream)
+    }
+}
+
+#[cfg_attr(feature = "async-trait-compat", async_trait::async_trait(?Send))]
+impl<U: UnixSocketInterface> ServiceStartable<U> for DynamicService {
+    type ProcessHandle = Child;
+
+    fn run_service_command_raw(
+        &self,
+        executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
+        liveness_path: Option<&Path>,
+        liveness_token: Option<&str>,
+        prepared_spawn: spawn::PreparedSpawn,
+    ) -> IoResult<Child> {
+        let mut args: Vec<&OsStr> = self.descriptor.args.iter().map(OsStr::new).collect();
+        if let (Some(position), Some(liveness_path)) = (self.descriptor.liveness_arg, liveness_path)
+        {
+            // In range, as the descriptor was validated.
+            args.insert(position, liveness_path.as_os_str());
+        }
+        let mut command_line: Vec<&OsStr> = executor_commandline_prefix
+            .into_iter()
+            .flatten()
+            .map(AsRef::as_ref)
+            .collect();
+        command_line.push(self.descriptor.command.as_os_str());
+        command_line.extend(args);
+
+        let mut command = Command::new(command_line[0]);
+        command.args(&command_line[1..]);
+        liveness::set_liveness_environment(&mut command, liveness_path, liveness_token);
+        prepared_spawn.apply(&mut command);
+        command.spawn()
+    }
+}
+
+/// A set of named [`ServiceDescriptor`]s, as read from a definitions file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceDefinitions {
+    /// Descriptors of the services, by name.
+    #[serde(default)]
+    pub services: BTreeMap<String, ServiceDescriptor>,
+}
+
+impl ServiceDefinitions {
+    /// Read definitions from a file - TOML if its extension is `.toml`, and JSON otherwise.
+    /// Invalid definitions are an [`io::ErrorKind::InvalidData`] error.
+    pub fn load(path: impl AsRef<Path>) -> IoResult<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        match path.extension() {
+            Some(extension) if extension == "toml" => Self::from_toml_str(&contents),
+            _ => Self::from_json_str(&contents),
+        }
+    }
+
+    /// Parse definitions from JSON.
+    pub fn from_json_str(source: &str) -> IoResult<Self> {
+        let definitions: Self = serde_json::from_str(source).map_err(invalid_definitions)?;
+        definitions.validate()?;
+        Ok(definitions)
+    }
+
+    /// Parse definitions from TOML. Without the `dynamic-toml` feature, this always fails with
+    /// [`io::ErrorKind::Unsupported`].
+    pub fn from_toml_str(source: &str) -> IoResult<Self> {
+        #[cfg(feature = "dynamic-toml")]
+        {
+            let definitions: Self = toml::from_str(source).map_err(invalid_definitions)?;
+            definitions.validate()?;
+            Ok(definitions)
+        }
+        #[cfg(not(feature = "dynamic-toml"))]
+        {
+            let _ = source;
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TOML service definitions need the dynamic-toml feature",
+            ))
+        }
+    }
+
+    /// Check every descriptor - see [`ServiceDescriptor::validate`].
+    pub fn validate(&self) -> IoResult<()> {
+        self.services
+            .values()
+            .try_for_each(ServiceDescriptor::validate)
+    }
+
+    /// The service with the given name, if there is one.
+    pub fn service(&self, name: &str) -> Option<DynamicService> {
+        let descriptor = self.services.get(name)?;
+        Some(DynamicService {
+            name: name.to_owned(),
+            descriptor: descriptor.clone(),
+        })
+    }
+
+    /// All the services, in order of name.
+    pub fn services(&self) -> impl Iterator<Item = DynamicService> + '_ {
+        self.services
+            .iter()
+            .map(|(name, descriptor)| DynamicService {
+                name: name.clone(),
+                descriptor: descriptor.clone(),
+            })
+    }
+}
+
+fn invalid_definitions(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future::block_on;
+
+    use super::*;
+    use crate::{
+        context_dir::ContextDir, error::Error, socket_shims::StdThreadpoolUSocks, ServiceExt,
+    };
+
+    #[test]
+    pub fn json_definitions_are_validated() {
+        let definitions = ServiceDefinitions::from_json_str(
+            r#"{"services": {"cache": {"command": "cache-server", "socket": "cache.sock"}}}"#,
+        )
+        .unwrap();
+        let cache = definitions.service("cache").unwrap();
+        assert_eq!(cache.name(), "cache");
+        assert_eq!(
+            Service::<StdThreadpoolUSocks>::socket_name(&cache),
+            OsStr::new("cache.sock")
+        );
+        assert_eq!(definitions.service("missing"), None);
+
+        for invalid in [
+            r#"{"services": {"cache": {"command": "cache-server", "socket": "../cache.sock"}}}"#,
+            r#"{"services": {"cache": {"command": "c", "socket": "c.sock", "liveness_arg": 1}}}"#,
+            r#"{"services": {"cache": {"command": "c", "socket": "c.sock", "shards": 4}}}"#,
+        ] {
+            assert_eq!(
+                ServiceDefinitions::from_json_str(invalid)
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[cfg(feature = "dynamic-toml")]
+    #[test]
+    pub fn toml_definitions_are_parsed() {
+        let definitions = ServiceDefinitions::from_toml_str(
+            "[services.cache]\n\
+             command = \"/usr/lib/app/cache-server\"\n\
+             args = [\"--liveness\", \"--shards\", \"4\"]\n\
+             socket = \"cache.sock\"\n\
+             liveness_arg = 1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            definitions.services["cache"],
+            ServiceDescriptor {
+                command: PathBuf::from("/usr/lib/app/cache-server"),
+                args: vec!["--liveness".into(), "--shards".into(), "4".into()],
+                socket: "cache.sock".into(),
+                liveness_arg: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    pub fn liveness_path_is_passed_as_argument() {
+        // Exits with a status telling whether the first argument is the liveness path.
+        let service = DynamicService::new(
+            "liveness-arg",
+            ServiceDescriptor {
+                command: PathBuf::from("sh"),
+                args: vec![
+                    "-c".into(),
+                    "[ \"$1\" = \"$SUSS_LIVENESS_SOCKET_PATH\" ] && exit 3; exit 4".into(),
+                    "sh".into(),
+                ],
+                socket: "dynamic-liveness-arg-test.sock".into(),
+                liveness_arg: Some(3),
+            },
+        )
+        .unwrap();
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(service, &context);
+        assert!(matches!(
+            block_on(reified.connect()),
+            Err(Error::SpawnExited { status, .. }) if status.code() == Some(3)
+        ));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,151 @@
+//! Obtaining everything a service server needs to start up from its environment.
+
+use std::{
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, instrument};
+
+use crate::{error, liveness, IoResult, ServerExt, Service, UnixSocketInterface};
+
+/// Environment variable that [`ServerEnvironment::from_env`] reads the base context directory
+/// from.
+pub const CONTEXT_DIR_ENV_VAR: &str = "SUSS_CONTEXT_DIR";
+
+/// The base context directory and liveness socket path of a service server, as provided by its
+/// environment.
+///
+/// This lets a service `main()` shrink down to something like:
+/// ```rust,ignore
+/// let environment = ServerEnvironment::from_env()?;
+/// environment.start_and_run_server(&MyServer, &MyService).await?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerEnvironment {
+    /// Directory to create the service socket in.
+    pub base_context_directory: PathBuf,
+    /// Liveness socket to ping once the service socket exists, if the service was started by a
+    /// client - see [`liveness::retrieve_liveness_path`].
+    pub liveness_socket_path: Option<PathBuf>,
+}
+
+impl ServerEnvironment {
+    /// Read the base context directory from [`CONTEXT_DIR_ENV_VAR`], and the liveness socket path
+    /// from [`liveness::LIVENESS_ENV_VAR`].
+    pub fn from_env() -> IoResult<Self> {
+        Self::from_env_var(CONTEXT_DIR_ENV_VAR)
+    }
+
+    /// Read the base context directory from the given environment variable, and the liveness
+    /// socket path from [`liveness::LIVENESS_ENV_VAR`].
+    ///
+    /// Fails with [`io::ErrorKind::NotFound`] if the context directory variable is unset or
+    /// empty. Like [`liveness::retrieve_liveness_path`], this removes the liveness variable from
+    /// the environment of the current process.
+    #[instrument]
+    pub fn from_env_var(context_dir_var: &str) -> IoResult<Self> {
+        let base_context_directory = std::env::var_os(context_dir_var)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "Base context directory environment variable {} is not set",
+                        context_dir_var
+                    ),
+                )
+            })?;
+        let liveness_socket_path = liveness::retrieve_liveness_path();
+        debug!(
+            "Base context directory is {}, liveness socket is {:?}",
+            base_context_directory.display(),
+            liveness_socket_path
+        );
+        Ok(Self {
+            base_context_directory,
+            liveness_socket_path,
+        })
+    }
+
+    /// Directory to create the service socket in.
+    pub fn base_context_directory(&self) -> &Path {
+        &self.base_context_directory
+    }
+
+    /// Liveness socket to ping once the service socket exists, if any.
+    pub fn liveness_socket_path(&self) -> Option<&Path> {
+        self.liveness_socket_path.as_deref()
+    }
+
+    /// Path the socket of the given service will be bound at in this environment.
+    pub fn service_socket_path(&self, socket_name: &OsStr) -> PathBuf {
+        self.base_context_directory.join(socket_name)
+    }
+
+    /// Run a server for the service in this environment - see
+    /// [`ServerExt::start_and_run_server`].
+    pub async fn start_and_run_server<S, U, Srv>(
+        &self,
+        server: &Srv,
+        service: &S,
+    ) -> error::Result<Srv::FinalOutput>
+    where
+        S: Service<U>,
+        U: UnixSocketInterface,
+        Srv: ServerExt<S, U>,
+    {
+        server
+            .start_and_run_server(
+                service,
+                &self.base_context_directory,
+                self.liveness_socket_path(),
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn server_environment_from_env_var() {
+        let var = "SUSS_TEST_ENVIRONMENT_CONTEXT_DIR";
+        std::env::remove_var(var);
+        assert_eq!(
+            ServerEnvironment::from_env_var(var).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        std::env::set_var(var, "/run/some-app");
+        let environment = ServerEnvironment::from_env_var(var).unwrap();
+        assert_eq!(
+            environment.base_context_directory(),
+            Path::new("/run/some-app")
+        );
+        assert_eq!(
+            environment.service_socket_path(OsStr::new("hello.sock")),
+            Path::new("/run/some-app/hello.sock")
+        );
+        std::env::remove_var(var);
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
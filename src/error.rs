@@ -0,0 +1,536 @@
+//! Structured errors for connecting to, starting, and serving services.
+//!
+//! Functions implemented by users - such as [`crate::Service::wrap_connection`] or
+//! [`crate::Server::run_server`] - still return plain [`std::io::Result`]s. The library wraps any
+//! failures in an [`Error`] that says which step failed and for which service socket.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    io,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    time::Duration,
+};
+
+use crate::handshake::ServiceIdentity;
+
+/// Result type used by the connection and server machinery of this crate.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The socket of the service an [`Error`] relates to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceSocket {
+    /// Name of the socket within the base context directory - see
+    /// [`crate::Service::socket_name`]
+    pub name: OsString,
+    /// Full path of the socket.
+    pub path: PathBuf,
+}
+
+impl ServiceSocket {
+    /// Identify the socket with the given name, inside the given base context directory.
+    pub fn new(socket_name: &OsStr, base_context_directory: &Path) -> Self {
+        Self {
+            name: socket_name.to_owned(),
+            path: base_context_directory.join(socket_name),
+        }
+    }
+
+    /// Path of the advisory lock file used to coordinate operations on this socket between
+    /// processes - the socket path with `.lock` appended.
+    pub fn lock_path(&self) -> PathBuf {
+        let mut lock_path = self.path.clone().into_os_string();
+        lock_path.push(".lock");
+        lock_path.into()
+    }
+
+    /// Path of the control socket a server may listen on next to this socket - the socket path
+    /// with the extension replaced by `.ctl.sock` if it is `.sock`, or with `.ctl.sock` appended
+    /// otherwise. See [`crate::control`].
+    pub fn control_path(&self) -> PathBuf {
+        if self.path.extension() == Some(OsStr::new("sock")) {
+            self.path.with_extension("ctl.sock")
+        } else {
+            let mut control_path = self.path.clone().into_os_string();
+            control_path.push(".ctl.sock");
+            control_path.into()
+        }
+    }
+
+    /// Path of the log file that spawned service processes can write their output to, next to
+    /// this socket - the socket path with the extension replaced by `.log` if it is `.sock`, or
+    /// with `.log` appended otherwise. See [`crate::spawn::OutputTarget::LogFile`].
+    pub fn log_path(&self) -> PathBuf {
+        if self.path.extension() == Some(OsStr::new("sock")) {
+            self.path.with_extension("log")
+        } else {
+            let mut log_path = self.path.clone().into_os_string();
+            log_path.push(".log");
+            log_path.into()
+        }
+    }
+
+    /// The base context directory the socket is in.
+    pub fn base_context_directory(&self) -> &Path {
+        let depth = Path::new(&self.name).components().count();
+        self.path.ancestors().nth(depth).unwrap_or(Path::new(""))
+    }
+
+    /// Path a server moves this socket to while it doesn't accept connections - the socket path
+    /// with `.paused` appended. See [`crate::serve::AtCapacity::PauseAccepting`].
+    pub fn paused_path(&self) -> PathBuf {
+        let mut paused_path = self.path.clone().into_os_string();
+        paused_path.push(".paused");
+        paused_path.into()
+    }
+
+    /// Path a server binds this socket at while it initializes, before publishing it - the socket
+    /// path with `.tmp` appended. See [`crate::ServerExt::bind_hidden`].
+    pub fn hidden_path(&self) -> PathBuf {
+        let mut hidden_path = self.path.clone().into_os_string();
+        hidden_path.push(".tmp");
+        hidden_path.into()
+    }
+
+    /// Path of the state file a server leaves next to this socket while it runs - the socket path
+    /// with `.state.json` appended. See [`crate::status`].
+    pub fn state_path(&self) -> PathBuf {
+        let mut state_path = self.path.clone().into_os_string();
+        state_path.push(".state.json");
+        state_path.into()
+    }
+
+    /// Path of the port registry file a server exposed over TCP leaves next to this socket - the
+    /// socket path with `.tcp` appended, holding the address it listens on. See
+    /// [`crate::transport::TcpTransport`].
+    pub fn tcp_registry_path(&self) -> PathBuf {
+        let mut tcp_registry_path = self.path.clone().into_os_string();
+        tcp_registry_path.push(".tcp");
+        tcp_registry_path.into()
+    }
+}
+
+impl Display for ServiceSocket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+/// Error produced when connecting to, starting, or serving a service.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Couldn't connect to the service socket - usually because the service isn't running.
+    ConnectFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// The service socket file exists, but nothing is accepting connections on it - most likely
+    /// left over by a service that didn't clean up after itself.
+    StaleSocket {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// Couldn't acquire the lock file of the service socket - see [`ServiceSocket::lock_path`].
+    LockFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// Couldn't perform the handshake on a connection - see [`crate::handshake`].
+    HandshakeFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// The other side of a connection identified itself as a different service in the
+    /// handshake - most likely because two services use the same socket name.
+    WrongService {
+        socket: ServiceSocket,
+        expected: Box<ServiceIdentity>,
+        found: Box<ServiceIdentity>,
+    },
+    /// The two sides of a connection don't have any protocol version in common - see
+    /// [`crate::handshake::negotiate_version`].
+    VersionMismatch {
+        socket: ServiceSocket,
+        ours: RangeInclusive<u32>,
+        theirs: RangeInclusive<u32>,
+    },
+    /// Couldn't check who owns the service socket file or the server on the other end of a
+    /// connection - see [`crate::Service::trusted_owner_uid`].
+    PeerCheckFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// The service socket file or the server on the other end of a connection belongs to a
+    /// different user than expected - see [`crate::Service::trusted_owner_uid`].
+    UntrustedPeer {
+        socket: ServiceSocket,
+        expected_uid: u32,
+        found_uid: u32,
+    },
+    /// The [`crate::Service::authorizer`] of the server denied the connection - reported on both
+    /// sides of it. See [`crate::authorization`].
+    Unauthorized {
+        socket: ServiceSocket,
+        reason: String,
+    },
+    /// The service socket path or its base context directory failed the checks of the
+    /// [`crate::Service::security_policy`], so it wasn't used.
+    InsecureSocketPath {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// Connected to the service, but [`crate::Service::wrap_connection`] failed - or, on the
+    /// server side, [`crate::Service::wrap_incoming`].
+    WrapFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// Timed out setting up a connection to the service once connected - see
+    /// [`crate::Service::connect_timeout`].
+    ConnectTimeout {
+        socket: ServiceSocket,
+        timeout: Duration,
+    },
+    /// Couldn't create or use the ephemeral liveness socket while starting the service.
+    LivenessSocketFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// Couldn't spawn the service - see [`crate::ServiceStartable::run_service_command_raw`]
+    SpawnFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// The started service process exited unsuccessfully before pinging the liveness socket.
+    SpawnExited {
+        socket: ServiceSocket,
+        status: ExitStatus,
+    },
+    /// The started service didn't ping the liveness socket in time.
+    LivenessTimeout {
+        socket: ServiceSocket,
+        timeout: Duration,
+    },
+    /// The started service reported over the liveness socket that it failed to start - see
+    /// [`crate::liveness::report_liveness_failure`].
+    StartupFailed {
+        socket: ServiceSocket,
+        message: String,
+    },
+    /// The service became live, but
+    /// [`crate::ServiceStartable::after_post_liveness_subprocess`] failed.
+    PostLivenessFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// Couldn't bind the service socket in a server - or in a client, for a service it starts with
+    /// [`crate::liveness::LivenessTransport::InheritedListener`].
+    BindFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// Bound the service socket, but [`crate::Server::wrap_listener_socket`] failed.
+    ListenerWrapFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// [`crate::Server::run_server`] failed.
+    ServerFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// Couldn't send a command over the control channel of the service, or the server responded
+    /// with an error - see [`crate::control`].
+    ControlFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// The service was asked to stop, but its socket was still there after the timeout.
+    StopTimeout {
+        socket: ServiceSocket,
+        timeout: Duration,
+    },
+    /// Couldn't kill the process of an unresponsive service, as recorded in its state file - see
+    /// [`crate::status`].
+    KillFailed {
+        socket: ServiceSocket,
+        pid: u32,
+        source: io::Error,
+    },
+    /// Couldn't forward the service socket from a remote context directory - see
+    /// [`crate::remote`].
+    ForwardFailed {
+        socket: ServiceSocket,
+        source: io::Error,
+    },
+    /// The service wasn't running, and was started too often lately to start it again yet - see
+    /// [`crate::throttle`].
+    StartThrottled {
+        socket: ServiceSocket,
+        retry_after: Duration,
+    },
+    /// The service wasn't running, and failed to start too many times in a row to try again yet -
+    /// see [`crate::throttle`].
+    CircuitOpen {
+        socket: ServiceSocket,
+        retry_after: Duration,
+    },
+}
+
+impl Error {
+    /// The socket of the service this error relates to.
+    pub fn socket(&self) -> &ServiceSocket {
+        match self {
+            Error::ConnectFailed { socket, .. }
+            | Error::StaleSocket { socket, .. }
+            | Error::LockFailed { socket, .. }
+            | Error::HandshakeFailed { socket, .. }
+            | Error::WrongService { socket, .. }
+            | Error::VersionMismatch { socket, .. }
+            | Error::PeerCheckFailed { socket, .. }
+            | Error::UntrustedPeer { socket, .. }
+            | Error::Unauthorized { socket, .. }
+            | Error::InsecureSocketPath { socket, .. }
+            | Error::WrapFailed { socket, .. }
+            | Error::ConnectTimeout { socket, .. }
+            | Error::LivenessSocketFailed { socket, .. }
+            | Error::SpawnFailed { socket, .. }
+            | Error::SpawnExited { socket, .. }
+            | Error::LivenessTimeout { socket, .. }
+            | Error::StartupFailed { socket, .. }
+            | Error::PostLivenessFailed { socket, .. }
+            | Error::BindFailed { socket, .. }
+            | Error::ListenerWrapFailed { socket, .. }
+            | Error::ServerFailed { socket, .. }
+            | Error::ControlFailed { socket, .. }
+            | Error::StopTimeout { socket, .. }
+            | Error::KillFailed { socket, .. }
+            | Error::ForwardFailed { socket, .. }
+            | Error::StartThrottled { socket, .. }
+            | Error::CircuitOpen { socket, .. } => socket,
+        }
+    }
+
+    /// The underlying io error, if there is one.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            Error::ConnectFailed { source, .. }
+            | Error::StaleSocket { source, .. }
+            | Error::LockFailed { source, .. }
+            | Error::HandshakeFailed { source, .. }
+            | Error::PeerCheckFailed { source, .. }
+            | Error::InsecureSocketPath { source, .. }
+            | Error::WrapFailed { source, .. }
+            | Error::LivenessSocketFailed { source, .. }
+            | Error::SpawnFailed { source, .. }
+            | Error::PostLivenessFailed { source, .. }
+            | Error::BindFailed { source, .. }
+            | Error::ListenerWrapFailed { source, .. }
+            | Error::ServerFailed { source, .. }
+            | Error::ControlFailed { source, .. }
+            | Error::KillFailed { source, .. }
+            | Error::ForwardFailed { source, .. } => Some(source),
+            Error::WrongService { .. }
+            | Error::VersionMismatch { .. }
+            | Error::UntrustedPeer { .. }
+            | Error::Unauthorized { .. }
+            | Error::SpawnExited { .. }
+            | Error::LivenessTimeout { .. }
+            | Error::ConnectTimeout { .. }
+            | Error::StartupFailed { .. }
+            | Error::StopTimeout { .. }
+            | Error::StartThrottled { .. }
+            | Error::CircuitOpen { .. } => None,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::ConnectFailed { socket, source } => {
+                write!(f, "Failed to connect to service @ {socket} - {source}")
+            }
+            Error::StaleSocket { socket, source } => write!(
+                f,
+                "Service socket @ {socket} exists but is not accepting connections - {source}"
+            ),
+            Error::LockFailed { socket, source } => write!(
+                f,
+                "Failed to lock service socket @ {socket} - {source}"
+            ),
+            Error::HandshakeFailed { socket, source } => write!(
+                f,
+                "Handshake on connection to service @ {socket} failed - {source}"
+            ),
+            Error::WrongService {
+                socket,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Expected service {expected} @ {socket}, but found service {found}"
+            ),
+            Error::VersionMismatch {
+                socket,
+                ours,
+                theirs,
+            } => write!(
+                f,
+                "No common protocol version with service @ {socket} - we support {}..={}, they support {}..={}",
+                ours.start(),
+                ours.end(),
+                theirs.start(),
+                theirs.end()
+            ),
+            Error::PeerCheckFailed { socket, source } => write!(
+                f,
+                "Failed to check the owner of service @ {socket} - {source}"
+            ),
+            Error::UntrustedPeer {
+                socket,
+                expected_uid,
+                found_uid,
+            } => write!(
+                f,
+                "Service @ {socket} belongs to uid {found_uid}, but uid {expected_uid} was expected"
+            ),
+            Error::Unauthorized { socket, reason } => write!(
+                f,
+                "Connection to service @ {socket} was not authorized - {reason}"
+            ),
+            Error::InsecureSocketPath { socket, source } => write!(
+                f,
+                "Refusing to use insecure socket path @ {socket} - {source}"
+            ),
+            Error::WrapFailed { socket, source } => write!(
+                f,
+                "Failed to wrap connection to service @ {socket} - {source}"
+            ),
+            Error::LivenessSocketFailed { socket, source } => write!(
+                f,
+                "Ephemeral liveness socket failed while starting service @ {socket} - {source}"
+            ),
+            Error::SpawnFailed { socket, source } => {
+                write!(f, "Failed to start service @ {socket} - {source}")
+            }
+            Error::SpawnExited { socket, status } => write!(
+                f,
+                "Service process for @ {socket} exited before becoming live - {status}"
+            ),
+            Error::LivenessTimeout { socket, timeout } => write!(
+                f,
+                "Timed out waiting for service @ {socket} to become live after {}",
+                humantime::format_duration(*timeout)
+            ),
+            Error::StartupFailed { socket, message } => {
+                write!(f, "Service @ {socket} failed to start - {message}")
+            }
+            Error::PostLivenessFailed { socket, source } => write!(
+                f,
+                "Failed handling started service process @ {socket} - {source}"
+            ),
+            Error::BindFailed { socket, source } => {
+                write!(f, "Failed to bind service socket @ {socket} - {source}")
+            }
+            Error::ListenerWrapFailed { socket, source } => write!(
+                f,
+                "Failed to wrap listener socket of service @ {socket} - {source}"
+            ),
+            Error::ServerFailed { socket, source } => {
+                write!(f, "Server for service @ {socket} failed - {source}")
+            }
+            Error::ControlFailed { socket, source } => write!(
+                f,
+                "Control command for service @ {socket} failed - {source}"
+            ),
+            Error::ConnectTimeout { socket, timeout } => write!(
+                f,
+                "Timed out setting up connection to service @ {socket} after {}",
+                humantime::format_duration(*timeout)
+            ),
+            Error::StopTimeout { socket, timeout } => write!(
+                f,
+                "Timed out waiting for service @ {socket} to stop after {}",
+                humantime::format_duration(*timeout)
+            ),
+            Error::KillFailed {
+                socket,
+                pid,
+                source,
+            } => write!(
+                f,
+                "Failed to kill process {pid} of service @ {socket} - {source}"
+            ),
+            Error::ForwardFailed { socket, source } => write!(
+                f,
+                "Failed to forward service socket @ {socket} from its remote context - {source}"
+            ),
+            Error::StartThrottled {
+                socket,
+                retry_after,
+            } => write!(
+                f,
+                "Service @ {socket} was started too often lately - not starting it again for another {}",
+                humantime::format_duration(*retry_after)
+            ),
+            Error::CircuitOpen {
+                socket,
+                retry_after,
+            } => write!(
+                f,
+                "Service @ {socket} failed to start too many times in a row - not starting it again for another {}",
+                humantime::format_duration(*retry_after)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.io_error()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// Convert back into an [`io::Error`] - preserving the kind of the underlying error - for code
+/// that still deals in plain io errors.
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        let kind = match &e {
+            Error::LivenessTimeout { .. }
+            | Error::ConnectTimeout { .. }
+            | Error::StopTimeout { .. } => io::ErrorKind::TimedOut,
+            Error::WrongService { .. } | Error::VersionMismatch { .. } => {
+                io::ErrorKind::InvalidData
+            }
+            Error::UntrustedPeer { .. } | Error::Unauthorized { .. } => {
+                io::ErrorKind::PermissionDenied
+            }
+            other => other
+                .io_error()
+                .map(io::Error::kind)
+                .unwrap_or(io::ErrorKind::Other),
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
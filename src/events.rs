@@ -0,0 +1,161 @@
+//! Lifecycle events of services, for driving UIs and metrics without scraping the tracing output -
+//! see [`subscribe`] and [`crate::ReifiedService::events`].
+//!
+//! Events are broadcast within this process to every subscriber for the socket of the service
+//! they are about. Clients emit events as they start and connect to services, and servers as they
+//! accept connections and shut down - so in a process that is both, subscribers see both sides.
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+
+use async_channel::{Receiver, Sender};
+use futures_lite::Stream;
+
+use crate::ServiceSocket;
+
+/// Subscribers in this process, keyed by the socket path of the service they are subscribed to.
+static SUBSCRIBERS: Mutex<BTreeMap<PathBuf, Vec<Sender<ServiceEvent>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Something that happened in the lifecycle of a service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceEvent {
+    /// A client is starting the service process on-demand.
+    Starting { socket: ServiceSocket },
+    /// The started service process reported that it is live.
+    LivenessReceived { socket: ServiceSocket },
+    /// A connection was made. Clients emit this once a connection is wrapped, and servers once
+    /// they accept one with [`crate::serve::serve_service_connections`].
+    Connected { socket: ServiceSocket },
+    /// A server finished handling a connection accepted with
+    /// [`crate::serve::serve_service_connections`].
+    ConnectionClosed { socket: ServiceSocket },
+    /// The service stopped. Servers emit this once they have removed their socket, and clients
+    /// once a [`crate::ReifiedService::stop`] has succeeded.
+    Stopped { socket: ServiceSocket },
+    /// Starting the service process failed, for the given reason.
+    StartFailed {
+        socket: ServiceSocket,
+        reason: String,
+    },
+}
+
+impl ServiceEvent {
+    /// The socket of the service the event is about.
+    pub fn socket(&self) -> &ServiceSocket {
+        match self {
+            ServiceEvent::Starting { socket }
+            | ServiceEvent::LivenessReceived { socket }
+            | ServiceEvent::Connected { socket }
+            | ServiceEvent::ConnectionClosed { socket }
+            | ServiceEvent::Stopped { socket }
+            | ServiceEvent::StartFailed { socket, .. } => socket,
+        }
+    }
+}
+
+/// Stream of the lifecycle events of a service - see [`subscribe`]. Dropping it unsubscribes.
+///
+/// Events are buffered without limit until they are read, so keep polling it while subscribed.
+#[derive(Debug)]
+pub struct ServiceEvents {
+    receiver: Pin<Box<Receiver<ServiceEvent>>>,
+}
+
+impl Stream for ServiceEvents {
+    type Item = ServiceEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.as_mut().poll_next(cx)
+    }
+}
+
+/// Subscribe to the events about the service on the given socket emitted in this process from
+/// now on.
+pub fn subscribe(service_socket: &ServiceSocket) -> ServiceEvents {
+    let (sender, receiver) = async_channel::unbounded();
+    SUBSCRIBERS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .entry(service_socket.path.clone())
+        .or_default()
+        .push(sender);
+    ServiceEvents {
+        receiver: Box::pin(receiver),
+    }
+}
+
+/// Send an event to everything subscribed to its service, forgetting subscribers that are gone.
+pub(crate) fn emit(event: ServiceEvent) {
+    let mut subscribers = SUBSCRIBERS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let Some(senders) = subscribers.get_mut(&event.socket().path) else {
+        return;
+    };
+    senders.retain(|sender| sender.try_send(event.clone()).is_ok());
+    if senders.is_empty() {
+        subscribers.remove(&event.socket().path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, path::Path};
+
+    use futures_lite::{future::block_on, StreamExt};
+
+    use super::{emit, subscribe, ServiceEvent, SUBSCRIBERS};
+    use crate::ServiceSocket;
+
+    #[test]
+    pub fn subscribe_test() {
+        let socket = ServiceSocket::new(OsStr::new("events-test.sock"), Path::new("/nonexistent"));
+        let other = ServiceSocket::new(OsStr::new("other.sock"), Path::new("/nonexistent"));
+        let mut first = subscribe(&socket);
+        let mut second = subscribe(&socket);
+
+        emit(ServiceEvent::Starting {
+            socket: socket.clone(),
+        });
+        emit(ServiceEvent::Starting {
+            socket: other.clone(),
+        });
+        for subscriber in [&mut first, &mut second] {
+            assert_eq!(
+                block_on(subscriber.next()),
+                Some(ServiceEvent::Starting {
+                    socket: socket.clone()
+                })
+            );
+        }
+
+        drop(first);
+        drop(second);
+        emit(ServiceEvent::Stopped {
+            socket: socket.clone(),
+        });
+        assert!(!SUBSCRIBERS.lock().unwrap().contains_key(&socket.path));
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,398 @@
+//! Passing file descriptors - sockets, memfds, pidfds and the like - over the stream of a
+//! service with `SCM_RIGHTS`. See [`send_fd`], [`recv_fd`] and [`FdPassingConnection`].
+//!
+//! File descriptors travel attached to the bytes written along with them, and arrive with the
+//! read that receives those bytes. Any read that doesn't ask for them closes them - so both sides
+//! of a connection have to agree on when file descriptors are sent, and streams that receive them
+//! should only ever be read through [`UnixSocketInterface::unix_stream_recv_with_fds`], which
+//! every read of a [`FdPassingConnection`] goes through - writing them is unaffected. That is what
+//! the `fdpass` method of [`crate::declare_service`] wraps streams in.
+//!
+//! Received file descriptors are close-on-exec.
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::{self, ErrorKind, IoSlice, IoSliceMut},
+    mem::MaybeUninit,
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    sync::Arc,
+};
+
+use blocking::unblock;
+use rustix::{
+    event::{PollFd, PollFlags},
+    net::{
+        RecvAncillaryBuffer, RecvAncillaryMessage, RecvFlags, ReturnFlags, SendAncillaryBuffer,
+        SendAncillaryMessage, SendFlags,
+    },
+};
+
+use crate::{DefaultUnixSocks, IoResult, UnixSocketInterface};
+
+/// Most file descriptors received with a single read - any more sent along with the same bytes
+/// are an [`io::ErrorKind::InvalidData`] error, and get closed.
+pub const MAX_FDS_PER_READ: usize = 32;
+
+/// Byte sent along with file descriptors by [`send_fds`], as they can't be sent on their own.
+const FD_MARKER: u8 = 0;
+
+/// Write from the buffer to the socket with `sendmsg`, attaching the file descriptors.
+pub(crate) fn send_with_fds(
+    socket: BorrowedFd<'_>,
+    buf: &[u8],
+    fds: &[BorrowedFd<'_>],
+) -> IoResult<usize> {
+    let mut space = vec![MaybeUninit::uninit(); rustix::cmsg_space!(ScmRights(fds.len()))];
+    let mut control = SendAncillaryBuffer::new(&mut space);
+    if !fds.is_empty() && !control.push(SendAncillaryMessage::ScmRights(fds)) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("couldn't attach {} file descriptors", fds.len()),
+        ));
+    }
+    Ok(rustix::net::sendmsg(
+        socket,
+        &[IoSlice::new(buf)],
+        &mut control,
+        SendFlags::NOSIGNAL,
+    )?)
+}
+
+/// Read from the socket into the buffer with `recvmsg`, adding the file descriptors that came
+/// with it to `fds`.
+pub(crate) fn recv_with_fds(
+    socket: BorrowedFd<'_>,
+    buf: &mut [u8],
+    fds: &mut Vec<OwnedFd>,
+    flags: RecvFlags,
+) -> IoResult<usize> {
+    let mut space = vec![MaybeUninit::uninit(); rustix::cmsg_space!(ScmRights(MAX_FDS_PER_READ))];
+    let mut control = RecvAncillaryBuffer::new(&mut space);
+    let received = rustix::net::recvmsg(
+        socket,
+        &mut [IoSliceMut::new(buf)],
+        &mut control,
+        flags | RecvFlags::CMSG_CLOEXEC,
+    )?;
+    for message in control.drain() {
+        if let RecvAncillaryMessage::ScmRights(received_fds) = message {
+            fds.extend(received_fds);
+        }
+    }
+    if received.flags.contains(ReturnFlags::CTRUNC) {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "more than {} file descriptors were sent at once",
+                MAX_FDS_PER_READ
+            ),
+        ));
+    }
+    Ok(received.bytes)
+}
+
+/// Block until the socket is ready for the given events.
+fn wait_for(socket: BorrowedFd<'_>, events: PollFlags) -> IoResult<()> {
+    loop {
+        match rustix::event::poll(&mut [PollFd::new(&socket, events)], None) {
+            Err(e) if e == rustix::io::Errno::INTR => continue,
+            polled => return polled.map(drop).map_err(Into::into),
+        }
+    }
+}
+
+/// [`send_with_fds`] on a duplicate of the socket of a stream, which may be nonblocking - see
+/// [`UnixSocketInterface::unix_stream_send_with_fds`].
+pub(crate) async fn send_through_duplicate(
+    socket: OwnedFd,
+    buf: Vec<u8>,
+    fds: Vec<OwnedFd>,
+) -> IoResult<usize> {
+    unblock(move || {
+        let fds: Vec<_> = fds.iter().map(AsFd::as_fd).collect();
+        loop {
+            match send_with_fds(socket.as_fd(), &buf, &fds) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    wait_for(socket.as_fd(), PollFlags::OUT)?
+                }
+                sent => return sent,
+            }
+        }
+    })
+    .await
+}
+
+/// [`recv_with_fds`] on a duplicate of the socket of a stream, which may be nonblocking - see
+/// [`UnixSocketInterface::unix_stream_recv_with_fds`].
+///
+/// Only waiting happens on the thread pool, so nothing is received if this is dropped early.
+pub(crate) async fn recv_through_duplicate(
+    socket: OwnedFd,
+    buf: &mut [u8],
+    fds: &mut Vec<OwnedFd>,
+) -> IoResult<usize> {
+    let socket = Arc::new(socket);
+    loop {
+        match recv_with_fds(socket.as_fd(), buf, fds, RecvFlags::DONTWAIT) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                let socket = socket.clone();
+                unblock(move || wait_for(socket.as_fd(), PollFlags::IN)).await?
+            }
+            received => return received,
+        }
+    }
+}
+
+/// Fill the buffer from a duplicate of the socket of a stream, which may be nonblocking.
+///
+/// Nothing past the end of the buffer is read, so the stream itself carries on where this left
+/// off - and, unlike reading through a stream of [`crate::socket_shims::StdThreadpoolUSocks`], no
+/// read is left in flight that writing to the stream would have to wait for.
+pub(crate) async fn read_exact_through_duplicate(socket: OwnedFd, buf: &mut [u8]) -> IoResult<()> {
+    let socket = Arc::new(socket);
+    let mut filled = 0;
+    while filled < buf.len() {
+        match rustix::net::recv(socket.as_fd(), &mut buf[filled..], RecvFlags::DONTWAIT) {
+            Ok((0, _)) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok((received, _)) => filled += received,
+            Err(e) if e == rustix::io::Errno::AGAIN => {
+                let socket = socket.clone();
+                unblock(move || wait_for(socket.as_fd(), PollFlags::IN)).await?
+            }
+            Err(e) if e == rustix::io::Errno::INTR => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Send file descriptors over the stream, along with a single byte.
+pub async fn send_fds<U: UnixSocketInterface>(
+    stream: &mut U::UnixStream,
+    fds: &[BorrowedFd<'_>],
+) -> IoResult<()> {
+    match U::unix_stream_send_with_fds(stream, &[FD_MARKER], fds).await? {
+        0 => Err(ErrorKind::WriteZero.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Send a file descriptor over the stream - see [`send_fds`].
+pub async fn send_fd<U: UnixSocketInterface>(
+    stream: &mut U::UnixStream,
+    fd: BorrowedFd<'_>,
+) -> IoResult<()> {
+    send_fds::<U>(stream, &[fd]).await
+}
+
+/// Receive the file descriptors sent by [`send_fds`], which must be the next thing on the stream.
+pub async fn recv_fds<U: UnixSocketInterface>(
+    stream: &mut U::UnixStream,
+) -> IoResult<Vec<OwnedFd>> {
+    let mut marker = [0u8];
+    let mut fds = Vec::new();
+    match U::unix_stream_recv_with_fds(stream, &mut marker, &mut fds).await? {
+        0 => Err(ErrorKind::UnexpectedEof.into()),
+        _ if marker[0] != FD_MARKER => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "expected file descriptors, but received other data",
+        )),
+        _ => Ok(fds),
+    }
+}
+
+/// Receive a single file descriptor sent by [`send_fd`] - see [`recv_fds`].
+pub async fn recv_fd<U: UnixSocketInterface>(stream: &mut U::UnixStream) -> IoResult<OwnedFd> {
+    let mut fds = recv_fds::<U>(stream).await?;
+    match fds.len() {
+        1 => Ok(fds.remove(0)),
+        received => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("expected one file descriptor, but received {}", received),
+        )),
+    }
+}
+
+/// Stream that file descriptors can be sent over and received from along with regular data - see
+/// the [module documentation](self).
+///
+/// File descriptors that arrive during regular reads are kept, and handed out - in the order they
+/// arrived - by [`Self::take_fds`], or ahead of new ones by [`Self::recv_fd`].
+pub struct FdPassingConnection<U: UnixSocketInterface = DefaultUnixSocks> {
+    stream: U::UnixStream,
+    received_fds: VecDeque<OwnedFd>,
+}
+
+impl<U: UnixSocketInterface> Debug for FdPassingConnection<U>
+where
+    U::UnixStream: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FdPassingConnection")
+            .field("stream", &self.stream)
+            .field("received_fds", &self.received_fds)
+            .finish()
+    }
+}
+
+impl<U: UnixSocketInterface> FdPassingConnection<U> {
+    /// Wrap a bare stream.
+    pub fn new(stream: U::UnixStream) -> Self {
+        Self {
+            stream,
+            received_fds: VecDeque::new(),
+        }
+    }
+
+    /// The bare stream.
+    pub fn get_ref(&self) -> &U::UnixStream {
+        &self.stream
+    }
+
+    /// Take back the bare stream. File descriptors received but not yet taken are closed.
+    pub fn into_inner(self) -> U::UnixStream {
+        self.stream
+    }
+
+    /// Write some bytes from `buf`, returning how many.
+    pub async fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        U::unix_stream_write(&mut self.stream, buf).await
+    }
+
+    /// Write all of `buf`.
+    pub async fn write_all(&mut self, buf: &[u8]) -> IoResult<()> {
+        U::unix_stream_write_all(&mut self.stream, buf).await
+    }
+
+    /// Read some bytes into `buf`, returning how many - Ok(0) means the other side closed the
+    /// connection. File descriptors that arrive with them are kept for [`Self::take_fds`].
+    pub async fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let mut fds = Vec::new();
+        let received = U::unix_stream_recv_with_fds(&mut self.stream, buf, &mut fds).await;
+        // Keep what arrived even if the read failed, as it is gone from the socket either way.
+        self.received_fds.extend(fds);
+        received
+    }
+
+    /// Fill all of `buf`.
+    pub async fn read_exact(&mut self, mut buf: &mut [u8]) -> IoResult<()> {
+        while !buf.is_empty() {
+            match self.read(buf).await? {
+                0 => return Err(ErrorKind::UnexpectedEof.into()),
+                read => buf = &mut buf[read..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Send file descriptors - see [`send_fds`].
+    pub async fn send_fds(&mut self, fds: &[BorrowedFd<'_>]) -> IoResult<()> {
+        send_fds::<U>(&mut self.stream, fds).await
+    }
+
+    /// Send a file descriptor - see [`send_fd`].
+    pub async fn send_fd(&mut self, fd: BorrowedFd<'_>) -> IoResult<()> {
+        self.send_fds(&[fd]).await
+    }
+
+    /// Receive the next file descriptor - one that already arrived during a read, or else one
+    /// sent by [`send_fd`], which must be the next thing on the stream.
+    pub async fn recv_fd(&mut self) -> IoResult<OwnedFd> {
+        if let Some(fd) = self.received_fds.pop_front() {
+            return Ok(fd);
+        }
+        self.received_fds
+            .extend(recv_fds::<U>(&mut self.stream).await?);
+        self.received_fds.pop_front().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                "expected a file descriptor, but none was sent",
+            )
+        })
+    }
+
+    /// Take the file descriptors that arrived during reads.
+    pub fn take_fds(&mut self) -> Vec<OwnedFd> {
+        self.received_fds.drain(..).collect()
+    }
+
+    /// Shut the stream down - see [`UnixSocketInterface::unix_stream_shutdown`].
+    pub async fn shutdown(&mut self) -> IoResult<()> {
+        U::unix_stream_shutdown(&mut self.stream).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Seek, Write},
+        os::{fd::AsFd, unix::net::UnixStream},
+    };
+
+    use blocking::Unblock;
+    use futures_lite::future::block_on;
+
+    use super::{recv_through_duplicate, send_through_duplicate, FdPassingConnection};
+    use crate::{socket_shims::StdThreadpoolUSocks, ContextDir};
+
+    #[test]
+    pub fn fd_passing_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let mut file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(context.join("passed"))
+            .unwrap();
+        file.write_all(b"passed along").unwrap();
+        let (a, b) = UnixStream::pair().unwrap();
+        block_on(async {
+            let mut client = FdPassingConnection::<StdThreadpoolUSocks>::new(Unblock::new(a));
+            let mut server = FdPassingConnection::<StdThreadpoolUSocks>::new(Unblock::new(b));
+            client.write_all(b"file:").await.unwrap();
+            client.send_fd(file.as_fd()).await.unwrap();
+            let mut header = [0u8; 5];
+            server.read_exact(&mut header).await.unwrap();
+            assert_eq!(&header, b"file:");
+            let mut received = std::fs::File::from(server.recv_fd().await.unwrap());
+            received.rewind().unwrap();
+            let mut contents = String::new();
+            received.read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "passed along");
+            assert!(server.take_fds().is_empty());
+        });
+
+        // Nonblocking sockets wait for the other side, rather than failing.
+        let (a, b) = UnixStream::pair().unwrap();
+        b.set_nonblocking(true).unwrap();
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            let file = file.as_fd().try_clone_to_owned().unwrap();
+            block_on(send_through_duplicate(a.into(), b"!".to_vec(), vec![file])).unwrap()
+        });
+        let mut buf = [0u8; 4];
+        let mut fds = Vec::new();
+        let received = block_on(recv_through_duplicate(b.into(), &mut buf, &mut fds)).unwrap();
+        assert_eq!(sender.join().unwrap(), 1);
+        assert_eq!((received, &buf[..1], fds.len()), (1, &b"!"[..], 1));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,362 @@
+//! Typed, framed connections - requires the `framed-serde` feature.
+//!
+//! A [`TypedConnection`] sends and receives whole serde-encoded messages over a unix stream. Each
+//! message is a frame made of its length as a big-endian `u32` followed by the message encoded as
+//! JSON. This is what the `framed serde` method of [`crate::declare_service`] wraps streams in.
+//!
+//! Frames longer than the limit of a connection - [`MAX_FRAME_LENGTH`] unless set with
+//! [`TypedConnection::with_max_frame_length`] - are never buffered, so a peer can't make a process
+//! allocate whatever it likes with one length prefix. Messages that can't be decoded are handled
+//! according to the [`DecodeErrorPolicy`] of the connection.
+
+use std::{fmt::Debug, io, marker::PhantomData, os::fd::OwnedFd};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::debug;
+
+use crate::{split::ReadHalf, DefaultUnixSocks, IoResult, UnixSocketInterface};
+
+/// Largest frame accepted when receiving, to avoid allocating huge buffers for garbage lengths.
+pub const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// What a [`TypedConnection`] does when it receives a message it can't decode.
+///
+/// Either way, the message is an [`io::ErrorKind::InvalidData`] error. Frames over the length
+/// limit always shut the connection down, as there's no telling where the next frame starts
+/// without reading the whole thing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DecodeErrorPolicy {
+    /// Carry on with the next message - the connection stays usable.
+    #[default]
+    Error,
+    /// Shut the connection down, so later receives produce `None`.
+    Close,
+}
+
+/// Connection sending messages of type `Out` and receiving messages of type `In`.
+///
+/// Clients of a service declared with `framed serde <Request, Response>` get a
+/// `TypedConnection<Request, Response>`, and its servers a `TypedConnection<Response, Request>`.
+pub struct TypedConnection<Out, In, U: UnixSocketInterface = DefaultUnixSocks> {
+    stream: U::UnixStream,
+    max_frame_length: usize,
+    decode_error_policy: DecodeErrorPolicy,
+    /// Shut down after a malformed frame.
+    closed: bool,
+    /// Duplicate of the socket, taken before the first receive to shut the connection down with -
+    /// the socket interface may not get at the stream until the read in flight is done.
+    socket: Option<OwnedFd>,
+    _messages: PhantomData<fn(Out) -> In>,
+}
+
+impl<Out, In, U: UnixSocketInterface> Debug for TypedConnection<Out, In, U>
+where
+    U::UnixStream: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TypedConnection")
+            .field("stream", &self.stream)
+            .field("max_frame_length", &self.max_frame_length)
+            .field("decode_error_policy", &self.decode_error_policy)
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+impl<Out, In, U: UnixSocketInterface> TypedConnection<Out, In, U> {
+    /// Wrap a bare stream.
+    pub fn new(stream: U::UnixStream) -> Self {
+        Self {
+            stream,
+            max_frame_length: MAX_FRAME_LENGTH,
+            decode_error_policy: DecodeErrorPolicy::default(),
+            closed: false,
+            socket: None,
+            _messages: PhantomData,
+        }
+    }
+
+    /// Limit frames sent and received to the given length, rather than [`MAX_FRAME_LENGTH`].
+    /// Limits above [`MAX_FRAME_LENGTH`] are lowered to it.
+    pub fn with_max_frame_length(mut self, max_frame_length: usize) -> Self {
+        self.max_frame_length = max_frame_length.min(MAX_FRAME_LENGTH);
+        self
+    }
+
+    /// Handle messages that can't be decoded according to the policy.
+    pub fn with_decode_error_policy(mut self, decode_error_policy: DecodeErrorPolicy) -> Self {
+        self.decode_error_policy = decode_error_policy;
+        self
+    }
+
+    /// Longest frame sent or received.
+    pub fn max_frame_length(&self) -> usize {
+        self.max_frame_length
+    }
+
+    /// How messages that can't be decoded are handled.
+    pub fn decode_error_policy(&self) -> DecodeErrorPolicy {
+        self.decode_error_policy
+    }
+
+    /// Take back the bare stream.
+    pub fn into_inner(self) -> U::UnixStream {
+        self.stream
+    }
+}
+
+impl<Out: Serialize, In: DeserializeOwned, U: UnixSocketInterface> TypedConnection<Out, In, U> {
+    /// Send a single message.
+    pub async fn send(&mut self, message: &Out) -> IoResult<()> {
+        let encoded = serde_json::to_vec(message)?;
+        if encoded.len() > self.max_frame_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Message of {} bytes is over the {} byte limit",
+                    encoded.len(),
+                    self.max_frame_length
+                ),
+            ));
+        }
+        let frame = frame(&encoded)?;
+        U::unix_stream_write_all(&mut self.stream, &frame).await
+    }
+
+    /// Receive a single message, or `None` if the other side closed the connection between
+    /// messages, or it was shut down after a malformed frame.
+    pub async fn receive(&mut self) -> IoResult<Option<In>> {
+        if self.closed {
+            return Ok(None);
+        }
+        if self.socket.is_none() {
+            self.socket = Some(U::unix_stream_duplicate_fd(&mut self.stream).await?);
+        }
+        let mut length = [0u8; 4];
+        let mut filled = 0;
+        while filled < length.len() {
+            match U::unix_stream_read(&mut self.stream, &mut length[filled..]).await? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => filled += n,
+            }
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        if length > self.max_frame_length {
+            self.close();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Frame of {} bytes is over the {} byte limit",
+                    length, self.max_frame_length
+                ),
+            ));
+        }
+        let mut encoded = vec![0u8; length];
+        U::unix_stream_read_exact(&mut self.stream, &mut encoded).await?;
+        match serde_json::from_slice(&encoded) {
+            Ok(message) => Ok(Some(message)),
+            Err(e) => {
+                if self.decode_error_policy == DecodeErrorPolicy::Close {
+                    self.close();
+                }
+                Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    /// Shut the connection down after a malformed frame.
+    fn close(&mut self) {
+        self.closed = true;
+        let Some(socket) = &self.socket else {
+            return;
+        };
+        if let Err(e) = rustix::net::shutdown(socket, rustix::net::Shutdown::Both) {
+            debug!(
+                "Couldn't shut down connection after malformed frame - {}",
+                e
+            );
+        }
+    }
+
+    /// Send a message and wait for the reply. The connection being closed before a reply arrives
+    /// is an [`io::ErrorKind::UnexpectedEof`] error.
+    pub async fn request(&mut self, message: &Out) -> IoResult<In> {
+        self.send(message).await?;
+        self.receive()
+            .await?
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+/// Frame an encoded message - its length as a big-endian `u32`, followed by the message.
+pub(crate) fn frame(encoded: &[u8]) -> IoResult<Vec<u8>> {
+    let length = u32::try_from(encoded.len())
+        .ok()
+        .filter(|l| *l as usize <= MAX_FRAME_LENGTH)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Message too large"))?;
+    let mut frame = Vec::with_capacity(4 + encoded.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(encoded);
+    Ok(frame)
+}
+
+/// Reads frames off the read half of a stream, keeping partly read ones when reading is abandoned
+/// so the next read picks up where it left off - unlike [`TypedConnection::receive`], which
+/// loses its place.
+pub(crate) struct FrameReader<U: UnixSocketInterface> {
+    half: ReadHalf<U>,
+    length: [u8; 4],
+    length_read: usize,
+    frame: Vec<u8>,
+    frame_read: usize,
+}
+
+impl<U: UnixSocketInterface> FrameReader<U> {
+    pub(crate) fn new(half: ReadHalf<U>) -> Self {
+        Self {
+            half,
+            length: [0; 4],
+            length_read: 0,
+            frame: Vec::new(),
+            frame_read: 0,
+        }
+    }
+
+    /// The next encoded message, or `None` if the other side closed the connection between
+    /// messages.
+    pub(crate) async fn next(&mut self) -> IoResult<Option<Vec<u8>>> {
+        while self.length_read < self.length.len() {
+            match self.half.read(&mut self.length[self.length_read..]).await? {
+                0 if self.length_read == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.length_read += n,
+            }
+        }
+        let length = u32::from_be_bytes(self.length) as usize;
+        if length > MAX_FRAME_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Frame of {} bytes is too large", length),
+            ));
+        }
+        self.frame.resize(length, 0);
+        while self.frame_read < length {
+            match self.half.read(&mut self.frame[self.frame_read..]).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.frame_read += n,
+            }
+        }
+        self.length_read = 0;
+        self.frame_read = 0;
+        Ok(Some(std::mem::take(&mut self.frame)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use blocking::Unblock;
+    use futures_lite::future::block_on;
+
+    use super::*;
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn messages_round_trip() {
+        // Only one direction per stream - the threadpool shim can't switch a stream from reading
+        // back to writing while a blocking read is in flight.
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut client =
+            TypedConnection::<String, usize, StdThreadpoolUSocks>::new(Unblock::new(a));
+        let mut server =
+            TypedConnection::<usize, String, StdThreadpoolUSocks>::new(Unblock::new(b));
+        block_on(async {
+            client.send(&"hello".to_owned()).await.unwrap();
+            client.send(&"world!".to_owned()).await.unwrap();
+            assert_eq!(server.receive().await.unwrap().unwrap(), "hello");
+            assert_eq!(server.receive().await.unwrap().unwrap(), "world!");
+        });
+        drop(client);
+        assert!(block_on(server.receive()).unwrap().is_none());
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut client =
+            TypedConnection::<String, usize, StdThreadpoolUSocks>::new(Unblock::new(a));
+        let mut server =
+            TypedConnection::<usize, String, StdThreadpoolUSocks>::new(Unblock::new(b));
+        block_on(server.send(&5)).unwrap();
+        assert_eq!(block_on(client.receive()).unwrap(), Some(5));
+    }
+
+    #[test]
+    pub fn malformed_frames_are_handled_by_policy() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut peer = Unblock::new(a);
+        let mut connection =
+            TypedConnection::<usize, usize, StdThreadpoolUSocks>::new(Unblock::new(b))
+                .with_max_frame_length(16);
+        block_on(async {
+            // Undecodable messages are skipped by default.
+            let mut frames = frame(b"\"nope\"").unwrap();
+            frames.extend(frame(b"7").unwrap());
+            StdThreadpoolUSocks::unix_stream_write_all(&mut peer, &frames)
+                .await
+                .unwrap();
+            assert_eq!(
+                connection.receive().await.unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+            assert_eq!(connection.receive().await.unwrap(), Some(7));
+
+            // Giant length prefixes end the connection without being read.
+            StdThreadpoolUSocks::unix_stream_write_all(&mut peer, &u32::MAX.to_be_bytes())
+                .await
+                .unwrap();
+            assert_eq!(
+                connection.receive().await.unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+            assert_eq!(connection.receive().await.unwrap(), None);
+            // The peer is told, even though it never sent the rest of the frame.
+            assert_eq!(
+                StdThreadpoolUSocks::unix_stream_read(&mut peer, &mut [0; 1])
+                    .await
+                    .unwrap(),
+                0
+            );
+        });
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut peer = Unblock::new(a);
+        let mut connection =
+            TypedConnection::<usize, usize, StdThreadpoolUSocks>::new(Unblock::new(b))
+                .with_decode_error_policy(DecodeErrorPolicy::Close);
+        block_on(async {
+            let mut frames = frame(b"\"nope\"").unwrap();
+            frames.extend(frame(b"7").unwrap());
+            StdThreadpoolUSocks::unix_stream_write_all(&mut peer, &frames)
+                .await
+                .unwrap();
+            assert!(connection.receive().await.is_err());
+            assert_eq!(connection.receive().await.unwrap(), None);
+        });
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,273 @@
+//! Garbage collection of base context directories - removing what servers that crashed, or were
+//! killed by a power loss, left behind. See [`gc_context`].
+
+use std::{
+    ffi::OsString,
+    io::ErrorKind,
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt, net::UnixStream},
+    path::{Path, PathBuf},
+};
+
+use blocking::unblock;
+use tracing::{info, instrument, warn};
+
+use crate::{
+    datagram, lockfile::LockFile, remove_stale_socket, seqpacket,
+    socket_shims::StdThreadpoolUSocks, IoResult, ServiceSocket, UnixSocketInterface,
+};
+
+/// Suffix of the control sockets of services - see [`ServiceSocket::control_path`].
+pub(crate) const CONTROL_SOCKET_SUFFIX: &str = ".ctl.sock";
+/// Suffix of the state files of services - see [`ServiceSocket::state_path`].
+const STATE_FILE_SUFFIX: &str = ".state.json";
+/// Suffix of the lock files of services - see [`ServiceSocket::lock_path`].
+const LOCK_FILE_SUFFIX: &str = ".lock";
+
+/// What [`gc_context`] found in a base context directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Sockets nothing answered on, which were removed - service sockets and control sockets.
+    pub stale_sockets: Vec<PathBuf>,
+    /// State files and lock files of services without a socket, which were removed.
+    pub orphaned_files: Vec<PathBuf>,
+    /// Service sockets with a live server, which were left alone.
+    pub live_sockets: Vec<PathBuf>,
+}
+
+impl GcReport {
+    /// Whether nothing was removed.
+    pub fn is_clean(&self) -> bool {
+        self.stale_sockets.is_empty() && self.orphaned_files.is_empty()
+    }
+}
+
+/// Remove the stale sockets in a base context directory - those nothing answers on - along with
+/// any state files and lock files whose service socket is gone, producing a report of what was
+/// done.
+///
+/// Service sockets are checked and removed while holding their lock file, the same way servers
+/// and clients do it, so this is safe to run while services are starting and stopping. Lock files
+/// are only removed if nobody holds them. Files other than sockets, state files and lock files are
+/// left alone, as are entries that can't be inspected or removed - which is logged.
+#[instrument(skip_all, fields(context_dir = %context_dir.display()))]
+pub async fn gc_context(context_dir: &Path) -> IoResult<GcReport> {
+    let mut report = GcReport::default();
+    let sockets = list_entries(context_dir, true).await?;
+
+    // Control sockets are only checked once it is known whether their service is live.
+    let (control_sockets, service_sockets): (Vec<_>, Vec<_>) = sockets
+        .into_iter()
+        .partition(|name| name.as_bytes().ends_with(CONTROL_SOCKET_SUFFIX.as_bytes()));
+    for name in service_sockets {
+        let service_socket = ServiceSocket::new(&name, context_dir);
+        match remove_stale_socket::<StdThreadpoolUSocks>(&service_socket).await {
+            Ok(Some(mut probe)) => {
+                let _ = StdThreadpoolUSocks::unix_stream_shutdown(&mut probe).await;
+                report.live_sockets.push(service_socket.path);
+            }
+            Ok(None) => {
+                // The socket may also have vanished by itself in the meantime.
+                report.stale_sockets.push(service_socket.path);
+            }
+            Err(e) if seqpacket::is_wrong_socket_kind(&e) => {
+                match datagram::remove_stale_socket(&service_socket).await {
+                    Ok(true) => report.live_sockets.push(service_socket.path),
+                    Ok(false) => report.stale_sockets.push(service_socket.path),
+                    Err(e) => warn!("Couldn't check socket @ {} - {}", service_socket, e),
+                }
+            }
+            Err(e) => warn!("Couldn't check socket @ {} - {}", service_socket, e),
+        }
+    }
+    for name in control_sockets {
+        let path = context_dir.join(&name);
+        // Don't bother live servers with a probe on their control channel.
+        let base = strip_suffix(&name, CONTROL_SOCKET_SUFFIX);
+        let mut service_socket_name = base.clone();
+        service_socket_name.push(".sock");
+        if [base, service_socket_name]
+            .iter()
+            .any(|service| report.live_sockets.contains(&context_dir.join(service)))
+        {
+            continue;
+        }
+        match remove_stale_control_socket(path.clone()).await {
+            Ok(true) => report.stale_sockets.push(path),
+            Ok(false) => {}
+            Err(e) => warn!("Couldn't check control socket @ {} - {}", path.display(), e),
+        }
+    }
+
+    // Checking the service sockets creates their lock files if they were missing, so look again.
+    for name in list_entries(context_dir, false).await? {
+        let path = context_dir.join(&name);
+        let (service_socket_name, is_lock_file) =
+            if name.as_bytes().ends_with(STATE_FILE_SUFFIX.as_bytes()) {
+                (strip_suffix(&name, STATE_FILE_SUFFIX), false)
+            } else if name.as_bytes().ends_with(LOCK_FILE_SUFFIX.as_bytes()) {
+                (strip_suffix(&name, LOCK_FILE_SUFFIX), true)
+            } else {
+                continue;
+            };
+        match context_dir.join(service_socket_name).symlink_metadata() {
+            Ok(_) => continue,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                warn!("Couldn't check socket for {} - {}", path.display(), e);
+                continue;
+            }
+        }
+        match remove_orphaned_file(path.clone(), is_lock_file).await {
+            Ok(true) => report.orphaned_files.push(path),
+            Ok(false) => {}
+            Err(e) => warn!("Couldn't remove orphaned file {} - {}", path.display(), e),
+        }
+    }
+    info!(
+        "Removed {} stale sockets and {} orphaned files",
+        report.stale_sockets.len(),
+        report.orphaned_files.len()
+    );
+    Ok(report)
+}
+
+/// Names of either the sockets or the other entries in a directory, sorted.
+pub(crate) async fn list_entries(directory: &Path, sockets: bool) -> IoResult<Vec<OsString>> {
+    let directory = directory.to_owned();
+    unblock(move || {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+            if entry.file_type()?.is_socket() == sockets {
+                names.push(entry.file_name());
+            }
+        }
+        names.sort();
+        Ok(names)
+    })
+    .await
+}
+
+/// Remove a control socket if nothing answers on it, producing whether it was removed.
+async fn remove_stale_control_socket(path: PathBuf) -> IoResult<bool> {
+    unblock(move || match UnixStream::connect(&path) {
+        Ok(_probe) => Ok(false),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            warn!("Removing stale control socket @ {}", path.display());
+            remove_if_present(&path)
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    })
+    .await
+}
+
+/// Remove a state file or lock file whose socket is gone, producing whether it was removed. Lock
+/// files are only removed while we hold them, so ones in use by other processes stay put.
+async fn remove_orphaned_file(path: PathBuf, is_lock_file: bool) -> IoResult<bool> {
+    unblock(move || {
+        let _lock = match is_lock_file {
+            true => match LockFile::try_acquire_existing(&path) {
+                Ok(Some(lock)) => Some(lock),
+                Ok(None) => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+                Err(e) => return Err(e),
+            },
+            false => None,
+        };
+        warn!("Removing orphaned file {}", path.display());
+        remove_if_present(&path)
+    })
+    .await
+}
+
+/// Remove a file, producing whether it was there to remove.
+fn remove_if_present(path: &Path) -> IoResult<bool> {
+    match std::fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// File name with an ASCII suffix it is known to end with removed.
+fn strip_suffix(name: &OsString, suffix: &str) -> OsString {
+    let name = name.as_bytes();
+    std::ffi::OsStr::from_bytes(&name[..name.len() - suffix.len()]).to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::net::UnixListener};
+
+    use futures_lite::future::block_on;
+
+    use super::{gc_context, GcReport};
+    use crate::ContextDir;
+
+    #[test]
+    pub fn gc_context_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let _live = UnixListener::bind(context.join("live.sock")).unwrap();
+        let _live_control = UnixListener::bind(context.join("live.ctl.sock")).unwrap();
+        drop(UnixListener::bind(context.join("dead.sock")).unwrap());
+        drop(UnixListener::bind(context.join("dead.ctl.sock")).unwrap());
+        for file in [
+            "live.sock.lock",
+            "live.sock.state.json",
+            "dead.sock.state.json",
+            "gone.sock.lock",
+            "unrelated.txt",
+        ] {
+            fs::write(context.join(file), "").unwrap();
+        }
+
+        let report = block_on(gc_context(&context)).unwrap();
+        assert_eq!(
+            report,
+            GcReport {
+                stale_sockets: vec![context.join("dead.sock"), context.join("dead.ctl.sock")],
+                orphaned_files: vec![
+                    context.join("dead.sock.lock"),
+                    context.join("dead.sock.state.json"),
+                    context.join("gone.sock.lock"),
+                ],
+                live_sockets: vec![context.join("live.sock")],
+            }
+        );
+        let mut remaining = fs::read_dir(&context)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec![
+                "live.ctl.sock",
+                "live.sock",
+                "live.sock.lock",
+                "live.sock.state.json",
+                "unrelated.txt"
+            ]
+        );
+
+        assert!(block_on(gc_context(&context)).unwrap().is_clean());
+        fs::remove_dir_all(&context).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
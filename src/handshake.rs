@@ -0,0 +1,178 @@
+//! Optional handshake identifying the service on both ends of a connection.
+//!
+//! Services opt in via [`crate::Service::handshake_protocol_versions`]. Then, on every
+//! connection, the client and the server each send a hello - [`MAGIC`], the range of protocol
+//! versions they support, and the socket name of the service. Each side checks that the socket
+//! name matches its own, which stops clients from silently talking to the wrong server when two
+//! services accidentally share a socket name, and then both pick the highest protocol version
+//! they have in common - see [`negotiate_version`].
+//!
+//! The hello is encoded as the magic bytes, the minimum and maximum protocol versions as
+//! big-endian `u32`s, the length of the socket name as a big-endian `u16`, and then the socket
+//! name itself.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    io,
+    ops::RangeInclusive,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+};
+
+use crate::{IoResult, UnixSocketInterface};
+
+/// Magic bytes starting every handshake hello.
+pub const MAGIC: &[u8; 4] = b"SUSS";
+
+/// Identity of a service, as exchanged in the handshake.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServiceIdentity {
+    /// Socket name of the service - see [`crate::Service::socket_name`]
+    pub socket_name: OsString,
+    /// Versions of the protocol of the service that are supported.
+    pub protocol_versions: RangeInclusive<u32>,
+}
+
+impl ServiceIdentity {
+    pub fn new(socket_name: &OsStr, protocol_versions: RangeInclusive<u32>) -> Self {
+        Self {
+            socket_name: socket_name.to_owned(),
+            protocol_versions,
+        }
+    }
+
+    fn encode(&self) -> IoResult<Vec<u8>> {
+        if self.protocol_versions.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Empty protocol version range for handshake",
+            ));
+        }
+        let name = self.socket_name.as_bytes();
+        let name_length = u16::try_from(name.len()).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Socket name too long for handshake",
+            )
+        })?;
+        let mut hello = Vec::with_capacity(MAGIC.len() + 10 + name.len());
+        hello.extend_from_slice(MAGIC);
+        hello.extend_from_slice(&self.protocol_versions.start().to_be_bytes());
+        hello.extend_from_slice(&self.protocol_versions.end().to_be_bytes());
+        hello.extend_from_slice(&name_length.to_be_bytes());
+        hello.extend_from_slice(name);
+        Ok(hello)
+    }
+}
+
+impl Display for ServiceIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (protocol versions {}..={})",
+            self.socket_name.to_string_lossy(),
+            self.protocol_versions.start(),
+            self.protocol_versions.end()
+        )
+    }
+}
+
+/// The protocol version both sides of a connection should speak - the highest version in both
+/// ranges - or `None` if the ranges don't overlap.
+pub fn negotiate_version(ours: &RangeInclusive<u32>, theirs: &RangeInclusive<u32>) -> Option<u32> {
+    let lowest = *ours.start().max(theirs.start());
+    let highest = *ours.end().min(theirs.end());
+    (lowest <= highest).then_some(highest)
+}
+
+/// Send our identity over the stream, and receive the identity of the other side.
+///
+/// Fails with [`io::ErrorKind::InvalidData`] if the other side doesn't speak the handshake at
+/// all. Checking whether the identities match is up to the caller.
+pub async fn exchange_identities<U: UnixSocketInterface>(
+    stream: &mut U::UnixStream,
+    ours: &ServiceIdentity,
+) -> IoResult<ServiceIdentity> {
+    U::unix_stream_write_all(stream, &ours.encode()?).await?;
+
+    let mut magic = [0u8; 4];
+    U::unix_stream_read_exact(stream, &mut magic).await?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Other side of the connection didn't send a service handshake",
+        ));
+    }
+    let mut min_version = [0u8; 4];
+    U::unix_stream_read_exact(stream, &mut min_version).await?;
+    let mut max_version = [0u8; 4];
+    U::unix_stream_read_exact(stream, &mut max_version).await?;
+    let mut name_length = [0u8; 2];
+    U::unix_stream_read_exact(stream, &mut name_length).await?;
+    let mut name = vec![0u8; u16::from_be_bytes(name_length).into()];
+    U::unix_stream_read_exact(stream, &mut name).await?;
+    Ok(ServiceIdentity {
+        socket_name: OsString::from_vec(name),
+        protocol_versions: u32::from_be_bytes(min_version)..=u32::from_be_bytes(max_version),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use blocking::Unblock;
+    use futures_lite::future::{block_on, zip};
+
+    use super::*;
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn identities_are_exchanged() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (mut a, mut b) = (Unblock::new(a), Unblock::new(b));
+        let client = ServiceIdentity::new(OsStr::new("hello.sock"), 1..=3);
+        let server = ServiceIdentity::new(OsStr::new("hello.sock"), 2..=4);
+        let (from_server, from_client) = block_on(zip(
+            exchange_identities::<StdThreadpoolUSocks>(&mut a, &client),
+            exchange_identities::<StdThreadpoolUSocks>(&mut b, &server),
+        ));
+        assert_eq!(from_server.unwrap(), server);
+        assert_eq!(from_client.unwrap(), client);
+    }
+
+    #[test]
+    pub fn highest_common_version_is_negotiated() {
+        assert_eq!(negotiate_version(&(1..=3), &(2..=4)), Some(3));
+        assert_eq!(negotiate_version(&(2..=2), &(1..=5)), Some(2));
+        assert_eq!(negotiate_version(&(1..=2), &(3..=4)), None);
+    }
+
+    #[test]
+    pub fn non_handshake_peer_is_rejected() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let (mut a, mut b) = (Unblock::new(a), Unblock::new(b));
+        let ours = ServiceIdentity::new(OsStr::new("hello.sock"), 1..=1);
+        let (result, _) = block_on(zip(
+            exchange_identities::<StdThreadpoolUSocks>(&mut a, &ours),
+            StdThreadpoolUSocks::unix_stream_write_all(&mut b, b"HTTP/1.1 400 Bad"),
+        ));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
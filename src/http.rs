@@ -0,0 +1,141 @@
+//! HTTP over service sockets - requires the `http` feature, which also enables `tokio`.
+//!
+//! Services declared with the `http` method of [`crate::declare_service`] produce
+//! [`HttpConnection`]s, which can be handed straight to hyper - for instance to
+//! [`hyper::client::conn::http1::handshake`] on the client side. Servers can use [`serve_http`]
+//! to drive a hyper service over every connection accepted on the service socket.
+
+use std::{error::Error as StdError, future::Future};
+
+use hyper::{
+    body::{Body, Incoming},
+    server::conn::http1,
+    service::Service as HyperService,
+    Request, Response,
+};
+use tracing::{instrument, warn};
+
+use crate::{serve::serve_connections, socket_shims::TokioUSocks, IoResult, UnixSocketInterface};
+
+pub use hyper;
+pub use hyper_util::rt::TokioIo;
+
+/// Connection to or from an HTTP service, usable as hyper IO when the unix socket interface is
+/// [`TokioUSocks`].
+pub type HttpConnection<U = TokioUSocks> = TokioIo<<U as UnixSocketInterface>::UnixStream>;
+
+/// Serve HTTP/1 with a hyper service on every connection accepted on the listener, until
+/// `shutdown` completes - see [`serve_connections`], which this uses under the hood. Each
+/// connection is spawned as a tokio task.
+///
+/// As with [`serve_connections`], in-flight connections are waited on after shutdown, which
+/// includes idle keep-alive connections - so pair this with a drain timeout, like the one of
+/// [`crate::ServerExt::start_and_run_server_with_shutdown`].
+#[instrument(skip_all)]
+pub async fn serve_http<S, B>(
+    listener: &mut tokio::net::UnixListener,
+    service: S,
+    shutdown: impl Future<Output = ()>,
+) -> IoResult<()>
+where
+    S: HyperService<Request<Incoming>, Response = Response<B>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<Box<dyn StdError + Send + Sync>>,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    serve_connections::<TokioUSocks, _, _, _>(
+        listener,
+        |stream, _addr| {
+            let service = service.clone();
+            async move {
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    warn!("Error serving HTTP connection - {}", e);
+                }
+            }
+        },
+        |connection| {
+            tokio::spawn(connection);
+        },
+        shutdown,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::{client::conn::http1 as client_http1, service::service_fn};
+
+    use super::*;
+
+    #[test]
+    pub fn http_is_served_over_unix_socket() {
+        let socket_path =
+            std::env::temp_dir().join(format!("http-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut listener = tokio::net::UnixListener::bind(&socket_path).unwrap();
+            let client = async {
+                let stream = TokioUSocks::unix_stream_connect(&socket_path)
+                    .await
+                    .unwrap();
+                let (mut sender, connection) =
+                    client_http1::handshake(TokioIo::new(stream)).await.unwrap();
+                tokio::spawn(connection);
+                let response = sender
+                    .send_request(
+                        Request::builder()
+                            .uri("/hello")
+                            .header("host", "suss")
+                            .body(String::new())
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), 200);
+                assert_eq!(response.headers()["x-path"], "/hello");
+            };
+            serve_http(
+                &mut listener,
+                service_fn(|request: Request<Incoming>| async move {
+                    Ok::<_, Infallible>(
+                        Response::builder()
+                            .header("x-path", request.uri().path())
+                            .body(String::new())
+                            .unwrap(),
+                    )
+                }),
+                client,
+            )
+            .await
+            .unwrap();
+        });
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,160 @@
+//! Newline-delimited JSON connections - requires the `json-lines` feature.
+//!
+//! A [`JsonLinesConnection`] exchanges messages encoded as single-line JSON values, each followed
+//! by a newline. This is easy to speak from non-Rust tooling - even `socat` - that connects to
+//! the same sockets. This is what the `json_lines` method of [`crate::declare_service`] wraps
+//! streams in.
+
+use std::{fmt::Debug, io, marker::PhantomData};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{DefaultUnixSocks, IoResult, UnixSocketInterface};
+
+/// Longest line accepted when receiving, to avoid buffering without bound.
+pub const MAX_LINE_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Connection sending messages of type `Out` and receiving messages of type `In`, as JSON lines.
+///
+/// Clients of a service declared with `json_lines <Request, Response>` get a
+/// `JsonLinesConnection<Request, Response>`, and its servers a
+/// `JsonLinesConnection<Response, Request>`.
+pub struct JsonLinesConnection<Out, In, U: UnixSocketInterface = DefaultUnixSocks> {
+    stream: U::UnixStream,
+    /// Received bytes that aren't part of a returned message yet.
+    buffer: Vec<u8>,
+    _messages: PhantomData<fn(Out) -> In>,
+}
+
+impl<Out, In, U: UnixSocketInterface> Debug for JsonLinesConnection<Out, In, U>
+where
+    U::UnixStream: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JsonLinesConnection")
+            .field("stream", &self.stream)
+            .field("buffered", &self.buffer.len())
+            .finish()
+    }
+}
+
+impl<Out, In, U: UnixSocketInterface> JsonLinesConnection<Out, In, U> {
+    /// Wrap a bare stream.
+    pub fn new(stream: U::UnixStream) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            _messages: PhantomData,
+        }
+    }
+
+    /// Take back the bare stream. Anything received but not yet returned as a message is lost.
+    pub fn into_inner(self) -> U::UnixStream {
+        self.stream
+    }
+}
+
+impl<Out: Serialize, In: DeserializeOwned, U: UnixSocketInterface> JsonLinesConnection<Out, In, U> {
+    /// Send a single message.
+    pub async fn send(&mut self, message: &Out) -> IoResult<()> {
+        // Compact JSON never contains a raw newline.
+        let mut line = serde_json::to_vec(message)?;
+        line.push(b'\n');
+        U::unix_stream_write_all(&mut self.stream, &line).await
+    }
+
+    /// Receive a single message, or `None` if the other side closed the connection between
+    /// messages. Blank lines are skipped.
+    pub async fn receive(&mut self) -> IoResult<Option<In>> {
+        loop {
+            if let Some(newline) = self.buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=newline).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                return Ok(Some(serde_json::from_slice(&line)?));
+            }
+            if self.buffer.len() > MAX_LINE_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Received line is too long",
+                ));
+            }
+            let mut chunk = [0u8; 4096];
+            match U::unix_stream_read(&mut self.stream, &mut chunk).await? {
+                0 if self.buffer.iter().all(u8::is_ascii_whitespace) => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => self.buffer.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    /// Send a message and wait for the reply. The connection being closed before a reply arrives
+    /// is an [`io::ErrorKind::UnexpectedEof`] error.
+    pub async fn request(&mut self, message: &Out) -> IoResult<In> {
+        self.send(message).await?;
+        self.receive()
+            .await?
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use blocking::Unblock;
+    use futures_lite::future::block_on;
+
+    use super::*;
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn json_lines_are_split_and_skipped() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut raw_client = Unblock::new(a);
+        let mut server =
+            JsonLinesConnection::<usize, Vec<u32>, StdThreadpoolUSocks>::new(Unblock::new(b));
+        block_on(async {
+            StdThreadpoolUSocks::unix_stream_write_all(&mut raw_client, b"[1, 2]\n\n  \n[3]\n")
+                .await
+                .unwrap();
+            assert_eq!(server.receive().await.unwrap(), Some(vec![1, 2]));
+            assert_eq!(server.receive().await.unwrap(), Some(vec![3]));
+        });
+        drop(raw_client);
+        assert_eq!(block_on(server.receive()).unwrap(), None);
+    }
+
+    #[test]
+    pub fn sent_messages_are_single_lines() {
+        let (a, b) = UnixStream::pair().unwrap();
+        let mut client =
+            JsonLinesConnection::<Vec<&str>, usize, StdThreadpoolUSocks>::new(Unblock::new(a));
+        let mut raw_server = Unblock::new(b);
+        block_on(client.send(&vec!["multi\nline"])).unwrap();
+        let mut received = [0u8; 16];
+        block_on(StdThreadpoolUSocks::unix_stream_read_exact(
+            &mut raw_server,
+            &mut received,
+        ))
+        .unwrap();
+        assert_eq!(&received, b"[\"multi\\nline\"]\n");
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,211 @@
+//! Starting services without running a command - see [`ServiceLauncher`] and
+//! [`crate::ServiceStartable::launcher`].
+//!
+//! In-process services are started by running their server on a thread or task of the process
+//! connecting to them, still bound to the service socket in the base context directory. This is
+//! handy for tests, and for deployments that want the same topology of services without shipping
+//! a binary per service.
+
+use std::{
+    fmt::Debug,
+    future::Future,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use crate::{child::ServiceProcessHandle, error, task::TaskSpawner, ServiceSocket};
+
+/// Function starting the server of a service in this process.
+pub type InProcessLaunchFn =
+    dyn Fn(InProcessLaunch) -> std::io::Result<InProcessService> + Send + Sync;
+
+/// How a service is started when it can't be connected to.
+#[derive(Clone, Default)]
+pub enum ServiceLauncher {
+    /// Spawn a process with [`crate::ServiceStartable::run_service_command_raw`].
+    #[default]
+    Command,
+    /// Run the server in this process with the given function, which should get it going on a
+    /// thread or task - see [`InProcessService`] - and return straight away.
+    ///
+    /// The server reports liveness over an ephemeral socket, as with
+    /// [`crate::liveness::LivenessTransport::TempSocket`], whatever the liveness transport of the
+    /// service. Spawn options, child strategies and
+    /// [`crate::ServiceStartable::after_post_liveness_subprocess`] are for processes, so don't
+    /// apply.
+    InProcess(Arc<InProcessLaunchFn>),
+}
+
+impl ServiceLauncher {
+    /// Run the server in this process with the given function - see [`Self::InProcess`].
+    pub fn in_process(
+        launch: impl Fn(InProcessLaunch) -> std::io::Result<InProcessService> + Send + Sync + 'static,
+    ) -> Self {
+        Self::InProcess(Arc::new(launch))
+    }
+}
+
+impl Debug for ServiceLauncher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Command => f.write_str("Command"),
+            Self::InProcess(_) => f.write_str("InProcess(..)"),
+        }
+    }
+}
+
+/// What an in-process server needs to know to start up - pass these on to
+/// [`crate::ServerExt::start_and_run_server`] or one of its siblings.
+#[derive(Debug, Clone)]
+pub struct InProcessLaunch {
+    /// Base context directory to bind the service socket in.
+    pub base_context_directory: PathBuf,
+    /// The service socket the server should bind.
+    pub service_socket: ServiceSocket,
+    /// Ephemeral socket to ping once the service socket is bound.
+    pub liveness_path: PathBuf,
+}
+
+/// Handle on the server of a service running in this process. Dropping it leaves the server
+/// running.
+#[derive(Debug, Clone)]
+pub struct InProcessService {
+    outcome: Arc<Mutex<Option<error::Result<()>>>>,
+}
+
+impl InProcessService {
+    /// Run the server on a thread of its own - usually by blocking on one of the
+    /// [`crate::ServerExt`] methods.
+    pub fn thread(
+        run: impl FnOnce() -> error::Result<()> + Send + 'static,
+    ) -> std::io::Result<Self> {
+        let service = Self::new();
+        let outcome = service.outcome.clone();
+        std::thread::Builder::new()
+            .name("suss-in-process".to_owned())
+            .spawn(move || {
+                let result = run();
+                *outcome.lock().unwrap_or_else(|e| e.into_inner()) = Some(result);
+            })?;
+        Ok(service)
+    }
+
+    /// Run the server as a task of the given spawner.
+    pub fn task(
+        spawner: &dyn TaskSpawner,
+        run: impl Future<Output = error::Result<()>> + Send + 'static,
+    ) -> Self {
+        let service = Self::new();
+        let outcome = service.outcome.clone();
+        spawner.spawn_task(Box::pin(async move {
+            let result = run.await;
+            *outcome.lock().unwrap_or_else(|e| e.into_inner()) = Some(result);
+        }));
+        service
+    }
+
+    fn new() -> Self {
+        Self {
+            outcome: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Whether the server has finished - successfully or not.
+    pub fn is_finished(&self) -> bool {
+        self.outcome
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    /// Take the outcome of the server, if it has finished and it hasn't been taken yet.
+    pub fn take_outcome(&self) -> Option<error::Result<()>> {
+        self.outcome
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
+}
+
+/// A service that has been started, but may not be live yet.
+#[derive(Debug)]
+pub(crate) enum Launched<P: ServiceProcessHandle> {
+    Process(P),
+    InProcess(InProcessService),
+}
+
+impl<P: ServiceProcessHandle> Launched<P> {
+    /// Process id to check liveness pings against - in-process servers ping from this process, so
+    /// there is nothing to check.
+    pub(crate) fn pid(&self) -> Option<u32> {
+        match self {
+            Self::Process(child) => child.pid(),
+            Self::InProcess(_) => None,
+        }
+    }
+
+    /// The error the service failed with before becoming live, if it has. Services that finish
+    /// successfully may have handed over to a daemonised process, so they don't count.
+    pub(crate) fn failure(&mut self, service_socket: &ServiceSocket) -> Option<error::Error> {
+        match self {
+            Self::Process(child) => match child.try_wait() {
+                Ok(Some(status)) if !status.success() => Some(error::Error::SpawnExited {
+                    socket: service_socket.clone(),
+                    status,
+                }),
+                _ => None,
+            },
+            Self::InProcess(service) => service.take_outcome()?.err(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::InProcessService;
+    use crate::{error::Error, task::ThreadSpawner, ServiceSocket};
+
+    #[test]
+    pub fn in_process_outcome_test() {
+        let socket = ServiceSocket::new("in-process-test.sock".as_ref(), "/tmp".as_ref());
+        let failing = {
+            let socket = socket.clone();
+            InProcessService::task(&ThreadSpawner, async move {
+                Err(Error::ServerFailed {
+                    socket,
+                    source: std::io::ErrorKind::Other.into(),
+                })
+            })
+        };
+        let succeeding = InProcessService::thread(|| Ok(())).unwrap();
+        let started = Instant::now();
+        while !(failing.is_finished() && succeeding.is_finished()) {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(
+            failing.take_outcome(),
+            Some(Err(Error::ServerFailed { .. }))
+        ));
+        assert!(failing.take_outcome().is_none());
+        assert!(matches!(succeeding.take_outcome(), Some(Ok(()))));
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -4,34 +4,338 @@ pub use blocking;
 /// Re-export of the chaining-transformation convenience functions crate.
 pub use chain_trans;
 
+pub mod audit;
+pub mod authorization;
+mod blocking_client;
+pub mod buffered;
+pub mod bundle;
+pub mod child;
 mod cleanable_path;
+#[cfg(any(feature = "compression-zstd", feature = "compression-lz4"))]
+pub mod compression;
+pub mod connect_options;
+pub mod connection_cache;
+pub mod container;
+pub mod context_dir;
+pub mod control;
+pub mod credentials;
+pub mod datagram;
+pub mod dependencies;
+pub mod dyn_service;
+#[cfg(feature = "dynamic")]
+pub mod dynamic;
+pub mod environment;
+pub mod error;
+pub mod events;
+pub mod fdpass;
+#[cfg(feature = "framed-serde")]
+pub mod framed;
+pub mod gc;
+pub mod handshake;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "json-lines")]
+pub mod json_lines;
+pub mod launcher;
+mod lockfile;
 pub mod mapfut;
+pub mod mux;
+#[cfg(feature = "noise")]
+pub mod noise;
+pub mod pool;
+pub mod pubsub;
+pub mod reconnect;
+pub mod remote;
+#[cfg(feature = "framed-serde")]
+pub mod rpc;
+pub mod runtime_streams;
+pub mod security;
+pub mod self_service;
+pub mod seqpacket;
+pub mod serve;
+// Shared memory regions are sealed memfds.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod shm;
+#[cfg(feature = "signals")]
+pub mod signals;
+pub mod socket_permissions;
 pub mod socket_shims;
+pub mod spawn;
+pub mod split;
+mod start_dedup;
+pub mod status;
+pub mod supervisor;
+pub mod task;
+pub mod throttle;
 pub mod timefut;
+pub mod transport;
 
 pub mod liveness {
     //! Module containing utilities for managing the liveness socket.
 
+    //!
+    //! A service signals that it is live by connecting to the liveness socket and closing the
+    //! connection again. If it instead fails to start, it can write a status line starting with
+    //! [`LIVENESS_FAILURE_PREFIX`] before closing the connection - see
+    //! [`report_liveness_failure`] - and the message ends up in the
+    //! [`crate::Error::StartupFailed`] produced by the client that started it.
+    //!
+    //! Services whose [`crate::ServiceStartable::liveness_transport`] is
+    //! [`LivenessTransport::InheritedFd`] are instead handed one end of a socketpair, as an
+    //! inherited file descriptor whose "path" is `/dev/fd/<number>` - see
+    //! [`inherited_liveness_fd`]. There, the service has to write a line to report that it is live
+    //! (an empty one) or that it failed, as closing the descriptor is what happens when it
+    //! crashes. This needs no socket file, so there is nothing to clean up if the client crashes
+    //! while starting the service. The functions in this module handle both transports.
+    //!
+    //! Anyone who can reach the directory of an ephemeral socket can connect to it, and so fake the
+    //! liveness of a service. Services whose transport is
+    //! [`LivenessTransport::AuthenticatedTempSocket`] are handed a random token in
+    //! [`LIVENESS_TOKEN_ENV_VAR`] as well, which they have to send back as the first line over the
+    //! ephemeral socket - connections without it are ignored.
+    //!
+    //! Services whose transport is [`LivenessTransport::InheritedListener`] skip the liveness
+    //! check altogether. The client binds the service socket itself and hands the listening
+    //! socket to the service as an inherited file descriptor, named by a `/dev/fd/<number>`
+    //! liveness path just like [`LivenessTransport::InheritedFd`]. So the service is connectable
+    //! as soon as it is spawned. The servers of this library tell the two apart with
+    //! [`inherited_listener_fd`], and take over the listener with [`listener_from_inherited_fd`].
+
     use std::{
+        io::{Result as IoResult, Write},
+        os::{
+            fd::{BorrowedFd, FromRawFd, OwnedFd, RawFd},
+            unix::net::{UnixListener, UnixStream},
+        },
         path::{Path, PathBuf},
         process::Command,
+        sync::Mutex,
     };
 
+    use blocking::unblock;
+
+    use crate::{socket_shims::SocketKind, UnixSocketInterface};
+
+    /// How a started service reports to the client that started it that it is live.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub enum LivenessTransport {
+        /// Connect to an ephemeral socket in the temporary directory, and close the connection.
+        #[default]
+        TempSocket,
+        /// Write a line to one end of a socketpair, inherited from the client as a file
+        /// descriptor.
+        InheritedFd,
+        /// Like [`LivenessTransport::TempSocket`], but first write back the token passed in
+        /// [`LIVENESS_TOKEN_ENV_VAR`] as a line, so other processes can't fake the liveness of the
+        /// service.
+        AuthenticatedTempSocket,
+        /// Report nothing - the client binds the service socket, and the service inherits the
+        /// listener as a file descriptor and serves connections on it. The socket gets the
+        /// permissions the umask of the client gives it, rather than
+        /// [`crate::Server::socket_permissions`].
+        InheritedListener,
+    }
+
+    /// Directory that liveness "paths" of inherited file descriptors are in - the liveness path
+    /// of file descriptor 3 is `/dev/fd/3`.
+    pub const INHERITED_FD_DIR: &str = "/dev/fd";
+
     /// Environment variable used by [`super::declare_service`] as a means of communicating the liveness
     /// socket path.
     pub const LIVENESS_ENV_VAR: &str = "SUSS_LIVENESS_SOCKET_PATH";
 
+    /// Environment variable used by [`super::declare_service`] to pass the liveness token of
+    /// [`LivenessTransport::AuthenticatedTempSocket`] services.
+    pub const LIVENESS_TOKEN_ENV_VAR: &str = "SUSS_LIVENESS_TOKEN";
+
+    /// Liveness token taken out of the environment by [`retrieve_liveness_path`].
+    static RETRIEVED_LIVENESS_TOKEN: Mutex<Option<String>> = Mutex::new(None);
+
+    /// Prefix of the status line a service sends over the liveness socket to report that it
+    /// failed to start.
+    pub const LIVENESS_FAILURE_PREFIX: &str = "ERR ";
+
+    /// Maximum number of bytes read from the liveness connection - anything beyond this is
+    /// ignored.
+    pub const MAX_LIVENESS_STATUS_LENGTH: usize = 4096;
+
+    /// Report to the process that started this service - via the liveness socket - that the
+    /// service failed to start, with a short message. Line breaks in the message are replaced by
+    /// spaces.
+    pub async fn report_liveness_failure<U: UnixSocketInterface>(
+        liveness_socket_path: &Path,
+        message: &str,
+    ) -> IoResult<()> {
+        let status = format!(
+            "{}{}\n",
+            LIVENESS_FAILURE_PREFIX,
+            message.replace(['\r', '\n'], " ")
+        );
+        if let Some(fd) = inherited_liveness_fd(liveness_socket_path) {
+            return write_inherited_fd(fd, status.into_bytes()).await;
+        }
+        let mut sock = U::unix_stream_connect(liveness_socket_path).await?;
+        write_liveness_token::<U>(&mut sock).await?;
+        U::unix_stream_write_all(&mut sock, status.as_bytes()).await?;
+        U::unix_stream_shutdown(&mut sock).await
+    }
+
+    /// Send the liveness token of this process - if it was given one - as the first line over an
+    /// ephemeral liveness connection.
+    pub(crate) async fn write_liveness_token<U: UnixSocketInterface>(
+        sock: &mut U::UnixStream,
+    ) -> IoResult<()> {
+        match liveness_token() {
+            Some(token) => U::unix_stream_write_all(sock, format!("{token}\n").as_bytes()).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Generate a fresh random liveness token, of 32 hex characters.
+    pub(crate) fn generate_liveness_token() -> String {
+        use nanorand::rand::{chacha::ChaCha20, Rng};
+        let mut gen = ChaCha20::new();
+        format!(
+            "{:016x}{:016x}",
+            gen.generate::<u64>(),
+            gen.generate::<u64>()
+        )
+    }
+
+    /// Whether the first line received over a liveness connection is the expected token. This
+    /// takes the same time wherever the line differs, so the token can't be guessed piece by piece.
+    pub(crate) fn liveness_token_matches(expected: &str, line: &[u8]) -> bool {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        line.len() == expected.len()
+            && line
+                .iter()
+                .zip(expected.as_bytes())
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    }
+
+    /// The inherited file descriptor a liveness path stands for, if it is of the form
+    /// `/dev/fd/<number>` - see [`LivenessTransport::InheritedFd`].
+    pub fn inherited_liveness_fd(liveness_path: &Path) -> Option<RawFd> {
+        liveness_path
+            .strip_prefix(INHERITED_FD_DIR)
+            .ok()?
+            .to_str()?
+            .parse()
+            .ok()
+    }
+
+    /// Write a status to the inherited liveness file descriptor and close it.
+    pub(crate) async fn write_inherited_fd(fd: RawFd, status: Vec<u8>) -> IoResult<()> {
+        // SAFETY: the file descriptor was inherited from the client for this, and the liveness
+        // path naming it is only ever used once, as retrieve_liveness_path clears it.
+        let mut stream = UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        unblock(move || stream.write_all(&status)).await
+    }
+
+    /// Create a socketpair for [`LivenessTransport::InheritedFd`], producing the end the client
+    /// keeps and the end the service process inherits - which stays open across `exec` and should
+    /// be dropped once the process is spawned.
+    ///
+    /// Processes spawned by other threads in the meantime inherit it too, so clients also watch
+    /// for the started process exiting, rather than relying on the descriptor being closed.
+    pub(crate) fn inheritable_pair() -> IoResult<(UnixStream, OwnedFd)> {
+        let (ours, theirs) = UnixStream::pair()?;
+        let theirs = OwnedFd::from(theirs);
+        rustix::io::fcntl_setfd(&theirs, rustix::io::FdFlags::empty())?;
+        Ok((ours, theirs))
+    }
+
+    /// Bind a listener socket for [`LivenessTransport::InheritedListener`] at the path, producing
+    /// it as a file descriptor that stays open across `exec` - drop it once the service process is
+    /// spawned.
+    pub(crate) fn inheritable_listener(socket_path: &Path, kind: SocketKind) -> IoResult<OwnedFd> {
+        let listener = OwnedFd::from(match kind {
+            SocketKind::Stream => UnixListener::bind(socket_path)?,
+            SocketKind::SeqPacket => crate::seqpacket::bind_std(socket_path)?,
+        });
+        rustix::io::fcntl_setfd(&listener, rustix::io::FdFlags::empty())?;
+        Ok(listener)
+    }
+
+    /// The inherited file descriptor a liveness path stands for, if it is a listening socket - see
+    /// [`LivenessTransport::InheritedListener`].
+    ///
+    /// # Safety
+    /// A liveness path of the form `/dev/fd/<number>` must name a file descriptor that is open for
+    /// the duration of the call - like those the client starting this process passes along, see
+    /// [`retrieve_liveness_path`].
+    pub unsafe fn inherited_listener_fd(liveness_path: &Path) -> Option<RawFd> {
+        let fd = inherited_liveness_fd(liveness_path)?;
+        // SAFETY: the descriptor is open, as the caller guarantees, and only borrowed for the
+        // check.
+        let fd_ref = unsafe { BorrowedFd::borrow_raw(fd) };
+        #[cfg(not(target_vendor = "apple"))]
+        let listening = rustix::net::sockopt::socket_acceptconn(fd_ref).unwrap_or(false);
+        // Apple platforms can't tell listening sockets apart.
+        #[cfg(target_vendor = "apple")]
+        let listening = {
+            let _ = fd_ref;
+            false
+        };
+        listening.then_some(fd)
+    }
+
+    /// Take over the listening socket inherited as the given file descriptor - see
+    /// [`inherited_listener_fd`] - as the listener of a server.
+    ///
+    /// # Safety
+    /// This takes ownership of the descriptor, so it must be an open unix listening socket that
+    /// nothing else owns or closes - it must only be called once for it.
+    pub async unsafe fn listener_from_inherited_fd<U: UnixSocketInterface>(
+        fd: RawFd,
+    ) -> IoResult<U::UnixListener> {
+        // SAFETY: the caller gives up the file descriptor, which nothing else owns.
+        let listener = UnixListener::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        U::unix_listener_from_std(listener).await
+    }
+
+    /// Directory ephemeral liveness sockets are created in unless configured otherwise - the
+    /// user's runtime directory ([`crate::context_dir::XDG_RUNTIME_DIR_ENV_VAR`]) if there is
+    /// one, otherwise [`std::env::temp_dir`], which is often shared or watched in hardened
+    /// deployments.
+    pub fn default_ephemeral_dir() -> PathBuf {
+        std::env::var_os(crate::context_dir::XDG_RUNTIME_DIR_ENV_VAR)
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute() && dir.is_dir())
+            .unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Liveness path that names an inherited file descriptor.
+    pub(crate) fn inherited_fd_path(fd: RawFd) -> PathBuf {
+        Path::new(INHERITED_FD_DIR).join(fd.to_string())
+    }
+
+    /// Extract the failure message from what a service sent over the liveness connection, if it
+    /// reported a failure to start.
+    pub fn parse_liveness_failure(status: &[u8]) -> Option<String> {
+        String::from_utf8_lossy(status)
+            .strip_prefix(LIVENESS_FAILURE_PREFIX)
+            .map(|message| message.lines().next().unwrap_or_default().to_owned())
+    }
+
     /// Ensure that, for the command given, the environment variable [`LIVENESS_ENV_VAR`] exists
     /// with the correct liveness socket path as passed to this function, or if the liveness path
-    /// is None, ensures that the environment variable doesn't exist. This function is
-    /// automatically used with [`super::declare_service`]
+    /// is None, ensures that the environment variable doesn't exist - and likewise for the
+    /// liveness token and [`LIVENESS_TOKEN_ENV_VAR`]. This function is automatically used with
+    /// [`super::declare_service`]
     ///
     /// On a service server, see [`retrieve_liveness_path`] for obtaining the liveness path from
     /// the environment and clearing the environment to avoid polluting child processes.
     pub fn set_liveness_environment<'c>(
         command: &'c mut Command,
         child_liveness_path_state: Option<&Path>,
+        child_liveness_token: Option<&str>,
     ) -> &'c mut Command {
+        match child_liveness_token {
+            Some(token) => command.env(LIVENESS_TOKEN_ENV_VAR, token),
+            None => command.env_remove(LIVENESS_TOKEN_ENV_VAR),
+        };
         match child_liveness_path_state {
             Some(liveness_path) => command.env(LIVENESS_ENV_VAR, liveness_path.as_os_str()),
             None => command.env_remove(LIVENESS_ENV_VAR),
@@ -44,28 +348,71 @@ pub mod liveness {
     ///
     /// In your service declarations, use [`set_liveness_environment`] on your commands to
     /// configure this to work.
+    ///
+    /// The liveness token is taken out of the environment too, and kept for
+    /// [`liveness_token`].
     pub fn retrieve_liveness_path() -> Option<PathBuf> {
         let path = std::env::var_os(LIVENESS_ENV_VAR).map(PathBuf::from);
         std::env::remove_var(LIVENESS_ENV_VAR);
+        if let Ok(token) = std::env::var(LIVENESS_TOKEN_ENV_VAR) {
+            *RETRIEVED_LIVENESS_TOKEN
+                .lock()
+                .unwrap_or_else(|e| e.into_inner()) = Some(token);
+        }
+        std::env::remove_var(LIVENESS_TOKEN_ENV_VAR);
         path
     }
+
+    /// The liveness token this process was started with, if any - from
+    /// [`LIVENESS_TOKEN_ENV_VAR`], or kept by [`retrieve_liveness_path`] after clearing it. The
+    /// servers of this library send it back automatically.
+    pub fn liveness_token() -> Option<String> {
+        RETRIEVED_LIVENESS_TOKEN
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .or_else(|| std::env::var(LIVENESS_TOKEN_ENV_VAR).ok())
+    }
 }
 
 /// Provide async_trait for convenience.
 pub use async_trait::async_trait;
 use chain_trans::Trans;
 use cleanable_path::CleanablePathBuf;
+pub use connect_options::ConnectOptions;
+pub use context_dir::ContextDir;
+pub use credentials::{CredentialPolicy, PeerCredentials};
+use dependencies::{DependencyGraph, DependencyStartFuture, DependencyStarter};
+pub use environment::ServerEnvironment;
+pub use error::{Error, ServiceSocket};
+pub use events::ServiceEvent;
 pub use futures_lite::future;
+pub use gc::{gc_context, GcReport};
+use lockfile::LockFile;
+use start_dedup::StartClaim;
+pub use status::{list_services, ServiceStatus};
 
-pub use socket_shims::UnixSocketInterface;
+pub use security::SecurityPolicy;
+pub use socket_permissions::SocketPermissions;
+pub use socket_shims::{DefaultUnixSocks, UnixSocketInterface};
+pub use spawn::SpawnOptions;
+pub use task::TaskSpawner;
 
+use future::FutureExt;
+use mapfut::map_fut;
 use std::{
     ffi::{OsStr, OsString},
     fmt::Debug,
+    future::Future,
     marker::PhantomData,
     path::Path,
+    pin::pin,
+};
+use std::{
+    io::Result as IoResult,
+    process::ExitStatus,
+    time::{Duration, Instant},
 };
-use std::{io::Result as IoResult, process::Child, time::Duration};
 use timefut::with_timeout;
 use tracing::{debug, error, info, instrument, warn};
 
@@ -107,27 +454,124 @@ use tracing::{debug, error, info, instrument, warn};
 /// Socket files, actually running the service, etc. are not handled by this trait. Instead, they
 /// are handled by a [`ServerExt`], which takes care of things like cleaning up socket files
 /// afterward automatically in [`Drop`]
-#[async_trait(?Send)]
-pub trait Service<UnixSockets: UnixSocketInterface>: Debug {
+///
+/// The unix socket interface parameter defaults to [`DefaultUnixSocks`], but any implementation of
+/// [`UnixSocketInterface`] can be plugged in - including ones from outside this crate.
+///
+/// The async methods of this trait, [`ServiceStartable`], [`ServiceExt`] and [`ServerExt`] are
+/// native `async fn`s, so connecting doesn't allocate a boxed future at every step. Their futures
+/// aren't required to be `Send`. For compilers older than 1.75, the `async-trait-compat`
+/// feature implements them with [`macro@async_trait`] instead, in which case manual
+/// implementations need `#[async_trait(?Send)]` too ([`declare_service!`] handles this).
+#[cfg_attr(feature = "async-trait-compat", async_trait(?Send))]
+#[allow(async_fn_in_trait)]
+pub trait Service<UnixSockets: UnixSocketInterface = DefaultUnixSocks>: Debug {
     /// A connection to the service server - must be generatable from a stream as specified in the
     /// unix socket interface parameters.
     type ServiceClientConnection;
 
+    /// A connection from a client, as seen by the service server - generated from streams
+    /// accepted on the service socket.
+    type ServiceServerConnection;
+
     /// Obtain the name of the socket file in the base context path. In your collection of
     /// services, the result should be unique, or you might end up with service collisions when
     /// trying to grab sockets.
-    fn socket_name(&self) -> &std::ffi::OsStr;
+    ///
+    /// The name can be computed when asked for - for instance from a shard number - in which case
+    /// it is owned rather than borrowed.
+    fn socket_name(&self) -> std::borrow::Cow<'_, std::ffi::OsStr>;
 
-    /// Convert a bare unix stream into a [`Self::ServiceClientConnection`]
+    /// Range of protocol versions to exchange - along with the socket name - in a [`handshake`]
+    /// at the start of every connection. This means clients can't silently end up talking to a
+    /// different service that uses the same socket name, or to a server speaking an incompatible
+    /// version of the protocol. The default of `None` means no handshake is performed.
+    ///
+    /// Clients and servers of the service must agree on whether there is a handshake.
+    fn handshake_protocol_versions(&self) -> Option<std::ops::RangeInclusive<u32>> {
+        None
+    }
+
+    /// Time limit on setting up a connection to the service once connected - the handshake and
+    /// wrapping of the stream - used by [`ReifiedService::connect`] unless overridden in the
+    /// [`ConnectOptions`]. Connections that take longer fail with [`Error::ConnectTimeout`]. The
+    /// default of `None` waits for as long as it takes.
+    fn connect_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Version of the service, recorded in the state file of its servers so tools can tell which
+    /// version is running without connecting to it - see [`status::read_state`]. The default of
+    /// `None` records no version.
+    fn service_version(&self) -> Option<&str> {
+        None
+    }
+
+    /// Command line that starts the service, without any executor prefix - for describing the
+    /// service, as in [`bundle::BundleManifest`], rather than for running it. [`declare_service!`]
+    /// fills this in for services started by a command. The default of `None` means the service
+    /// isn't started by a command, or doesn't say how.
+    fn command_line(&self) -> Option<Vec<OsString>> {
+        None
+    }
+
+    /// User that clients expect to own both the service socket file and the server on the other
+    /// end of their connections - checked before the [`handshake`] and
+    /// [`Service::wrap_connection`], so a socket squatted by another user in a shared directory
+    /// isn't trusted. `Some(credentials::current_uid())` is the usual choice for per-user
+    /// services. The default of `None` performs no check.
+    ///
+    /// Reading the credentials of the server needs
+    /// [`UnixSocketInterface::unix_stream_peer_credentials`], so see [`credentials`] for the
+    /// supported platforms.
+    fn trusted_owner_uid(&self) -> Option<u32> {
+        None
+    }
+
+    /// Checks made on the socket path - by clients before connecting to it or removing it when
+    /// stale, and by servers before binding it - to refuse symlinks and unsafe base context
+    /// directories. See [`SecurityPolicy`]. The default makes no checks.
+    fn security_policy(&self) -> SecurityPolicy {
+        SecurityPolicy::default()
+    }
+
+    /// Hooks authorizing every connection to the service once the [`handshake`] is done - the
+    /// client presents what [`authorization::Authorizer::on_connect`] fills in, and the server
+    /// decides with [`authorization::Authorizer::on_accept`]. Denied connections fail with
+    /// [`Error::Unauthorized`] on both sides. The default of `None` authorizes nothing.
     ///
-    /// Bound on unix stream says that the unix stream lives as long as the produced future,
-    /// essentially.
+    /// Clients and servers of the service must agree on whether there is an authorizer.
+    fn authorizer(&self) -> Option<std::sync::Arc<dyn authorization::Authorizer>> {
+        None
+    }
+
+    /// Transport clients reach the server over, instead of connecting to the service socket - see
+    /// [`transport`]. Servers still bind the service socket, and expose it over the transport
+    /// too. The default of `None` uses the service socket directly.
+    fn transport(&self) -> Option<std::sync::Arc<dyn transport::Transport>> {
+        None
+    }
+
+    /// Kind of unix socket the service binds and clients connect to - see [`seqpacket`]. The
+    /// default is a plain [`socket_shims::SocketKind::Stream`].
+    fn socket_kind(&self) -> socket_shims::SocketKind {
+        socket_shims::SocketKind::Stream
+    }
+
+    /// Convert a bare unix stream into a [`Self::ServiceClientConnection`]
     async fn wrap_connection(
         &self,
         bare_stream: UnixSockets::UnixStream,
-    ) -> IoResult<Self::ServiceClientConnection>
-    where
-        UnixSockets::UnixStream: 'async_trait;
+    ) -> ::std::io::Result<Self::ServiceClientConnection>;
+
+    /// Convert a bare unix stream accepted by a server into a [`Self::ServiceServerConnection`].
+    ///
+    /// This lets servers - for instance the handlers passed to [`serve::serve_connections`] - reuse
+    /// whatever framing the service already defines, rather than duplicating it by hand.
+    async fn wrap_incoming(
+        &self,
+        bare_stream: UnixSockets::UnixStream,
+    ) -> ::std::io::Result<Self::ServiceServerConnection>;
 }
 
 /// An extension trait to [`Service`] that provides a means of starting a service automatically
@@ -136,8 +580,14 @@ pub trait Service<UnixSockets: UnixSocketInterface>: Debug {
 /// See the documentation for [`Service`] for info on base context paths, executor prefixes, etc,
 /// and have a look at [`declare_service`] for an easy way to implement services that call out to
 /// commands when they can't be started.
-#[async_trait(?Send)]
-pub trait ServiceStartable<U: UnixSocketInterface>: Service<U> {
+#[cfg_attr(feature = "async-trait-compat", async_trait(?Send))]
+#[allow(async_fn_in_trait)]
+pub trait ServiceStartable<U: UnixSocketInterface = DefaultUnixSocks>: Service<U> {
+    /// Handle on the started service process - usually a [`std::process::Child`], which is what
+    /// [`declare_service!`] uses, but anything implementing [`child::ServiceProcessHandle`] will
+    /// do for services launched some other way.
+    type ProcessHandle: child::ServiceProcessHandle;
+
     /// This should attempt to start the service, with the given ephemeral liveness
     /// socket path passed through if present to that service - in [`declare_service!`], this is
     /// done with an environment variable.
@@ -149,12 +599,60 @@ pub trait ServiceStartable<U: UnixSocketInterface>: Service<U> {
     /// by the running service process (this is handled automatically by [`ServiceExt`] if you
     /// use that to run your service).
     ///
+    /// For [`liveness::LivenessTransport::AuthenticatedTempSocket`] services, the liveness token
+    /// should be passed through too - [`liveness::set_liveness_environment`] handles both.
+    ///
+    /// The process should be set up with the given [`spawn::PreparedSpawn`] - its standard input
+    /// and output, and whatever else [`Self::spawn_options`] asks for.
+    ///
     /// Ephemeral liveness check timeouts are applied by the library later on.
     fn run_service_command_raw(
         &self,
         executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
         liveness_path: Option<&Path>,
-    ) -> IoResult<Child>;
+        liveness_token: Option<&str>,
+        prepared_spawn: spawn::PreparedSpawn,
+    ) -> IoResult<Self::ProcessHandle>;
+
+    /// How to spawn the service process - where its standard input and output go, which user it
+    /// runs as, what resources it may use, whether it dies with the process starting it, and
+    /// whether it runs in its own session. See [`SpawnOptions`]. The default inherits everything
+    /// from the process starting the service, and leaves the service running when that process
+    /// exits.
+    fn spawn_options(&self) -> SpawnOptions {
+        SpawnOptions::default()
+    }
+
+    /// How long to wait for the service to become live when starting it, used by
+    /// [`ReifiedService::connect`] - set this to suit the service, so slow-starting ones aren't
+    /// given up on too early by every caller. The default is
+    /// [`ConnectOptions::DEFAULT_LIVENESS_TIMEOUT`].
+    fn liveness_timeout(&self) -> Duration {
+        ConnectOptions::DEFAULT_LIVENESS_TIMEOUT
+    }
+
+    /// How the started service reports that it is live. With
+    /// [`liveness::LivenessTransport::InheritedFd`], the liveness path passed to
+    /// [`Self::run_service_command_raw`] names a file descriptor the spawned process inherits -
+    /// pass it on the same way, and the servers of this library handle the rest. Servers of older
+    /// versions of this library only understand the default of
+    /// [`liveness::LivenessTransport::TempSocket`].
+    fn liveness_transport(&self) -> liveness::LivenessTransport {
+        liveness::LivenessTransport::default()
+    }
+
+    /// Whether to only accept liveness pings over an ephemeral socket from the spawned process or
+    /// its descendants, going by the credentials of the connection - see
+    /// [`credentials::is_descendant_of`]. Pings from other processes are ignored. The default is
+    /// `false`.
+    ///
+    /// Processes that daemonise by forking and letting their parent exit are reparented, so stop
+    /// counting as descendants - only turn this on for services that ping the liveness socket
+    /// from the spawned process, or from a child of it that is still running. See
+    /// [`credentials`] for the supported platforms - elsewhere, liveness checks fail.
+    fn verify_liveness_peer(&self) -> bool {
+        false
+    }
 
     /// This function is applied to the child process after it has passed the liveness check but
     /// before it has been connected to. In here you can add it to a threadpool or something if you want to
@@ -165,24 +663,41 @@ pub trait ServiceStartable<U: UnixSocketInterface>: Service<U> {
     /// lifetime of the service to the lifetime of the parent process, spawning a task that just
     /// .wait()s on the child or does some async equivalent may be sufficient. Well, it might also
     /// block your own process until the child dies but hey ho!, sort that out yourself :) - you
-    /// probably want to use your runtime's equivalent of `spawn` for this.
+    /// probably want to use your runtime's equivalent of `spawn` for this. Or let the library do it
+    /// with a [`TaskSpawner`] in [`ConnectOptions::with_task_spawner`], in which case this function
+    /// isn't called at all.
     ///
     /// (by default: [see here for
     /// info](https://unix.stackexchange.com/questions/149319/new-parent-process-when-the-parent-process-dies))
     ///
     /// Of course this function, like the [`Self::run_service_command_raw`] function, are not used at all
     /// if the service already exists in base context directory.
-    async fn after_post_liveness_subprocess(&self, _: Child) -> IoResult<()> {
+    async fn after_post_liveness_subprocess(&self, _: Self::ProcessHandle) -> IoResult<()> {
         Ok(())
     }
+
+    /// What to do with the child process after it has passed the liveness check - one of the
+    /// ready-made strategies of [`child::ChildStrategy`], or by default
+    /// [`child::ChildStrategy::Custom`], which hands it to
+    /// [`Self::after_post_liveness_subprocess`].
+    fn child_strategy(&self) -> child::ChildStrategy {
+        child::ChildStrategy::Custom
+    }
+
+    /// How the service is started - by default by spawning a process with
+    /// [`Self::run_service_command_raw`], or alternatively by running its server in this process -
+    /// see [`launcher::ServiceLauncher`].
+    fn launcher(&self) -> launcher::ServiceLauncher {
+        launcher::ServiceLauncher::default()
+    }
 }
 
-/// Utility function to obtain a random path in [`std::env::tempdir`], of the form
-/// `$tempdir/temp-XXXXXXXXXXXXXXXX.sock` (16 xs), where the x's are replaced by numbers
+/// Utility function to obtain a random path in the given ephemeral socket directory, of the form
+/// `$dir/temp-XXXXXXXXXXXXXXXX.sock` (16 xs), where the x's are replaced by numbers
 /// from 0-9a-f (hex)
-fn get_random_sockpath() -> std::path::PathBuf {
+fn get_random_sockpath(ephemeral_dir: &Path) -> std::path::PathBuf {
     use nanorand::rand::{chacha::ChaCha20, Rng};
-    let mut path = std::env::temp_dir();
+    let mut path = ephemeral_dir.to_owned();
     let mut gen = ChaCha20::new();
     // 1 byte => 2 chars
     // 16 chars => 8 bytes => 64 bits => u64
@@ -197,8 +712,10 @@ fn get_random_sockpath() -> std::path::PathBuf {
 /// meant to ping the liveness socket.
 #[instrument]
 async fn ephemeral_liveness_socket_create<U: UnixSocketInterface>(
-) -> IoResult<(U::UnixListener, CleanablePathBuf)> {
-    let ephemeral_socket_path = CleanablePathBuf::new(get_random_sockpath());
+    service_socket: &ServiceSocket,
+    ephemeral_dir: &Path,
+) -> error::Result<(U::UnixListener, CleanablePathBuf)> {
+    let ephemeral_socket_path = CleanablePathBuf::new(get_random_sockpath(ephemeral_dir));
     info!(
         "Creating ephemeral liveness socket @ {}",
         ephemeral_socket_path.as_ref().display()
@@ -211,7 +728,10 @@ async fn ephemeral_liveness_socket_create<U: UnixSocketInterface>(
                 ephemeral_socket_path.as_ref().display(),
                 e
             );
-            e
+            Error::LivenessSocketFailed {
+                socket: service_socket.clone(),
+                source: e,
+            }
         })?
         .trans(|ul| Ok((ul, ephemeral_socket_path)))
 }
@@ -219,184 +739,910 @@ async fn ephemeral_liveness_socket_create<U: UnixSocketInterface>(
 /// Wait for a connection ping on the liveness socket after starting the relevant process, with a
 /// timeout. Check out [`ephemeral_liveness_socket_create`].
 ///
+/// With a liveness token, connections that don't send it first are ignored, and waiting continues -
+/// likewise for connections from processes other than the child process and its descendants, if
+/// its process ID is given.
+///
 /// If we failed, return an error - this includes timeouts as well.
 async fn ephemeral_liveness_socket_check_with_timeout<U: UnixSocketInterface>(
+    service_socket: &ServiceSocket,
     mut ephemeral_listener: U::UnixListener,
     listener_path: CleanablePathBuf,
+    liveness_token: Option<&str>,
+    child_pid: Option<u32>,
     liveness_timeout: Duration,
-) -> IoResult<()> {
-    // Some(Result(temp stream)) if successful without timing out.
-    let maybe_temp_unix_stream = with_timeout(
-        U::unix_listener_accept(&mut ephemeral_listener),
-        liveness_timeout,
-    )
-    .await;
-    // If we timed out trying to accept some connection, we get None, so turn that into an Err() variant
-    let temp_unix_stream = maybe_temp_unix_stream.unwrap_or_else(|| {
-        Err(std::io::Error::new(
-            std::io::ErrorKind::TimedOut,
-            format!(
-                "Timed out waiting for service to become live after {}",
-                humantime::format_duration(liveness_timeout)
-            ),
-        ))
-    });
+) -> error::Result<()> {
+    let waiting_since = Instant::now();
+    let timed_out = |waiting_for: &str| {
+        error!(
+            "Timed out waiting for {} for service on ephemeral socket {} after {}",
+            waiting_for,
+            listener_path.as_ref().display(),
+            humantime::format_duration(liveness_timeout)
+        );
+        Error::LivenessTimeout {
+            socket: service_socket.clone(),
+            timeout: liveness_timeout,
+        }
+    };
+    let (mut temp_unix_stream, status) = loop {
+        let remaining_timeout = liveness_timeout.saturating_sub(waiting_since.elapsed());
+        // Some(Result(temp stream)) if successful without timing out.
+        let maybe_temp_unix_stream = with_timeout(
+            U::unix_listener_accept(&mut ephemeral_listener),
+            remaining_timeout,
+        )
+        .await;
 
-    // Log errors and forward them up to the caller.
-    let mut temp_unix_stream = temp_unix_stream
-        .map_err(|e| {
-            error!(
-                "Failed to receive liveness ping for service on ephemeral socket {} - {}",
-                listener_path.as_ref().display(),
-                e
-            );
-            e
-        })?
+        // Log errors and forward them up to the caller.
+        let mut temp_unix_stream = match maybe_temp_unix_stream {
+            Some(accept_result) => accept_result.map_err(|e| {
+                error!(
+                    "Failed to receive liveness ping for service on ephemeral socket {} - {}",
+                    listener_path.as_ref().display(),
+                    e
+                );
+                Error::LivenessSocketFailed {
+                    socket: service_socket.clone(),
+                    source: e,
+                }
+            })?,
+            // If we timed out trying to accept some connection, we get None
+            None => return Err(timed_out("liveness ping")),
+        }
         .trans(|(stream, _addr)| stream);
 
-    U::unix_stream_shutdown(&mut temp_unix_stream).await?;
+        if let Some(child_pid) = child_pid {
+            let (peer, from_child) = U::unix_stream_peer_credentials(&mut temp_unix_stream)
+                .await
+                .and_then(|peer| Ok((peer, credentials::is_descendant_of(peer.pid, child_pid)?)))
+                .map_err(|e| {
+                    error!(
+                        "Couldn't check where liveness ping on ephemeral socket {} came from - {}",
+                        listener_path.as_ref().display(),
+                        e
+                    );
+                    Error::LivenessSocketFailed {
+                        socket: service_socket.clone(),
+                        source: e,
+                    }
+                })?;
+            if !from_child {
+                warn!(
+                    "Ignoring liveness ping from {}, which isn't child process {} or a descendant",
+                    peer, child_pid
+                );
+                let _ = U::unix_stream_shutdown(&mut temp_unix_stream).await;
+                continue;
+            }
+        }
+
+        // The service may report a startup failure before closing the connection, so read until it
+        // does - within what remains of the timeout. The token comes first, on a line of its own.
+        let remaining_timeout = liveness_timeout.saturating_sub(waiting_since.elapsed());
+        let status_lines = if liveness_token.is_some() { 2 } else { 1 };
+        let mut status = match with_timeout(
+            read_liveness_status::<U>(&mut temp_unix_stream, status_lines),
+            remaining_timeout,
+        )
+        .await
+        {
+            Some(status) => status.map_err(|e| Error::LivenessSocketFailed {
+                socket: service_socket.clone(),
+                source: e,
+            })?,
+            None => return Err(timed_out("service to close liveness connection")),
+        };
+        if let Some(token) = liveness_token {
+            let token_line_length = status
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(status.len(), |i| i + 1);
+            if !liveness::liveness_token_matches(token, &status[..token_line_length]) {
+                warn!(
+                    "Ignoring liveness ping without the liveness token on ephemeral socket {}",
+                    listener_path.as_ref().display()
+                );
+                let _ = U::unix_stream_shutdown(&mut temp_unix_stream).await;
+                continue;
+            }
+            status.drain(..token_line_length);
+        }
+        break (temp_unix_stream, status);
+    };
+
+    U::unix_stream_shutdown(&mut temp_unix_stream)
+        .await
+        .map_err(|e| Error::LivenessSocketFailed {
+            socket: service_socket.clone(),
+            source: e,
+        })?;
     // Clean up the path and delete the listener
     drop(ephemeral_listener);
     drop(listener_path);
-    Ok(())
+    check_liveness_status(service_socket, &status)
 }
 
-#[async_trait(?Send)]
-pub trait ServiceExt<UnixSockets: UnixSocketInterface>: Service<UnixSockets> {
-    /// Reify this [`Service`] into a [`ReifiedService`] that carries around necessary context for
-    /// connecting to it.
-    fn reify(self, base_context_directory: &Path) -> ReifiedService<'_, Self, UnixSockets>
-    where
-        Self: Sized,
+/// Wait for the service to report its liveness over the client end of an inherited liveness
+/// socketpair, with a timeout - see [`liveness::LivenessTransport::InheritedFd`].
+async fn inherited_fd_liveness_check_with_timeout(
+    service_socket: &ServiceSocket,
+    liveness_stream: std::os::unix::net::UnixStream,
+    liveness_timeout: Duration,
+) -> error::Result<()> {
+    let mut liveness_stream = blocking::Unblock::new(liveness_stream);
+    let status = match with_timeout(
+        read_liveness_status::<socket_shims::StdThreadpoolUSocks>(&mut liveness_stream, 1),
+        liveness_timeout,
+    )
+    .await
     {
-        ReifiedService::reify_service(self, base_context_directory)
+        Some(status) => status.map_err(|e| Error::LivenessSocketFailed {
+            socket: service_socket.clone(),
+            source: e,
+        })?,
+        None => {
+            error!(
+                "Timed out waiting for liveness report for service @ {} after {}",
+                service_socket,
+                humantime::format_duration(liveness_timeout)
+            );
+            return Err(Error::LivenessTimeout {
+                socket: service_socket.clone(),
+                timeout: liveness_timeout,
+            });
+        }
+    };
+    // Closing the inherited end is what happens when the service crashes, so only a whole line
+    // counts as a report.
+    if !status.ends_with(b"\n") {
+        error!(
+            "Service @ {} closed its liveness file descriptor without reporting liveness",
+            service_socket
+        );
+        return Err(Error::LivenessSocketFailed {
+            socket: service_socket.clone(),
+            source: std::io::ErrorKind::UnexpectedEof.into(),
+        });
     }
+    check_liveness_status(service_socket, &status)
+}
 
-    /// Reify this [`Service`] into a [`ReifiedService`] that carries around necessary context for
-    /// connecting to it, including an executor prefix command.
-    fn reify_with_executor<'i, EPC: AsRef<OsStr> + Sized + Debug>(
-        self,
-        base_context_directory: &'i Path,
-        executor_prefix: &'i [EPC],
-    ) -> ReifiedService<'i, Self, UnixSockets, EPC>
-    where
-        Self: Sized,
-    {
-        ReifiedService::reify_service_with_executor(self, base_context_directory, executor_prefix)
+/// Turn a liveness status the service reported into an [`Error::StartupFailed`] if it reported a
+/// failure.
+fn check_liveness_status(service_socket: &ServiceSocket, status: &[u8]) -> error::Result<()> {
+    match liveness::parse_liveness_failure(status) {
+        Some(message) => {
+            error!(
+                "Service @ {} reported that it failed to start - {}",
+                service_socket, message
+            );
+            Err(Error::StartupFailed {
+                socket: service_socket.clone(),
+                message,
+            })
+        }
+        None => Ok(()),
     }
+}
 
-    /// Attempt to connect to an already running service. This will not try to start the service on
-    /// failure - for that, see [`Self::connect_to_service`], which requires the service implements
-    /// [`ServiceStartable`]
-    ///
-    /// See [`Service`] for information on base context directories.
-    #[instrument]
-    async fn connect_to_running_service(
-        &self,
-        base_context_directory: &Path,
-    ) -> IoResult<Self::ServiceClientConnection> {
-        let server_socket_path = base_context_directory.join(Self::socket_name(self));
-        info!(
-            "Attempting connection to service @ {}",
-            server_socket_path.display()
-        );
-        let unix_stream = UnixSockets::unix_stream_connect(&server_socket_path)
-            .await
-            .map_err(|e| {
-                error!(
-                    "Failed to connect to service @ {}",
-                    server_socket_path.display()
-                );
-                e
-            })?;
+/// Where the liveness report of a service process that is being started will arrive - see
+/// [`liveness::LivenessTransport`].
+enum PendingLiveness<U: UnixSocketInterface> {
+    /// Ephemeral listener socket, its self-cleaning path, and the liveness token the service has
+    /// to send back, if any.
+    TempSocket(U::UnixListener, CleanablePathBuf, Option<String>),
+    /// Our end of the socketpair, and the end the service process inherits.
+    InheritedFd(std::os::unix::net::UnixStream, std::os::fd::OwnedFd),
+    /// The service socket - cleaned up unless the service process is spawned - and its listener,
+    /// which the service process inherits.
+    InheritedListener(CleanablePathBuf, std::os::fd::OwnedFd),
+}
 
-        info!("Successfully connected @ {}", server_socket_path.display());
-        self.wrap_connection(unix_stream).await
+impl<U: UnixSocketInterface> PendingLiveness<U> {
+    /// Liveness path to hand to the service process.
+    fn liveness_path(&self) -> std::path::PathBuf {
+        use std::os::fd::AsRawFd;
+        match self {
+            PendingLiveness::TempSocket(_, path, _) => path.as_ref().to_owned(),
+            PendingLiveness::InheritedFd(_, theirs)
+            | PendingLiveness::InheritedListener(_, theirs) => {
+                liveness::inherited_fd_path(theirs.as_raw_fd())
+            }
+        }
     }
 
-    /// Attempt to connect to the given service in the given runtime context directory. This
-    /// requires that the service is startable on failure. If you just want to connect to an
-    /// already running service, see [`Service`].
-    ///
-    /// See [`Service`] for information on executor commandline prefixes and the base context
-    /// directory.
-    ///
-    /// If the service is not already running, then `liveness_timeout` is the maximum time before a
-    /// non-response to the liveness check will result in an error.
-    #[instrument]
-    async fn connect_to_service(
-        &self,
-        executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
-        base_context_directory: &Path,
-        liveness_timeout: Duration,
-    ) -> IoResult<Self::ServiceClientConnection>
-    where
-        Self: ServiceStartable<UnixSockets>,
-    {
-        match self
-            .connect_to_running_service(base_context_directory)
-            .await
-        {
-            Ok(s) => Ok(s),
-            Err(e) => {
-                warn!("Error connecting to existing service - {} - attempting on-demand service start", e);
-                let (ephemeral_listener, ephemeral_socket_path) =
-                    ephemeral_liveness_socket_create::<UnixSockets>().await?;
-
-                // We have an ephemeral socket, so begin running the child process, using `unblock`
-                let child_proc = self
-                    .run_service_command_raw(
-                        executor_commandline_prefix,
-                        Some(ephemeral_socket_path.as_ref()),
-                    )
-                    .map_err(|e| {
-                        error!("Could not start child service process - {}", e);
-                        e
-                    })?;
+    /// Liveness token to hand to the service process, if it has to send one back.
+    fn liveness_token(&self) -> Option<&str> {
+        match self {
+            PendingLiveness::TempSocket(_, _, token) => token.as_deref(),
+            PendingLiveness::InheritedFd(..) | PendingLiveness::InheritedListener(..) => None,
+        }
+    }
 
-                ephemeral_liveness_socket_check_with_timeout::<UnixSockets>(
-                    ephemeral_listener,
-                    ephemeral_socket_path,
+    /// Wait for the liveness report of the spawned service process, with a timeout - only from
+    /// the process with the given ID or its descendants, if there is one.
+    async fn check_with_timeout(
+        self,
+        service_socket: &ServiceSocket,
+        child_pid: Option<u32>,
+        liveness_timeout: Duration,
+    ) -> error::Result<()> {
+        match self {
+            PendingLiveness::TempSocket(listener, path, token) => {
+                ephemeral_liveness_socket_check_with_timeout::<U>(
+                    service_socket,
+                    listener,
+                    path,
+                    token.as_deref(),
+                    child_pid,
                     liveness_timeout,
                 )
-                .await?;
-
-                self.after_post_liveness_subprocess(child_proc).await?;
-                info!("Successfully received ephemeral liveness ping - trying to connect to service again.");
-                self.connect_to_running_service(base_context_directory)
+                .await
+            }
+            PendingLiveness::InheritedFd(ours, theirs) => {
+                // The service process has its own copy by now.
+                drop(theirs);
+                inherited_fd_liveness_check_with_timeout(service_socket, ours, liveness_timeout)
                     .await
             }
+            PendingLiveness::InheritedListener(socket_path, listener) => {
+                // The service process owns the socket now - it is connectable straight away.
+                drop(listener);
+                socket_path.keep();
+                Ok(())
+            }
         }
     }
 }
 
-impl<U: UnixSocketInterface, S: Service<U>> ServiceExt<U> for S {}
-
-/// Server implementation for a [`Service`]
-#[async_trait]
-pub trait Server<S: Service<U>, U: UnixSocketInterface>: Debug {
-    /// Type that wraps a unix socket listener.
-    type ListenerWrapper;
-    type FinalOutput;
+/// How often to check whether a started service process has exited, while waiting for it to
+/// become live.
+const CHILD_EXIT_POLL_INTERVAL: Duration = Duration::from_millis(25);
 
-    /// Wrap a listening socket into a more structured form - for instance an API wrapper or
-    /// something similar.
-    ///
-    /// This takes a raw [`UnixSocketInterface::UnixListener`]. Async frameworks should let you convert to and from
-    /// standard library unix sockets.
-    async fn wrap_listener_socket(
-        &self,
-        service: &S,
-        socket: U::UnixListener,
-    ) -> IoResult<Self::ListenerWrapper>;
+/// Complete once the child process exits unsuccessfully, producing its exit status.
+///
+/// Successful exits never complete this, as the process may have handed over to a daemonised
+/// process that will ping the liveness socket.
+async fn child_failure(child: &mut impl child::ServiceProcessHandle) -> ExitStatus {
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if !status.success() => return status,
+            Ok(Some(_)) => {
+                debug!("Child service process exited successfully, still waiting for liveness");
+                return future::pending().await;
+            }
+            Ok(None) => timefut::sleep(CHILD_EXIT_POLL_INTERVAL).await,
+            Err(e) => {
+                warn!("Couldn't check if child service process exited - {}", e);
+                return future::pending().await;
+            }
+        }
+    }
+}
 
-    /// Run the server. Note that you don't need to worry about cleaning up the socket path - that's
-    /// handled by the library.
-    async fn run_server(
-        &self,
-        service: &S,
-        wrapper: Self::ListenerWrapper,
-    ) -> IoResult<Self::FinalOutput>;
+/// Complete once a launched service fails before becoming live, producing the error - see
+/// [`child_failure`] for processes.
+async fn launch_failure(
+    launched: &mut launcher::Launched<impl child::ServiceProcessHandle>,
+    service_socket: &ServiceSocket,
+) -> Error {
+    match launched {
+        launcher::Launched::Process(child) => Error::SpawnExited {
+            socket: service_socket.clone(),
+            status: child_failure(child).await,
+        },
+        launcher::Launched::InProcess(in_process) => loop {
+            match in_process.take_outcome() {
+                Some(Err(e)) => return e,
+                Some(Ok(())) => {
+                    debug!("In-process service finished successfully, still waiting for liveness");
+                    return future::pending().await;
+                }
+                None => timefut::sleep(CHILD_EXIT_POLL_INTERVAL).await,
+            }
+        },
+    }
+}
+
+/// Read the status lines the service sends over the liveness connection - everything up to the
+/// given number of line breaks or until it is closed, up to
+/// [`liveness::MAX_LIVENESS_STATUS_LENGTH`] bytes.
+async fn read_liveness_status<U: UnixSocketInterface>(
+    stream: &mut U::UnixStream,
+    lines: usize,
+) -> IoResult<Vec<u8>> {
+    let mut status = Vec::new();
+    let mut buf = [0u8; 256];
+    while status.len() < liveness::MAX_LIVENESS_STATUS_LENGTH
+        && status.iter().filter(|b| **b == b'\n').count() < lines
+    {
+        match U::unix_stream_read(stream, &mut buf).await? {
+            0 => break,
+            n => status.extend_from_slice(&buf[..n]),
+        }
+    }
+    status.truncate(liveness::MAX_LIVENESS_STATUS_LENGTH);
+    Ok(status)
+}
+
+/// Connect to the socket of an already running service, without wrapping the resulting stream in
+/// the service's client connection type.
+///
+/// If the socket file exists but refuses connections, this produces [`Error::StaleSocket`] rather
+/// than [`Error::ConnectFailed`].
+#[instrument]
+async fn connect_to_running_service_raw<U: UnixSocketInterface, S: Service<U> + ?Sized>(
+    service: &S,
+    base_context_directory: &Path,
+) -> error::Result<U::UnixStream> {
+    let service_socket = ServiceSocket::new(&service.socket_name(), base_context_directory);
+    service.security_policy().check_socket(&service_socket)?;
+    info!("Attempting connection to service @ {}", service_socket);
+    let connected = match service.transport() {
+        Some(transport) => transport::connect::<U>(transport, &service_socket).await,
+        None => {
+            service
+                .socket_kind()
+                .connect::<U>(&service_socket.path)
+                .await
+        }
+    };
+    let unix_stream = connected.map_err(|e| {
+        error!("Failed to connect to service @ {}", service_socket);
+        if e.kind() == std::io::ErrorKind::ConnectionRefused && service_socket.path.exists() {
+            Error::StaleSocket {
+                socket: service_socket.clone(),
+                source: e,
+            }
+        } else {
+            Error::ConnectFailed {
+                socket: service_socket.clone(),
+                source: e,
+            }
+        }
+    })?;
+
+    info!("Successfully connected @ {}", service_socket);
+    Ok(unix_stream)
+}
+
+/// Connect to the socket of a service, starting it on-demand if it isn't already running. The
+/// resulting stream is not wrapped in the service's client connection type.
+///
+/// Only one start of a service runs at a time in this process - concurrent calls wait for it and
+/// then connect to the started service (see [`start_dedup`]). Between processes, the start is done
+/// while holding the lock file of the service socket, so other processes wait - for up to the
+/// liveness timeout - until the service is started, and then connect to it.
+#[instrument]
+async fn connect_to_service_raw<U: UnixSocketInterface, S: ServiceStartable<U> + ?Sized>(
+    service: &S,
+    executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
+    base_context_directory: &Path,
+    liveness_timeout: Duration,
+    ephemeral_dir: Option<&Path>,
+    task_spawner: Option<&dyn TaskSpawner>,
+    started_children: Option<&std::sync::Mutex<Vec<child::ChildGuard>>>,
+) -> error::Result<U::UnixStream> {
+    match connect_to_running_service_raw::<U, S>(service, base_context_directory).await {
+        Ok(s) => Ok(s),
+        Err(e) => {
+            warn!(
+                "Error connecting to existing service - {} - attempting on-demand service start",
+                e
+            );
+            let service_socket = e.socket().clone();
+            let _start_guard = match start_dedup::claim_start(&service_socket.path) {
+                StartClaim::Starter(guard) => guard,
+                StartClaim::Waiter(finished) => {
+                    info!(
+                        "Service @ {} is already being started in this process - waiting for that",
+                        service_socket
+                    );
+                    finished.await;
+                    return connect_to_running_service_raw::<U, S>(service, base_context_directory)
+                        .await;
+                }
+            };
+            let _start_lock = lock_service_socket(&service_socket, liveness_timeout).await?;
+            // Another start - in this process or another - may have finished between our
+            // connection attempt and taking the lock.
+            let e = match connect_to_running_service_raw::<U, S>(service, base_context_directory)
+                .await
+            {
+                Ok(s) => return Ok(s),
+                Err(e) => e,
+            };
+            if matches!(e, Error::StaleSocket { .. }) {
+                // A new server would fail to bind over the stale socket, so get rid of it first -
+                // unless another process already started the service in the meantime.
+                let maybe_live_stream = remove_stale_socket_locked::<U>(&service_socket)
+                    .await
+                    .map_err(|e| Error::StaleSocket {
+                        socket: service_socket.clone(),
+                        source: e,
+                    })?;
+                if let Some(live_stream) = maybe_live_stream {
+                    return Ok(live_stream);
+                }
+            }
+            events::emit(ServiceEvent::Starting {
+                socket: service_socket.clone(),
+            });
+            audit::record(Some(&service_socket), audit::AuditEvent::StartAttempted);
+            let started: error::Result<()> = async {
+                let service_launcher = service.launcher();
+                // In-process servers can't be handed anything but a path to ping.
+                let liveness_transport = match service_launcher {
+                    launcher::ServiceLauncher::Command => service.liveness_transport(),
+                    launcher::ServiceLauncher::InProcess(_) => {
+                        liveness::LivenessTransport::TempSocket
+                    }
+                };
+                let pending_liveness: PendingLiveness<U> = match liveness_transport {
+                    transport @ (liveness::LivenessTransport::TempSocket
+                    | liveness::LivenessTransport::AuthenticatedTempSocket) => {
+                        let (ephemeral_listener, ephemeral_socket_path) =
+                            ephemeral_liveness_socket_create::<U>(
+                                &service_socket,
+                                &ephemeral_dir
+                                    .map(Path::to_owned)
+                                    .unwrap_or_else(liveness::default_ephemeral_dir),
+                            )
+                            .await?;
+                        let liveness_token = (transport
+                            == liveness::LivenessTransport::AuthenticatedTempSocket)
+                            .then(liveness::generate_liveness_token);
+                        PendingLiveness::TempSocket(
+                            ephemeral_listener,
+                            ephemeral_socket_path,
+                            liveness_token,
+                        )
+                    }
+                    liveness::LivenessTransport::InheritedFd => {
+                        let (ours, theirs) = liveness::inheritable_pair().map_err(|e| {
+                            Error::LivenessSocketFailed {
+                                socket: service_socket.clone(),
+                                source: e,
+                            }
+                        })?;
+                        PendingLiveness::InheritedFd(ours, theirs)
+                    }
+                    liveness::LivenessTransport::InheritedListener => {
+                        info!("Binding socket @ {} for the service", service_socket);
+                        let listener = liveness::inheritable_listener(
+                            &service_socket.path,
+                            service.socket_kind(),
+                        )
+                        .map_err(|e| Error::BindFailed {
+                            socket: service_socket.clone(),
+                            source: e,
+                        })?;
+                        PendingLiveness::InheritedListener(
+                            service_socket.path.clone().into(),
+                            listener,
+                        )
+                    }
+                };
+
+                // We have somewhere to receive liveness, so begin running the service
+                let mut launched = launch_service::<U, S>(
+                    service,
+                    &service_launcher,
+                    executor_commandline_prefix,
+                    base_context_directory,
+                    &service_socket,
+                    &pending_liveness.liveness_path(),
+                    pending_liveness.liveness_token(),
+                )?;
+
+                let liveness_check = pending_liveness.check_with_timeout(
+                    &service_socket,
+                    launched.pid().filter(|_| service.verify_liveness_peer()),
+                    liveness_timeout,
+                );
+                // Don't wait out the whole timeout if the service crashes straight away.
+                let liveness_or_exit = map_fut(liveness_check, Ok)
+                    .or(map_fut(launch_failure(&mut launched, &service_socket), Err))
+                    .await;
+                match liveness_or_exit {
+                    Ok(liveness_result) => {
+                        liveness_result?;
+                        events::emit(ServiceEvent::LivenessReceived {
+                            socket: service_socket.clone(),
+                        });
+                    }
+                    Err(e) => {
+                        error!("Service exited before becoming live - {}", e);
+                        return Err(e);
+                    }
+                }
+
+                let child_proc = match launched {
+                    launcher::Launched::Process(child_proc) => child_proc,
+                    // Nothing to look after - the server keeps running on its own.
+                    launcher::Launched::InProcess(_) => return Ok(()),
+                };
+                match (task_spawner, service.child_strategy()) {
+                    (Some(task_spawner), _) => task::reap_child(task_spawner, child_proc),
+                    (None, child::ChildStrategy::Custom) => service
+                        .after_post_liveness_subprocess(child_proc)
+                        .await
+                        .map_err(|e| Error::PostLivenessFailed {
+                            socket: service_socket.clone(),
+                            source: e,
+                        })?,
+                    (None, strategy) => {
+                        child::apply_strategy(&strategy, child_proc, started_children)
+                    }
+                }
+                Ok(())
+            }
+            .await;
+            if let Err(e) = &started {
+                events::emit(ServiceEvent::StartFailed {
+                    socket: service_socket.clone(),
+                    reason: e.to_string(),
+                });
+            }
+            started?;
+            info!("Successfully received ephemeral liveness ping - trying to connect to service again.");
+            connect_to_running_service_raw::<U, S>(service, base_context_directory).await
+        }
+    }
+}
+
+/// Start a service with the given launcher, handing it the liveness path and token.
+#[allow(clippy::too_many_arguments)]
+fn launch_service<U: UnixSocketInterface, S: ServiceStartable<U> + ?Sized>(
+    service: &S,
+    launcher: &launcher::ServiceLauncher,
+    executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
+    base_context_directory: &Path,
+    service_socket: &ServiceSocket,
+    liveness_path: &Path,
+    liveness_token: Option<&str>,
+) -> error::Result<launcher::Launched<S::ProcessHandle>> {
+    let spawn_failed = |e| {
+        error!("Could not start service - {}", e);
+        Error::SpawnFailed {
+            socket: service_socket.clone(),
+            source: e,
+        }
+    };
+    match launcher {
+        launcher::ServiceLauncher::Command => {
+            let mut child_proc = service
+                .spawn_options()
+                .prepare(service_socket)
+                .and_then(|prepared_spawn| {
+                    service.run_service_command_raw(
+                        executor_commandline_prefix,
+                        Some(liveness_path),
+                        liveness_token,
+                        prepared_spawn,
+                    )
+                })
+                .map_err(spawn_failed)?;
+            spawn::forward_output(&mut child_proc, service_socket);
+            Ok(launcher::Launched::Process(child_proc))
+        }
+        launcher::ServiceLauncher::InProcess(launch) => {
+            info!("Starting service @ {} in this process", service_socket);
+            launch(launcher::InProcessLaunch {
+                base_context_directory: base_context_directory.to_owned(),
+                service_socket: service_socket.clone(),
+                liveness_path: liveness_path.to_owned(),
+            })
+            .map(launcher::Launched::InProcess)
+            .map_err(spawn_failed)
+        }
+    }
+}
+
+/// Wrap a bare stream connected to a service in the service's client connection type.
+async fn wrap_service_connection<U: UnixSocketInterface, S: Service<U> + ?Sized>(
+    service: &S,
+    base_context_directory: &Path,
+    mut unix_stream: U::UnixStream,
+) -> error::Result<S::ServiceClientConnection> {
+    let socket = ServiceSocket::new(&service.socket_name(), base_context_directory);
+    if let Some(expected_uid) = service.trusted_owner_uid() {
+        let peer = U::unix_stream_peer_credentials(&mut unix_stream).await;
+        credentials::verify_owner(&socket, expected_uid, peer)?;
+    }
+    verify_service_handshake::<U, S>(service, base_context_directory, &mut unix_stream).await?;
+    if let Some(authorizer) = service.authorizer() {
+        authorization::authorize_outgoing::<U>(&socket, &*authorizer, &mut unix_stream).await?;
+    }
+    let connection = service
+        .wrap_connection(unix_stream)
+        .await
+        .map_err(|e| Error::WrapFailed {
+            socket: socket.clone(),
+            source: e,
+        })?;
+    events::emit(ServiceEvent::Connected { socket });
+    Ok(connection)
+}
+
+/// Wrap a stream accepted by a server of the service, after performing the handshake and
+/// authorizing the connection if the service has them.
+async fn wrap_incoming_service_connection<U: UnixSocketInterface, S: Service<U> + ?Sized>(
+    service: &S,
+    base_context_directory: &Path,
+    mut unix_stream: U::UnixStream,
+) -> error::Result<S::ServiceServerConnection> {
+    let socket = ServiceSocket::new(&service.socket_name(), base_context_directory);
+    verify_service_handshake::<U, S>(service, base_context_directory, &mut unix_stream).await?;
+    if let Some(authorizer) = service.authorizer() {
+        authorization::authorize_incoming::<U>(&socket, &*authorizer, &mut unix_stream).await?;
+    }
+    service
+        .wrap_incoming(unix_stream)
+        .await
+        .map_err(|e| Error::WrapFailed { socket, source: e })
+}
+
+/// Perform the [`handshake`] on a fresh connection if the service has one, checking that the
+/// other side is the same service.
+async fn verify_service_handshake<U: UnixSocketInterface, S: Service<U> + ?Sized>(
+    service: &S,
+    base_context_directory: &Path,
+    unix_stream: &mut U::UnixStream,
+) -> error::Result<()> {
+    match service.handshake_protocol_versions() {
+        Some(protocol_versions) => {
+            let socket = ServiceSocket::new(&service.socket_name(), base_context_directory);
+            verify_handshake::<U>(&socket, protocol_versions, unix_stream).await
+        }
+        None => Ok(()),
+    }
+}
+
+/// Exchange identities with the other side of a connection to the given service socket, checking
+/// that it is the same service and has a protocol version in common with us.
+async fn verify_handshake<U: UnixSocketInterface>(
+    socket: &ServiceSocket,
+    protocol_versions: std::ops::RangeInclusive<u32>,
+    unix_stream: &mut U::UnixStream,
+) -> error::Result<()> {
+    let socket = socket.clone();
+    let expected = handshake::ServiceIdentity::new(&socket.name, protocol_versions);
+    let found = handshake::exchange_identities::<U>(unix_stream, &expected)
+        .await
+        .map_err(|e| Error::HandshakeFailed {
+            socket: socket.clone(),
+            source: e,
+        })?;
+    if found.socket_name != expected.socket_name {
+        error!(
+            "Expected service {} @ {}, but found {}",
+            expected, socket, found
+        );
+        return Err(Error::WrongService {
+            socket,
+            expected: Box::new(expected),
+            found: Box::new(found),
+        });
+    }
+    match handshake::negotiate_version(&expected.protocol_versions, &found.protocol_versions) {
+        Some(version) => {
+            debug!(
+                "Handshake with {} @ {} succeeded, using protocol version {}",
+                found, socket, version
+            );
+            Ok(())
+        }
+        None => {
+            error!(
+                "No common protocol version with {} @ {} - we support {}",
+                found, socket, expected
+            );
+            Err(Error::VersionMismatch {
+                socket,
+                ours: expected.protocol_versions,
+                theirs: found.protocol_versions,
+            })
+        }
+    }
+}
+
+/// Extension trait providing the means to connect to (and start) any [`Service`].
+///
+/// All connection and liveness machinery is generic over the [`UnixSocketInterface`] used by the
+/// service, which defaults to [`DefaultUnixSocks`].
+#[cfg_attr(feature = "async-trait-compat", async_trait(?Send))]
+#[allow(async_fn_in_trait)]
+pub trait ServiceExt<UnixSockets: UnixSocketInterface = DefaultUnixSocks>:
+    Service<UnixSockets>
+{
+    /// Reify this [`Service`] into a [`ReifiedService`] that carries around necessary context for
+    /// connecting to it.
+    fn reify(self, base_context_directory: &Path) -> ReifiedService<'_, Self, UnixSockets>
+    where
+        Self: Sized,
+    {
+        ReifiedService::reify_service(self, base_context_directory)
+    }
+
+    /// Reify this [`Service`] into a [`ReifiedService`] that carries around necessary context for
+    /// connecting to it, including an executor prefix command.
+    fn reify_with_executor<'i, EPC: AsRef<OsStr> + Sized + Debug>(
+        self,
+        base_context_directory: &'i Path,
+        executor_prefix: &'i [EPC],
+    ) -> ReifiedService<'i, Self, UnixSockets, EPC>
+    where
+        Self: Sized,
+    {
+        ReifiedService::reify_service_with_executor(self, base_context_directory, executor_prefix)
+    }
+
+    /// Attempt to connect to an already running service. This will not try to start the service on
+    /// failure - for that, see [`Self::connect_to_service`], which requires the service implements
+    /// [`ServiceStartable`]
+    ///
+    /// See [`Service`] for information on base context directories.
+    #[instrument]
+    async fn connect_to_running_service(
+        &self,
+        base_context_directory: &Path,
+    ) -> error::Result<Self::ServiceClientConnection> {
+        let unix_stream =
+            connect_to_running_service_raw::<UnixSockets, _>(self, base_context_directory).await?;
+        wrap_service_connection::<UnixSockets, _>(self, base_context_directory, unix_stream).await
+    }
+
+    /// Attempt to connect to the given service in the given runtime context directory. This
+    /// requires that the service is startable on failure. If you just want to connect to an
+    /// already running service, see [`Service`].
+    ///
+    /// See [`Service`] for information on executor commandline prefixes and the base context
+    /// directory.
+    ///
+    /// If the service is not already running, then `liveness_timeout` is the maximum time before a
+    /// non-response to the liveness check will result in an error.
+    #[instrument]
+    async fn connect_to_service(
+        &self,
+        executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
+        base_context_directory: &Path,
+        liveness_timeout: Duration,
+    ) -> error::Result<Self::ServiceClientConnection>
+    where
+        Self: ServiceStartable<UnixSockets>,
+    {
+        let unix_stream = connect_to_service_raw::<UnixSockets, _>(
+            self,
+            executor_commandline_prefix,
+            base_context_directory,
+            liveness_timeout,
+            None,
+            None,
+            None,
+        )
+        .await?;
+        wrap_service_connection::<UnixSockets, _>(self, base_context_directory, unix_stream).await
+    }
+
+    /// Blocking version of [`Self::connect_to_running_service`], for programs without an async
+    /// runtime.
+    ///
+    /// As wrapping connections is asynchronous, this produces the bare std stream rather than a
+    /// [`Service::ServiceClientConnection`] - though the [`handshake`] is still performed.
+    #[instrument]
+    fn connect_to_running_service_blocking(
+        &self,
+        base_context_directory: &Path,
+    ) -> error::Result<std::os::unix::net::UnixStream> {
+        blocking_client::connect_to_running_service_blocking::<UnixSockets, _>(
+            self,
+            base_context_directory,
+        )
+    }
+
+    /// Blocking version of [`Self::connect_to_service`], for programs without an async runtime.
+    ///
+    /// Like [`Self::connect_to_running_service_blocking`], this produces the bare std stream. The
+    /// service is started in the same way, but note that
+    /// [`ServiceStartable::after_post_liveness_subprocess`] gets run to completion on the current
+    /// thread - so it can't rely on being inside an async runtime.
+    #[instrument]
+    fn connect_to_service_blocking(
+        &self,
+        executor_commandline_prefix: Option<&[impl AsRef<OsStr> + Sized + Debug]>,
+        base_context_directory: &Path,
+        liveness_timeout: Duration,
+    ) -> error::Result<std::os::unix::net::UnixStream>
+    where
+        Self: ServiceStartable<UnixSockets>,
+    {
+        blocking_client::connect_to_service_blocking::<UnixSockets, _>(
+            self,
+            executor_commandline_prefix,
+            base_context_directory,
+            liveness_timeout,
+        )
+    }
+
+    /// In a server, turn a stream accepted on the service socket into a
+    /// [`Service::ServiceServerConnection`], performing the [`handshake`] first if the service has
+    /// one.
+    #[instrument(skip(unix_stream))]
+    async fn wrap_incoming_connection(
+        &self,
+        base_context_directory: &Path,
+        unix_stream: UnixSockets::UnixStream,
+    ) -> error::Result<Self::ServiceServerConnection> {
+        wrap_incoming_service_connection::<UnixSockets, _>(
+            self,
+            base_context_directory,
+            unix_stream,
+        )
+        .await
+    }
+}
+
+impl<U: UnixSocketInterface, S: Service<U>> ServiceExt<U> for S {}
+
+/// Server implementation for a [`Service`]
+///
+/// Unlike the other traits here, this always uses [`macro@async_trait`], as server futures are
+/// required to be `Send`.
+#[async_trait]
+pub trait Server<S: Service<U>, U: UnixSocketInterface = DefaultUnixSocks>: Debug {
+    /// Type that wraps a unix socket listener.
+    type ListenerWrapper;
+    type FinalOutput;
+
+    /// Wrap a listening socket into a more structured form - for instance an API wrapper or
+    /// something similar.
+    ///
+    /// This takes a raw [`UnixSocketInterface::UnixListener`]. Async frameworks should let you convert to and from
+    /// standard library unix sockets.
+    async fn wrap_listener_socket(
+        &self,
+        service: &S,
+        socket: U::UnixListener,
+    ) -> IoResult<Self::ListenerWrapper>;
+
+    /// File mode and group to give the service socket, and whether to create its base context
+    /// directory privately - see [`SocketPermissions`]. These are in place before the liveness
+    /// socket is pinged or any client can connect. The default leaves the socket to the umask.
+    fn socket_permissions(&self) -> SocketPermissions {
+        SocketPermissions::default()
+    }
+
+    /// File descriptor to write a newline to - and then close - once the service socket is bound,
+    /// in the style of s6 readiness notification. This lets the server run under an s6
+    /// supervision tree, with the same number as in the `notification-fd` file of its service
+    /// directory, as well as being started on demand by clients. The default of `None` notifies
+    /// nobody.
+    ///
+    /// The file descriptor is taken over by the server, so it must not be used elsewhere.
+    fn readiness_notification_fd(&self) -> Option<std::os::fd::RawFd> {
+        None
+    }
+
+    /// Heartbeat for watchdogs - while the server runs, the modification time of its state file is
+    /// bumped at this interval from within [`ServerExt`], so clients and supervisors can tell it
+    /// is hung if that stops, even though its socket still accepts connections. See [`status`].
+    /// The default of `None` has no heartbeat.
+    fn heartbeat(&self) -> Option<status::Heartbeat> {
+        None
+    }
+
+    /// Run the server. Note that you don't need to worry about cleaning up the socket path - that's
+    /// handled by the library.
+    ///
+    /// For a ready-made accept loop that hands each connection to a handler, see
+    /// [`serve::serve_connections`].
+    async fn run_server(
+        &self,
+        service: &S,
+        wrapper: Self::ListenerWrapper,
+    ) -> IoResult<Self::FinalOutput>;
 }
 
 /// Internal function to notify a liveness socket by connecting and then immediately disconnecting
@@ -407,6 +1653,10 @@ pub trait Server<S: Service<U>, U: UnixSocketInterface>: Debug {
 async fn notify_liveness_socket<U: UnixSocketInterface>(
     liveness_socket_path: &Path,
 ) -> IoResult<()> {
+    if let Some(fd) = liveness::inherited_liveness_fd(liveness_socket_path) {
+        info!("Reporting liveness over inherited file descriptor {}", fd);
+        return liveness::write_inherited_fd(fd, b"\n".to_vec()).await;
+    }
     let mut sock = U::unix_stream_connect(liveness_socket_path).await
         .map_err(|e| {
             warn!("Couldn't connect to parent process's ephemeral liveness socket @ {} - error was: {}", liveness_socket_path.display(), e);
@@ -417,12 +1667,140 @@ async fn notify_liveness_socket<U: UnixSocketInterface>(
                 liveness_socket_path.display()
             );
         });
+    liveness::write_liveness_token::<U>(&mut sock).await?;
     U::unix_stream_shutdown(&mut sock).await
 }
 
+/// Write the s6-style readiness notification - a newline - to the given file descriptor, and close
+/// it. See [`Server::readiness_notification_fd`].
+#[instrument]
+async fn notify_readiness_fd(fd: std::os::fd::RawFd) -> IoResult<()> {
+    use std::{io::Write, os::fd::FromRawFd};
+    info!("Notifying readiness on file descriptor {}", fd);
+    // SAFETY: the server hands the file descriptor over for this, and it is only used once.
+    let mut notification = unsafe { std::fs::File::from_raw_fd(fd) };
+    blocking::unblock(move || notification.write_all(b"\n")).await
+}
+
+/// Remove the socket file of a service if it is stale - i.e. it exists, but nothing answers on it
+/// because it was left behind by a server that crashed.
+///
+/// This is done while holding the lock file of the socket, and the socket is probed under the lock,
+/// so concurrent processes can't remove each other's live sockets. If a live server answers the
+/// probe, the probe connection is returned.
+#[instrument]
+async fn remove_stale_socket<U: UnixSocketInterface>(
+    service_socket: &ServiceSocket,
+) -> IoResult<Option<U::UnixStream>> {
+    let lock = LockFile::acquire(&service_socket.lock_path()).await?;
+    debug!("Acquired lock file @ {}", lock.path().display());
+    remove_stale_socket_locked::<U>(service_socket).await
+}
+
+/// [`remove_stale_socket`], when the lock file of the socket is already held.
+async fn remove_stale_socket_locked<U: UnixSocketInterface>(
+    service_socket: &ServiceSocket,
+) -> IoResult<Option<U::UnixStream>> {
+    match seqpacket::probe_connect::<U>(&service_socket.path).await {
+        Ok(probe) => {
+            info!("Socket @ {} is in use by a live server", service_socket);
+            Ok(Some(probe))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            warn!("Removing stale socket @ {}", service_socket);
+            match std::fs::remove_file(&service_socket.path) {
+                Ok(()) => {
+                    audit::record(Some(service_socket), audit::AuditEvent::StaleSocketRemoved);
+                    Ok(None)
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+        // The socket vanished in the meantime, so there's nothing to remove.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Take the lock file of a service socket, to start the service while holding it. If another
+/// process is holding it, this waits for up to `timeout` - the liveness timeout of the start.
+#[instrument]
+async fn lock_service_socket(
+    service_socket: &ServiceSocket,
+    timeout: Duration,
+) -> error::Result<LockFile> {
+    let lock = with_timeout(LockFile::acquire(&service_socket.lock_path()), timeout)
+        .await
+        .ok_or_else(|| {
+            error!(
+                "Timed out waiting for another process to start service @ {}",
+                service_socket
+            );
+            Error::LivenessTimeout {
+                socket: service_socket.clone(),
+                timeout,
+            }
+        })?
+        .map_err(|e| Error::LockFailed {
+            socket: service_socket.clone(),
+            source: e,
+        })?;
+    debug!("Acquired lock file @ {}", lock.path().display());
+    Ok(lock)
+}
+
+/// Bind the listener socket of a service with the given permissions, once the path has passed the
+/// security policy. If the socket file already exists but is stale, it is removed (see
+/// [`remove_stale_socket`]) and binding is attempted again.
+#[instrument]
+async fn bind_service_socket<U: UnixSocketInterface>(
+    service_socket: &ServiceSocket,
+    permissions: &SocketPermissions,
+    security: &SecurityPolicy,
+    kind: socket_shims::SocketKind,
+) -> error::Result<U::UnixListener> {
+    let bind_failed = |e| Error::BindFailed {
+        socket: service_socket.clone(),
+        source: e,
+    };
+    security.check_socket(service_socket)?;
+    permissions
+        .prepare_context_dir(&service_socket.path)
+        .map_err(bind_failed)?;
+    match permissions.bind::<U>(&service_socket.path, kind).await {
+        Ok(listener) => return Ok(listener),
+        Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            debug!(
+                "Socket @ {} already exists - checking if it is stale",
+                service_socket
+            );
+        }
+        Err(e) => return Err(bind_failed(e)),
+    };
+
+    // Whatever is in the way may have been swapped out since the first check.
+    security.check_socket(service_socket)?;
+    if let Some(mut probe) = remove_stale_socket::<U>(service_socket)
+        .await
+        .map_err(bind_failed)?
+    {
+        error!("Socket @ {} is in use by a live server", service_socket);
+        let _ = U::unix_stream_shutdown(&mut probe).await;
+        return Err(bind_failed(std::io::ErrorKind::AddrInUse.into()));
+    }
+    permissions
+        .bind::<U>(&service_socket.path, kind)
+        .await
+        .map_err(bind_failed)
+}
+
 /// Extension trait that lets you run servers well
-#[async_trait(?Send)]
-pub trait ServerExt<S: Service<U>, U: UnixSocketInterface>: Server<S, U> {
+#[cfg_attr(feature = "async-trait-compat", async_trait(?Send))]
+#[allow(async_fn_in_trait)]
+pub trait ServerExt<S: Service<U>, U: UnixSocketInterface = DefaultUnixSocks>:
+    Server<S, U>
+{
     /// Create the listener socket, notify the liveness socket, and when finally returning, clean the listener socket up after ourselves.
     ///
     /// ## Methods used to create sockets
@@ -436,46 +1814,677 @@ pub trait ServerExt<S: Service<U>, U: UnixSocketInterface>: Server<S, U> {
     /// conventional means of service definition via [`liveness::retrieve_liveness_path`].
     ///
     /// The only thing necessary to indicate liveness is simply connecting to the socket (and then
-    /// you can shut down the socket connection).
+    /// you can shut down the socket connection) - plus sending back the liveness token if the
+    /// service was given one, which this does automatically (see [`liveness::liveness_token`]).
     ///
     /// In this implementation, the liveness socket is ping'd after the creation of a receiving
     /// socket at the standard path for the service. This is a protocol requirement - if you ping
-    /// the liveness socket with a connection, it means that a socket exists to connect to.
+    /// the liveness socket with a connection, it means that a socket exists to connect to. If the
+    /// receiving socket can't be created, this is reported over the liveness socket instead - see
+    /// [`liveness::report_liveness_failure`].
     #[instrument]
     async fn start_and_run_server(
         &self,
         service: &S,
         context_base_path: &Path,
         liveness_socket_path: Option<&Path>,
-    ) -> IoResult<Self::FinalOutput> {
-        let socket_path: CleanablePathBuf = context_base_path.join(service.socket_name()).into();
-        info!("Obtaining socket @ {}", socket_path.as_ref().display());
-        let raw_listener_socket = U::unix_listener_bind(socket_path.as_ref()).await?;
-        info!(
-            "Successfully listening @ {}",
-            socket_path.as_ref().display()
-        );
-        let _ = match liveness_socket_path {
-            Some(p) => notify_liveness_socket::<U>(p).await,
-            None => {
-                info!("No liveness socket path provided, assuming autonomous.");
-                Ok(())
-            }
-        };
-
-        debug!("Wrapping raw socket in API");
-        let api = self
-            .wrap_listener_socket(service, raw_listener_socket)
-            .await?;
-        info!("Starting service @ {}", socket_path.as_ref().display());
-        let res = self.run_server(service, api).await?;
-        info!("Cleaning up socket @ {}", socket_path.as_ref().display());
-        drop(socket_path);
-        Ok(res)
+    ) -> error::Result<Self::FinalOutput> {
+        let service_socket = ServiceSocket::new(&service.socket_name(), context_base_path);
+        bind_server_listener(
+            self,
+            service,
+            service_socket,
+            liveness_socket_path,
+            BindAt::Service,
+        )
+        .await?
+        .publish()
+        .await?
+        .run()
+        .await
     }
-}
 
-impl<U: UnixSocketInterface, S: Service<U>, T: Server<S, U>> ServerExt<S, U> for T {}
+    /// Like [`ServerExt::start_and_run_server`], but also stop the server when `shutdown`
+    /// completes.
+    ///
+    /// When that happens, the socket file is removed straight away so no new clients can find the
+    /// service, and [`Server::run_server`] is given up to `drain_timeout` to finish any in-flight
+    /// work. If it finishes in time its output is returned, otherwise it is dropped and this
+    /// returns `Ok(None)`.
+    ///
+    /// If your server uses [`serve::serve_connections`], pass it a future that completes on the
+    /// same shutdown signal so it stops accepting connections and drains the active ones.
+    #[instrument(skip(shutdown))]
+    async fn start_and_run_server_with_shutdown(
+        &self,
+        service: &S,
+        context_base_path: &Path,
+        liveness_socket_path: Option<&Path>,
+        shutdown: impl Future<Output = ()>,
+        drain_timeout: Duration,
+    ) -> error::Result<Option<Self::FinalOutput>> {
+        let service_socket = ServiceSocket::new(&service.socket_name(), context_base_path);
+        bind_server_listener(
+            self,
+            service,
+            service_socket,
+            liveness_socket_path,
+            BindAt::Service,
+        )
+        .await?
+        .publish()
+        .await?
+        .run_with_shutdown(shutdown, drain_timeout)
+        .await
+    }
+
+    /// Like [`ServerExt::start_and_run_server_with_shutdown`], but shut down when a
+    /// [`control::ControlCommand::Stop`] is received over the [`control`] channel of the service,
+    /// which is opened once the service socket is bound. `on_reload` is called for every
+    /// [`control::ControlCommand::Reload`].
+    ///
+    /// The listener is also handed over to a new instance of the service that asks for it - see
+    /// [`ServerExt::take_over`] - after which this server shuts down the same way. The control
+    /// socket gets the [`Server::socket_permissions`] of the server, and only takes commands from
+    /// processes of the user it runs as.
+    ///
+    /// To also shut down on other events - like termination signals - use a
+    /// [`control::ControlChannel`] directly in the shutdown future instead.
+    #[instrument(skip(on_reload))]
+    async fn start_and_run_server_with_control(
+        &self,
+        service: &S,
+        context_base_path: &Path,
+        liveness_socket_path: Option<&Path>,
+        drain_timeout: Duration,
+        on_reload: impl FnMut() -> IoResult<()>,
+    ) -> error::Result<Option<Self::FinalOutput>> {
+        let service_socket = ServiceSocket::new(&service.socket_name(), context_base_path);
+        bind_server_listener(
+            self,
+            service,
+            service_socket,
+            liveness_socket_path,
+            BindAt::Service,
+        )
+        .await?
+        .publish()
+        .await?
+        .run_with_control(drain_timeout, on_reload)
+        .await
+    }
+
+    /// Take over the listener of the running instance of the service, for a zero-downtime
+    /// upgrade - see [Handoff](control#handoff). The running instance must be run with
+    /// [`ServerExt::start_and_run_server_with_control`] or [`PublishedServer::run_with_control`].
+    ///
+    /// The listener stays at the service socket the whole time, so clients connecting meanwhile
+    /// queue up on it until this server runs. The old instance shuts down once the listener is
+    /// sent, draining its in-flight work - servers that keep accepting while they drain share
+    /// the listener with this one until they finish.
+    ///
+    /// This fails with [`Error::ControlFailed`] if the running instance couldn't be reached or has
+    /// no listener to hand over, in which case it keeps running.
+    #[instrument]
+    async fn take_over<'a>(
+        &'a self,
+        service: &'a S,
+        context_base_path: &Path,
+        timeout: Duration,
+    ) -> error::Result<HiddenServer<'a, S, U, Self>> {
+        let service_socket = ServiceSocket::new(&service.socket_name(), context_base_path);
+        info!("Requesting listener handoff @ {}", service_socket);
+        let fd = control::request_handoff(&service_socket, timeout)
+            .await
+            .map_err(|e| Error::ControlFailed {
+                socket: service_socket.clone(),
+                source: e,
+            })?;
+        let listener = U::unix_listener_from_std(std::os::unix::net::UnixListener::from(fd))
+            .await
+            .map_err(|e| Error::BindFailed {
+                socket: service_socket.clone(),
+                source: e,
+            })?;
+        info!("Took over listener @ {}", service_socket);
+        Ok(HiddenServer {
+            server: self,
+            service,
+            service_socket,
+            liveness_socket_path: None,
+            listener,
+            hidden_path: None,
+        })
+    }
+
+    /// Bind the listener socket next to the service socket - at [`ServiceSocket::hidden_path`] -
+    /// where clients won't find it, so the server can finish initializing before
+    /// [`HiddenServer::publish`] moves it into place. Only then is the liveness socket pinged, so
+    /// clients never connect to a half-initialized service - unlike with
+    /// [`ServerExt::start_and_run_server`], where the service counts as ready as soon as its
+    /// socket is bound.
+    ///
+    /// If a client bound the service socket for the server and handed over the listener - see
+    /// [`liveness::LivenessTransport::InheritedListener`] - it is in place already, and clients
+    /// are queued on it until the server runs.
+    #[instrument]
+    async fn bind_hidden<'a>(
+        &'a self,
+        service: &'a S,
+        context_base_path: &Path,
+        liveness_socket_path: Option<&'a Path>,
+    ) -> error::Result<HiddenServer<'a, S, U, Self>> {
+        let service_socket = ServiceSocket::new(&service.socket_name(), context_base_path);
+        bind_server_listener(
+            self,
+            service,
+            service_socket,
+            liveness_socket_path,
+            BindAt::Hidden,
+        )
+        .await
+    }
+
+    /// Bind the listener socket at the service socket, asking the server that owns it - if one is
+    /// running - to stop first with [`control::stop_service`], and waiting up to `timeout` for
+    /// its socket to vanish. For "restart with new config" flows, where the new server should
+    /// always win rather than fail with [`std::io::ErrorKind::AddrInUse`].
+    ///
+    /// The running server must have a [`control`] channel - see
+    /// [`ServerExt::start_and_run_server_with_control`] - otherwise this fails with
+    /// [`Error::ControlFailed`]. Unlike with [`ServerExt::take_over`], clients may find no socket
+    /// between the old server stopping and this one binding.
+    #[instrument]
+    async fn bind_taking_over<'a>(
+        &'a self,
+        service: &'a S,
+        context_base_path: &Path,
+        liveness_socket_path: Option<&'a Path>,
+        timeout: Duration,
+    ) -> error::Result<HiddenServer<'a, S, U, Self>> {
+        let service_socket = ServiceSocket::new(&service.socket_name(), context_base_path);
+        let bind_at = BindAt::TakingOver(timeout);
+        bind_server_listener(self, service, service_socket, liveness_socket_path, bind_at).await
+    }
+}
+
+/// Where [`bind_server_listener`] binds the listener socket.
+#[derive(Debug, Clone, Copy)]
+enum BindAt {
+    /// At the service socket, failing if a live server owns it.
+    Service,
+    /// At [`ServiceSocket::hidden_path`] - see [`ServerExt::bind_hidden`].
+    Hidden,
+    /// At the service socket, stopping the live server that owns it first - see
+    /// [`ServerExt::bind_taking_over`].
+    TakingOver(Duration),
+}
+
+/// Bind the listener socket of a service where asked to, or take over the one inherited from the
+/// client that started it, reporting failure over the liveness socket if there is one.
+async fn bind_server_listener<'a, S, U, Srv>(
+    server: &'a Srv,
+    service: &'a S,
+    service_socket: ServiceSocket,
+    liveness_socket_path: Option<&'a Path>,
+    bind_at: BindAt,
+) -> error::Result<HiddenServer<'a, S, U, Srv>>
+where
+    S: Service<U>,
+    U: UnixSocketInterface,
+    Srv: Server<S, U> + ?Sized,
+{
+    // A client that bound the socket for us hands over the listener in place of a liveness path.
+    // SAFETY: liveness paths naming file descriptors are handed to servers by the clients that
+    // start them, for descriptors they inherited - just like for writing the liveness status.
+    let inherited_listener =
+        liveness_socket_path.and_then(|path| unsafe { liveness::inherited_listener_fd(path) });
+    let liveness_socket_path = liveness_socket_path.filter(|_| inherited_listener.is_none());
+    let (listener, hidden_path) = if let Some(fd) = inherited_listener {
+        info!(
+            "Taking over socket @ {} from inherited file descriptor {}",
+            service_socket, fd
+        );
+        // SAFETY: the inherited listener is only ever taken over here, once per server.
+        let listener = unsafe { liveness::listener_from_inherited_fd::<U>(fd) }
+            .await
+            .map_err(|e| Error::BindFailed {
+                socket: service_socket.clone(),
+                source: e,
+            })?;
+        (listener, None)
+    } else {
+        let hidden = matches!(bind_at, BindAt::Hidden);
+        let bound_socket = match hidden {
+            true => {
+                let mut name = service_socket.name.clone();
+                name.push(".tmp");
+                ServiceSocket {
+                    name,
+                    path: service_socket.hidden_path(),
+                }
+            }
+            false => service_socket.clone(),
+        };
+        info!("Obtaining socket @ {}", bound_socket);
+        let permissions = server.socket_permissions();
+        let security = service.security_policy();
+        let bind = || {
+            bind_service_socket::<U>(
+                &bound_socket,
+                &permissions,
+                &security,
+                service.socket_kind(),
+            )
+        };
+        let bound = match (bind().await, bind_at) {
+            (Err(Error::BindFailed { source, .. }), BindAt::TakingOver(timeout))
+                if source.kind() == std::io::ErrorKind::AddrInUse =>
+            {
+                info!(
+                    "Socket @ {} is in use by a live server - asking it to stop",
+                    bound_socket
+                );
+                match control::stop_service(&bound_socket, timeout).await {
+                    Ok(()) => bind().await,
+                    Err(e) => Err(e),
+                }
+            }
+            (bound, _) => bound,
+        };
+        match bound {
+            // Only clean up the socket once it is actually ours.
+            Ok(listener) => (listener, hidden.then(|| bound_socket.path.into())),
+            Err(e) => {
+                // Let whoever started us know straight away, rather than having them time out.
+                if let Some(p) = liveness_socket_path {
+                    let _ = liveness::report_liveness_failure::<U>(p, &e.to_string()).await;
+                }
+                return Err(e);
+            }
+        }
+    };
+    Ok(HiddenServer {
+        server,
+        service,
+        service_socket,
+        liveness_socket_path,
+        listener,
+        hidden_path,
+    })
+}
+
+/// Server whose listener socket is bound, but not yet where clients look for it - see
+/// [`ServerExt::bind_hidden`]. Dropping it removes the hidden socket.
+pub struct HiddenServer<'a, S, U, Srv>
+where
+    S: Service<U>,
+    U: UnixSocketInterface,
+    Srv: Server<S, U> + ?Sized,
+{
+    server: &'a Srv,
+    service: &'a S,
+    service_socket: ServiceSocket,
+    liveness_socket_path: Option<&'a Path>,
+    listener: U::UnixListener,
+    /// Where the listener is bound until published - `None` if it is at the service socket
+    /// already.
+    hidden_path: Option<CleanablePathBuf>,
+}
+
+impl<'a, S, U, Srv> Debug for HiddenServer<'a, S, U, Srv>
+where
+    S: Service<U>,
+    U: UnixSocketInterface,
+    Srv: Server<S, U> + ?Sized,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HiddenServer")
+            .field("server", &self.server)
+            .field("service_socket", &self.service_socket)
+            .field("hidden_path", &self.hidden_path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, S, U, Srv> HiddenServer<'a, S, U, Srv>
+where
+    S: Service<U>,
+    U: UnixSocketInterface,
+    Srv: Server<S, U> + ?Sized,
+{
+    /// The socket of the service the server will be published at.
+    pub fn service_socket(&self) -> &ServiceSocket {
+        &self.service_socket
+    }
+
+    /// Move the listener socket into place at the service socket, so clients can connect to it.
+    /// This is atomic - clients either don't find the socket, or find it listening - and never
+    /// replaces a live server's socket, though a stale one is removed first.
+    ///
+    /// Then the service is exposed over its [`Service::transport`], the state file is written,
+    /// readiness is notified on [`Server::readiness_notification_fd`], the liveness socket is
+    /// pinged, and finally the listener is wrapped for [`PublishedServer::run`].
+    #[instrument]
+    pub async fn publish(mut self) -> error::Result<PublishedServer<'a, S, U, Srv>> {
+        let service_socket = &self.service_socket;
+        let liveness_socket_path = self.liveness_socket_path;
+        let report_failure = |e: Error| async move {
+            if let Some(p) = liveness_socket_path {
+                let _ = liveness::report_liveness_failure::<U>(p, &e.to_string()).await;
+            }
+            e
+        };
+        if let Some(hidden_path) = self.hidden_path.take() {
+            info!(
+                "Publishing socket @ {} from {}",
+                service_socket,
+                hidden_path.as_ref().display()
+            );
+            if let Err(e) = publish_hidden_socket::<U>(service_socket, hidden_path.as_ref()).await {
+                return Err(report_failure(e).await);
+            }
+            // The service socket is ours now, and the hidden path is cleaned up on drop.
+        }
+        // Only clean up the socket once it is actually ours.
+        let socket_path: CleanablePathBuf = service_socket.path.clone().into();
+        info!(
+            "Successfully listening @ {}",
+            socket_path.as_ref().display()
+        );
+        let exposure = match self.service.transport() {
+            Some(transport) => {
+                let exposed_socket = service_socket.clone();
+                match blocking::unblock(move || transport.expose(&exposed_socket)).await {
+                    Ok(exposure) => exposure,
+                    Err(e) => {
+                        return Err(report_failure(Error::BindFailed {
+                            socket: service_socket.clone(),
+                            source: e,
+                        })
+                        .await)
+                    }
+                }
+            }
+            None => transport::Exposure::none(),
+        };
+        let state = status::ServiceState::for_this_process(
+            self.service.service_version(),
+            self.service.handshake_protocol_versions(),
+        )
+        .with_heartbeat(self.server.heartbeat());
+        let state_file = status::write_state_file(service_socket, &state)
+            .map_err(|e| warn!("Couldn't write state file for {} - {}", service_socket, e))
+            .ok();
+        spawn::watch_parent();
+        if let Some(fd) = self.server.readiness_notification_fd() {
+            let _ = notify_readiness_fd(fd).await.map_err(|e| {
+                warn!(
+                    "Couldn't notify readiness on file descriptor {} - {}",
+                    fd, e
+                )
+            });
+        }
+        let _ = match liveness_socket_path {
+            Some(p) => notify_liveness_socket::<U>(p).await,
+            None => {
+                info!("No liveness socket path provided, assuming autonomous.");
+                Ok(())
+            }
+        };
+
+        // Kept so the listener can be handed off to a new instance - see
+        // `PublishedServer::run_with_control`.
+        let handoff_listener = U::unix_listener_duplicate_fd(&mut self.listener)
+            .await
+            .map_err(|e| debug!("Can't hand off listener @ {} - {}", service_socket, e))
+            .ok();
+        debug!("Wrapping raw socket in API");
+        let api = self
+            .server
+            .wrap_listener_socket(self.service, self.listener)
+            .await
+            .map_err(|e| Error::ListenerWrapFailed {
+                socket: self.service_socket.clone(),
+                source: e,
+            })?;
+        let pause = serve::PauseHandle::new(self.service_socket.clone());
+        Ok(PublishedServer {
+            server: self.server,
+            service: self.service,
+            service_socket: self.service_socket,
+            api,
+            socket_path,
+            state_file,
+            exposure,
+            pause,
+            handoff_listener,
+        })
+    }
+}
+
+/// Link the socket bound at the hidden path into place at the service socket - which fails rather
+/// than replacing whatever is there, so a stale socket is removed first - and remove the hidden
+/// path.
+async fn publish_hidden_socket<U: UnixSocketInterface>(
+    service_socket: &ServiceSocket,
+    hidden_path: &Path,
+) -> error::Result<()> {
+    let bind_failed = |e| Error::BindFailed {
+        socket: service_socket.clone(),
+        source: e,
+    };
+    match std::fs::hard_link(hidden_path, &service_socket.path) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            debug!(
+                "Socket @ {} already exists - checking if it is stale",
+                service_socket
+            );
+        }
+        Err(e) => return Err(bind_failed(e)),
+    }
+    if let Some(mut probe) = remove_stale_socket::<U>(service_socket)
+        .await
+        .map_err(bind_failed)?
+    {
+        error!("Socket @ {} is in use by a live server", service_socket);
+        let _ = U::unix_stream_shutdown(&mut probe).await;
+        return Err(bind_failed(std::io::ErrorKind::AddrInUse.into()));
+    }
+    std::fs::hard_link(hidden_path, &service_socket.path).map_err(bind_failed)
+}
+
+/// Server whose listener socket is in place for clients to connect to, ready to run - see
+/// [`HiddenServer::publish`]. Dropping it removes the service socket.
+pub struct PublishedServer<'a, S, U, Srv>
+where
+    S: Service<U>,
+    U: UnixSocketInterface,
+    Srv: Server<S, U> + ?Sized,
+{
+    server: &'a Srv,
+    service: &'a S,
+    service_socket: ServiceSocket,
+    api: Srv::ListenerWrapper,
+    socket_path: CleanablePathBuf,
+    state_file: Option<CleanablePathBuf>,
+    exposure: transport::Exposure,
+    pause: serve::PauseHandle,
+    /// Duplicate of the listener, if the socket interface could make one.
+    handoff_listener: Option<std::os::fd::OwnedFd>,
+}
+
+impl<'a, S, U, Srv> Debug for PublishedServer<'a, S, U, Srv>
+where
+    S: Service<U>,
+    U: UnixSocketInterface,
+    Srv: Server<S, U> + ?Sized,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PublishedServer")
+            .field("server", &self.server)
+            .field("service_socket", &self.service_socket)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a, S, U, Srv> PublishedServer<'a, S, U, Srv>
+where
+    S: Service<U>,
+    U: UnixSocketInterface,
+    Srv: Server<S, U> + ?Sized,
+{
+    /// Handle to pause and resume accepting new clients while the server runs - see
+    /// [`serve::PauseHandle`].
+    pub fn pause_handle(&self) -> serve::PauseHandle {
+        self.pause.clone()
+    }
+
+    /// Run the server, cleaning up the service socket once it finishes - like
+    /// [`ServerExt::start_and_run_server`].
+    pub async fn run(self) -> error::Result<Srv::FinalOutput> {
+        info!("Starting service @ {}", self.socket_path.as_ref().display());
+        let res = status::with_heartbeat(
+            self.server.run_server(self.service, self.api),
+            self.state_file
+                .as_ref()
+                .map(|path| path.as_ref().to_owned()),
+            self.server.heartbeat(),
+        )
+        .await;
+        info!(
+            "Cleaning up socket @ {}",
+            self.socket_path.as_ref().display()
+        );
+        drop(self.exposure);
+        // Leave the socket path and state file alone if another server took them over while
+        // paused, or after a handoff.
+        match self.pause.stopped().await {
+            true => {
+                drop(self.state_file);
+                drop(self.socket_path);
+            }
+            false => {
+                if let Some(state_file) = self.state_file {
+                    state_file.keep();
+                }
+                self.socket_path.keep();
+            }
+        }
+        let res = res.map_err(|e| Error::ServerFailed {
+            socket: self.service_socket.clone(),
+            source: e,
+        })?;
+        events::emit(ServiceEvent::Stopped {
+            socket: self.service_socket,
+        });
+        Ok(res)
+    }
+
+    /// Run the server until `shutdown` completes - like
+    /// [`ServerExt::start_and_run_server_with_shutdown`].
+    pub async fn run_with_shutdown(
+        self,
+        shutdown: impl Future<Output = ()>,
+        drain_timeout: Duration,
+    ) -> error::Result<Option<Srv::FinalOutput>> {
+        let service_socket = self.service_socket;
+        info!("Starting service @ {}", self.socket_path.as_ref().display());
+        let mut server = pin!(status::with_heartbeat(
+            self.server.run_server(self.service, self.api),
+            self.state_file
+                .as_ref()
+                .map(|path| path.as_ref().to_owned()),
+            self.server.heartbeat(),
+        ));
+        // None means shutdown was requested before the server finished.
+        let finished = map_fut(server.as_mut(), Some)
+            .or(map_fut(shutdown, |_| None))
+            .await;
+        info!(
+            "Cleaning up socket @ {}",
+            self.socket_path.as_ref().display()
+        );
+        drop(self.exposure);
+        // Leave the socket path and state file alone if another server took them over while
+        // paused, or after a handoff.
+        match self.pause.stopped().await {
+            true => {
+                drop(self.state_file);
+                drop(self.socket_path);
+            }
+            false => {
+                if let Some(state_file) = self.state_file {
+                    state_file.keep();
+                }
+                self.socket_path.keep();
+            }
+        }
+        events::emit(ServiceEvent::Stopped {
+            socket: service_socket.clone(),
+        });
+        let output = match finished {
+            Some(res) => Some(res),
+            None => {
+                info!(
+                    "Shutdown requested, draining in-flight work for up to {}",
+                    humantime::format_duration(drain_timeout)
+                );
+                with_timeout(server, drain_timeout).await.or_else(|| {
+                    warn!(
+                        "Server for {} didn't finish draining in time",
+                        service_socket
+                    );
+                    None
+                })
+            }
+        };
+        output.transpose().map_err(|e| Error::ServerFailed {
+            socket: service_socket,
+            source: e,
+        })
+    }
+
+    /// Run the server until a [`control::ControlCommand::Stop`] is received, or the listener is
+    /// handed off to a new instance - like [`ServerExt::start_and_run_server_with_control`].
+    pub async fn run_with_control(
+        mut self,
+        drain_timeout: Duration,
+        on_reload: impl FnMut() -> IoResult<()>,
+    ) -> error::Result<Option<Srv::FinalOutput>> {
+        let service_socket = self.service_socket.clone();
+        let pause = self.pause.clone();
+        let handoff_listener = self.handoff_listener.take();
+        let permissions = self.server.socket_permissions();
+        // Only polled once the service socket is ours, so binding can't clobber the control
+        // socket of another running server.
+        let stop = async {
+            match control::ControlChannel::bind(&service_socket, &permissions) {
+                Ok(control) => {
+                    let control = match handoff_listener {
+                        Some(listener) => control.with_handoff_listener(listener),
+                        None => control,
+                    };
+                    control.run(on_reload).await;
+                    if control.handed_off() {
+                        pause.handed_off().await;
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "Couldn't open control channel for {} - {}",
+                        service_socket, e
+                    );
+                    future::pending().await
+                }
+            }
+        };
+        self.run_with_shutdown(stop, drain_timeout).await
+    }
+}
+
+impl<U: UnixSocketInterface, S: Service<U>, T: Server<S, U>> ServerExt<S, U> for T {}
 
 /// Holds a particular instance of a [`Service`], along with a base context directory and optional
 /// executor prefix.
@@ -486,12 +2495,21 @@ impl<U: UnixSocketInterface, S: Service<U>, T: Server<S, U>> ServerExt<S, U> for
 pub struct ReifiedService<
     'info,
     S: Service<U>,
-    U: UnixSocketInterface,
+    U: UnixSocketInterface = DefaultUnixSocks,
     ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug = OsString,
 > {
     executor_prefix: Option<&'info [ExecutorPrefixComponent]>,
     base_context_directory: &'info Path,
     bare_service: S,
+    dependency_starter: Option<DependencyStarter<'info>>,
+    ephemeral_dir: Option<&'info Path>,
+    remote_context: Option<&'info remote::RemoteContext>,
+    /// Processes started with [`child::ChildStrategy::KillOnDrop`].
+    started_children: std::sync::Mutex<Vec<child::ChildGuard>>,
+    start_throttle: Option<throttle::Throttled>,
+    /// Connection shared by [`Self::connection`].
+    cached_connection:
+        async_lock::Mutex<Option<connection_cache::Cached<S::ServiceClientConnection>>>,
     _unix_socket_iface: PhantomData<U>,
 }
 
@@ -507,6 +2525,8 @@ impl<
             .field("executor_prefix", &self.executor_prefix)
             .field("base_context_directory", &self.base_context_directory)
             .field("bare_service", &self.bare_service)
+            .field("ephemeral_dir", &self.ephemeral_dir)
+            .field("remote_context", &self.remote_context)
             .finish_non_exhaustive()
     }
 }
@@ -524,6 +2544,12 @@ impl<
             executor_prefix: None,
             base_context_directory,
             bare_service: service,
+            dependency_starter: None,
+            ephemeral_dir: None,
+            remote_context: None,
+            started_children: Default::default(),
+            start_throttle: None,
+            cached_connection: async_lock::Mutex::new(None),
             _unix_socket_iface: PhantomData,
         }
     }
@@ -538,65 +2564,656 @@ impl<
             executor_prefix: Some(executor_prefix),
             base_context_directory,
             bare_service: service,
+            dependency_starter: None,
+            ephemeral_dir: None,
+            remote_context: None,
+            started_children: Default::default(),
+            start_throttle: None,
+            cached_connection: async_lock::Mutex::new(None),
             _unix_socket_iface: PhantomData,
         }
     }
 
-    /// Connect to this [`Service`], trying to start it if not possible.
-    ///
-    /// The timeout is for how long to wait until concluding that - in the case we attempted to
-    /// start a service because it wasn't running - the service failed to begin.
-    ///
-    /// If you don't care about starting the service on-demand, take a look at
-    /// [`Self::connect_to_running`]
-    #[instrument]
-    pub async fn connect(&self, liveness_timeout: Duration) -> IoResult<S::ServiceClientConnection>
+    /// Attach a function that starts the dependencies of this service. It is run by
+    /// [`Self::connect`] before starting the service on-demand - see [`dependencies`].
+    pub fn with_dependency_starter(
+        mut self,
+        dependency_starter: impl Fn(Duration) -> DependencyStartFuture<'info> + 'info,
+    ) -> Self {
+        self.dependency_starter = Some(Box::new(dependency_starter));
+        self
+    }
+
+    /// Create the ephemeral liveness sockets for starting this service in the given directory,
+    /// rather than in [`liveness::default_ephemeral_dir`]. This has no effect on services with a
+    /// [`liveness::LivenessTransport::InheritedFd`] transport, which need no socket file.
+    pub fn with_ephemeral_dir(mut self, ephemeral_dir: &'info Path) -> Self {
+        self.ephemeral_dir = Some(ephemeral_dir);
+        self
+    }
+
+    /// Use this service on another machine, through the remote context - see [`remote`]. Its
+    /// socket is forwarded into the local context directory of the remote context, which becomes
+    /// the base context directory of this service, whenever it is connected to. Remote services
+    /// are never started from here, and neither are their dependencies, so connecting fails if
+    /// the service isn't running on the other machine.
+    pub fn with_remote_context(mut self, remote_context: &'info remote::RemoteContext) -> Self {
+        self.base_context_directory = remote_context.local_context_directory();
+        self.remote_context = Some(remote_context);
+        self
+    }
+
+    /// The remote context this service is used through, if any.
+    pub fn remote_context(&self) -> Option<&'info remote::RemoteContext> {
+        self.remote_context
+    }
+
+    /// Make sure the socket of this service is forwarded, if it is used through a remote context.
+    async fn forward_remote_socket(&self) -> error::Result<()> {
+        let Some(remote_context) = self.remote_context else {
+            return Ok(());
+        };
+        remote_context
+            .forward(&self.bare_service.socket_name())
+            .await
+            .map(drop)
+            .map_err(|e| Error::ForwardFailed {
+                socket: self.service_socket(),
+                source: e,
+            })
+    }
+
+    /// Limit how often this service is started when it isn't running, and stop trying for a
+    /// while once it keeps failing to start - see [`throttle`]. Starts that aren't allowed fail
+    /// fast with [`Error::StartThrottled`] or [`Error::CircuitOpen`], while connecting to a
+    /// running service is never held back.
+    pub fn with_start_throttle(mut self, start_throttle: throttle::StartThrottle) -> Self {
+        self.start_throttle = Some(throttle::Throttled::new(start_throttle));
+        self
+    }
+
+    /// How often this service may be started, if limited.
+    pub fn start_throttle(&self) -> Option<&throttle::StartThrottle> {
+        self.start_throttle
+            .as_ref()
+            .map(throttle::Throttled::throttle)
+    }
+
+    /// The socket of this service, within its base context directory.
+    pub fn service_socket(&self) -> ServiceSocket {
+        ServiceSocket::new(
+            &self.bare_service.socket_name(),
+            self.base_context_directory,
+        )
+    }
+
+    /// Connect to the bare socket of this [`Service`], trying to start it if not possible. This
+    /// does not start any dependencies.
+    pub(crate) async fn connect_raw(
+        &self,
+        liveness_timeout: Duration,
+        task_spawner: Option<&dyn TaskSpawner>,
+    ) -> error::Result<U::UnixStream>
     where
         S: ServiceStartable<U>,
     {
-        self.bare_service
-            .connect_to_service(
+        if self.remote_context.is_some() {
+            self.forward_remote_socket().await?;
+            return connect_to_running_service_raw::<U, S>(
+                &self.bare_service,
+                self.base_context_directory,
+            )
+            .await;
+        }
+        let connect = || {
+            connect_to_service_raw::<U, S>(
+                &self.bare_service,
                 self.executor_prefix,
                 self.base_context_directory,
                 liveness_timeout,
+                self.ephemeral_dir,
+                task_spawner,
+                Some(&self.started_children),
             )
-            .await
+        };
+        let Some(start_throttle) = &self.start_throttle else {
+            return connect().await;
+        };
+        match connect_to_running_service_raw::<U, S>(
+            &self.bare_service,
+            self.base_context_directory,
+        )
+        .await
+        {
+            Ok(unix_stream) => {
+                start_throttle.succeeded();
+                return Ok(unix_stream);
+            }
+            Err(e) if !connect_options::is_retryable(&e) => return Err(e),
+            Err(e) => debug!("Service isn't running - {} - starting it if allowed", e),
+        }
+        let service_socket = self.service_socket();
+        start_throttle.acquire(&service_socket)?;
+        let result = connect().await;
+        match &result {
+            Ok(_) => start_throttle.succeeded(),
+            Err(e) if throttle::is_start_failure(e) => start_throttle.failed(&service_socket),
+            Err(_) => {}
+        }
+        result
     }
 
-    /// Connect to this [`Service`], without attempts to start it upon failure.
+    /// Connect to this [`Service`], trying to start it if not possible.
     ///
-    /// If you want to try and start the service on-demand, take a look at [`Self::connect`]
+    /// The service decides how long to wait until concluding that - in the case we attempted to
+    /// start a service because it wasn't running - the service failed to begin, with
+    /// [`ServiceStartable::liveness_timeout`], and how long setting up the connection may take,
+    /// with [`Service::connect_timeout`]. To wait for a different time, see
+    /// [`Self::connect_with_timeout`].
+    ///
+    /// If the service has dependencies and needs to be started, those are started first, each with
+    /// the same liveness timeout.
+    ///
+    /// If you don't care about starting the service on-demand, take a look at
+    /// [`Self::connect_to_running`]. To retry failed attempts, see [`Self::connect_with_options`].
     #[instrument]
-    pub async fn connect_to_running(&self) -> IoResult<S::ServiceClientConnection> {
-        self.bare_service
-            .connect_to_running_service(self.base_context_directory)
+    pub async fn connect(&self) -> error::Result<S::ServiceClientConnection>
+    where
+        S: ServiceStartable<U>,
+    {
+        self.connect_with_options(&self.default_connect_options())
             .await
     }
 
+    /// Like [`Self::connect`], but waiting for the service to become live for this long instead
+    /// of the [`ServiceStartable::liveness_timeout`] of the service.
     #[instrument]
-    /// Run an actual server for this service, with a provided implementation and optional [`liveness`]
-    /// socket path.
-    pub async fn serve_service_implementation<ServiceServer: ServerExt<S, U>>(
+    pub async fn connect_with_timeout(
         &self,
-        server: &ServiceServer,
-        liveness_socket_path: Option<&Path>,
-    ) -> IoResult<ServiceServer::FinalOutput> {
-        server
-            .start_and_run_server(
-                &self.bare_service,
-                self.base_context_directory,
-                liveness_socket_path,
-            )
-            .await
+        liveness_timeout: Duration,
+    ) -> error::Result<S::ServiceClientConnection>
+    where
+        S: ServiceStartable<U>,
+    {
+        self.connect_with_options(
+            &self
+                .default_connect_options()
+                .with_liveness_timeout(liveness_timeout),
+        )
+        .await
     }
-}
 
-/// Trait implemented by "bundles" of services that all work together and call each other.
+    /// The options [`Self::connect`] uses - the liveness and connect timeouts of the service, and
+    /// no retries. This is a good starting point for [`Self::connect_with_options`].
+    pub fn default_connect_options(&self) -> ConnectOptions
+    where
+        S: ServiceStartable<U>,
+    {
+        let options = ConnectOptions::new(self.bare_service.liveness_timeout());
+        match self.bare_service.connect_timeout() {
+            Some(connect_timeout) => options.with_connect_timeout(connect_timeout),
+            None => options,
+        }
+    }
+
+    /// Like [`Self::connect`], but retrying failed attempts - including starting the service - with
+    /// exponential backoff, as configured in the [`ConnectOptions`].
+    ///
+    /// Errors that retrying can't fix, like [`Error::WrongService`], are returned straight away.
+    /// Otherwise, the error of the last attempt is returned once the retries or the deadline run
+    /// out.
+    #[instrument]
+    pub async fn connect_with_options(
+        &self,
+        options: &ConnectOptions,
+    ) -> error::Result<S::ServiceClientConnection>
+    where
+        S: ServiceStartable<U>,
+    {
+        let started = Instant::now();
+        let mut retry = 0;
+        loop {
+            let liveness_timeout = match options.deadline() {
+                Some(deadline) => options
+                    .liveness_timeout()
+                    .min(deadline.saturating_sub(started.elapsed())),
+                None => options.liveness_timeout(),
+            };
+            let task_spawner = options.task_spawner().map(|s| &**s as &dyn TaskSpawner);
+            let error = match self
+                .connect_once(liveness_timeout, options.connect_timeout(), task_spawner)
+                .await
+            {
+                Ok(connection) => return Ok(connection),
+                Err(e) => e,
+            };
+            if retry >= options.retries() || !connect_options::is_retryable(&error) {
+                return Err(error);
+            }
+            let backoff = options.jittered_backoff(retry);
+            if let Some(deadline) = options.deadline() {
+                if started.elapsed() + backoff >= deadline {
+                    warn!(
+                        "Connecting to service failed - {} - and the deadline of {} leaves no time to retry",
+                        error,
+                        humantime::format_duration(deadline)
+                    );
+                    return Err(error);
+                }
+            }
+            warn!(
+                "Connecting to service failed - {} - retrying in {}",
+                error,
+                humantime::format_duration(backoff)
+            );
+            timefut::sleep(backoff).await;
+            retry += 1;
+        }
+    }
+
+    /// A single attempt at [`Self::connect`].
+    async fn connect_once(
+        &self,
+        liveness_timeout: Duration,
+        connect_timeout: Option<Duration>,
+        task_spawner: Option<&dyn TaskSpawner>,
+    ) -> error::Result<S::ServiceClientConnection>
+    where
+        S: ServiceStartable<U>,
+    {
+        let unix_stream = self
+            .connect_once_raw(liveness_timeout, task_spawner)
+            .await?;
+        self.wrap_within(unix_stream, connect_timeout).await
+    }
+
+    /// Wrap a connection to the service, failing with [`Error::ConnectTimeout`] if that takes
+    /// longer than the connect timeout.
+    async fn wrap_within(
+        &self,
+        unix_stream: U::UnixStream,
+        connect_timeout: Option<Duration>,
+    ) -> error::Result<S::ServiceClientConnection> {
+        let wrap = wrap_service_connection::<U, S>(
+            &self.bare_service,
+            self.base_context_directory,
+            unix_stream,
+        );
+        let Some(connect_timeout) = connect_timeout else {
+            return wrap.await;
+        };
+        wrap.or(async {
+            timefut::sleep(connect_timeout).await;
+            Err(Error::ConnectTimeout {
+                socket: self.service_socket(),
+                timeout: connect_timeout,
+            })
+        })
+        .await
+    }
+
+    /// [`Self::connect_once`], without wrapping the connection.
+    async fn connect_once_raw(
+        &self,
+        liveness_timeout: Duration,
+        task_spawner: Option<&dyn TaskSpawner>,
+    ) -> error::Result<U::UnixStream>
+    where
+        S: ServiceStartable<U>,
+    {
+        if let (Some(start_dependencies), None) = (&self.dependency_starter, self.remote_context) {
+            match connect_to_running_service_raw::<U, S>(
+                &self.bare_service,
+                self.base_context_directory,
+            )
+            .await
+            {
+                Ok(unix_stream) => return Ok(unix_stream),
+                Err(e) => {
+                    warn!(
+                        "Error connecting to existing service - {} - starting dependencies",
+                        e
+                    );
+                    start_dependencies(liveness_timeout).await?;
+                }
+            }
+        }
+        self.connect_raw(liveness_timeout, task_spawner).await
+    }
+
+    /// A pool of up to `max_connections` connections to this [`Service`], for clients with many
+    /// requests in flight at once - see [`pool::ConnectionPool`]. The liveness timeout is for
+    /// starting the service, as in [`Self::connect_with_timeout`].
+    pub fn pool(
+        &self,
+        max_connections: usize,
+        liveness_timeout: Duration,
+    ) -> pool::ConnectionPool<'_, 'info, S, U, ExecutorPrefixComponent>
+    where
+        S: ServiceStartable<U>,
+    {
+        pool::ConnectionPool::new(self, max_connections, liveness_timeout)
+    }
+
+    /// A connection to this [`Service`] that is re-established - starting the service again if
+    /// need be - whenever it is lost, see [`reconnect::Reconnecting`].
+    pub fn reconnecting(
+        &self,
+        liveness_timeout: Duration,
+    ) -> reconnect::Reconnecting<'_, 'info, S, U, ExecutorPrefixComponent>
+    where
+        S: ServiceStartable<U>,
+    {
+        reconnect::Reconnecting::new(self, liveness_timeout)
+    }
+
+    /// Connect to this [`Service`] like [`Self::connect`], but keep the connection around and
+    /// share it with every later call, instead of connecting anew each time.
+    ///
+    /// The connection is established on first use. Before it is handed out again, it is checked
+    /// for having been hung up by the server - if it has, a fresh connection is established,
+    /// starting the service again if it is no longer running. Only one user has the connection
+    /// at a time, and the others wait until the [`connection_cache::CachedConnection`] is
+    /// dropped. If an exchange goes wrong half way, use
+    /// [`connection_cache::CachedConnection::invalidate`] so the next user doesn't pick up the
+    /// pieces.
+    ///
+    /// Without [`UnixSocketInterface::unix_stream_duplicate_fd`], the connection can't be
+    /// checked on, and is reused until invalidated.
+    #[instrument]
+    pub async fn connection(
+        &self,
+        liveness_timeout: Duration,
+    ) -> error::Result<connection_cache::CachedConnection<'_, S::ServiceClientConnection>>
+    where
+        S: ServiceStartable<U>,
+    {
+        let mut cached = self.cached_connection.lock().await;
+        match cached.as_ref() {
+            Some(existing) if existing.is_healthy() => {
+                return Ok(connection_cache::CachedConnection::new(cached))
+            }
+            Some(_) => {
+                info!("Cached connection was hung up - reconnecting");
+                *cached = None;
+            }
+            None => debug!("No cached connection yet - connecting"),
+        }
+        *cached = Some(self.connect_cacheable(liveness_timeout).await?);
+        Ok(connection_cache::CachedConnection::new(cached))
+    }
+
+    /// A single attempt at [`Self::connect`], keeping a duplicate of the socket to check on the
+    /// connection with later.
+    pub(crate) async fn connect_cacheable(
+        &self,
+        liveness_timeout: Duration,
+    ) -> error::Result<connection_cache::Cached<S::ServiceClientConnection>>
+    where
+        S: ServiceStartable<U>,
+    {
+        let mut unix_stream = self.connect_once_raw(liveness_timeout, None).await?;
+        let socket = match U::unix_stream_duplicate_fd(&mut unix_stream).await {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                debug!("Can't health-check the connection - {}", e);
+                None
+            }
+        };
+        let connection = self
+            .wrap_within(unix_stream, self.bare_service.connect_timeout())
+            .await?;
+        Ok(connection_cache::Cached { connection, socket })
+    }
+
+    /// Make sure this [`Service`] is running - starting it and its dependencies on-demand, like
+    /// [`Self::connect`] - without keeping a connection to it. The connection used to check is
+    /// closed again without being wrapped, so [`Service::wrap_connection`] isn't called.
+    #[instrument]
+    pub async fn ensure_started(&self, liveness_timeout: Duration) -> error::Result<()>
+    where
+        S: ServiceStartable<U>,
+    {
+        if let (Some(start_dependencies), None) = (&self.dependency_starter, self.remote_context) {
+            match self.status().await {
+                ServiceStatus::Running { .. } => return Ok(()),
+                status => {
+                    warn!("Service is {} - starting dependencies", status);
+                    start_dependencies(liveness_timeout).await?;
+                }
+            }
+        }
+        self.ensure_started_without_dependencies(liveness_timeout)
+            .await
+    }
+
+    /// [`Self::ensure_started`], without starting any dependencies.
+    pub(crate) async fn ensure_started_without_dependencies(
+        &self,
+        liveness_timeout: Duration,
+    ) -> error::Result<()>
+    where
+        S: ServiceStartable<U>,
+    {
+        let mut probe = self.connect_raw(liveness_timeout, None).await?;
+        U::unix_stream_shutdown(&mut probe)
+            .await
+            .map_err(|e| Error::ConnectFailed {
+                socket: self.service_socket(),
+                source: e,
+            })
+    }
+
+    /// Connect to this [`Service`], without attempts to start it upon failure.
+    ///
+    /// If you want to try and start the service on-demand, take a look at [`Self::connect`]
+    #[instrument]
+    pub async fn connect_to_running(&self) -> error::Result<S::ServiceClientConnection> {
+        self.forward_remote_socket().await?;
+        self.bare_service
+            .connect_to_running_service(self.base_context_directory)
+            .await
+    }
+
+    /// Check whether this [`Service`] is running, without trying to start it.
+    ///
+    /// This makes a test connection to the service socket, which is closed straight away - so
+    /// the server sees a connection, but nothing is sent on it. The process ID of the server is
+    /// read from its state file, if it has one - see [`status`]. Servers that accept the
+    /// connection but have missed their [`Server::heartbeat`] are
+    /// [`ServiceStatus::Unresponsive`].
+    #[instrument]
+    pub async fn status(&self) -> ServiceStatus {
+        let service_socket = self.service_socket();
+        if let Err(e) = self.forward_remote_socket().await {
+            debug!("{}", e);
+            return ServiceStatus::NotRunning;
+        }
+        match connect_to_running_service_raw::<U, S>(
+            &self.bare_service,
+            self.base_context_directory,
+        )
+        .await
+        {
+            Ok(mut probe) => {
+                let _ = U::unix_stream_shutdown(&mut probe).await;
+                status::running_status(
+                    &service_socket,
+                    status::read_state(&service_socket).as_ref(),
+                )
+            }
+            Err(Error::StaleSocket { .. }) => ServiceStatus::StaleSocket,
+            Err(_) => ServiceStatus::NotRunning,
+        }
+    }
+
+    /// Subscribe to the lifecycle [`events`] of this [`Service`] emitted in this process from now
+    /// on - both as a client, and as a server if it is served here.
+    pub fn events(&self) -> events::ServiceEvents {
+        events::subscribe(&self.service_socket())
+    }
+
+    /// Read the state file left by the server of this [`Service`], without connecting to it - see
+    /// [`status::read_state`]. A state file may be left behind by a server that crashed, so check
+    /// [`Self::status`] to find out whether the server is actually running.
+    pub fn read_state(&self) -> Option<status::ServiceState> {
+        status::read_state(&self.service_socket())
+    }
+
+    /// Send a command over the [`control`] channel of this [`Service`], producing the text of the
+    /// response.
+    #[instrument]
+    pub async fn control(
+        &self,
+        command: control::ControlCommand,
+        timeout: Duration,
+    ) -> error::Result<String> {
+        let service_socket = self.service_socket();
+        control::send_control_command(&service_socket, command, timeout)
+            .await
+            .map_err(|e| Error::ControlFailed {
+                socket: service_socket,
+                source: e,
+            })
+    }
+
+    /// Ask this [`Service`] to stop over its [`control`] channel, and wait until its socket is
+    /// gone - which happens before it finishes draining in-flight work.
+    #[instrument]
+    pub async fn stop(&self, timeout: Duration) -> error::Result<()> {
+        control::stop_service(&self.service_socket(), timeout).await
+    }
+
+    /// Restart this [`Service`] and connect to the new instance.
+    ///
+    /// If the service is running, it is asked to [`Self::stop`] first. Should that fail - for
+    /// instance because it has no [`control`] channel, or doesn't respond - the server process
+    /// recorded in its state file is killed instead (see [`status`]). Services that are
+    /// [`ServiceStatus::Unresponsive`] are killed straight away. Then the service is started
+    /// and connected to as in [`Self::connect_with_timeout`]. The liveness timeout is also used
+    /// for stopping the service, and for waiting on it to exit if it has to be killed.
+    #[instrument]
+    pub async fn restart(
+        &self,
+        liveness_timeout: Duration,
+    ) -> error::Result<S::ServiceClientConnection>
+    where
+        S: ServiceStartable<U>,
+    {
+        let kill = match self.status().await {
+            ServiceStatus::Running { pid } => match self.stop(liveness_timeout).await {
+                Ok(()) => None,
+                Err(e) => {
+                    let pid = match pid {
+                        Some(pid) => pid,
+                        None => return Err(e),
+                    };
+                    warn!(
+                        "Couldn't stop service cleanly - {} - killing process {}",
+                        e, pid
+                    );
+                    Some(pid)
+                }
+            },
+            ServiceStatus::Unresponsive { pid } => {
+                warn!("Service is unresponsive - killing process {}", pid);
+                Some(pid)
+            }
+            ServiceStatus::StaleSocket | ServiceStatus::NotRunning => None,
+        };
+        if let Some(pid) = kill {
+            status::kill_process(pid, liveness_timeout)
+                .await
+                .map_err(|e| Error::KillFailed {
+                    socket: self.service_socket(),
+                    pid,
+                    source: e,
+                })?;
+        }
+        self.connect_with_timeout(liveness_timeout).await
+    }
+
+    /// In a server for this [`Service`], wrap a stream accepted on its socket - see
+    /// [`ServiceExt::wrap_incoming_connection`].
+    pub async fn wrap_incoming_connection(
+        &self,
+        unix_stream: U::UnixStream,
+    ) -> error::Result<S::ServiceServerConnection> {
+        self.bare_service
+            .wrap_incoming_connection(self.base_context_directory, unix_stream)
+            .await
+    }
+
+    #[instrument]
+    /// Run an actual server for this service, with a provided implementation and optional [`liveness`]
+    /// socket path.
+    pub async fn serve_service_implementation<ServiceServer: ServerExt<S, U>>(
+        &self,
+        server: &ServiceServer,
+        liveness_socket_path: Option<&Path>,
+    ) -> error::Result<ServiceServer::FinalOutput> {
+        server
+            .start_and_run_server(
+                &self.bare_service,
+                self.base_context_directory,
+                liveness_socket_path,
+            )
+            .await
+    }
+
+    #[instrument(skip(on_reload))]
+    /// Like [`ReifiedService::serve_service_implementation`], but with a [`control`] channel that
+    /// can stop the server - see [`ServerExt::start_and_run_server_with_control`].
+    pub async fn serve_service_implementation_with_control<ServiceServer: ServerExt<S, U>>(
+        &self,
+        server: &ServiceServer,
+        liveness_socket_path: Option<&Path>,
+        drain_timeout: Duration,
+        on_reload: impl FnMut() -> IoResult<()>,
+    ) -> error::Result<Option<ServiceServer::FinalOutput>> {
+        server
+            .start_and_run_server_with_control(
+                &self.bare_service,
+                self.base_context_directory,
+                liveness_socket_path,
+                drain_timeout,
+                on_reload,
+            )
+            .await
+    }
+
+    #[instrument(skip(shutdown))]
+    /// Like [`ReifiedService::serve_service_implementation`], but stop when `shutdown` completes
+    /// and give in-flight work up to `drain_timeout` to finish - see
+    /// [`ServerExt::start_and_run_server_with_shutdown`].
+    pub async fn serve_service_implementation_with_shutdown<ServiceServer: ServerExt<S, U>>(
+        &self,
+        server: &ServiceServer,
+        liveness_socket_path: Option<&Path>,
+        shutdown: impl Future<Output = ()>,
+        drain_timeout: Duration,
+    ) -> error::Result<Option<ServiceServer::FinalOutput>> {
+        server
+            .start_and_run_server_with_shutdown(
+                &self.bare_service,
+                self.base_context_directory,
+                liveness_socket_path,
+                shutdown,
+                drain_timeout,
+            )
+            .await
+    }
+}
+
+/// Trait implemented by "bundles" of services that all work together and call each other.
 ///
 /// Provides a unified interface for applying *base context directories* and *executor commands* to
 /// all of a collection of services, to then instantiate a defined service (these are inherent impl
 /// methods on generated types).
-pub trait ServiceBundle<ExecutorPrefixComponent: AsRef<OsStr> + Sized = OsString> {
+///
+/// Bundles also carry the [`dependencies::DependencyGraph`] between their services. Bundle
+/// constructors should panic if it contains a cycle.
+pub trait ServiceBundle<ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug = OsString> {
     /// Create the service bundle with the given base context directory,
     fn new(base_context_directory: &Path) -> Self;
 
@@ -605,6 +3222,58 @@ pub trait ServiceBundle<ExecutorPrefixComponent: AsRef<OsStr> + Sized = OsString
         base_context_directory: &Path,
         executor_prefix: &[ExecutorPrefixComponent],
     ) -> Self;
+
+    /// The base context directory shared by all services in this bundle.
+    fn base_context_directory(&self) -> &Path;
+
+    /// The executor prefix shared by all services in this bundle, if any.
+    fn executor_prefix(&self) -> Option<&[ExecutorPrefixComponent]>;
+
+    /// Dependencies between the services in this bundle. By default, there are none.
+    fn dependency_graph() -> DependencyGraph
+    where
+        Self: Sized,
+    {
+        DependencyGraph::default()
+    }
+
+    /// Reify any [`Service`] with the base context directory and executor prefix of this bundle.
+    fn reify<S: Service<U>, U: UnixSocketInterface>(
+        &self,
+        service: S,
+    ) -> ReifiedService<'_, S, U, ExecutorPrefixComponent> {
+        match self.executor_prefix() {
+            Some(executor_prefix) => ReifiedService::reify_service_with_executor(
+                service,
+                self.base_context_directory(),
+                executor_prefix,
+            ),
+            None => ReifiedService::reify_service(service, self.base_context_directory()),
+        }
+    }
+}
+
+#[cfg(feature = "async-trait-compat")]
+#[doc(hidden)]
+#[macro_export]
+/// Apply [`macro@async_trait`] to an implementation of one of the async traits in this crate, as
+/// they are implemented with it under the `async-trait-compat` feature.
+macro_rules! __service_async_impl {
+    ($($item:tt)*) => {
+        #[$crate::async_trait(?Send)]
+        $($item)*
+    };
+}
+
+#[cfg(not(feature = "async-trait-compat"))]
+#[doc(hidden)]
+#[macro_export]
+/// Pass through an implementation of one of the async traits in this crate, as they use native
+/// `async fn` without the `async-trait-compat` feature.
+macro_rules! __service_async_impl {
+    ($($item:tt)*) => {
+        $($item)*
+    };
 }
 
 #[macro_export]
@@ -616,9 +3285,9 @@ pub trait ServiceBundle<ExecutorPrefixComponent: AsRef<OsStr> + Sized = OsString
 /// executor prefix implementation.
 ///
 /// Wrapping [`std::os::unix::net::UnixStream`]s in higher-level abstractions can be specified in a
-/// number of ways (in future, currently we only implement bare functions). These methods are
-/// called *USP*s (**U**nix **S**tream **P**reprocessors), of which there is currently one (though
-/// it should be able to implement any other with sufficient effort).
+/// number of ways. These methods are called *USP*s (**U**nix **S**tream **P**reprocessors) - there
+/// are currently bare functions, which can implement any other with sufficient effort, and typed
+/// serde messages in a couple of encodings, and HTTP.
 ///
 /// Using this macro goes something like the following:
 ///
@@ -627,27 +3296,73 @@ pub trait ServiceBundle<ExecutorPrefixComponent: AsRef<OsStr> + Sized = OsString
 ///
 /// declare_service! {
 ///     /// My wonderful service
-///     pub WonderfulService <unix stream interface type name> = {
+///     pub WonderfulService /*optional*/ { fields } /*end opt*/ <unix stream interface type name> = {
 ///         /*optional starting method*/ "some-wonderful-command" "--and" "--commandline" "args" /*end opt*/ @ "unix-socket-filename.sock"
+///         /*optional*/ handshake 1 ..= 3 /*end opt*/
+///         /*optional*/ liveness inherited_fd /*end opt*/
 ///         as some_usp_method some_usp_method_specifications
-///     } /* optional generic params */ impl { type-parameters-and-constraints-that-go-in-<-and-> }
+///     } /* optional generic params */ impl<type-parameters-and-constraints>
 /// }
 /// ```
 ///
+/// Everything the macro generates refers to items by their full paths, so it works without any
+/// imports in scope.
+///
 /// Services are just unit types in this case, and can have any visibility you like and
 /// documentation or other things like `#[derive]` on them as desired.
 ///
+/// Services that carry configuration - say, which shard of a cache they are, or where their binary
+/// lives - can have named fields instead, which go in braces after the service name:
+///
+/// ```rust,compile_fail
+/// declare_service! {
+///     pub ShardedCache { pub shard: String, pub socket: String } <U> = {
+///         "cache-server" "--shard" self.shard @ self.socket as ...
+///     } impl {U: UnixSocketInterface}
+/// }
+/// ```
+///
+/// The command, its arguments and the socket name can then be `self.<field>` as well as string
+/// literals, for any field that is `AsRef<OsStr>`.
+///
+/// Services with fields can be generic too, with type parameters after the service name. Their
+/// bounds go in the generic parameters at the end, next to the one for the unix stream interface
+/// - the service needs to be `Debug`, so parameters usually need that bound as well:
+///
+/// ```rust,compile_fail
+/// declare_service! {
+///     pub Cache<P> { pub socket: P } <U> = {
+///         "cache-server" @ self.socket as ...
+///     } impl<P: AsRef<OsStr> + Debug, U: UnixSocketInterface>
+/// }
+/// ```
+///
+/// The command and its arguments can also be any expression evaluating to something `AsRef<OsStr>`,
+/// in parentheses - like `(config.server_binary())` or `(format!("--shard={}", self.shard))`.
+/// Prefixing an expression with `..` makes it a group of arguments instead, which can be anything
+/// that iterates over `AsRef<OsStr>` items - like `..(&self.extra_flags)`. This way paths from
+/// configuration files or flags depending on features can be used without implementing
+/// [`ServiceStartable`] by hand. Services with fields can't go in a
+/// [`declare_service_bundle`], as the bundle has no values to give them.
+///
 /// The first part of the definition if provided controls what command to run to execute the service, and the
 /// socket it will serve on. The ephemeral liveness socket, as described in
-/// [`ServerExt::start_and_run_server`], is passed through via an environment variable.
+/// [`ServerExt::start_and_run_server`], is passed through via the [`liveness::LIVENESS_ENV_VAR`]
+/// environment variable rather than as a commandline argument, so the service's own argument
+/// parsing is left alone. Servers can read it with [`liveness::retrieve_liveness_path`].
 ///
 /// The literal after the @ is the name of the socket within the *base context directory* that
 /// this service hosts itself upon. For example, if your base context directory is `/var/run`, and
 /// the socket name for a service is `hello-service.sock`, then the service should receive
 /// connections on `/var/run/hello-service.sock`.
 ///
-/// Note that there is *no easy way* to pass in the base context directory to the command if
-/// starting it. This is a concious decision - this library is designed for *services*, not
+/// Rather than a literal, the socket name can be computed - by a macro call like
+/// `@ format!("cache-{}.sock", self.shard)`, an expression in parentheses, or a block like a
+/// method body - from anything `AsRef<OsStr>`. This lets sharded or otherwise parameterised
+/// services be declared with this macro too.
+///
+/// Note that the base context directory is *not* passed to the command by default when starting
+/// it. This is a concious decision - this library is designed for *services*, not
 /// just *subprocesses*, and hence other programs should be able to find a service via some
 /// method derived from the environment.
 ///
@@ -656,6 +3371,93 @@ pub trait ServiceBundle<ExecutorPrefixComponent: AsRef<OsStr> + Sized = OsString
 /// environment, whether that be `XDG`, or a global fixed directory, or an environment variable, or
 /// any combination of the above or some other environmental context.
 ///
+/// Adding `env "<NAME>" = <value>` clauses after the socket name sets environment variables for
+/// the command, where the value is a string literal, `self.<field>`, or an expression in
+/// parentheses - anything `AsRef<OsStr>`. As a shortcut for the common case of the environment
+/// variable convention above, `env SUSS_CONTEXT_DIR` passes the base context directory through in
+/// [`environment::CONTEXT_DIR_ENV_VAR`], which [`environment::ServerEnvironment::from_env`] reads
+/// - for instance:
+///
+/// ```rust,compile_fail
+///  ... @ "cache.sock"
+///     env "RUST_LOG" = "info"
+///     env "CACHE_SHARD" = (self.shard.to_string())
+///     env SUSS_CONTEXT_DIR
+///  ...
+/// ```
+///
+/// Adding `cwd = <path>` after the socket name runs the command in that working directory, rather
+/// than wherever the client happened to be - the path is a string literal, `self.<field>`, or an
+/// expression in parentheses, anything `AsRef<Path>`. Adding `stdio = null`, `stdio = inherit` or
+/// `stdio = log` sets up standard input, output and error all at once - with `log`, output goes to
+/// the log file next to the socket, and standard input is null. Both are mapped onto
+/// [`ServiceStartable::spawn_options`], with any `spawn` options below taking precedence.
+///
+/// Adding `liveness_timeout = <duration>` or `connect_timeout = <duration>` after the socket name
+/// sets the defaults used by [`ReifiedService::connect`] - see
+/// [`ServiceStartable::liveness_timeout`] and [`Service::connect_timeout`]. The duration is
+/// either written out like `5s` or `1500ms` - in `ns`, `us`, `ms`, `s`, `m`, `h` or `d` - which
+/// fails to compile if it isn't valid, or an expression in parentheses evaluating to a
+/// [`Duration`](std::time::Duration). Callers can still override them with
+/// [`ReifiedService::connect_with_timeout`] and [`ReifiedService::connect_with_options`].
+///
+/// Adding `@ tcp <address>` after the socket name has clients reach the server over TCP, through
+/// a [`transport::TcpTransport`] - for deployments that share the base context directory, but
+/// can't share sockets in it. The address is the one the server binds, as a string literal or an
+/// expression in parentheses - with port 0, any free port is picked and recorded in a port
+/// registry file next to the socket. Servers still bind the service socket as well.
+///
+/// ```rust,compile_fail
+///  ... @ "cache.sock" @ tcp "127.0.0.1:0" ...
+/// ```
+///
+/// Adding `handshake <protocol version>` after the socket name makes clients and servers of the
+/// service perform a [`handshake`] on every connection - see
+/// [`Service::handshake_protocol_versions`]. If the service supports a range of protocol versions,
+/// declare it as `handshake <min version> ..= <max version>`, and the highest version both sides
+/// support gets picked.
+///
+/// Adding `liveness inherited_fd` after that makes the started service report its liveness over
+/// an inherited file descriptor rather than an ephemeral socket in the temporary directory - see
+/// [`ServiceStartable::liveness_transport`]. The default is `liveness temp_socket`, and `liveness
+/// authenticated_temp_socket` makes the service prove it was the one started, with a token. With
+/// `liveness inherited_listener`, the client binds the service socket and the service inherits the
+/// listener, so there is no liveness check to wait for at all.
+///
+/// Adding `spawn { <option>, ... }` after that sets up how the service process is started - see
+/// [`ServiceStartable::spawn_options`]. `stdin` can be `inherit` or `null`, and `stdout` and
+/// `stderr` can be `inherit`, `null`, `log_file` or `tracing`. `die_with_parent` makes the service
+/// exit with the process that started it, `detach` starts it in its own session so it outlives
+/// the terminal of that process, and `user(<uid>, <gid>)` runs it as another user.
+/// `nofile_limit(<n>)`, `core_limit(<bytes>)`, `address_space_limit(<bytes>)`, `nice(<n>)` and
+/// `io_priority(<spawn::IoPriority>)` bound the resources it uses - for instance
+/// `spawn { stdin null, stdout log_file, stderr tracing, detach, user(65534, 65534), nice(10) }`.
+/// Everything is inherited by default, and the service keeps running.
+///
+/// Adding `children <strategy>` after that picks what happens to the started service process once
+/// it is live - see [`child::ChildStrategy`]. It can be `detach`, `reap(<task spawner>)` - for
+/// instance `children reap(suss::task::ThreadSpawner)` - or `kill_on_drop`. Without it,
+/// [`ServiceStartable::after_post_liveness_subprocess`] is called.
+///
+/// Adding `in_process (<launch function>)` after that starts the service by running its server in
+/// this process instead of running the command, which can then be left out - see
+/// [`launcher::ServiceLauncher::InProcess`]. The function is given an
+/// [`launcher::InProcessLaunch`] and starts the server with [`launcher::InProcessService`] - for
+/// instance:
+///
+/// ```rust,compile_fail
+///  @ "cache.sock" in_process (|launch: suss::launcher::InProcessLaunch| {
+///      suss::launcher::InProcessService::thread(move || {
+///          block_on(CacheServer.start_and_run_server(
+///              &CacheService,
+///              &launch.base_context_directory,
+///              Some(&launch.liveness_path),
+///          ))
+///          .map(drop)
+///      })
+///  }) as ...
+/// ```
+///
 /// This optionally defines how a service is started and how to locate it. The stuff after the *as* provides
 /// information on what to do once you've got a connection.
 ///
@@ -667,273 +3469,3297 @@ pub trait ServiceBundle<ExecutorPrefixComponent: AsRef<OsStr> + Sized = OsString
 /// [`UnixSocketInterface::UnixStream`] and produces (wrapped in a [`std::io::Result`]), a
 /// higher-level abstraction over the stream that the rest of the world will have access to.
 ///
+/// The same function wraps both ends of a connection - it is used for
+/// [`Service::wrap_connection`] on the client side, and [`Service::wrap_incoming`] on the server
+/// side.
+///
 /// ```rust,compile_fail
 ///  ...rest-of-arg... as raw |name_of_raw_std_unix_socket_variable| -> Io<abstracted_and_wrapped_connection_type> {
 ///     Ok(some_wrapped_type)
 ///  } ...
 /// ```
 ///
-/// You can either implement a service over a specific socket implementation - either one of those
-/// defined in [`socket_shims`] or even your own custom implementation - or you can make a service
-/// generic over all of them by including some impl <...> parameters and constraints after the
-/// method. These go inside {} rather than <> due to macro constraints.
+/// #### Buffered
+///
+/// The `buffered` method wraps the stream in a [`buffered::BufferedConnection`], with a read
+/// buffer and a write buffer - remember to flush it before waiting for a reply.
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as buffered ...
+/// ```
+///
+/// #### Split
+///
+/// The `split` method [`split::split`]s the stream into a [`split::ReadHalf`] and a
+/// [`split::WriteHalf`], for reading and writing at the same time. This needs a socket interface
+/// that can take over std streams - see [`UnixSocketInterface::unix_stream_from_std`].
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as split ...
+/// ```
+///
+/// #### SeqPacket
+///
+/// The `seqpacket` method makes the service bind a `SOCK_SEQPACKET` socket rather than a stream
+/// one, and wraps connections in a [`seqpacket::SeqPacketConnection`] that sends and receives
+/// whole messages - their boundaries are kept by the socket itself, so there is no framing.
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as seqpacket ...
+/// ```
+///
+/// #### File descriptor passing
+///
+/// The `fdpass` method wraps the stream in a [`fdpass::FdPassingConnection`], which can send and
+/// receive file descriptors along with regular data.
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as fdpass ...
+/// ```
+///
+/// #### Compressed
+///
+/// The `compressed` method - available with the `compression-zstd` and `compression-lz4`
+/// features - wraps the stream in a [`compression::CompressedConnection`], which compresses
+/// everything written to it. Both sides agree on the best compression they were both built with
+/// when the connection is wrapped.
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as compressed ...
+/// ```
+///
+/// #### Encrypted
+///
+/// The `noise` method - available with the `noise` feature - wraps the stream in a
+/// [`noise::NoiseConnection`], which encrypts everything sent over it with keys only the
+/// clients and servers of the base context directory have. This needs a socket interface that
+/// can duplicate streams - see [`UnixSocketInterface::unix_stream_duplicate_fd`] - to find the
+/// context directory by the socket, so it doesn't work over other transports.
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as noise ...
+/// ```
+///
+/// #### Multiplexed
+///
+/// The `mux` method wraps the stream in a [`mux::Session`], which carries many concurrent
+/// [`mux::LogicalStream`]s over the one connection - either side can open them, and the other
+/// accepts them. Like `split`, this needs a socket interface that can take over std streams.
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as mux ...
+/// ```
+///
+/// #### Pub/sub
+///
+/// The `pubsub` method is for services that broadcast notifications. Clients get a
+/// [`pubsub::Subscription`] to read published messages from, while servers get the bare stream,
+/// to hand to [`pubsub::Hub::serve`].
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as pubsub ...
+/// ```
+///
+/// #### Runtime streams
+///
+/// The `tokio`, `async_io` and `smol` methods - available with the `tokio`, `async-io` and `smol`
+/// features - hand out the stream type of that runtime, whichever socket interface the service
+/// uses. See [`runtime_streams`] - with `tokio`, connections have to be wrapped within a tokio
+/// runtime.
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as tokio ...
+/// ```
+///
+/// #### Framed serde
+///
+/// The `framed serde` method - available with the `framed-serde` feature - wraps the stream in a
+/// [`framed::TypedConnection`] that exchanges whole, serde-encoded messages, so you never have to
+/// touch the stream itself. Clients send `Request`s and receive `Response`s, and servers the
+/// other way around.
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as framed serde <Request, Response> ...
+/// ```
+///
+/// #### RPC
+///
+/// The `rpc` method - also available with the `framed-serde` feature - is like `framed serde`,
+/// but tags every request with a correlation id so many can be in flight at once. Clients get an
+/// [`rpc::RpcConnection`] to `call` the server with, and servers an [`rpc::RpcServer`] to
+/// `serve` requests from, with a handler producing each reply.
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as rpc <Request, Response> ...
+/// ```
+///
+/// #### JSON lines
+///
+/// The `json_lines` method - available with the `json-lines` feature - is like `framed serde`,
+/// but wraps the stream in a [`json_lines::JsonLinesConnection`] that exchanges newline-delimited
+/// JSON values instead. This makes the service easy to talk to from non-Rust tooling.
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as json_lines <Request, Response> ...
+/// ```
+///
+/// #### HTTP
+///
+/// The `http` method - available with the `http` feature - wraps the stream in a
+/// [`http::HttpConnection`], ready to be used with hyper. This needs the [`socket_shims::TokioUSocks`]
+/// socket interface. Servers can use [`http::serve_http`].
+///
+/// ```rust,compile_fail
+///  ...rest-of-arg... as http ...
+/// ```
+///
+/// You can either implement a service over a specific socket implementation - either one of those
+/// defined in [`socket_shims`] or even your own custom implementation - or you can make a service
+/// generic over all of them by including some `impl<...>` parameters and constraints after the
+/// method. They can also go inside braces, as `impl {...}`, like in [`declare_service_bundle`].
 macro_rules! declare_service {
     {
         $(#[$service_meta:meta])*
-        $vis:vis $service_name:ident <$unix_sock_impl:ty> = {
-            $($command:literal $($args:literal)*)? @ $socket_name:literal
+        $vis:vis $service_name:ident <$($struct_param:ident),+ $(,)?> {$($fields:tt)*} <$unix_sock_impl:ty> = {
+            $($definition:tt)*
+        } $($impl_clause:tt)*
+    } => {
+        $crate::declare_service!{@impl_clause
+            [$(#[$service_meta])* $vis $service_name [$($struct_param),+] {$($fields)*} <$unix_sock_impl>]
+            {$($definition)*} $($impl_clause)*
+        }
+    };
+    {
+        $(#[$service_meta:meta])*
+        $vis:vis $service_name:ident $({$($fields:tt)*})? <$unix_sock_impl:ty> = {
+            $($definition:tt)*
+        } $($impl_clause:tt)*
+    } => {
+        $crate::declare_service!{@impl_clause
+            [$(#[$service_meta])* $vis $service_name [] $({$($fields)*})? <$unix_sock_impl>]
+            {$($definition)*} $($impl_clause)*
+        }
+    };
+    // Normalise `impl<...>` generics into the `impl {...}` form, tracking nested angle brackets so
+    // the generics end at the right `>`.
+    {@impl_clause [$($header:tt)*] {$($definition:tt)*}} => {
+        $crate::declare_service!{@cli [$($header)*] (self) [] $($definition)*}
+    };
+    {@impl_clause [$($header:tt)*] {$($definition:tt)*} impl {$($typeparam_constraints:tt)*}} => {
+        $crate::declare_service!{@cli [$($header)* impl {$($typeparam_constraints)*}] (self) [] $($definition)*}
+    };
+    {@impl_clause $header:tt $definition:tt impl < $($generics:tt)*} => {
+        $crate::declare_service!{@impl_generics $header $definition [] [] $($generics)*}
+    };
+    {@impl_generics $header:tt $definition:tt [$($generics:tt)*] [] >} => {
+        $crate::declare_service!{@impl_clause $header $definition impl {$($generics)*}}
+    };
+    {@impl_generics $header:tt $definition:tt $generics:tt [] > $($rest:tt)+} => {
+        ::core::compile_error!("Unexpected tokens after the impl generics of a service")
+    };
+    {@impl_generics $header:tt $definition:tt $generics:tt $depth:tt >> $($rest:tt)*} => {
+        $crate::declare_service!{@impl_generics $header $definition $generics $depth > > $($rest)*}
+    };
+    {@impl_generics $header:tt $definition:tt $generics:tt $depth:tt << $($rest:tt)*} => {
+        $crate::declare_service!{@impl_generics $header $definition $generics $depth < < $($rest)*}
+    };
+    {@impl_generics $header:tt $definition:tt [$($generics:tt)*] [< $($depth:tt)*] > $($rest:tt)*} => {
+        $crate::declare_service!{@impl_generics $header $definition [$($generics)* >] [$($depth)*] $($rest)*}
+    };
+    {@impl_generics $header:tt $definition:tt [$($generics:tt)*] [$($depth:tt)*] < $($rest:tt)*} => {
+        $crate::declare_service!{@impl_generics $header $definition [$($generics)* <] [< $($depth)*] $($rest)*}
+    };
+    {@impl_generics $header:tt $definition:tt [$($generics:tt)*] $depth:tt $next:tt $($rest:tt)*} => {
+        $crate::declare_service!{@impl_generics $header $definition [$($generics)* $next] $depth $($rest)*}
+    };
+    // Munch the command line and socket name, which may refer to fields of the service. `self`
+    // is only usable in the methods generated from it if it comes from the same place as the
+    // references, so the `self` of a reference becomes the receiver of those methods.
+    {@cli [$($header:tt)*] ($receiver:tt) [$($parts:tt)*] $part:literal $($rest:tt)*} => {
+        $crate::declare_service!{@cli [$($header)*] ($receiver) [$($parts)* (one $part)] $($rest)*}
+    };
+    {@cli [$($header:tt)*] ($receiver:tt) [$($parts:tt)*] $this:ident . $field:ident $($rest:tt)*} => {
+        $crate::declare_service!{@cli [$($header)*] ($this) [$($parts)* (one $this.$field)] $($rest)*}
+    };
+    {@cli [$($header:tt)*] ($receiver:tt) [$($parts:tt)*] ($($part:tt)*) $($rest:tt)*} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($part)*] {@cli [$($header)*]} {[$($parts)* (one $($part)*)] $($rest)*}}
+    };
+    {@cli [$($header:tt)*] ($receiver:tt) [$($parts:tt)*] .. ($($part:tt)*) $($rest:tt)*} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($part)*] {@cli [$($header)*]} {[$($parts)* (many $($part)*)] $($rest)*}}
+    };
+    {@cli [$($header:tt)*] ($receiver:tt) [$($parts:tt)*] @ $socket_name:literal $($rest:tt)*} => {
+        $crate::declare_service!{@clauses [$($header)*] ($receiver) [cli [$($parts)*] socket (borrowed $socket_name)] [] [] [] $($rest)*}
+    };
+    {@cli [$($header:tt)*] ($receiver:tt) [$($parts:tt)*] @ $this:ident . $field:ident $($rest:tt)*} => {
+        $crate::declare_service!{@clauses [$($header)*] ($this) [cli [$($parts)*] socket (borrowed $this.$field)] [] [] [] $($rest)*}
+    };
+    {@cli [$($header:tt)*] ($receiver:tt) [$($parts:tt)*] @ $macro_name:ident ! $macro_args:tt $($rest:tt)*} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$macro_args] {@clauses [$($header)*]} {[cli [$($parts)*] socket (owned $macro_name ! $macro_args)] [] [] [] $($rest)*}}
+    };
+    {@cli [$($header:tt)*] ($receiver:tt) [$($parts:tt)*] @ ($($socket_name:tt)*) $($rest:tt)*} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($socket_name)*] {@clauses [$($header)*]} {[cli [$($parts)*] socket (owned $($socket_name)*)] [] [] [] $($rest)*}}
+    };
+    {@cli [$($header:tt)*] ($receiver:tt) [$($parts:tt)*] @ {$($socket_name:tt)*} $($rest:tt)*} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($socket_name)*] {@clauses [$($header)*]} {[cli [$($parts)*] socket (owned {$($socket_name)*})] [] [] [] $($rest)*}}
+    };
+    // Munch the clauses about the command that follow the socket name - which may refer to fields
+    // of the service too.
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] env SUSS_CONTEXT_DIR $($rest:tt)*} => {
+        $crate::declare_service!{@clauses [$($header)*] ($receiver) [$($done)*] [$($env)* (context_dir)] [$($spawn)*] [$($defaults)*] $($rest)*}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] env $name:literal = $value:literal $($rest:tt)*} => {
+        $crate::declare_service!{@clauses [$($header)*] ($receiver) [$($done)*] [$($env)* (set $name $value)] [$($spawn)*] [$($defaults)*] $($rest)*}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] env $name:literal = $this:ident . $field:ident $($rest:tt)*} => {
+        $crate::declare_service!{@clauses [$($header)*] ($this) [$($done)*] [$($env)* (set $name $this.$field)] [$($spawn)*] [$($defaults)*] $($rest)*}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] env $name:literal = ($($value:tt)*) $($rest:tt)*} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($value)*] {@clauses [$($header)*]} {[$($done)*] [$($env)* (set $name $($value)*)] [$($spawn)*] [$($defaults)*] $($rest)*}}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] cwd = $current_dir:literal $($rest:tt)*} => {
+        $crate::declare_service!{@clauses [$($header)*] ($receiver) [$($done)*] [$($env)*] [$($spawn)* (current_dir ($current_dir))] [$($defaults)*] $($rest)*}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] cwd = $this:ident . $field:ident $($rest:tt)*} => {
+        $crate::declare_service!{@clauses [$($header)*] ($this) [$($done)*] [$($env)*] [$($spawn)* (current_dir ($this.$field))] [$($defaults)*] $($rest)*}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] cwd = ($($current_dir:tt)*) $($rest:tt)*} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($current_dir)*] {@clauses [$($header)*]} {[$($done)*] [$($env)*] [$($spawn)* (current_dir ($($current_dir)*))] [$($defaults)*] $($rest)*}}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] stdio = $stdio:ident $($rest:tt)*} => {
+        $crate::declare_service!{@clauses [$($header)*] ($receiver) [$($done)*] [$($env)*] [$($spawn)* (stdio $stdio)] [$($defaults)*] $($rest)*}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] @ tcp $address:literal $($rest:tt)*} => {
+        $crate::declare_service!{@clauses [$($header)*] ($receiver) [$($done)*] [$($env)*] [$($spawn)*] [$($defaults)* (tcp (literal $address))] $($rest)*}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] @ tcp ($($address:tt)*) $($rest:tt)*} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($address)*] {@clauses [$($header)*]} {[$($done)*] [$($env)*] [$($spawn)*] [$($defaults)* (tcp (expr $($address)*))] $($rest)*}}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] $timeout:ident = $duration:literal $($rest:tt)*} => {
+        $crate::declare_service!{@clauses [$($header)*] ($receiver) [$($done)*] [$($env)*] [$($spawn)*] [$($defaults)* ($timeout (literal $duration))] $($rest)*}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] $timeout:ident = ($($duration:tt)*) $($rest:tt)*} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($duration)*] {@clauses [$($header)*]} {[$($done)*] [$($env)*] [$($spawn)*] [$($defaults)* ($timeout (expr $($duration)*))] $($rest)*}}
+    };
+    {@clauses [$($header:tt)*] ($receiver:tt) [$($done:tt)*] [$($env:tt)*] [$($spawn:tt)*] [$($defaults:tt)*] $($rest:tt)*} => {
+        $crate::declare_service!{@service [$($header)*] ($receiver) $($done)* env [$($env)*] spawn [$($spawn)*] defaults [$($defaults)*] $($rest)*}
+    };
+    // Look through some tokens - groups and all - for a `self` to use as the receiver, then carry
+    // on with it put between the `before` and `after` tokens.
+    {@find_receiver ($receiver:tt) [] {$($before:tt)*} {$($after:tt)*}} => {
+        $crate::declare_service!{$($before)* ($receiver) $($after)*}
+    };
+    {@find_receiver ($receiver:tt) [($($inner:tt)*) $($scan:tt)*] $before:tt $after:tt} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($inner)* $($scan)*] $before $after}
+    };
+    {@find_receiver ($receiver:tt) [[$($inner:tt)*] $($scan:tt)*] $before:tt $after:tt} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($inner)* $($scan)*] $before $after}
+    };
+    {@find_receiver ($receiver:tt) [{$($inner:tt)*} $($scan:tt)*] $before:tt $after:tt} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($inner)* $($scan)*] $before $after}
+    };
+    {@find_receiver ($receiver:tt) [$first:tt $($scan:tt)*] $before:tt $after:tt} => {
+        $crate::declare_service!{@check_receiver ($first) ($first) ($receiver) [$($scan)*] $before $after}
+    };
+    {@check_receiver (self) ($this:tt) ($receiver:tt) [$($scan:tt)*] {$($before:tt)*} {$($after:tt)*}} => {
+        $crate::declare_service!{$($before)* ($this) $($after)*}
+    };
+    {@check_receiver ($other:tt) ($this:tt) ($receiver:tt) [$($scan:tt)*] $before:tt $after:tt} => {
+        $crate::declare_service!{@find_receiver ($receiver) [$($scan)*] $before $after}
+    };
+    {@service [
+        $(#[$service_meta:meta])*
+        $vis:vis $service_name:ident [$($struct_param:ident),*] $({$($fields:tt)*})? <$unix_sock_impl:ty> $(impl {$($typeparam_constraints:tt)*})?
+    ] ($receiver:tt) cli [$(($command_kind:ident $command:expr) $(($args_kind:ident $args:expr))*)?] socket ($socket_name_kind:ident $socket_name:expr) env [$($env:tt)*] spawn [$($command_spawn_option:tt)*] defaults [$($defaults:tt)*]
+                $(handshake $min_protocol_version:literal $(..= $max_protocol_version:literal)?)?
+                $(liveness $liveness_transport:ident)?
+                $(spawn { $($spawn_option:ident $(($($spawn_args:expr),* $(,)?))? $($spawn_value:ident)?),* $(,)? })?
+                $(children $child_strategy:ident $(($child_spawner:expr))?)?
+                $(in_process ($in_process_launch:expr))?
                 as $unix_stream_preprocess_method:ident $($unix_stream_preprocess_spec:tt)*
-        } $(impl {$($typeparam_constraints:tt)*})?
     } => {
+        $crate::declare_service!{@struct [$(#[$service_meta])*] $vis $service_name [$($struct_param),*] $({$($fields)*})?}
+
+        $crate::__service_async_impl! {
+        impl $(<$($typeparam_constraints)*>)? $crate::Service <$unix_sock_impl> for $service_name <$($struct_param),*> {
+            type ServiceClientConnection = $crate::declare_service!(@socket_connection_type ($unix_sock_impl) $unix_stream_preprocess_method $($unix_stream_preprocess_spec)*);
+            type ServiceServerConnection = $crate::declare_service!(@server_connection_type ($unix_sock_impl) $unix_stream_preprocess_method $($unix_stream_preprocess_spec)*);
+
+            #[inline]
+            fn socket_name(&$receiver) -> ::std::borrow::Cow<'_, ::std::ffi::OsStr> {
+                $crate::declare_service!(@socket_name $socket_name_kind $socket_name)
+            }
+
+            #[inline]
+            fn connect_timeout(&$receiver) -> ::core::option::Option<::core::time::Duration> {
+                $crate::declare_service!(@declared_connect_timeout (::core::option::Option::None) $($defaults)*)
+            }
+
+            #[inline]
+            fn transport(&$receiver) -> ::core::option::Option<::std::sync::Arc<dyn $crate::transport::Transport>> {
+                $crate::declare_service!(@declared_transport (::core::option::Option::None) $($defaults)*)
+            }
+
+            #[inline]
+            fn socket_kind(&$receiver) -> $crate::socket_shims::SocketKind {
+                $crate::declare_service!(@socket_kind $unix_stream_preprocess_method)
+            }
+
+            $(
+                // Parts are pushed one macro repetition at a time.
+                #[allow(clippy::vec_init_then_push)]
+                fn command_line(&$receiver) -> ::core::option::Option<::std::vec::Vec<::std::ffi::OsString>> {
+                    // Parts may use `?` on io errors, as they do when running the command - like
+                    // the current executable of self services.
+                    let collect = || -> ::std::io::Result<::std::vec::Vec<::std::ffi::OsString>> {
+                        let mut command_line = ::std::vec::Vec::new();
+                        $crate::declare_service!(@command_line_part command_line $command_kind $command);
+                        $($crate::declare_service!(@command_line_part command_line $args_kind $args);)*
+                        ::core::result::Result::Ok(command_line)
+                    };
+                    collect().ok()
+                }
+            )?
+
+            $(
+                #[inline]
+                fn handshake_protocol_versions(&self) -> ::core::option::Option<::core::ops::RangeInclusive<u32>> {
+                    ::core::option::Option::Some($crate::declare_service!(@protocol_versions $min_protocol_version $(..= $max_protocol_version)?))
+                }
+            )?
+
+            #[inline]
+            async fn wrap_connection(&self, bare_stream: <$unix_sock_impl as $crate::socket_shims::UnixSocketInterface>::UnixStream) -> ::std::io::Result<Self::ServiceClientConnection> {
+                $crate::declare_service!(@wrap_implementation ($unix_sock_impl) bare_stream $unix_stream_preprocess_method $($unix_stream_preprocess_spec)*)
+            }
+
+            #[inline]
+            async fn wrap_incoming(&self, bare_stream: <$unix_sock_impl as $crate::socket_shims::UnixSocketInterface>::UnixStream) -> ::std::io::Result<Self::ServiceServerConnection> {
+                $crate::declare_service!(@wrap_incoming_implementation ($unix_sock_impl) bare_stream $unix_stream_preprocess_method $($unix_stream_preprocess_spec)*)
+            }
+        }
+        }
+
+        $crate::declare_service!{@maybe_autostart_impl $(with_cli {($command_kind $command) $(($args_kind $args))*})? $(with_liveness $liveness_transport)? with_spawn {$($command_spawn_option)* $($(($spawn_option $(($($spawn_args),*))? $($spawn_value)?))*)?} $(with_children {$child_strategy $(($child_spawner))?})? $(with_in_process ($in_process_launch))? with_env [$($env)*] with_defaults [$($defaults)*] with_name $service_name [$($struct_param),*] <$unix_sock_impl> with_receiver $receiver $(with_constraints {$($typeparam_constraints)*})?}
+
+    };
+    {@struct [$(#[$service_meta:meta])*] $vis:vis $service_name:ident []} => {
         $(#[$service_meta])*
-        #[derive(Debug)]
+        #[derive(::core::fmt::Debug)]
         $vis struct $service_name;
+    };
+    {@struct [$(#[$service_meta:meta])*] $vis:vis $service_name:ident [$($struct_param:ident),*] {$($fields:tt)*}} => {
+        $(#[$service_meta])*
+        #[derive(::core::fmt::Debug)]
+        $vis struct $service_name <$($struct_param),*> {$($fields)*}
+    };
+    {@maybe_autostart_impl
+        with_cli {$(($part_kind:ident $part:expr))+}
+        $(with_liveness $liveness_transport:ident)?
+        with_spawn {$($spawn_option:tt)*}
+        $(with_children {$child_strategy:ident $(($child_spawner:expr))?})?
+        $(with_in_process ($in_process_launch:expr))?
+        with_env [$($env:tt)*]
+        with_defaults [$($defaults:tt)*]
+        with_name $service_name:ident [$($struct_param:ident),*] <$unix_sock_impl:ty>
+            with_receiver $receiver:tt
+            $(with_constraints {$($typeparam_constraints:tt)*})?
+    } => {
+        impl $(<$($typeparam_constraints)*>)? $crate::ServiceStartable <$unix_sock_impl> for $service_name <$($struct_param),*> {
+            type ProcessHandle = ::std::process::Child;
+
+            $(
+                #[inline]
+                fn liveness_transport(&self) -> $crate::liveness::LivenessTransport {
+                    $crate::declare_service!(@liveness_transport $liveness_transport)
+                }
+            )?
+
+            #[inline]
+            fn liveness_timeout(&$receiver) -> ::core::time::Duration {
+                $crate::declare_service!(@declared_liveness_timeout ($crate::ConnectOptions::DEFAULT_LIVENESS_TIMEOUT) $($defaults)*)
+            }
+
+            #[inline]
+            fn spawn_options(&$receiver) -> $crate::spawn::SpawnOptions {
+                $crate::declare_service!(@spawn_options ($crate::spawn::SpawnOptions::new()) $($spawn_option)*)
+            }
+
+            $(
+                #[inline]
+                fn child_strategy(&self) -> $crate::child::ChildStrategy {
+                    $crate::declare_service!(@child_strategy $child_strategy $(($child_spawner))?)
+                }
+            )?
+
+            $(
+                #[inline]
+                fn launcher(&self) -> $crate::launcher::ServiceLauncher {
+                    $crate::launcher::ServiceLauncher::in_process($in_process_launch)
+                }
+            )?
+
+            fn run_service_command_raw(
+                &$receiver,
+                executor_commandline_prefix: ::core::option::Option<&[impl ::core::convert::AsRef<::std::ffi::OsStr> + ::std::fmt::Debug]>,
+                liveness_path: ::core::option::Option<&::std::path::Path>,
+                liveness_token: ::core::option::Option<&str>,
+                prepared_spawn: $crate::spawn::PreparedSpawn,
+            ) -> ::std::io::Result<Self::ProcessHandle> {
+                use ::std::{process::Command, iter::{Iterator, IntoIterator}, ffi::{OsStr, OsString}};
+                use $crate::chain_trans::prelude::*;
+                // Collect all the CLI components and unconditionally take the first. This ends up
+                // being generally simpler in the long run than trying to wrangle matches and
+                // conditional inclusion of items.
+                let mut command_line: ::std::vec::Vec<OsString> = executor_commandline_prefix
+                    .into_iter()
+                    .flatten()
+                    .map(|component| OsStr::to_os_string(::core::convert::AsRef::<OsStr>::as_ref(component)))
+                    .collect();
+                $($crate::declare_service!(@command_line_part command_line $part_kind $part);)+
+                let mut all_components_iterator = command_line.into_iter();
+
+                let program = all_components_iterator.next().expect("There must be at least one thing in the iterator - the program to run, itself.");
+                Command::new(program)
+                    .trans_mut(|cmd| { $crate::liveness::set_liveness_environment(cmd, liveness_path, liveness_token); })
+                    $(.trans_mut(|cmd| { $crate::declare_service!(@env cmd prepared_spawn $env); }))*
+                    .trans_mut(|cmd| { prepared_spawn.apply(cmd); })
+                    .args(all_components_iterator)
+                    .spawn()
+            }
+        }
+    };
+    {@socket_name borrowed $socket_name:expr} => {
+        ::std::borrow::Cow::Borrowed(::core::convert::AsRef::<::std::ffi::OsStr>::as_ref(&$socket_name))
+    };
+    {@socket_name owned $socket_name:expr} => {
+        ::std::borrow::Cow::Owned(::std::ffi::OsStr::to_os_string(::core::convert::AsRef::<::std::ffi::OsStr>::as_ref(&$socket_name)))
+    };
+    // Pick the last of the declared timeouts of a kind, if any.
+    {@declared_liveness_timeout ($timeout:expr)} => { $timeout };
+    {@declared_liveness_timeout ($timeout:expr) (liveness_timeout $duration:tt) $($rest:tt)*} => {
+        $crate::declare_service!(@declared_liveness_timeout ($crate::declare_service!(@duration $duration)) $($rest)*)
+    };
+    {@declared_liveness_timeout ($timeout:expr) (connect_timeout $duration:tt) $($rest:tt)*} => {
+        $crate::declare_service!(@declared_liveness_timeout ($timeout) $($rest)*)
+    };
+    {@declared_liveness_timeout ($timeout:expr) (tcp $address:tt) $($rest:tt)*} => {
+        $crate::declare_service!(@declared_liveness_timeout ($timeout) $($rest)*)
+    };
+    {@declared_connect_timeout ($timeout:expr)} => { $timeout };
+    {@declared_connect_timeout ($timeout:expr) (connect_timeout $duration:tt) $($rest:tt)*} => {
+        $crate::declare_service!(@declared_connect_timeout (::core::option::Option::Some($crate::declare_service!(@duration $duration))) $($rest)*)
+    };
+    {@declared_connect_timeout ($timeout:expr) (liveness_timeout $duration:tt) $($rest:tt)*} => {
+        $crate::declare_service!(@declared_connect_timeout ($timeout) $($rest)*)
+    };
+    {@declared_connect_timeout ($timeout:expr) (tcp $address:tt) $($rest:tt)*} => {
+        $crate::declare_service!(@declared_connect_timeout ($timeout) $($rest)*)
+    };
+    // The TCP transport, if one is declared.
+    {@declared_transport ($transport:expr)} => { $transport };
+    {@declared_transport ($transport:expr) (tcp $address:tt) $($rest:tt)*} => {
+        $crate::declare_service!(@declared_transport (::core::option::Option::Some(
+            ::std::sync::Arc::new($crate::transport::TcpTransport::new($crate::declare_service!(@tcp_address $address)))
+                as ::std::sync::Arc<dyn $crate::transport::Transport>
+        )) $($rest)*)
+    };
+    {@declared_transport ($transport:expr) ($other:ident $value:tt) $($rest:tt)*} => {
+        $crate::declare_service!(@declared_transport ($transport) $($rest)*)
+    };
+    {@tcp_address (literal $address:literal)} => { $address };
+    {@tcp_address (expr $address:expr)} => { $address };
+    // Parsed as a constant, so invalid durations fail to compile.
+    {@duration (literal $duration:literal)} => {{
+        const DECLARED: ::core::time::Duration =
+            $crate::connect_options::declared_duration(::core::stringify!($duration));
+        DECLARED
+    }};
+    {@duration (expr $duration:expr)} => { $duration };
+    {@env $command:ident $prepared_spawn:ident (set $name:literal $value:expr)} => {
+        $command.env($name, ::core::convert::AsRef::<::std::ffi::OsStr>::as_ref(&$value))
+    };
+    {@env $command:ident $prepared_spawn:ident (context_dir)} => {
+        if let ::core::option::Option::Some(context_dir) = $prepared_spawn.context_dir() {
+            $command.env($crate::environment::CONTEXT_DIR_ENV_VAR, context_dir);
+        }
+    };
+    {@command_line_part $command_line:ident one $part:expr} => {
+        $command_line.push(::std::ffi::OsStr::to_os_string(::core::convert::AsRef::<::std::ffi::OsStr>::as_ref(&$part)))
+    };
+    {@command_line_part $command_line:ident many $parts:expr} => {
+        $command_line.extend(::core::iter::IntoIterator::into_iter($parts).map(|part| {
+            ::std::ffi::OsStr::to_os_string(::core::convert::AsRef::<::std::ffi::OsStr>::as_ref(&part))
+        }))
+    };
+    // Services started in-process need no cli impl.
+    {@maybe_autostart_impl
+        $(with_liveness $liveness_transport:ident)?
+        with_spawn {$($spawn_option:tt)*}
+        $(with_children {$child_strategy:ident $(($child_spawner:expr))?})?
+        with_in_process ($in_process_launch:expr)
+        with_env [$($env:tt)*]
+        with_defaults [$($defaults:tt)*]
+        with_name $service_name:ident [$($struct_param:ident),*] <$unix_sock_impl:ty>
+            with_receiver $receiver:tt
+            $(with_constraints {$($typeparam_constraints:tt)*})?
+    } => {
+        impl $(<$($typeparam_constraints)*>)? $crate::ServiceStartable <$unix_sock_impl> for $service_name <$($struct_param),*> {
+            type ProcessHandle = ::std::process::Child;
+
+            #[inline]
+            fn liveness_timeout(&$receiver) -> ::core::time::Duration {
+                $crate::declare_service!(@declared_liveness_timeout ($crate::ConnectOptions::DEFAULT_LIVENESS_TIMEOUT) $($defaults)*)
+            }
+
+            #[inline]
+            fn launcher(&self) -> $crate::launcher::ServiceLauncher {
+                $crate::launcher::ServiceLauncher::in_process($in_process_launch)
+            }
+
+            fn run_service_command_raw(
+                &self,
+                _executor_commandline_prefix: ::core::option::Option<&[impl ::core::convert::AsRef<::std::ffi::OsStr> + ::std::fmt::Debug]>,
+                _liveness_path: ::core::option::Option<&::std::path::Path>,
+                _liveness_token: ::core::option::Option<&str>,
+                _prepared_spawn: $crate::spawn::PreparedSpawn,
+            ) -> ::std::io::Result<Self::ProcessHandle> {
+                ::core::result::Result::Err(::std::io::Error::new(
+                    ::std::io::ErrorKind::Unsupported,
+                    "service is only started in-process",
+                ))
+            }
+        }
+    };
+    // No [`ServiceStartable`] if no cli impl.
+    {@maybe_autostart_impl
+        $(with_liveness $liveness_transport:ident)?
+        with_spawn {$($spawn_option:tt)*}
+        $(with_children {$child_strategy:ident $(($child_spawner:expr))?})?
+        $(with_in_process ($in_process_launch:expr))?
+        with_env [$($env:tt)*]
+        with_defaults [$($defaults:tt)*]
+        with_name $service_name:ident [$($struct_param:ident),*] <$unix_sock_impl:ty>
+            with_receiver $receiver:tt
+            $(with_constraints {$($typeparam_constraints:tt)*})?
+    } => {};
+    // macro "method" for extracting the result type from the preprocess method and specification
+    {@liveness_transport temp_socket} => { $crate::liveness::LivenessTransport::TempSocket };
+    {@liveness_transport inherited_fd} => { $crate::liveness::LivenessTransport::InheritedFd };
+    {@liveness_transport authenticated_temp_socket} => {
+        $crate::liveness::LivenessTransport::AuthenticatedTempSocket
+    };
+    {@liveness_transport inherited_listener} => {
+        $crate::liveness::LivenessTransport::InheritedListener
+    };
+    {@child_strategy detach} => { $crate::child::ChildStrategy::Detach };
+    {@child_strategy reap ($spawner:expr)} => {
+        $crate::child::ChildStrategy::Reap(::std::sync::Arc::new($spawner))
+    };
+    {@child_strategy kill_on_drop} => { $crate::child::ChildStrategy::KillOnDrop };
+    // Fold the spawn options of a service into builder calls.
+    {@spawn_options ($options:expr)} => { $options };
+    {@spawn_options ($options:expr) (stdin $source:ident) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_stdin($crate::declare_service!(@input_source $source))) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (stdout $target:ident) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_stdout($crate::declare_service!(@output_target $target))) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (stderr $target:ident) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_stderr($crate::declare_service!(@output_target $target))) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (die_with_parent) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_die_with_parent()) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (detach) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_detach()) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (user ($uid:expr, $gid:expr)) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_user($uid, $gid)) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (nofile_limit ($limit:expr)) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_nofile_limit($limit)) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (core_limit ($limit:expr)) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_core_limit($limit)) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (address_space_limit ($limit:expr)) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_address_space_limit($limit)) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (nice ($nice:expr)) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_nice($nice)) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (io_priority ($io_priority:expr)) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_io_priority($io_priority)) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (current_dir ($current_dir:expr)) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options.with_current_dir(::core::convert::AsRef::<::std::path::Path>::as_ref(&$current_dir))) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (stdio null) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options) (stdin null) (stdout null) (stderr null) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (stdio inherit) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options) (stdin inherit) (stdout inherit) (stderr inherit) $($rest)*)
+    };
+    {@spawn_options ($options:expr) (stdio log) $($rest:tt)*} => {
+        $crate::declare_service!(@spawn_options ($options) (stdin null) (stdout log_file) (stderr log_file) $($rest)*)
+    };
+    {@input_source inherit} => { $crate::spawn::InputSource::Inherit };
+    {@input_source null} => { $crate::spawn::InputSource::Null };
+    {@output_target inherit} => { $crate::spawn::OutputTarget::Inherit };
+    {@output_target null} => { $crate::spawn::OutputTarget::Null };
+    {@output_target log_file} => { $crate::spawn::OutputTarget::LogFile };
+    {@output_target tracing} => { $crate::spawn::OutputTarget::Tracing };
+    {@protocol_versions $version:literal} => { $version..=$version };
+    {@protocol_versions $min_version:literal ..= $max_version:literal} => { $min_version..=$max_version };
+    {@socket_connection_type ($unix_sock_impl:ty) raw |$unix_socket:ident| -> Io<$result:ty> $body:block } => { $result };
+    {@socket_connection_type ($unix_sock_impl:ty) buffered} => {
+        $crate::buffered::BufferedConnection<$unix_sock_impl>
+    };
+    {@socket_connection_type ($unix_sock_impl:ty) split} => {
+        ($crate::split::ReadHalf<$unix_sock_impl>, $crate::split::WriteHalf<$unix_sock_impl>)
+    };
+    {@socket_connection_type ($unix_sock_impl:ty) tokio} => { $crate::runtime_streams::TokioStream };
+    {@socket_connection_type ($unix_sock_impl:ty) async_io} => { $crate::runtime_streams::AsyncIoStream };
+    {@socket_connection_type ($unix_sock_impl:ty) smol} => { $crate::runtime_streams::SmolStream };
+    {@socket_connection_type ($unix_sock_impl:ty) framed serde <$request:ty, $response:ty>} => {
+        $crate::framed::TypedConnection<$request, $response, $unix_sock_impl>
+    };
+    {@socket_connection_type ($unix_sock_impl:ty) json_lines <$request:ty, $response:ty>} => {
+        $crate::json_lines::JsonLinesConnection<$request, $response, $unix_sock_impl>
+    };
+    {@socket_connection_type ($unix_sock_impl:ty) rpc <$request:ty, $response:ty>} => {
+        $crate::rpc::RpcConnection<$request, $response, $unix_sock_impl>
+    };
+    {@socket_connection_type ($unix_sock_impl:ty) http} => { $crate::http::HttpConnection<$unix_sock_impl> };
+    {@socket_connection_type ($unix_sock_impl:ty) fdpass} => {
+        $crate::fdpass::FdPassingConnection<$unix_sock_impl>
+    };
+    {@socket_connection_type ($unix_sock_impl:ty) seqpacket} => {
+        $crate::seqpacket::SeqPacketConnection<$unix_sock_impl>
+    };
+    {@socket_connection_type ($unix_sock_impl:ty) compressed} => {
+        $crate::compression::CompressedConnection<$unix_sock_impl>
+    };
+    {@socket_connection_type ($unix_sock_impl:ty) noise} => {
+        $crate::noise::NoiseConnection<$unix_sock_impl>
+    };
+    {@socket_connection_type ($unix_sock_impl:ty) mux} => { $crate::mux::Session<$unix_sock_impl> };
+    {@socket_connection_type ($unix_sock_impl:ty) pubsub} => {
+        $crate::pubsub::Subscription<$unix_sock_impl>
+    };
+    // The server side of a connection, where it differs from the client side.
+    {@server_connection_type ($unix_sock_impl:ty) framed serde <$request:ty, $response:ty>} => {
+        $crate::framed::TypedConnection<$response, $request, $unix_sock_impl>
+    };
+    {@server_connection_type ($unix_sock_impl:ty) json_lines <$request:ty, $response:ty>} => {
+        $crate::json_lines::JsonLinesConnection<$response, $request, $unix_sock_impl>
+    };
+    {@server_connection_type ($unix_sock_impl:ty) rpc <$request:ty, $response:ty>} => {
+        $crate::rpc::RpcServer<$request, $response, $unix_sock_impl>
+    };
+    {@server_connection_type ($unix_sock_impl:ty) pubsub} => {
+        <$unix_sock_impl as $crate::socket_shims::UnixSocketInterface>::UnixStream
+    };
+    {@server_connection_type ($unix_sock_impl:ty) $($client_connection_spec:tt)*} => {
+        $crate::declare_service!(@socket_connection_type ($unix_sock_impl) $($client_connection_spec)*)
+    };
+    // macro "method" for implementing the connection wrapper stuff
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident raw |$unix_socket:ident| -> Io<$result:ty> $body:block} => {{
+        let inner_closure = |$unix_socket| -> ::std::io::Result<$result> { $body };
+        async { inner_closure($stream_ident) }.await
+    }};
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident buffered} => {
+        ::core::result::Result::Ok($crate::buffered::BufferedConnection::new($stream_ident))
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident split} => {
+        $crate::split::split($stream_ident).await
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident tokio} => {
+        $crate::runtime_streams::into_tokio::<$unix_sock_impl>($stream_ident).await
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident async_io} => {
+        $crate::runtime_streams::into_async_io::<$unix_sock_impl>($stream_ident).await
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident smol} => {
+        $crate::runtime_streams::into_smol::<$unix_sock_impl>($stream_ident).await
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident framed serde <$request:ty, $response:ty>} => {
+        ::core::result::Result::Ok($crate::framed::TypedConnection::new($stream_ident))
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident http} => {
+        ::core::result::Result::Ok($crate::http::TokioIo::new($stream_ident))
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident json_lines <$request:ty, $response:ty>} => {
+        ::core::result::Result::Ok($crate::json_lines::JsonLinesConnection::new($stream_ident))
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident rpc <$request:ty, $response:ty>} => {
+        $crate::rpc::RpcConnection::new($stream_ident).await
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident fdpass} => {
+        ::core::result::Result::Ok($crate::fdpass::FdPassingConnection::new($stream_ident))
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident seqpacket} => {
+        ::core::result::Result::Ok($crate::seqpacket::SeqPacketConnection::new($stream_ident))
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident compressed} => {
+        $crate::compression::CompressedConnection::negotiate($stream_ident).await
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident noise} => {
+        $crate::noise::NoiseConnection::client_in_context($stream_ident).await
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident mux} => {
+        $crate::mux::Session::<$unix_sock_impl>::client($stream_ident).await
+    };
+    {@wrap_implementation ($unix_sock_impl:ty) $stream_ident:ident pubsub} => {
+        ::core::result::Result::Ok($crate::pubsub::Subscription::new($stream_ident))
+    };
+    // Wrapping incoming connections, where it differs from wrapping outgoing ones.
+    {@wrap_incoming_implementation ($unix_sock_impl:ty) $stream_ident:ident mux} => {
+        $crate::mux::Session::<$unix_sock_impl>::server($stream_ident).await
+    };
+    {@wrap_incoming_implementation ($unix_sock_impl:ty) $stream_ident:ident noise} => {
+        $crate::noise::NoiseConnection::server_in_context($stream_ident).await
+    };
+    {@wrap_incoming_implementation ($unix_sock_impl:ty) $stream_ident:ident pubsub} => {
+        ::core::result::Result::Ok($stream_ident)
+    };
+    {@wrap_incoming_implementation ($unix_sock_impl:ty) $stream_ident:ident rpc <$request:ty, $response:ty>} => {
+        $crate::rpc::RpcServer::new($stream_ident).await
+    };
+    {@wrap_incoming_implementation ($unix_sock_impl:ty) $stream_ident:ident $($wrap_spec:tt)*} => {
+        $crate::declare_service!(@wrap_implementation ($unix_sock_impl) $stream_ident $($wrap_spec)*)
+    };
+    // The kind of socket the connection method needs.
+    {@socket_kind seqpacket} => { $crate::socket_shims::SocketKind::SeqPacket };
+    {@socket_kind $unix_stream_preprocess_method:ident} => { $crate::socket_shims::SocketKind::Stream };
+}
+
+#[macro_export]
+/// Like [`declare_service`], but for services whose server lives in the same binary as their
+/// clients, which is started again with a subcommand to run the server.
+///
+/// The command line in the definition is the subcommand and its arguments, rather than a program
+/// to run - the program is always [`std::env::current_exe`]. Everything else is as in
+/// [`declare_service`]:
+///
+/// ```rust,compile_fail
+/// declare_self_service! {
+///     /// Daemon of this very program
+///     pub Daemon <U> = {
+///         "daemon" "--quiet" @ "daemon.sock" env SUSS_CONTEXT_DIR as ...
+///     } impl<U: UnixSocketInterface>
+/// }
+/// ```
+///
+/// The server side of the binary can recognise the subcommand, and pick up the liveness socket it
+/// was started with, using [`self_service::SelfServiceInvocation`]. With `env SUSS_CONTEXT_DIR`,
+/// [`environment::ServerEnvironment::from_env`] works just as well.
+macro_rules! declare_self_service {
+    // Find the definition, and put the current executable in front of it as the program.
+    {@definition [$($declaration:tt)*] = {$($definition:tt)*} $($rest:tt)*} => {
+        $crate::declare_service!{
+            $($declaration)* = { (::std::env::current_exe()?) $($definition)* } $($rest)*
+        }
+    };
+    {@definition [$($declaration:tt)*] $next:tt $($rest:tt)*} => {
+        $crate::declare_self_service!{@definition [$($declaration)* $next] $($rest)*}
+    };
+    {$($declaration:tt)*} => {
+        $crate::declare_self_service!{@definition [] $($declaration)*}
+    };
+}
+
+#[macro_export]
+/// This macro lets you create a service bundle, for unified initialisation of a collection of
+/// services.
+///
+/// The generated structure implements [`ServiceBundle`] as well as providing methods for reifying
+/// the services associated with it. Documentation for the services is attached to the service
+/// type. The structure has an attached unix socket type so it is easy to specify the unix socket
+/// interface for the entire bundle in one go - the functions available on the structure are
+/// exactly those where the service is valid for the socket type.
+///
+/// For documentation on how service definitions work, see [`declare_service`]. The only difference
+/// is that instead of `pub ServiceTypeName <unix socket interface type> = ` we
+/// have `pub service_bundle_function_name() -> ServiceTypeName <unix socket interface type> = `
+///
+/// This is used like the following:
+/// ```rust,compile_fail
+/// use suss::{declare_service_bundle, ServiceBundle};
+///
+/// declare_service_bundle!{
+///     /// Some wonderful docs
+///     pub WonderfulServices <WonderfulUnixSocketImplementationParameter>  {
+///         /// This is a wonderful service that prints hello
+///         pub fn wonderful_hello_service() -> WonderfulHelloService<U> = { ... service_definition ... } impl {U: UnixSocketInterface};
+///         /// This is a wonderful crate-private service that echos back written data
+///         pub(crate) fn wonderful_echo_service() -> WonderfulEchoService <U> = { ... definition ... };
+///     }
+/// }
+///
+/// // If a service needs to be started, this is the time to wait before assuming there was an
+/// // error if the liveness socket doesn't get pinged
+/// let liveness_timeout = std::time::Duration::from_milli(500);
+///
+/// // Configure the shared runtime directory for all your services.
+/// let wonderful_bundle = WonderfulServices::new("/fancy/rumtime/base/directory");
+/// // Inside crate only
+/// let echo_api = wonderful_bundle.wonderful_echo_service().connect_with_timeout(liveness_timeout).await?;
+/// // public interface
+/// let hello_api = wonderful_bundle.wonderful_hello_service().connect_with_timeout(liveness_timeout).await?;
+/// // Try to connect to an already running service.
+/// let hello_api_two = wonderful_bundle.wonderful_hello_service().connect_to_running().await?;
+/// ```
+///
+/// ### Dependencies
+///
+/// Services in a bundle can require other services of the same bundle to be running, by listing
+/// their service types after the definition:
+/// ```rust,compile_fail
+///         pub fn wonderful_hello_service() -> WonderfulHelloService<U> = { ... } impl {U: UnixSocketInterface}
+///             requires [WonderfulEchoService];
+/// ```
+///
+/// When [`ReifiedService::connect`] has to start a service, it first starts all of the (transitive)
+/// dependencies of that service, in topological order. Required services must be startable with
+/// the bundle's unix socket interface for the reification function to be available. Dependency
+/// cycles cause a compile error when reifying the affected services, and a panic when
+/// constructing the bundle - see [`dependencies::DependencyGraph`].
+///
+/// ### Manifest
+///
+/// Bundles also get a `describe` method, producing a [`bundle::BundleManifest`] of their services -
+/// socket names, command lines, dependencies and protocol versions - for external tooling and
+/// documentation. With the `manifest` feature, it can be serialized with serde:
+/// ```rust,compile_fail
+/// let manifest = serde_json::to_string_pretty(&wonderful_bundle.describe())?;
+/// ```
+macro_rules! declare_service_bundle {
+    {
+        $(#[$bundle_meta:meta])*
+        $bundle_vis:vis $bundle_name:ident <$socket_bundle_impl:ident> {$(
+            $(#[$service_meta:meta])*
+            $service_vis:vis fn $service_fn_name:ident () -> $service_type_name:ident <$unix_sock_impl:ty> = { $($service_definition:tt)*}
+                $(impl { $($unix_sock_constraints:tt)* })?
+                $(requires [$($dependency:ident),* $(,)?])?
+        );* $(;)?}
+    } => {
+
+        $(#[$bundle_meta])*
+        $bundle_vis struct $bundle_name <$socket_bundle_impl: $crate::socket_shims::UnixSocketInterface>{
+            executor_prefix: ::core::option::Option::<::std::vec::Vec::<::std::ffi::OsString>>,
+            base_context_path: ::std::path::PathBuf,
+            _socket_iface: ::core::marker::PhantomData<$socket_bundle_impl>
+        }
+
+        impl <$socket_bundle_impl: $crate::socket_shims::UnixSocketInterface> $crate::ServiceBundle for $bundle_name<$socket_bundle_impl>{
+            fn new(base_context_path: &::std::path::Path) -> Self {
+                use ::std::borrow::ToOwned;
+                if let ::core::result::Result::Err(cycle) = <Self as $crate::ServiceBundle>::dependency_graph().check_acyclic() {
+                    ::core::panic!("{}", cycle);
+                }
+                Self {
+                    base_context_path: base_context_path.to_owned(),
+                    executor_prefix: ::core::option::Option::None,
+                    _socket_iface: ::core::marker::PhantomData
+                }
+            }
+
+            fn with_executor_prefix(base_context_path: &::std::path::Path, executor_prefix: &[::std::ffi::OsString]) -> Self {
+                use ::std::borrow::ToOwned;
+                if let ::core::result::Result::Err(cycle) = <Self as $crate::ServiceBundle>::dependency_graph().check_acyclic() {
+                    ::core::panic!("{}", cycle);
+                }
+                Self {
+                    base_context_path: base_context_path.to_owned(),
+                    executor_prefix: ::core::option::Option::Some(executor_prefix.to_owned()),
+                    _socket_iface: ::core::marker::PhantomData
+                }
+            }
+
+            fn base_context_directory(&self) -> &::std::path::Path {
+                &self.base_context_path
+            }
+
+            fn executor_prefix(&self) -> ::core::option::Option<&[::std::ffi::OsString]> {
+                self.executor_prefix.as_deref()
+            }
+
+            fn dependency_graph() -> $crate::dependencies::DependencyGraph {
+                const SERVICES: &[(&str, &[&str])] = &[$(
+                    (::core::stringify!($service_type_name), &[$($(::core::stringify!($dependency)),*)?])
+                ),*];
+                $crate::dependencies::DependencyGraph::new(SERVICES)
+            }
+        }
+
+
+        // Implement service types by calling the declare_service! macro :)
+        $(
+            $crate::declare_service!{
+                $(#[$service_meta])*
+                $service_vis $service_type_name <$unix_sock_impl> = { $($service_definition)* } $(impl {$($unix_sock_constraints)*})?
+            }
+
+            #[$crate::async_trait(?Send)]
+            impl <$socket_bundle_impl: $crate::socket_shims::UnixSocketInterface>
+                $crate::dependencies::BundleDependencies<$bundle_name<$socket_bundle_impl>, $socket_bundle_impl> for $service_type_name
+                where
+                    $service_type_name: $crate::Service<$socket_bundle_impl>,
+                    $($($dependency: $crate::ServiceStartable<$socket_bundle_impl>
+                        + $crate::dependencies::BundleDependencies<$bundle_name<$socket_bundle_impl>, $socket_bundle_impl>,)*)?
+            {
+                #[allow(unused_variables)]
+                async fn start_dependencies(
+                    &self,
+                    bundle: &$bundle_name<$socket_bundle_impl>,
+                    liveness_timeout: ::core::time::Duration,
+                    already_started: &mut ::std::vec::Vec<&'static str>,
+                ) -> $crate::error::Result<()> {
+                    $($(
+                        if !already_started.contains(&::core::stringify!($dependency)) {
+                            $crate::dependencies::BundleDependencies::start_dependencies(&$dependency, bundle, liveness_timeout, already_started).await?;
+                            $crate::dependencies::ensure_dependency_started(
+                                &$crate::ServiceBundle::reify::<_, $socket_bundle_impl>(bundle, $dependency),
+                                liveness_timeout
+                            ).await?;
+                            already_started.push(::core::stringify!($dependency));
+                        }
+                    )*)?
+                    ::core::result::Result::Ok(())
+                }
+            }
+        )*
+
+        // Now create the reification functions on our service bundle :)
+        impl <$socket_bundle_impl: $crate::socket_shims::UnixSocketInterface> $bundle_name<$socket_bundle_impl> {$(
+            $service_vis fn $service_fn_name(&self) -> $crate::ReifiedService<'_, $service_type_name, $socket_bundle_impl>
+                where $service_type_name: $crate::dependencies::BundleDependencies<Self, $socket_bundle_impl>
+            {
+                let reified = $crate::ServiceBundle::reify(self, $service_type_name);
+                let dependency_graph = <Self as $crate::ServiceBundle>::dependency_graph();
+                if dependency_graph.dependencies_of(::core::stringify!($service_type_name)).is_empty() {
+                    return reified;
+                }
+                reified.with_dependency_starter(move |liveness_timeout| {
+                    ::std::boxed::Box::pin(async move {
+                        $crate::dependencies::BundleDependencies::<Self, $socket_bundle_impl>::start_dependencies(
+                            &$service_type_name,
+                            self,
+                            liveness_timeout,
+                            &mut ::std::vec::Vec::new()
+                        ).await
+                    })
+                })
+            }
+        )*}
+
+        impl <$socket_bundle_impl: $crate::socket_shims::UnixSocketInterface> $bundle_name<$socket_bundle_impl>
+            where $($service_type_name: $crate::dependencies::BundleDependencies<Self, $socket_bundle_impl>),*
+        {
+            /// Describe the services of this bundle and the dependencies between them - see
+            /// `suss::bundle::BundleManifest`.
+            #[allow(dead_code)]
+            $bundle_vis fn describe(&self) -> $crate::bundle::BundleManifest {
+                let dependency_graph = <Self as $crate::ServiceBundle>::dependency_graph();
+                $crate::bundle::BundleManifest {
+                    bundle: ::std::string::String::from(::core::stringify!($bundle_name)),
+                    services: ::std::vec![$(
+                        $crate::bundle::ServiceManifest::describe::<_, $socket_bundle_impl>(
+                            ::core::stringify!($service_type_name),
+                            &$service_type_name,
+                            dependency_graph.dependencies_of(::core::stringify!($service_type_name)),
+                        )
+                    ),*],
+                }
+            }
+
+            /// Make sure every service of this bundle is running - see
+            /// `ReifiedService::ensure_started` - with at most `parallelism` of them being started
+            /// at once.
+            #[allow(dead_code)]
+            $bundle_vis async fn start_all(
+                &self,
+                liveness_timeout: ::core::time::Duration,
+                parallelism: usize,
+            ) -> ::std::collections::BTreeMap<&'static str, $crate::error::Result<()>>
+                where $($service_type_name: $crate::ServiceStartable<$socket_bundle_impl>),*
+            {
+                $crate::bundle::run_bounded(::std::vec![$(
+                    (::core::stringify!($service_type_name), ::std::boxed::Box::pin(async move {
+                        self.$service_fn_name().ensure_started(liveness_timeout).await
+                    }) as $crate::bundle::BundleOperation<'_, _>)
+                ),*], parallelism).await
+            }
+
+            /// Check the status of every service of this bundle - see `ReifiedService::status` -
+            /// with at most `parallelism` of them being checked at once.
+            #[allow(dead_code)]
+            $bundle_vis async fn health_check_all(
+                &self,
+                parallelism: usize,
+            ) -> ::std::collections::BTreeMap<&'static str, $crate::ServiceStatus> {
+                $crate::bundle::run_bounded(::std::vec![$(
+                    (::core::stringify!($service_type_name), ::std::boxed::Box::pin(async move {
+                        self.$service_fn_name().status().await
+                    }) as $crate::bundle::BundleOperation<'_, _>)
+                ),*], parallelism).await
+            }
+
+            /// Stop every running service of this bundle - see `ReifiedService::stop` - with at
+            /// most `parallelism` of them being stopped at once. Services that aren't running are
+            /// left alone.
+            #[allow(dead_code)]
+            $bundle_vis async fn stop_all(
+                &self,
+                timeout: ::core::time::Duration,
+                parallelism: usize,
+            ) -> ::std::collections::BTreeMap<&'static str, $crate::error::Result<()>> {
+                $crate::bundle::run_bounded(::std::vec![$(
+                    (::core::stringify!($service_type_name), ::std::boxed::Box::pin(async move {
+                        let reified = self.$service_fn_name();
+                        match reified.status().await {
+                            $crate::ServiceStatus::Running { .. }
+                            | $crate::ServiceStatus::Unresponsive { .. } => reified.stop(timeout).await,
+                            _ => ::core::result::Result::Ok(()),
+                        }
+                    }) as $crate::bundle::BundleOperation<'_, _>)
+                ),*], parallelism).await
+            }
+        }
+    }
+}
+
+#[macro_export]
+/// Connect to several services at once, starting any that aren't running, for applications that
+/// need all of them before they can do anything.
+///
+/// This produces a future of a tuple of the connections, in the order the services were given,
+/// or of the first error connecting to any of them - in which case the other connections are
+/// dropped. The services are connected to concurrently with
+/// [`ReifiedService::connect_with_timeout`], each with the same liveness timeout, so starting
+/// several of them takes about as long as starting the slowest rather than all of them one after
+/// the other.
+///
+/// This is used like the following:
+/// ```rust,compile_fail
+/// let (echo_api, hello_api, greeting_api) = suss::connect_all!(liveness_timeout;
+///     bundle.echo_service(),
+///     bundle.hello_service(),
+///     bundle.greeting_service(),
+/// ).await?;
+/// ```
+macro_rules! connect_all {
+    {$liveness_timeout:expr; $($service:expr),+ $(,)?} => {
+        $crate::connect_all!{@name ($liveness_timeout) [] $($service),+}
+    };
+    // Pair every service with a variable for its connection - each step of the recursion
+    // introduces a `connection` of its own, which hygiene keeps apart from the others.
+    {@name ($liveness_timeout:expr) [$($named:tt)*] $service:expr $(, $rest:expr)*} => {
+        $crate::connect_all!{@name ($liveness_timeout) [$($named)* ($service, connection)] $($rest),*}
+    };
+    {@name ($liveness_timeout:expr) [$(($service:expr, $connection:ident))+]} => {
+        async {
+            let liveness_timeout: ::core::time::Duration = $liveness_timeout;
+            let $crate::connect_all!(@pattern $(($connection))+) =
+                $crate::connect_all!(@zip (liveness_timeout) $(($service))+).await?;
+            ::core::result::Result::<_, $crate::error::Error>::Ok(($($connection,)+))
+        }
+    };
+    {@zip ($liveness_timeout:ident) ($service:expr)} => {
+        $service.connect_with_timeout($liveness_timeout)
+    };
+    {@zip ($liveness_timeout:ident) ($service:expr) $($rest:tt)+} => {
+        $crate::future::try_zip(
+            $service.connect_with_timeout($liveness_timeout),
+            $crate::connect_all!(@zip ($liveness_timeout) $($rest)+),
+        )
+    };
+    {@pattern ($connection:ident)} => {
+        $connection
+    };
+    {@pattern ($connection:ident) $($rest:tt)+} => {
+        ($connection, $crate::connect_all!(@pattern $($rest)+))
+    };
+}
+
+/// Module for usually-necessary imports.
+pub mod prelude {
+    pub use super::{
+        connect_all, declare_service, declare_service_bundle, DefaultUnixSocks, ReifiedService,
+        ServiceBundle, ServiceExt, UnixSocketInterface,
+    };
+    pub use futures_lite::future::block_on as futures_lite_block_on;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+
+    use futures_lite::{future::block_on, StreamExt};
+
+    use crate::socket_shims::{SocketKind, StdThreadpoolUSocks};
+
+    use super::*;
+
+    /// Server usable with any of the test services, which never finishes by itself unless told to
+    /// accept a client
+    #[derive(Debug, Default)]
+    struct TestServer {
+        /// Where to notify readiness, if anywhere
+        readiness_notification_fd: Option<std::os::fd::RawFd>,
+        /// Whether to finish once a single client is accepted
+        accepts: bool,
+    }
+
+    #[async_trait]
+    impl<S: Service<StdThreadpoolUSocks> + Sync> Server<S, StdThreadpoolUSocks> for TestServer {
+        type ListenerWrapper = <StdThreadpoolUSocks as UnixSocketInterface>::UnixListener;
+        type FinalOutput = ();
+
+        async fn wrap_listener_socket(
+            &self,
+            _service: &S,
+            socket: Self::ListenerWrapper,
+        ) -> IoResult<Self::ListenerWrapper> {
+            Ok(socket)
+        }
+
+        fn readiness_notification_fd(&self) -> Option<std::os::fd::RawFd> {
+            self.readiness_notification_fd
+        }
+
+        async fn run_server(
+            &self,
+            _service: &S,
+            mut wrapper: Self::ListenerWrapper,
+        ) -> IoResult<()> {
+            match self.accepts {
+                true => {
+                    wrapper
+                        .with_mut(|listener| listener.accept().map(drop))
+                        .await
+                }
+                false => futures_lite::future::pending().await,
+            }
+        }
+    }
+
+    #[test]
+    pub fn service_declaration_and_start_fail_test() {
+        let tmpdir = temp_dir();
+        declare_service! {
+            /// Basic test service
+            pub TestService <U> = {
+                "sfdjfkosdgjsadgjlas" @ "test-service.sock" as raw |unix_socket| -> Io<U::UnixStream>  {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+
+        assert!(matches!(
+            block_on(
+                ServiceExt::<StdThreadpoolUSocks>::reify(TestService, &tmpdir)
+                    .connect_with_timeout(Duration::from_millis(50))
+            ),
+            Err(Error::SpawnFailed { .. })
+        ));
+
+        // service without a starting command
+        declare_service! {
+            /// Basic test service 2
+            #[allow(dead_code)]
+            pub TestService2 <U> = {@"test-service-2.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                Ok(unix_socket)
+            }} impl {U: UnixSocketInterface}
+        }
+    }
+
+    #[test]
+    pub fn service_bundle_macro_test() {
+        declare_service_bundle! {
+            pub TestBundle <B> {
+                /// some service
+                pub fn echo_service() -> EchoService <StdThreadpoolUSocks> = {
+                    "echo-executable-wekdasjkfgnjsd" "--and" "--some" "--args" @ "echo-service.sock" as raw |unix_socket| ->
+                        Io< <StdThreadpoolUSocks as UnixSocketInterface>::UnixStream> { Ok(unix_socket) }
+                };
+                pub fn hello_service() -> HelloService<U> = {
+                    "hello-executable-fjskldgkjsagd" "a" @ "hello-service.sock" as raw |unix_socket| -> Io<U::UnixStream> { Ok(unix_socket) }
+                } impl {U: UnixSocketInterface} requires [EchoService];
+                pub fn greeting_service() -> GreetingService<U> = {
+                    "greeting-executable-gjkdsfhgkdfs" @ "greeting-service.sock" as raw |unix_socket| -> Io<U::UnixStream> { Ok(unix_socket) }
+                } impl {U: UnixSocketInterface} requires [HelloService, EchoService];
+            }
+        }
+
+        let dependency_graph = TestBundle::<StdThreadpoolUSocks>::dependency_graph();
+        assert_eq!(
+            dependency_graph.start_order("GreetingService"),
+            Ok(vec!["EchoService", "HelloService"])
+        );
+
+        let tmpdir = temp_dir();
+        let wonderful_bundle = TestBundle::<StdThreadpoolUSocks>::new(&tmpdir);
+        let manifest = wonderful_bundle.describe();
+        assert_eq!(manifest.bundle, "TestBundle");
+        assert_eq!(
+            manifest
+                .services
+                .iter()
+                .map(|service| service.name.as_str())
+                .collect::<Vec<_>>(),
+            ["EchoService", "HelloService", "GreetingService"]
+        );
+        let greeting = manifest.service("GreetingService").unwrap();
+        assert_eq!(greeting.socket_name, "greeting-service.sock");
+        assert_eq!(
+            greeting.command_line.as_deref(),
+            Some(&["greeting-executable-gjkdsfhgkdfs".to_owned()][..])
+        );
+        assert_eq!(greeting.dependencies, ["HelloService", "EchoService"]);
+        assert_eq!(greeting.protocol_versions, None);
+        assert_eq!(
+            manifest.service("EchoService").unwrap().command_line,
+            Some(
+                [
+                    "echo-executable-wekdasjkfgnjsd",
+                    "--and",
+                    "--some",
+                    "--args"
+                ]
+                .map(String::from)
+                .to_vec()
+            )
+        );
+        assert!(block_on(
+            wonderful_bundle
+                .echo_service()
+                .connect_with_timeout(Duration::from_millis(50))
+        )
+        .is_err());
+        assert!(block_on(
+            wonderful_bundle
+                .hello_service()
+                .connect_with_timeout(Duration::from_millis(50))
+        )
+        .is_err());
+        // Fails on starting the echo service dependency
+        assert!(block_on(
+            wonderful_bundle
+                .greeting_service()
+                .connect_with_timeout(Duration::from_millis(50))
+        )
+        .is_err())
+    }
+
+    #[test]
+    pub fn stale_socket_rebind_test() {
+        let service_socket = ServiceSocket::new(
+            OsStr::new(&format!("stale-test-{}.sock", std::process::id())),
+            &temp_dir(),
+        );
+        // Leave a socket file behind with nothing listening on it.
+        drop(std::os::unix::net::UnixListener::bind(&service_socket.path).unwrap());
+        let listener = block_on(bind_service_socket::<StdThreadpoolUSocks>(
+            &service_socket,
+            &SocketPermissions::new(),
+            &SecurityPolicy::new(),
+            SocketKind::Stream,
+        ))
+        .expect("stale socket should be replaced");
+
+        // Now the socket is live, so binding should fail without removing it.
+        assert!(matches!(
+            block_on(bind_service_socket::<StdThreadpoolUSocks>(
+                &service_socket,
+                &SocketPermissions::new(),
+                &SecurityPolicy::new(),
+                SocketKind::Stream,
+            )),
+            Err(Error::BindFailed { .. })
+        ));
+        assert!(service_socket.path.exists());
+
+        drop(listener);
+        std::fs::remove_file(&service_socket.path).unwrap();
+        std::fs::remove_file(service_socket.lock_path()).unwrap();
+    }
+
+    #[test]
+    pub fn stale_socket_client_removal_test() {
+        declare_service! {
+            /// Service that leaves a stale socket behind
+            pub StaleService <U> = {
+                "stale-executable-dfgjkhsdfjkg" @ "stale-client-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let tmpdir = temp_dir();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(StaleService, &tmpdir);
+        let service_socket = reified.service_socket();
+        let _ = std::fs::remove_file(&service_socket.path);
+        drop(std::os::unix::net::UnixListener::bind(&service_socket.path).unwrap());
+
+        assert!(matches!(
+            block_on(reified.connect_to_running()),
+            Err(Error::StaleSocket { .. })
+        ));
+        // The stale socket gets removed before trying to start the service.
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_millis(50))),
+            Err(Error::SpawnFailed { .. })
+        ));
+        assert!(!service_socket.path.exists());
+        std::fs::remove_file(service_socket.lock_path()).unwrap();
+    }
+
+    #[test]
+    pub fn handshake_version_mismatch_test() {
+        declare_service! {
+            /// Service speaking versions 1 to 2 of its protocol
+            pub OldService <U> = {
+                @ "handshake-test.sock" handshake 1 ..= 2 as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        declare_service! {
+            /// Service speaking version 3 of its protocol, on the same socket
+            pub NewService <U> = {
+                @ "handshake-test.sock" handshake 3 as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let server_context = context.clone();
+        let mut listener = block_on(StdThreadpoolUSocks::unix_listener_bind(
+            context.join("handshake-test.sock"),
+        ))
+        .unwrap();
+        let server = std::thread::spawn(move || {
+            block_on(async {
+                let reified = ServiceExt::<StdThreadpoolUSocks>::reify(OldService, &server_context);
+                for _ in 0..2 {
+                    let (stream, _) = StdThreadpoolUSocks::unix_listener_accept(&mut listener)
+                        .await
+                        .unwrap();
+                    let _ = reified.wrap_incoming_connection(stream).await;
+                }
+            })
+        });
+
+        assert!(block_on(
+            ServiceExt::<StdThreadpoolUSocks>::reify(OldService, &context).connect_to_running()
+        )
+        .is_ok());
+        match block_on(
+            ServiceExt::<StdThreadpoolUSocks>::reify(NewService, &context).connect_to_running(),
+        ) {
+            Err(Error::VersionMismatch { ours, theirs, .. }) => {
+                assert_eq!(ours, 3..=3);
+                assert_eq!(theirs, 1..=2);
+            }
+            other => panic!("unexpected connection result {:?}", other.map(|_| ())),
+        }
+        server.join().unwrap();
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[cfg(feature = "framed-serde")]
+    #[test]
+    pub fn framed_serde_service_test() {
+        declare_service! {
+            /// Service exchanging typed messages
+            pub FramedService <U> = {
+                @ "framed-service-test.sock" as framed serde <String, usize>
+            } impl {U: UnixSocketInterface}
+        }
+        let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        block_on(async {
+            let mut client = Service::<StdThreadpoolUSocks>::wrap_connection(
+                &FramedService,
+                blocking::Unblock::new(a),
+            )
+            .await
+            .unwrap();
+            let mut server = Service::<StdThreadpoolUSocks>::wrap_incoming(
+                &FramedService,
+                blocking::Unblock::new(b),
+            )
+            .await
+            .unwrap();
+            client.send(&"hello".to_owned()).await.unwrap();
+            assert_eq!(server.receive().await.unwrap(), Some("hello".to_owned()));
+        });
+    }
+
+    #[cfg(feature = "framed-serde")]
+    #[test]
+    pub fn rpc_service_test() {
+        declare_service! {
+            /// Service answering calls
+            pub RpcService <U> = {
+                @ "rpc-service-test.sock" as rpc <String, usize>
+            } impl {U: UnixSocketInterface}
+        }
+        let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        block_on(async {
+            let client = Service::<StdThreadpoolUSocks>::wrap_connection(
+                &RpcService,
+                blocking::Unblock::new(a),
+            )
+            .await
+            .unwrap();
+            let server = Service::<StdThreadpoolUSocks>::wrap_incoming(
+                &RpcService,
+                blocking::Unblock::new(b),
+            )
+            .await
+            .unwrap();
+            let served = server.serve(|request: String| async move { request.len() });
+            let calls = async {
+                assert_eq!(client.call(&"hello".to_owned()).await.unwrap(), 5);
+                client.close().await.unwrap();
+            };
+            let (served, ()) = futures_lite::future::zip(served, calls).await;
+            served.unwrap();
+        });
+    }
+
+    #[test]
+    pub fn seqpacket_service_test() {
+        declare_service! {
+            /// Service exchanging whole messages
+            pub SeqPacketService <U> = {
+                @ "seqpacket-service-test.sock" as seqpacket
+            } impl {U: UnixSocketInterface}
+        }
+        assert_eq!(
+            Service::<StdThreadpoolUSocks>::socket_kind(&SeqPacketService),
+            SocketKind::SeqPacket
+        );
+        let (a, b) = seqpacket::pair().unwrap();
+        block_on(async {
+            let mut client = Service::<StdThreadpoolUSocks>::wrap_connection(
+                &SeqPacketService,
+                blocking::Unblock::new(a),
+            )
+            .await
+            .unwrap();
+            let mut server = Service::<StdThreadpoolUSocks>::wrap_incoming(
+                &SeqPacketService,
+                blocking::Unblock::new(b),
+            )
+            .await
+            .unwrap();
+            client.send(b"hello").await.unwrap();
+            client.send(b"world").await.unwrap();
+            assert_eq!(server.recv().await.unwrap().unwrap(), b"hello");
+            assert_eq!(server.recv().await.unwrap().unwrap(), b"world");
+        });
+    }
+
+    #[cfg(any(feature = "compression-zstd", feature = "compression-lz4"))]
+    #[test]
+    pub fn compressed_service_test() {
+        declare_service! {
+            /// Service with compressed connections
+            pub CompressedService <U> = {
+                @ "compressed-service-test.sock" as compressed
+            } impl {U: UnixSocketInterface}
+        }
+        let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        block_on(async {
+            let (client, server) = futures_lite::future::zip(
+                Service::<StdThreadpoolUSocks>::wrap_connection(
+                    &CompressedService,
+                    blocking::Unblock::new(a),
+                ),
+                Service::<StdThreadpoolUSocks>::wrap_incoming(
+                    &CompressedService,
+                    blocking::Unblock::new(b),
+                ),
+            )
+            .await;
+            let (mut client, mut server) = (client.unwrap(), server.unwrap());
+            assert_ne!(client.compression(), compression::Compression::None);
+            client.write_all(&[7; 4096]).await.unwrap();
+            let mut received = [0; 4096];
+            server.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, [7; 4096]);
+        });
+    }
+
+    #[test]
+    pub fn mux_service_test() {
+        declare_service! {
+            /// Service with many streams per connection
+            pub MuxService <U> = {
+                @ "mux-service-test.sock" as mux
+            } impl {U: UnixSocketInterface}
+        }
+        let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        block_on(async {
+            let client = Service::<StdThreadpoolUSocks>::wrap_connection(
+                &MuxService,
+                blocking::Unblock::new(a),
+            )
+            .await
+            .unwrap();
+            let server = Service::<StdThreadpoolUSocks>::wrap_incoming(
+                &MuxService,
+                blocking::Unblock::new(b),
+            )
+            .await
+            .unwrap();
+            let mut opened = client.open().await.unwrap();
+            opened.write_all(b"hello").await.unwrap();
+            let mut accepted = server.accept().await.unwrap().unwrap();
+            let mut hello = [0; 5];
+            accepted.read_exact(&mut hello).await.unwrap();
+            assert_eq!(&hello, b"hello");
+            // Both sides opening streams would clash if they didn't take different roles.
+            let pushed = server.open().await.unwrap();
+            assert_ne!(pushed.id(), opened.id());
+        });
+    }
+
+    #[test]
+    pub fn pubsub_service_test() {
+        declare_service! {
+            /// Service broadcasting notifications
+            pub PubSubService <U> = {
+                @ "pubsub-service-test.sock" as pubsub
+            } impl {U: UnixSocketInterface}
+        }
+        let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        let hub = pubsub::Hub::new();
+        block_on(async {
+            let mut subscription = Service::<StdThreadpoolUSocks>::wrap_connection(
+                &PubSubService,
+                blocking::Unblock::new(a),
+            )
+            .await
+            .unwrap();
+            let incoming = Service::<StdThreadpoolUSocks>::wrap_incoming(
+                &PubSubService,
+                blocking::Unblock::new(b),
+            )
+            .await
+            .unwrap();
+            let (served, ()) =
+                futures_lite::future::zip(hub.serve::<StdThreadpoolUSocks>(incoming), async {
+                    hub.publish(&b"notified"[..]);
+                    hub.close();
+                    assert_eq!(subscription.next().await.unwrap().unwrap(), b"notified");
+                    assert_eq!(subscription.next().await.unwrap(), None);
+                })
+                .await;
+            served.unwrap();
+        });
+    }
+
+    #[test]
+    pub fn buffered_and_split_service_test() {
+        declare_service! {
+            /// Service with a buffered connection
+            pub BufferedService <U> = {
+                @ "buffered-service-test.sock" as buffered
+            } impl {U: UnixSocketInterface}
+        }
+        declare_service! {
+            /// Service with a split connection
+            pub SplitService <U> = {
+                @ "split-service-test.sock" as split
+            } impl {U: UnixSocketInterface}
+        }
+        let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        block_on(async {
+            let mut client = Service::<StdThreadpoolUSocks>::wrap_connection(
+                &BufferedService,
+                blocking::Unblock::new(a),
+            )
+            .await
+            .unwrap();
+            let (mut server_read, mut server_write) =
+                Service::<StdThreadpoolUSocks>::wrap_incoming(
+                    &SplitService,
+                    blocking::Unblock::new(b),
+                )
+                .await
+                .unwrap();
+            client.write_all(b"hello\n").await.unwrap();
+            client.flush().await.unwrap();
+            let mut received = [0u8; 6];
+            server_read.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"hello\n");
+            server_write.write_all(b"world\n").await.unwrap();
+            let mut line = String::new();
+            client.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "world\n");
+        });
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    pub fn runtime_stream_service_test() {
+        use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+        declare_service! {
+            /// Service handing out smol streams
+            pub SmolService <U> = {
+                @ "smol-service-test.sock" as smol
+            } impl {U: UnixSocketInterface}
+        }
+        let (a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        block_on(async {
+            let mut client: runtime_streams::SmolStream =
+                Service::<StdThreadpoolUSocks>::wrap_connection(
+                    &SmolService,
+                    blocking::Unblock::new(a),
+                )
+                .await
+                .unwrap();
+            let mut server = Service::<StdThreadpoolUSocks>::wrap_incoming(
+                &SmolService,
+                blocking::Unblock::new(b),
+            )
+            .await
+            .unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let mut received = [0u8; 5];
+            server.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"hello");
+        });
+    }
+
+    #[test]
+    pub fn in_process_service_test() {
+        use std::io::{Read, Write};
+
+        #[derive(Debug)]
+        struct PongServer;
+
+        #[async_trait]
+        impl Server<PongService, StdThreadpoolUSocks> for PongServer {
+            type ListenerWrapper = <StdThreadpoolUSocks as UnixSocketInterface>::UnixListener;
+            type FinalOutput = ();
+
+            async fn wrap_listener_socket(
+                &self,
+                _service: &PongService,
+                socket: Self::ListenerWrapper,
+            ) -> IoResult<Self::ListenerWrapper> {
+                Ok(socket)
+            }
+
+            async fn run_server(
+                &self,
+                _service: &PongService,
+                mut listener: Self::ListenerWrapper,
+            ) -> IoResult<()> {
+                // Not through the socket interface, whose futures aren't `Send` with the
+                // async-trait-compat feature - unlike those of servers. The stream is closed once
+                // written to, so the client reads to the end.
+                let (mut stream, _) = listener.with_mut(|listener| listener.accept()).await?;
+                blocking::unblock(move || stream.write_all(b"pong")).await
+            }
+        }
+
+        declare_service! {
+            /// Service whose server runs on a thread of the process connecting to it
+            pub PongService <U> = {
+                @ "in-process-test.sock" in_process (|launch: launcher::InProcessLaunch| {
+                    launcher::InProcessService::thread(move || {
+                        block_on(PongServer.start_and_run_server(
+                            &PongService,
+                            &launch.base_context_directory,
+                            Some(&launch.liveness_path),
+                        ))
+                    })
+                }) as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(PongService, &context);
+        let connection = block_on(reified.connect_with_timeout(Duration::from_secs(30))).unwrap();
+        let mut received = Vec::new();
+        block_on(connection.into_inner())
+            .read_to_end(&mut received)
+            .unwrap();
+        assert_eq!(received, b"pong");
+
+        // The command is never run, so there is none to describe.
+        assert_eq!(
+            Service::<StdThreadpoolUSocks>::command_line(&PongService),
+            None
+        );
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn spawn_exit_test() {
+        declare_service! {
+            /// Service whose process always fails straight away
+            pub CrashingService <U> = {
+                "false" @ "crashing-service-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(CrashingService, &context);
+        let mut events = reified.events();
+        let started = Instant::now();
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_secs(30))),
+            Err(Error::SpawnExited { status, .. }) if !status.success()
+        ));
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(
+            block_on(events.next()),
+            Some(ServiceEvent::Starting {
+                socket: reified.service_socket()
+            })
+        );
+        assert!(matches!(
+            block_on(events.next()),
+            Some(ServiceEvent::StartFailed { reason, .. }) if reason.contains("exited")
+        ));
+        drop(events);
+
+        assert!(matches!(
+            block_on(reified.ensure_started(Duration::from_secs(30))),
+            Err(Error::SpawnExited { .. })
+        ));
+
+        // Retrying keeps failing the same way, after waiting out the backoff in between.
+        let options = ConnectOptions::new(Duration::from_secs(30))
+            .with_retries(2)
+            .with_initial_backoff(Duration::from_millis(100));
+        let started = Instant::now();
+        assert!(matches!(
+            block_on(reified.connect_with_options(&options)),
+            Err(Error::SpawnExited { .. })
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        // A deadline too short for the backoff stops retries early.
+        let started = Instant::now();
+        assert!(matches!(
+            block_on(
+                reified.connect_with_options(&options.with_deadline(Duration::from_millis(50)))
+            ),
+            Err(Error::SpawnExited { .. })
+        ));
+        assert!(started.elapsed() < Duration::from_millis(300));
+
+        // Once the circuit breaker trips, the service isn't started again.
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(CrashingService, &context)
+            .with_start_throttle(throttle::StartThrottle::new().with_failure_threshold(2));
+        for _ in 0..2 {
+            assert!(matches!(
+                block_on(reified.connect_with_timeout(Duration::from_secs(30))),
+                Err(Error::SpawnExited { .. })
+            ));
+        }
+        let mut events = reified.events();
+        assert!(matches!(
+            block_on(reified.connect_with_options(
+                &ConnectOptions::new(Duration::from_secs(30)).with_retries(2)
+            )),
+            Err(Error::CircuitOpen { .. })
+        ));
+        assert!(block_on(futures_lite::future::poll_once(events.next())).is_none());
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn service_fields_test() {
+        declare_service! {
+            /// Service whose exit status and socket name are configured at runtime
+            pub ConfiguredService {
+                exit_code: String,
+                socket: String,
+            } <U> = {
+                "sh" "-c" "exit \"$0\"" self.exit_code @ self.socket
+                    as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let service = ConfiguredService {
+            exit_code: "7".to_owned(),
+            socket: "configured-service-test.sock".to_owned(),
+        };
+        assert_eq!(
+            Service::<StdThreadpoolUSocks>::socket_name(&service),
+            OsStr::new("configured-service-test.sock")
+        );
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(service, &context);
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_secs(30))),
+            Err(Error::SpawnExited { status, .. }) if status.code() == Some(7)
+        ));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn generic_service_test() {
+        mod hygiene {
+            // The expansion of `async_trait` itself needs the prelude.
+            #![cfg_attr(not(feature = "async-trait-compat"), no_implicit_prelude)]
+
+            crate::declare_service! {
+                /// Service generic over what its socket name is kept in, declared without any
+                /// imports
+                pub GenericService<N> { pub name: N } <U> = {
+                    "sh" "-c" "exit 5" @ self.name as raw |unix_socket| -> Io<U::UnixStream> {
+                        ::core::result::Result::Ok(unix_socket)
+                    }
+                } impl<N: ::core::convert::AsRef<::std::ffi::OsStr> + ::core::fmt::Debug, U: crate::UnixSocketInterface>
+            }
+        }
+        let service = hygiene::GenericService {
+            name: std::path::PathBuf::from("generic-service-test.sock"),
+        };
+        assert_eq!(
+            Service::<StdThreadpoolUSocks>::socket_name(&service),
+            OsStr::new("generic-service-test.sock")
+        );
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(service, &context);
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_secs(30))),
+            Err(Error::SpawnExited { status, .. }) if status.code() == Some(5)
+        ));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn self_service_test() {
+        declare_self_service! {
+            /// Service run by this very test binary, which lists its tests instead
+            pub SelfService <U> = {
+                "--list" @ "self-service-test.sock"
+                    stdio = log
+                    as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl<U: UnixSocketInterface>
+        }
+        assert_eq!(
+            Service::<StdThreadpoolUSocks>::command_line(&SelfService),
+            Some(vec![
+                std::env::current_exe().unwrap().into_os_string(),
+                OsString::from("--list")
+            ])
+        );
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(SelfService, &context);
+        // Listing the tests exits successfully, like a daemonising service, so this has to wait
+        // out the liveness timeout.
+        assert!(block_on(reified.connect_with_timeout(Duration::from_secs(2))).is_err());
+        assert!(std::fs::read_to_string(reified.service_socket().log_path())
+            .unwrap()
+            .contains("tests::self_service_test: test"));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn command_expressions_test() {
+        declare_service! {
+            /// Service whose command line is built from expressions
+            pub ExpressionService {
+                shell: std::path::PathBuf,
+                summands: Vec<String>,
+            } <U> = {
+                (self.shell) (concat!("-", "c")) (format!("exit $(({} + $0 + $1))", 1)) ..(&self.summands)
+                    @ "expression-service-test.sock"
+                    as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let service = ExpressionService {
+            shell: std::path::PathBuf::from("sh"),
+            summands: vec!["2".to_owned(), "3".to_owned()],
+        };
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(service, &context);
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_secs(30))),
+            Err(Error::SpawnExited { status, .. }) if status.code() == Some(6)
+        ));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn computed_socket_name_test() {
+        declare_service! {
+            /// Service with a socket for every shard
+            pub ShardedService { shard: u32 } <U> = {
+                @ format!("sharded-{}.sock", self.shard) as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        declare_service! {
+            /// Service whose socket name is worked out by a block
+            pub BlockNamedService { name: String } <U> = {
+                @ {
+                    let mut name = OsString::from(&self.name);
+                    name.push(".sock");
+                    name
+                } as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified =
+            ServiceExt::<StdThreadpoolUSocks>::reify(ShardedService { shard: 3 }, &context);
+        assert_eq!(
+            reified.service_socket().path,
+            context.join("sharded-3.sock")
+        );
+        let service = BlockNamedService {
+            name: "block-named".to_owned(),
+        };
+        assert_eq!(
+            Service::<StdThreadpoolUSocks>::socket_name(&service),
+            OsStr::new("block-named.sock")
+        );
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn environment_variables_test() {
+        declare_service! {
+            /// Service that exits with a status telling whether its environment is as declared
+            pub EnvironmentService { shard: u32, context: std::path::PathBuf } <U> = {
+                "sh" "-c" "[ \"$GREETING\" = hello ] && [ \"$SHARD\" = 4 ] && [ \"$SUSS_CONTEXT_DIR\" = \"$0\" ] && exit 5; exit 6"
+                    self.context
+                    @ "environment-service-test.sock"
+                    env "GREETING" = "hello"
+                    env "SHARD" = (self.shard.to_string())
+                    env SUSS_CONTEXT_DIR
+                    as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service = EnvironmentService {
+            shard: 4,
+            context: context.to_path_buf(),
+        };
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(service, &context);
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_secs(30))),
+            Err(Error::SpawnExited { status, .. }) if status.code() == Some(5)
+        ));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn working_directory_and_stdio_test() {
+        declare_service! {
+            /// Service that logs its working directory and fails
+            pub WorkingDirectoryService { dir: std::path::PathBuf } <U> = {
+                "sh" "-c" "pwd -P; exit 3" @ "working-directory-test.sock"
+                    cwd = self.dir
+                    stdio = log
+                    as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let dir = context.join("working-directory");
+        std::fs::create_dir(&dir).unwrap();
+        let service = WorkingDirectoryService { dir: dir.clone() };
+        assert_eq!(
+            ServiceStartable::<StdThreadpoolUSocks>::spawn_options(&service),
+            SpawnOptions::new()
+                .with_current_dir(&dir)
+                .with_stdin(spawn::InputSource::Null)
+                .with_stdout(spawn::OutputTarget::LogFile)
+                .with_stderr(spawn::OutputTarget::LogFile)
+        );
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(service, &context);
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_secs(30))),
+            Err(Error::SpawnExited { .. })
+        ));
+        assert_eq!(
+            std::fs::read_to_string(reified.service_socket().log_path()).unwrap(),
+            format!("{}\n", dir.canonicalize().unwrap().display())
+        );
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn declared_timeouts_test() {
+        declare_service! {
+            /// Service that fails without starting up
+            pub DeclaredTimeoutsService { grace: Duration } <U> = {
+                "sh" "-c" "exit 3" @ "declared-timeouts-test.sock"
+                    liveness_timeout = 20s
+                    connect_timeout = (self.grace * 2)
+                    as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let service = DeclaredTimeoutsService {
+            grace: Duration::from_millis(250),
+        };
+        assert_eq!(
+            ServiceStartable::<StdThreadpoolUSocks>::liveness_timeout(&service),
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            Service::<StdThreadpoolUSocks>::connect_timeout(&service),
+            Some(Duration::from_millis(500))
+        );
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(service, &context);
+        assert_eq!(
+            reified.default_connect_options(),
+            ConnectOptions::new(Duration::from_secs(20))
+                .with_connect_timeout(Duration::from_millis(500))
+        );
+        assert!(matches!(
+            block_on(reified.connect()),
+            Err(Error::SpawnExited { status, .. }) if status.code() == Some(3)
+        ));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn declared_tcp_transport_test() {
+        declare_service! {
+            /// Service reached over TCP
+            pub DeclaredTcpService <U> = {
+                "true" @ "declared-tcp-test.sock" @ tcp "127.0.0.1:0"
+                    connect_timeout = 2s
+                    as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let transport = Service::<StdThreadpoolUSocks>::transport(&DeclaredTcpService).unwrap();
+        assert!(format!("{:?}", transport).contains("127.0.0.1:0"));
+        assert_eq!(
+            Service::<StdThreadpoolUSocks>::connect_timeout(&DeclaredTcpService),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    pub fn ephemeral_dir_test() {
+        declare_service! {
+            /// Service that exits with a status telling whether its liveness socket is in the
+            /// configured directory
+            pub EphemeralDirService <U> = {
+                "sh" "-c" "case \"$SUSS_LIVENESS_SOCKET_PATH\" in */custom-ephemeral/temp-*.sock) exit 3;; *) exit 4;; esac"
+                    @ "ephemeral-dir-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let ephemeral_dir = context.join("custom-ephemeral");
+        std::fs::create_dir(&ephemeral_dir).unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(EphemeralDirService, &context)
+            .with_ephemeral_dir(&ephemeral_dir);
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_secs(30))),
+            Err(Error::SpawnExited { status, .. }) if status.code() == Some(3)
+        ));
+        // The liveness socket is cleaned up after the failed start.
+        assert_eq!(std::fs::read_dir(&ephemeral_dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn spawn_options_test() {
+        declare_service! {
+            /// Service whose process reads its standard input, writes some output and fails
+            pub LoggingService <U> = {
+                "sh" "-c" "read line; echo \"read $?\"; echo oops >&2; exit 3" @ "spawn-options-test.sock"
+                spawn {
+                    stdin null,
+                    stdout log_file,
+                    stderr tracing,
+                    die_with_parent,
+                    user(rustix::process::geteuid().as_raw(), rustix::process::getegid().as_raw()),
+                    nofile_limit(256),
+                    nice(5),
+                }
+                as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        assert_eq!(
+            ServiceStartable::<StdThreadpoolUSocks>::spawn_options(&LoggingService),
+            SpawnOptions::new()
+                .with_stdin(spawn::InputSource::Null)
+                .with_stdout(spawn::OutputTarget::LogFile)
+                .with_stderr(spawn::OutputTarget::Tracing)
+                .with_die_with_parent()
+                .with_user(
+                    rustix::process::geteuid().as_raw(),
+                    rustix::process::getegid().as_raw()
+                )
+                .with_nofile_limit(256)
+                .with_nice(5)
+        );
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(LoggingService, &context);
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_secs(10))),
+            Err(Error::SpawnExited { .. })
+        ));
+        // Reading from a null standard input fails straight away, rather than hanging.
+        assert_eq!(
+            std::fs::read_to_string(reified.service_socket().log_path()).unwrap(),
+            "read 1\n"
+        );
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn start_dedup_test() {
+        declare_service! {
+            /// Service started through an executor prefix that counts its starts and then fails
+            pub CountedService <U> = {
+                "counted-service" @ "start-dedup-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let count_path = context.join("starts");
+        let prefix = [
+            OsString::from("sh"),
+            OsString::from("-c"),
+            OsString::from("echo started >> \"$0\"; sleep 1; exit 1"),
+            count_path.clone().into_os_string(),
+        ];
+        let barrier = std::sync::Barrier::new(10);
+        std::thread::scope(|scope| {
+            for _ in 0..10 {
+                scope.spawn(|| {
+                    let reified = ServiceExt::<StdThreadpoolUSocks>::reify_with_executor(
+                        CountedService,
+                        &context,
+                        &prefix,
+                    );
+                    barrier.wait();
+                    assert!(
+                        block_on(reified.connect_with_timeout(Duration::from_secs(30))).is_err()
+                    );
+                });
+            }
+        });
+        assert_eq!(std::fs::read_to_string(&count_path).unwrap(), "started\n");
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn start_lock_test() {
+        declare_service! {
+            /// Service whose process always fails straight away
+            pub LockedService <U> = {
+                "false" @ "start-lock-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(LockedService, &context);
+        let service_socket = reified.service_socket();
+
+        // Another "process" holding the lock for too long means we time out without spawning.
+        let lock = block_on(LockFile::acquire(&service_socket.lock_path())).unwrap();
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_millis(200))),
+            Err(Error::LivenessTimeout { .. })
+        ));
+
+        // Once it has started the service and released the lock, we connect to that service.
+        let socket_path = service_socket.path.clone();
+        let starter = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            let listener = std::os::unix::net::UnixListener::bind(socket_path).unwrap();
+            drop(lock);
+            listener.accept().unwrap();
+        });
+        assert!(block_on(reified.connect_with_timeout(Duration::from_secs(10))).is_ok());
+        starter.join().unwrap();
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn blocking_connect_test() {
+        declare_service! {
+            /// Service whose process always fails straight away
+            pub CrashingBlockingService <U> = {
+                "false" @ "crashing-blocking-service-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        assert!(matches!(
+            ServiceExt::<StdThreadpoolUSocks>::connect_to_running_service_blocking(
+                &CrashingBlockingService,
+                &context
+            ),
+            Err(Error::ConnectFailed { .. })
+        ));
+        let started = Instant::now();
+        assert!(matches!(
+            ServiceExt::<StdThreadpoolUSocks>::connect_to_service_blocking(
+                &CrashingBlockingService,
+                None::<&[&OsStr]>,
+                &context,
+                Duration::from_secs(30)
+            ),
+            Err(Error::SpawnExited { status, .. }) if !status.success()
+        ));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // A running service is connected to directly.
+        let socket_path = context.join("crashing-blocking-service-test.sock");
+        let listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+        let client = ServiceExt::<StdThreadpoolUSocks>::connect_to_running_service_blocking(
+            &CrashingBlockingService,
+            &context,
+        );
+        assert!(client.is_ok());
+        listener.accept().unwrap();
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn liveness_failure_report_test() {
+        let service_socket = ServiceSocket::new(OsStr::new("liveness-report.sock"), &temp_dir());
+        let check = |report: Option<&'static str>| {
+            let (listener, path) =
+                block_on(ephemeral_liveness_socket_create::<StdThreadpoolUSocks>(
+                    &service_socket,
+                    &temp_dir(),
+                ))
+                .unwrap();
+            let liveness_path = path.as_ref().to_owned();
+            let child = std::thread::spawn(move || {
+                block_on(async {
+                    match report {
+                        Some(message) => {
+                            liveness::report_liveness_failure::<StdThreadpoolUSocks>(
+                                &liveness_path,
+                                message,
+                            )
+                            .await
+                        }
+                        None => notify_liveness_socket::<StdThreadpoolUSocks>(&liveness_path).await,
+                    }
+                })
+                .unwrap()
+            });
+            let result = block_on(ephemeral_liveness_socket_check_with_timeout::<
+                StdThreadpoolUSocks,
+            >(
+                &service_socket,
+                listener,
+                path,
+                None,
+                None,
+                Duration::from_secs(5),
+            ));
+            child.join().unwrap();
+            result
+        };
+
+        assert!(check(None).is_ok());
+        match check(Some("config file\nis missing")) {
+            Err(Error::StartupFailed { message, .. }) => {
+                assert_eq!(message, "config file is missing")
+            }
+            other => panic!("unexpected liveness result {:?}", other),
+        }
+    }
+
+    #[test]
+    pub fn liveness_token_test() {
+        use std::io::Write;
+
+        assert!(liveness::liveness_token_matches("0123abcd", b"0123abcd\n"));
+        assert!(!liveness::liveness_token_matches("0123abcd", b"0123abce\n"));
+        assert!(!liveness::liveness_token_matches("0123abcd", b"0123abc\n"));
+        assert!(!liveness::liveness_token_matches("0123abcd", b""));
+
+        let service_socket = ServiceSocket::new(OsStr::new("liveness-token.sock"), &temp_dir());
+        let check =
+            |pings: &'static [&'static str], timeout: Duration| {
+                let (listener, path) = block_on(ephemeral_liveness_socket_create::<
+                    StdThreadpoolUSocks,
+                >(&service_socket, &temp_dir()))
+                .unwrap();
+                let token = liveness::generate_liveness_token();
+                let liveness_path = path.as_ref().to_owned();
+                let child_token = token.clone();
+                let child = std::thread::spawn(move || {
+                    for ping in pings {
+                        let mut stream =
+                            std::os::unix::net::UnixStream::connect(&liveness_path).unwrap();
+                        let ping = ping.replace("TOKEN", &child_token);
+                        stream.write_all(ping.as_bytes()).unwrap();
+                    }
+                });
+                let result = block_on(ephemeral_liveness_socket_check_with_timeout::<
+                    StdThreadpoolUSocks,
+                >(
+                    &service_socket,
+                    listener,
+                    path,
+                    Some(&token),
+                    None,
+                    timeout,
+                ));
+                child.join().unwrap();
+                result
+            };
+
+        // Pings without the token are ignored, rather than counting as liveness or failure.
+        assert!(check(&["", "not the token\n", "TOKEN\n"], Duration::from_secs(5)).is_ok());
+        assert!(matches!(
+            check(&["ERR spoofed\n", "TOKEN\nERR bad config\n"], Duration::from_secs(5)),
+            Err(Error::StartupFailed { message, .. }) if message == "bad config"
+        ));
+        assert!(matches!(
+            check(&["", "ERR spoofed\n"], Duration::from_millis(200)),
+            Err(Error::LivenessTimeout { .. })
+        ));
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn liveness_peer_test() {
+        let service_socket = ServiceSocket::new(OsStr::new("liveness-peer.sock"), &temp_dir());
+        let check =
+            |child_pid: u32, timeout: Duration| {
+                let (listener, path) = block_on(ephemeral_liveness_socket_create::<
+                    StdThreadpoolUSocks,
+                >(&service_socket, &temp_dir()))
+                .unwrap();
+                let liveness_path = path.as_ref().to_owned();
+                let pinger = std::thread::spawn(move || {
+                    block_on(notify_liveness_socket::<StdThreadpoolUSocks>(
+                        &liveness_path,
+                    ))
+                    .unwrap()
+                });
+                let result = block_on(ephemeral_liveness_socket_check_with_timeout::<
+                    StdThreadpoolUSocks,
+                >(
+                    &service_socket,
+                    listener,
+                    path,
+                    None,
+                    Some(child_pid),
+                    timeout,
+                ));
+                pinger.join().unwrap();
+                result
+            };
+
+        // The ping comes from this very process.
+        assert!(check(std::process::id(), Duration::from_secs(5)).is_ok());
+        let mut unrelated = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        assert!(matches!(
+            check(unrelated.id(), Duration::from_millis(200)),
+            Err(Error::LivenessTimeout { .. })
+        ));
+        unrelated.kill().unwrap();
+        unrelated.wait().unwrap();
+    }
+
+    #[test]
+    pub fn liveness_token_process_test() {
+        declare_service! {
+            /// Service that exits with a status telling whether it was handed a liveness token
+            pub TokenLivenessService <U> = {
+                "sh" "-c" "test ${#SUSS_LIVENESS_TOKEN} -eq 32 && exit 3; exit 4" @ "token-liveness-test.sock"
+                liveness authenticated_temp_socket
+                as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(TokenLivenessService, &context);
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_secs(10))),
+            Err(Error::SpawnExited { status, .. }) if status.code() == Some(3)
+        ));
+        assert!(matches!(
+            ServiceExt::<StdThreadpoolUSocks>::connect_to_service_blocking(
+                &TokenLivenessService,
+                None::<&[&str]>,
+                &context,
+                Duration::from_secs(10)
+            ),
+            Err(Error::SpawnExited { status, .. }) if status.code() == Some(3)
+        ));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn inherited_fd_liveness_test() {
+        use std::os::fd::IntoRawFd;
+
+        let service_socket = ServiceSocket::new(OsStr::new("liveness-fd.sock"), &temp_dir());
+        let check = |report: Option<Option<&'static str>>| {
+            let (ours, theirs) = liveness::inheritable_pair().unwrap();
+            let liveness_path = liveness::inherited_fd_path(theirs.into_raw_fd());
+            let child = std::thread::spawn(move || {
+                block_on(async {
+                    match report {
+                        Some(Some(message)) => {
+                            liveness::report_liveness_failure::<StdThreadpoolUSocks>(
+                                &liveness_path,
+                                message,
+                            )
+                            .await
+                        }
+                        Some(None) => {
+                            notify_liveness_socket::<StdThreadpoolUSocks>(&liveness_path).await
+                        }
+                        // Like a crash - closing it without reporting anything.
+                        None => {
+                            let fd = liveness::inherited_liveness_fd(&liveness_path).unwrap();
+                            liveness::write_inherited_fd(fd, Vec::new()).await
+                        }
+                    }
+                })
+                .unwrap()
+            });
+            let result = block_on(inherited_fd_liveness_check_with_timeout(
+                &service_socket,
+                ours,
+                Duration::from_secs(5),
+            ));
+            child.join().unwrap();
+            result
+        };
+
+        assert!(check(Some(None)).is_ok());
+        assert!(matches!(
+            check(Some(Some("no config"))),
+            Err(Error::StartupFailed { message, .. }) if message == "no config"
+        ));
+        assert!(matches!(
+            check(None),
+            Err(Error::LivenessSocketFailed { source, .. })
+                if source.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test]
+    pub fn inherited_fd_liveness_process_test() {
+        declare_service! {
+            /// Service whose process reports liveness over its inherited file descriptor, but
+            /// never listens on its socket
+            pub FdLivenessService <U> = {
+                // Unlike bash, dash can't redirect to file descriptors above 9.
+                "bash" "-c" "eval \"echo >&${SUSS_LIVENESS_SOCKET_PATH#/dev/fd/}\"" @ "fd-liveness-test.sock"
+                liveness inherited_fd
+                as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        assert_eq!(
+            ServiceStartable::<StdThreadpoolUSocks>::liveness_transport(&FdLivenessService),
+            liveness::LivenessTransport::InheritedFd
+        );
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(FdLivenessService, &context);
+        let mut events = reified.events();
+        assert!(matches!(
+            block_on(reified.connect_with_timeout(Duration::from_secs(10))),
+            Err(Error::ConnectFailed { .. })
+        ));
+        block_on(events.next());
+        assert_eq!(
+            block_on(events.next()),
+            Some(ServiceEvent::LivenessReceived {
+                socket: reified.service_socket()
+            })
+        );
+        assert!(matches!(
+            ServiceExt::<StdThreadpoolUSocks>::connect_to_service_blocking(
+                &FdLivenessService,
+                None::<&[&str]>,
+                &context,
+                Duration::from_secs(10)
+            ),
+            Err(Error::ConnectFailed { .. })
+        ));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn inherited_listener_test() {
+        use std::os::fd::{AsRawFd, IntoRawFd};
+
+        let context = ContextDir::temp_for_tests().unwrap();
+        let socket_path = context.join("inherited-listener.sock");
+        let listener = liveness::inheritable_listener(&socket_path, SocketKind::Stream).unwrap();
+        let (_ours, theirs) = liveness::inheritable_pair().unwrap();
+        // SAFETY: both descriptors are open for the checks.
+        let inherited_listener_fd =
+            |fd| unsafe { liveness::inherited_listener_fd(&liveness::inherited_fd_path(fd)) };
+        assert_eq!(inherited_listener_fd(theirs.as_raw_fd()), None);
+        let fd = inherited_listener_fd(listener.as_raw_fd()).unwrap();
+        assert_eq!(fd, listener.into_raw_fd());
+
+        // SAFETY: the listener was given up with into_raw_fd.
+        let mut listener =
+            block_on(unsafe { liveness::listener_from_inherited_fd::<StdThreadpoolUSocks>(fd) })
+                .unwrap();
+        let _client = std::os::unix::net::UnixStream::connect(&socket_path).unwrap();
+        block_on(StdThreadpoolUSocks::unix_listener_accept(&mut listener)).unwrap();
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn inherited_listener_process_test() {
+        declare_service! {
+            /// Service whose process holds on to the listener it inherits, but never accepts
+            pub ListenerService <U> = {
+                "sleep" "2" @ "inherited-listener-test.sock"
+                liveness inherited_listener
+                as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(ListenerService, &context);
+        // Connections queue up on the inherited listener, without waiting for liveness.
+        block_on(reified.connect_with_timeout(Duration::from_secs(10))).unwrap();
+        assert!(reified.service_socket().path.exists());
+        std::fs::remove_dir_all(&context).unwrap();
+
+        let context = ContextDir::temp_for_tests().unwrap();
+        ServiceExt::<StdThreadpoolUSocks>::connect_to_service_blocking(
+            &ListenerService,
+            None::<&[&str]>,
+            &context,
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn task_spawner_reaps_service_test() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        declare_service! {
+            /// Service whose process holds on to the listener it inherits for a moment
+            pub ReapedService <U> = {
+                "sleep" "0.2" @ "reaped-service-test.sock"
+                liveness inherited_listener
+                as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        #[derive(Debug, Default)]
+        struct CountingSpawner(AtomicUsize);
+        impl TaskSpawner for CountingSpawner {
+            fn spawn_task(&self, task: task::Task) {
+                self.0.fetch_add(1, Ordering::AcqRel);
+                task::ThreadSpawner.spawn_task(task);
+            }
+        }
+
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(ReapedService, &context);
+        let spawner = Arc::new(CountingSpawner::default());
+        let options =
+            ConnectOptions::new(Duration::from_secs(10)).with_task_spawner(spawner.clone());
+        block_on(reified.connect_with_options(&options)).unwrap();
+        assert_eq!(spawner.0.load(Ordering::Acquire), 1);
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn child_strategy_test() {
+        declare_service! {
+            /// Service whose process holds on to the listener it inherits, until it is killed
+            pub GuardedService <U> = {
+                "sleep" "30" @ "guarded-service-test.sock"
+                liveness inherited_listener
+                children kill_on_drop
+                as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        declare_service! {
+            /// Service reaped on a thread of its own
+            pub ReapedService <U> = {
+                "true" @ "reaped-service-test.sock"
+                children reap(task::ThreadSpawner)
+                as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        assert!(matches!(
+            ServiceStartable::<StdThreadpoolUSocks>::child_strategy(&ReapedService),
+            child::ChildStrategy::Reap(_)
+        ));
+
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(GuardedService, &context);
+        let socket_path = reified.service_socket().path;
+        block_on(reified.connect_with_timeout(Duration::from_secs(10))).unwrap();
+        // Once the process is killed, nothing holds on to the listener any more.
+        drop(reified);
+        assert_eq!(
+            std::os::unix::net::UnixStream::connect(&socket_path)
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::ConnectionRefused
+        );
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn server_shutdown_test() {
+        declare_service! {
+            /// Service whose server never finishes by itself
+            pub EndlessService <U> = {
+                "endless-executable-hsdfjkgsd" @ "endless-shutdown-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+
+        let tmpdir = temp_dir();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(EndlessService, &tmpdir);
+        let service_socket = reified.service_socket();
+        let _ = std::fs::remove_file(&service_socket.path);
+
+        let output = block_on(reified.serve_service_implementation_with_shutdown(
+            &TestServer::default(),
+            None,
+            async {
+                assert_eq!(
+                    reified.status().await,
+                    ServiceStatus::Running {
+                        pid: Some(std::process::id())
+                    }
+                );
+                let state = reified.read_state().unwrap();
+                assert_eq!(state.suss_version, env!("CARGO_PKG_VERSION"));
+                assert_eq!(state.service_version, None);
+            },
+            Duration::from_millis(50),
+        ))
+        .unwrap();
+        assert_eq!(output, None);
+        assert!(!service_socket.path.exists());
+        assert!(!service_socket.state_path().exists());
+    }
+
+    #[test]
+    pub fn readiness_notification_test() {
+        use std::{io::Read, os::fd::IntoRawFd};
+
+        declare_service! {
+            /// Service whose server notifies readiness s6-style
+            pub NotifyingService <U> = {
+                "notifying-executable-adsfhjkl" @ "readiness-notification-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(NotifyingService, &context);
+        let (mut ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let server = TestServer {
+            readiness_notification_fd: Some(theirs.into_raw_fd()),
+            ..Default::default()
+        };
+        block_on(reified.serve_service_implementation_with_shutdown(
+            &server,
+            None,
+            async {
+                let mut notification = Vec::new();
+                ours.read_to_end(&mut notification).unwrap();
+                // The notification fd is closed once written to.
+                assert_eq!(notification, b"\n");
+                assert!(reified.service_socket().path.exists());
+            },
+            Duration::from_millis(50),
+        ))
+        .unwrap();
+        std::fs::remove_dir_all(&context).unwrap();
+    }
 
-        #[$crate::async_trait(?Send)]
-        impl $(<$($typeparam_constraints)*>)? $crate::Service <$unix_sock_impl> for $service_name {
-            type ServiceClientConnection = $crate::declare_service!(@socket_connection_type $unix_stream_preprocess_method $($unix_stream_preprocess_spec)*);
+    #[test]
+    pub fn hidden_bind_test() {
+        declare_service! {
+            /// Service whose server initializes before clients can find it
+            pub InitializingService <U> = {
+                "initializing-executable-adsfhjkl" @ "hidden-bind-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
 
-            #[inline]
-            fn socket_name(&self) -> &::std::ffi::OsStr {
-                ::std::ffi::OsStr::new($socket_name)
-            }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("hidden-bind-test.sock"), &context);
+        let server = TestServer::default();
+        block_on(async {
+            let hidden = server
+                .bind_hidden(&InitializingService, &context, None)
+                .await
+                .unwrap();
+            assert!(service_socket.hidden_path().exists());
+            assert!(!service_socket.path.exists());
 
-            #[inline]
-            async fn wrap_connection(&self, bare_stream: <$unix_sock_impl as $crate::socket_shims::UnixSocketInterface>::UnixStream) -> IoResult<Self::ServiceClientConnection>
-                where <$unix_sock_impl as $crate::socket_shims::UnixSocketInterface>::UnixStream: 'async_trait
-            {
-                $crate::declare_service!(@wrap_implementation bare_stream $unix_stream_preprocess_method $($unix_stream_preprocess_spec)*)
-            }
+            let published = hidden.publish().await.unwrap();
+            assert!(!service_socket.hidden_path().exists());
+            published
+                .run_with_shutdown(
+                    async {
+                        std::os::unix::net::UnixStream::connect(&service_socket.path).unwrap();
+                    },
+                    Duration::from_millis(50),
+                )
+                .await
+                .unwrap();
+        });
+        assert!(!service_socket.path.exists());
+        std::fs::remove_dir_all(&context).unwrap();
+    }
 
+    #[test]
+    pub fn pause_and_resume_test() {
+        declare_service! {
+            /// Service whose server pauses accepting new clients for a while
+            pub PausingService <U> = {
+                "pausing-executable-adsfhjkl" @ "pause-and-resume-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
         }
 
-        $crate::declare_service!{@maybe_autostart_impl $(with_cli {$command $($args)*})? with_name $service_name <$unix_sock_impl> $(with_constraints {$($typeparam_constraints)*})?}
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("pause-and-resume-test.sock"), &context);
+        let server = TestServer::default();
+        block_on(async {
+            let published = server
+                .bind_hidden(&PausingService, &context, None)
+                .await
+                .unwrap()
+                .publish()
+                .await
+                .unwrap();
+            let pause = published.pause_handle();
+            published
+                .run_with_shutdown(
+                    async {
+                        let connected =
+                            std::os::unix::net::UnixStream::connect(&service_socket.path).unwrap();
+                        pause.pause().await.unwrap();
+                        assert!(pause.is_paused().await);
+                        assert!(
+                            std::os::unix::net::UnixStream::connect(&service_socket.path).is_err()
+                        );
+                        assert!(pause.resume().await.unwrap());
+                        std::os::unix::net::UnixStream::connect(&service_socket.path).unwrap();
+                        pause.pause().await.unwrap();
+                        drop(connected);
+                    },
+                    Duration::from_millis(50),
+                )
+                .await
+                .unwrap();
+            // Stopping while paused cleans up the socket aside.
+            assert!(!pause.is_paused().await);
+            assert!(pause.resume().await.is_ok_and(|resumed| !resumed));
+        });
+        assert!(!service_socket.path.exists());
+        assert!(!service_socket.hidden_path().exists());
+        std::fs::remove_dir_all(&context).unwrap();
+    }
 
-    };
-    {@maybe_autostart_impl
-        with_cli {$command:literal $($args:literal)*}
-        with_name $service_name:ident <$unix_sock_impl:ty>
-            $(with_constraints {$($typeparam_constraints:tt)*})?
-    } => {
-        #[$crate::async_trait(?Send)]
-        impl $(<$($typeparam_constraints)*>)? $crate::ServiceStartable <$unix_sock_impl> for $service_name {
-            fn run_service_command_raw(
-                &self,
-                executor_commandline_prefix: ::core::option::Option<&[impl ::core::convert::AsRef<::std::ffi::OsStr> + ::std::fmt::Debug]>,
-                liveness_path: ::core::option::Option<&::std::path::Path>,
-            ) -> ::std::io::Result<::std::process::Child> {
-                use ::std::{process::Command, iter::{Iterator, IntoIterator, once}, ffi::OsStr};
-                use $crate::chain_trans::prelude::*;
-                // Build an iterator out of all the CLI components and unconditionally take the
-                // first. This ends up being generally simpler in the long run than trying to wrangle
-                // matches and conditional inclusion of items.
-                let mut all_components_iterator = executor_commandline_prefix
-                    .map(|l| l.iter()).into_iter()
-                    .flatten()
-                    .map(::core::convert::AsRef::as_ref)
-                    // This is the part that ensures that at least the first element always exists.
-                    .chain(once(OsStr::new($command)))
-                    // CLI args
-                    .chain([$(OsStr::new($args)),*].into_iter());
+    #[test]
+    pub fn listener_handoff_test() {
+        declare_service! {
+            /// Service upgraded to a new server without closing its socket
+            pub UpgradingService <U> = {
+                "upgrading-executable-hjkdfsa" @ "listener-handoff-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
 
-                let program = all_components_iterator.next().expect("There must be at least one thing in the iterator - the program to run, itself.");
-                Command::new(program)
-                    .trans_mut(|cmd| { $crate::liveness::set_liveness_environment(cmd, liveness_path); })
-                    .args(all_components_iterator)
-                    .spawn()
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("listener-handoff-test.sock"), &context);
+        std::thread::scope(|scope| {
+            let old = scope.spawn(|| {
+                block_on(TestServer::default().start_and_run_server_with_control(
+                    &UpgradingService,
+                    &context,
+                    None,
+                    Duration::from_millis(50),
+                    || Ok(()),
+                ))
+            });
+            while !service_socket.control_path().exists() {
+                std::thread::sleep(Duration::from_millis(10));
             }
+            // Queued on the listener, so only the new server can accept it.
+            let queued = std::os::unix::net::UnixStream::connect(&service_socket.path).unwrap();
+            let new = TestServer {
+                accepts: true,
+                ..Default::default()
+            };
+            block_on(async {
+                let published = new
+                    .take_over(&UpgradingService, &context, Duration::from_secs(5))
+                    .await
+                    .unwrap()
+                    .publish()
+                    .await
+                    .unwrap();
+                assert!(old.join().unwrap().unwrap().is_none());
+                // The old server left the socket to the new one.
+                assert!(service_socket.path.exists());
+                published.run().await.unwrap();
+            });
+            drop(queued);
+        });
+        assert!(!service_socket.path.exists());
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn bind_taking_over_test() {
+        declare_service! {
+            /// Service restarted with a new server that always wins
+            pub RestartedService <U> = {
+                "restarted-executable-fdsahjk" @ "bind-taking-over-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
         }
-    };
-    // No [`ServiceStartable`] if no cli impl.
-    {@maybe_autostart_impl
-        with_name $service_name:ident <$unix_sock_impl:ty>
-            $(with_constraints {$($typeparam_constraints:tt)*})?
-    } => {};
-    // macro "method" for extracting the result type from the preprocess method and specification
-    {@socket_connection_type raw |$unix_socket:ident| -> Io<$result:ty> $body:block } => { $result };
-    // macro "method" for implementing the connection wrapper stuff
-    {@wrap_implementation $stream_ident:ident raw |$unix_socket:ident| -> Io<$result:ty> $body:block} => {{
-        let inner_closure = |$unix_socket| -> ::std::io::Result<$result> { $body };
-        async { inner_closure($stream_ident) }.await
-    }};
-}
 
-#[macro_export]
-/// This macro lets you create a service bundle, for unified initialisation of a collection of
-/// services.
-///
-/// The generated structure implements [`ServiceBundle`] as well as providing methods for reifying
-/// the services associated with it. Documentation for the services is attached to the service
-/// type. The structure has an attached unix socket type so it is easy to specify the unix socket
-/// interface for the entire bundle in one go - the functions available on the structure are
-/// exactly those where the service is valid for the socket type.
-///
-/// For documentation on how service definitions work, see [`declare_service`]. The only difference
-/// is that instead of `pub ServiceTypeName <unix socket interface type> = ` we
-/// have `pub service_bundle_function_name() -> ServiceTypeName <unix socket interface type> = `
-///
-/// This is used like the following:
-/// ```rust,compile_fail
-/// use suss::{declare_service_bundle, ServiceBundle};
-///
-/// declare_service_bundle!{
-///     /// Some wonderful docs
-///     pub WonderfulServices <WonderfulUnixSocketImplementationParameter>  {
-///         /// This is a wonderful service that prints hello
-///         pub fn wonderful_hello_service() -> WonderfulHelloService<U> = { ... service_definition ... } impl {U: UnixSocketInterface};
-///         /// This is a wonderful crate-private service that echos back written data
-///         pub(crate) fn wonderful_echo_service() -> WonderfulEchoService <U> = { ... definition ... };
-///     }
-/// }
-///
-/// // If a service needs to be started, this is the time to wait before assuming there was an
-/// // error if the liveness socket doesn't get pinged
-/// let liveness_timeout = std::time::Duration::from_milli(500);
-///
-/// // Configure the shared runtime directory for all your services.
-/// let wonderful_bundle = WonderfulServices::new("/fancy/rumtime/base/directory");
-/// // Inside crate only
-/// let echo_api = wonderful_bundle.wonderful_echo_service().connect(liveness_timeout).await?;
-/// // public interface
-/// let hello_api = wonderful_bundle.wonderful_hello_service().connect(liveness_timeout).await?;
-/// // Try to connect to an already running service.
-/// let hello_api_two = wonderful_bundle.wonderful_hello_service().connect_to_running().await?;
-/// ```
-macro_rules! declare_service_bundle {
-    {
-        $(#[$bundle_meta:meta])*
-        $bundle_vis:vis $bundle_name:ident <$socket_bundle_impl:ident> {$(
-            $(#[$service_meta:meta])*
-            $service_vis:vis fn $service_fn_name:ident () -> $service_type_name:ident <$unix_sock_impl:ty> = { $($service_definition:tt)*}
-                $(impl { $($unix_sock_constraints:tt)* })?
-        );*}
-    } => {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("bind-taking-over-test.sock"), &context);
+        let timeout = Duration::from_secs(5);
+        let server = TestServer::default();
+        std::thread::scope(|scope| {
+            let old = scope.spawn(|| {
+                block_on(server.start_and_run_server_with_control(
+                    &RestartedService,
+                    &context,
+                    None,
+                    Duration::from_millis(50),
+                    || Ok(()),
+                ))
+            });
+            while !service_socket.control_path().exists() {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            block_on(async {
+                let published = server
+                    .bind_taking_over(&RestartedService, &context, None, timeout)
+                    .await
+                    .unwrap()
+                    .publish()
+                    .await
+                    .unwrap();
+                assert!(old.join().unwrap().unwrap().is_none());
+                published
+                    .run_with_shutdown(
+                        async {
+                            std::os::unix::net::UnixStream::connect(&service_socket.path).unwrap();
+                        },
+                        Duration::from_millis(50),
+                    )
+                    .await
+                    .unwrap();
+            });
+        });
+        assert!(!service_socket.path.exists());
+        std::fs::remove_dir_all(&context).unwrap();
+    }
 
-        $(#[$bundle_meta])*
-        $bundle_vis struct $bundle_name <$socket_bundle_impl: $crate::socket_shims::UnixSocketInterface>{
-            executor_prefix: ::core::option::Option::<::std::vec::Vec::<::std::ffi::OsString>>,
-            base_context_path: ::std::path::PathBuf,
-            _socket_iface: ::core::marker::PhantomData<$socket_bundle_impl>
+    #[test]
+    pub fn server_control_test() {
+        declare_service! {
+            /// Service whose server only stops when told to
+            pub ControlledService <U> = {
+                "controlled-executable-sdfhjkds" @ "control-stop-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
         }
 
-        impl <$socket_bundle_impl: $crate::socket_shims::UnixSocketInterface> $crate::ServiceBundle for $bundle_name<$socket_bundle_impl>{
-            fn new(base_context_path: &::std::path::Path) -> Self {
-                use ::std::borrow::ToOwned;
-                Self {
-                    base_context_path: base_context_path.to_owned(),
-                    executor_prefix: ::core::option::Option::None,
-                    _socket_iface: ::core::marker::PhantomData
-                }
+        let context = ContextDir::temp_for_tests().unwrap();
+        std::thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                let reified = ServiceExt::<StdThreadpoolUSocks>::reify(ControlledService, &context);
+                block_on(reified.serve_service_implementation_with_control(
+                    &TestServer::default(),
+                    None,
+                    Duration::from_millis(50),
+                    || Ok(()),
+                ))
+            });
+
+            let reified = ServiceExt::<StdThreadpoolUSocks>::reify(ControlledService, &context);
+            let status = || {
+                block_on(reified.control(control::ControlCommand::Status, Duration::from_secs(5)))
+            };
+            // Wait for the server to open its control channel.
+            let started = Instant::now();
+            while status().is_err() && started.elapsed() < Duration::from_secs(5) {
+                std::thread::sleep(Duration::from_millis(10));
             }
+            assert_eq!(status().unwrap(), std::process::id().to_string());
 
-            fn with_executor_prefix(base_context_path: &::std::path::Path, executor_prefix: &[::std::ffi::OsString]) -> Self {
-                use ::std::borrow::ToOwned;
-                Self {
-                    base_context_path: base_context_path.to_owned(),
-                    executor_prefix: ::core::option::Option::Some(executor_prefix.to_owned()),
-                    _socket_iface: ::core::marker::PhantomData
+            block_on(reified.stop(Duration::from_secs(5))).unwrap();
+            assert_eq!(server.join().unwrap().unwrap(), None);
+            assert!(!reified.service_socket().path.exists());
+            assert!(!reified.service_socket().control_path().exists());
+        });
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn service_status_test() {
+        declare_service! {
+            /// Service that is only ever "run" by the test itself
+            pub InspectedService <U> = {
+                "inspected-executable-fdsjkfhsd" @ "service-status-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
                 }
-            }
+            } impl {U: UnixSocketInterface}
         }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(InspectedService, &context);
+        assert_eq!(block_on(reified.status()), ServiceStatus::NotRunning);
 
+        let listener =
+            std::os::unix::net::UnixListener::bind(&reified.service_socket().path).unwrap();
+        assert_eq!(
+            block_on(reified.status()),
+            ServiceStatus::Running { pid: None }
+        );
 
-        // Implement service types by calling the declare_service! macro :)
-        $(
-            $crate::declare_service!{
-                $(#[$service_meta])*
-                $service_vis $service_type_name <$unix_sock_impl> = { $($service_definition)* } $(impl {$($unix_sock_constraints)*})?
-            }
-        )*
+        block_on(reified.ensure_started(Duration::from_secs(1))).unwrap();
 
-        // Now create the reification functions on our service bundle :)
-        impl <$socket_bundle_impl: $crate::socket_shims::UnixSocketInterface> $bundle_name<$socket_bundle_impl> {$(
-            $service_vis fn $service_fn_name(&self) -> $crate::ReifiedService<'_, $service_type_name, $socket_bundle_impl>
-                where $service_type_name: $crate::Service::<$socket_bundle_impl>
-            {
-                match &self.executor_prefix {
-                    Some(ep) => $crate::ReifiedService::reify_service_with_executor($service_type_name, &self.base_context_path, ep.as_slice()),
-                    None => $crate::ReifiedService::reify_service($service_type_name, &self.base_context_path)
+        // Without a control channel or state file, there's no way to restart it.
+        assert!(matches!(
+            block_on(reified.restart(Duration::from_secs(1))),
+            Err(Error::ControlFailed { .. })
+        ));
+
+        // The socket file is left behind when the listener goes away.
+        drop(listener);
+        assert_eq!(block_on(reified.status()), ServiceStatus::StaleSocket);
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn cached_connection_test() {
+        declare_service! {
+            /// Service that is only ever "run" by the test itself
+            pub CachedService <U> = {
+                "cached-executable-hjdsfkhsd" @ "cached-connection-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
                 }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(CachedService, &context);
+        let listener =
+            std::os::unix::net::UnixListener::bind(&reified.service_socket().path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+
+        // Established once, then reused.
+        drop(block_on(reified.connection(Duration::from_secs(1))).unwrap());
+        let (accepted, _) = listener.accept().unwrap();
+        drop(block_on(reified.connection(Duration::from_secs(1))).unwrap());
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
+
+        // Re-established once the server hangs up.
+        drop(accepted);
+        drop(block_on(reified.connection(Duration::from_secs(1))).unwrap());
+        listener.accept().unwrap();
+
+        // Re-established once invalidated.
+        block_on(reified.connection(Duration::from_secs(1)))
+            .unwrap()
+            .invalidate();
+        drop(block_on(reified.connection(Duration::from_secs(1))).unwrap());
+        listener.accept().unwrap();
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn connect_all_test() {
+        declare_service_bundle! {
+            pub ConnectAllBundle <B> {
+                pub fn first_service() -> FirstService<U> = {
+                    "first-executable-hdjskfhs" @ "connect-all-first.sock" as raw |unix_socket| -> Io<U::UnixStream> { Ok(unix_socket) }
+                } impl {U: UnixSocketInterface};
+                pub fn second_service() -> SecondService<U> = {
+                    "second-executable-hdjskfhs" @ "connect-all-second.sock" as raw |unix_socket| -> Io<U::UnixStream> { Ok(unix_socket) }
+                } impl {U: UnixSocketInterface};
+                pub fn crashing_service() -> CrashingAllService<U> = {
+                    "false" @ "connect-all-crashing.sock" as raw |unix_socket| -> Io<U::UnixStream> { Ok(unix_socket) }
+                } impl {U: UnixSocketInterface};
             }
-        )*}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let bundle = ConnectAllBundle::<StdThreadpoolUSocks>::new(&context);
+        let _first =
+            std::os::unix::net::UnixListener::bind(bundle.first_service().service_socket().path)
+                .unwrap();
+        let _second =
+            std::os::unix::net::UnixListener::bind(bundle.second_service().service_socket().path)
+                .unwrap();
+
+        let (first, second, first_again) = block_on(connect_all!(Duration::from_secs(1);
+            bundle.first_service(),
+            bundle.second_service(),
+            bundle.first_service(),
+        ))
+        .unwrap();
+        drop((first, second, first_again));
+        let (_first,) =
+            block_on(connect_all!(Duration::from_secs(1); bundle.first_service())).unwrap();
+
+        // One service failing to start fails the lot.
+        assert!(matches!(
+            block_on(connect_all!(Duration::from_secs(30);
+                bundle.first_service(),
+                bundle.crashing_service(),
+            )),
+            Err(Error::SpawnExited { .. })
+        ));
+        std::fs::remove_dir_all(&context).unwrap();
     }
-}
 
-/// Module for usually-necessary imports.
-pub mod prelude {
-    pub use super::{
-        declare_service, declare_service_bundle, ReifiedService, ServiceBundle, ServiceExt,
-        UnixSocketInterface,
-    };
-    pub use futures_lite::future::block_on as futures_lite_block_on;
-}
+    #[test]
+    pub fn connection_pool_test() {
+        declare_service! {
+            /// Service that is only ever "run" by the test itself
+            pub PooledService <U> = {
+                "pooled-executable-jkdshfjksd" @ "connection-pool-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
+                    Ok(unix_socket)
+                }
+            } impl {U: UnixSocketInterface}
+        }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(PooledService, &context);
+        let listener =
+            std::os::unix::net::UnixListener::bind(&reified.service_socket().path).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let assert_no_new_connection = || {
+            assert_eq!(
+                listener.accept().unwrap_err().kind(),
+                std::io::ErrorKind::WouldBlock
+            )
+        };
+        let pool = reified
+            .pool(2, Duration::from_secs(1))
+            .with_idle_timeout(Duration::from_millis(200));
 
-#[cfg(test)]
-mod tests {
-    use std::env::temp_dir;
+        // Connections are established up to the limit, after which checking out waits.
+        let first = block_on(pool.checkout()).unwrap();
+        let second = block_on(pool.checkout()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        let _accepted = listener.accept().unwrap();
+        assert_eq!(pool.checked_out(), 2);
+        let mut third = Box::pin(pool.checkout());
+        assert!(block_on(futures_lite::future::poll_once(&mut third)).is_none());
 
-    use futures_lite::future::block_on;
+        // Checked in connections are reused.
+        drop(first);
+        let third = block_on(third).unwrap();
+        assert_no_new_connection();
+        drop(third);
+        drop(second);
+        assert_eq!(pool.idle_connections(), 2);
+        assert_eq!(pool.checked_out(), 0);
 
-    use crate::socket_shims::StdThreadpoolUSocks;
+        // Connections the server hung up on aren't.
+        drop(accepted);
+        let (first, second) = (
+            block_on(pool.checkout()).unwrap(),
+            block_on(pool.checkout()).unwrap(),
+        );
+        let _accepted = listener.accept().unwrap();
+        assert_no_new_connection();
+        drop((first, second));
 
-    use super::*;
+        // Neither are discarded ones, nor ones idle for too long.
+        block_on(pool.checkout()).unwrap().discard();
+        assert_eq!(pool.idle_connections(), 1);
+        std::thread::sleep(Duration::from_millis(300));
+        drop(block_on(pool.checkout()).unwrap());
+        listener.accept().unwrap();
+        assert_eq!(pool.idle_connections(), 1);
+        std::fs::remove_dir_all(&context).unwrap();
+    }
 
     #[test]
-    pub fn service_declaration_and_start_fail_test() {
-        let tmpdir = temp_dir();
+    pub fn reconnecting_test() {
         declare_service! {
-            /// Basic test service
-            pub TestService <U> = {
-                "sfdjfkosdgjsadgjlas" @ "test-service.sock" as raw |unix_socket| -> Io<U::UnixStream>  {
+            /// Service that is only ever "run" by the test itself
+            pub FlakyService <U> = {
+                "flaky-executable-sdhjfkhsd" @ "reconnecting-test.sock" as raw |unix_socket| -> Io<U::UnixStream> {
                     Ok(unix_socket)
                 }
             } impl {U: UnixSocketInterface}
         }
+        let context = ContextDir::temp_for_tests().unwrap();
+        let reified = ServiceExt::<StdThreadpoolUSocks>::reify(FlakyService, &context);
+        let listener =
+            std::os::unix::net::UnixListener::bind(&reified.service_socket().path).unwrap();
+        // Echo a single byte on every connection, then hang up when told to - as if restarting in
+        // between.
+        let (hang_up_tx, hang_up_rx) = std::sync::mpsc::channel();
+        let (hung_up_tx, hung_up_rx) = std::sync::mpsc::channel();
+        let server = std::thread::spawn(move || {
+            use std::io::{Read, Write};
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut byte = [0u8];
+                stream.read_exact(&mut byte).unwrap();
+                stream.write_all(&byte).unwrap();
+                hang_up_rx.recv().unwrap();
+                drop(stream);
+                hung_up_tx.send(()).unwrap();
+            }
+        });
 
-        assert!(block_on(
-            ServiceExt::<StdThreadpoolUSocks>::reify(TestService, &tmpdir)
-                .connect(Duration::from_millis(50))
-        )
-        .is_err());
+        let mut connection = reified.reconnecting(Duration::from_secs(1));
+        let mut echo = |byte: u8| {
+            block_on(connection.run(|stream| {
+                Box::pin(async move {
+                    StdThreadpoolUSocks::unix_stream_write_all(stream, &[byte]).await?;
+                    let mut echoed = [0u8];
+                    StdThreadpoolUSocks::unix_stream_read_exact(stream, &mut echoed).await?;
+                    Ok(echoed[0])
+                })
+            }))
+        };
+        assert_eq!(echo(1).unwrap(), 1);
+        hang_up_tx.send(()).unwrap();
+        hung_up_rx.recv().unwrap();
+        // The first attempt finds the connection hung up, the retry gets through.
+        assert_eq!(echo(2).unwrap(), 2);
+        hang_up_tx.send(()).unwrap();
+        server.join().unwrap();
 
-        // service without a starting command
-        declare_service! {
-            /// Basic test service 2
-            pub TestService2 <U> = {@"test-service-2.sock" as raw |unix_socket| -> Io<U::UnixStream> {
-                Ok(unix_socket)
-            }} impl {U: UnixSocketInterface}
+        // Once the service is gone for good, the retry fails too.
+        std::fs::remove_file(reified.service_socket().path).unwrap();
+        assert!(echo(3).is_err());
+        assert!(!connection.is_connected());
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Cyclic service dependencies")]
+    #[allow(dead_code)]
+    pub fn service_bundle_dependency_cycle_test() {
+        declare_service_bundle! {
+            pub CyclicBundle <B> {
+                pub fn ping_service() -> PingService<U> = {
+                    "ping-executable-sdjkfghdsjkf" @ "ping-service.sock" as raw |unix_socket| -> Io<U::UnixStream> { Ok(unix_socket) }
+                } impl {U: UnixSocketInterface} requires [PongService];
+                pub fn pong_service() -> PongService<U> = {
+                    "pong-executable-dfjkghdfjkgh" @ "pong-service.sock" as raw |unix_socket| -> Io<U::UnixStream> { Ok(unix_socket) }
+                } impl {U: UnixSocketInterface} requires [PingService];
+            }
         }
+
+        CyclicBundle::<StdThreadpoolUSocks>::new(&temp_dir());
     }
 
     #[test]
-    pub fn service_bundle_macro_test() {
+    pub fn service_bundle_all_test() {
         declare_service_bundle! {
-            pub TestBundle <B> {
-                /// some service
-                pub fn echo_service() -> EchoService <StdThreadpoolUSocks> = {
-                    "echo-executable-wekdasjkfgnjsd" "--and" "--some" "--args" @ "echo-service.sock" as raw |unix_socket| ->
-                        Io< <StdThreadpoolUSocks as UnixSocketInterface>::UnixStream> { Ok(unix_socket) }
-                };
-                pub fn hello_service() -> HelloService<U> = {
-                    "hello-executable-fjskldgkjsagd" "a" @ "hello-service.sock" as raw |unix_socket| -> Io<U::UnixStream> { Ok(unix_socket) }
-                } impl {U: UnixSocketInterface}
+            CrashingBundle <B> {
+                fn front_service() -> FrontService<U> = {
+                    "false" @ "front-service.sock" as raw |unix_socket| -> Io<U::UnixStream> { Ok(unix_socket) }
+                } impl {U: UnixSocketInterface} requires [BackService];
+                fn back_service() -> BackService<U> = {
+                    "false" @ "back-service.sock" as raw |unix_socket| -> Io<U::UnixStream> { Ok(unix_socket) }
+                } impl {U: UnixSocketInterface};
             }
         }
 
-        let tmpdir = temp_dir();
-        let wonderful_bundle = TestBundle::<StdThreadpoolUSocks>::new(&tmpdir);
-        assert!(block_on(
-            wonderful_bundle
-                .echo_service()
-                .connect(Duration::from_millis(50))
-        )
-        .is_err());
-        assert!(block_on(
-            wonderful_bundle
-                .hello_service()
-                .connect(Duration::from_millis(50))
-        )
-        .is_err())
+        let context = ContextDir::temp_for_tests().unwrap();
+        let bundle = CrashingBundle::<StdThreadpoolUSocks>::new(&context);
+        let statuses = block_on(bundle.health_check_all(4));
+        assert_eq!(
+            statuses.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("BackService", ServiceStatus::NotRunning),
+                ("FrontService", ServiceStatus::NotRunning)
+            ]
+        );
+
+        let started = block_on(bundle.start_all(Duration::from_secs(30), 2));
+        assert_eq!(started.len(), 2);
+        // Both fail on the back service - one of them starting it, and the other waiting for
+        // that concurrent start to fail.
+        for result in started.values() {
+            assert_eq!(
+                result.as_ref().unwrap_err().socket().name,
+                "back-service.sock"
+            );
+        }
+
+        let stopped = block_on(bundle.stop_all(Duration::from_secs(1), 0));
+        assert!(stopped.values().all(Result::is_ok));
+        std::fs::remove_dir_all(&context).unwrap();
     }
 }
 
@@ -0,0 +1,71 @@
+//! Advisory lock files, used to coordinate processes operating on the same service socket.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+};
+
+use crate::IoResult;
+
+/// Exclusive advisory lock on a file - [`File::lock`] - held until this is dropped.
+///
+/// The lock file itself is left in place after unlocking, as removing it would race with other
+/// processes waiting on the lock.
+#[derive(Debug)]
+pub(crate) struct LockFile {
+    _file: File,
+    path: PathBuf,
+}
+
+impl LockFile {
+    /// Create the lock file if necessary, and wait until we hold the lock on it. The waiting is
+    /// done via [`blocking::unblock`].
+    pub async fn acquire(path: &Path) -> IoResult<Self> {
+        let path = path.to_owned();
+        blocking::unblock(move || {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            file.lock()?;
+            Ok(Self { _file: file, path })
+        })
+        .await
+    }
+
+    /// Take the lock on an existing lock file if nobody else holds it, producing `None` if they
+    /// do.
+    pub fn try_acquire_existing(path: &Path) -> IoResult<Option<Self>> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(Self {
+                _file: file,
+                path: path.to_owned(),
+            })),
+            Err(TryLockError::WouldBlock) => Ok(None),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// Path of the locked file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
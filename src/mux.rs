@@ -0,0 +1,736 @@
+//! Many concurrent logical streams over one connection - see [`Session`].
+//!
+//! Protocols that want several requests in flight at once otherwise have to either open a
+//! connection per request, or interleave them on one connection and have slow replies hold up
+//! quick ones. A [`Session`] wraps a connection and carries any number of [`LogicalStream`]s over
+//! it, each opened by either side and read and written independently of the others - like
+//! yamux, but without needing a runtime to spawn a connection driver on.
+//!
+//! Everything travels in frames of a 9 byte header - the stream id as a big endian `u32`, the
+//! frame kind, and the payload length as a big endian `u32` - followed by the payload. Each
+//! stream has a receive window of [`STREAM_WINDOW`] bytes, which the other side may not send
+//! beyond until it is told the data was read, so a stream nobody reads from can't hold up the
+//! others or make the session buffer without bound.
+//!
+//! There is no background task. Whichever stream or [`Session::accept`] call is waiting reads the
+//! next frame off the connection and hands it to the stream it belongs to. Frames are only read
+//! while something waits on the session, which is always the case when any stream is waiting
+//! for data or window.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    io::{self, Read},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use event_listener::Event;
+use futures_lite::future;
+
+use crate::{
+    split::{self, ReadHalf, WriteHalf},
+    DefaultUnixSocks, IoResult, UnixSocketInterface,
+};
+
+/// Largest payload of a single frame. Writes bigger than this are sent as several frames, and
+/// bigger frames are rejected as malformed.
+pub const MAX_FRAME_SIZE: usize = 16 * 1024;
+
+/// How many bytes either side may send on a stream before the other side reads them.
+pub const STREAM_WINDOW: u32 = 256 * 1024;
+
+/// Length of a frame header.
+const HEADER_LEN: usize = 9;
+
+/// Data for a stream. The first frame of a stream opens it, so opening sends an empty one.
+const DATA: u8 = 0;
+
+/// The sender won't write to the stream any more.
+const FIN: u8 = 1;
+
+/// The payload is a big endian `u32` of how many more bytes the sender has read from the stream.
+const WINDOW: u8 = 2;
+
+/// A connection carrying many [`LogicalStream`]s. Clones share the connection.
+///
+/// The two sides of a connection must take different roles - [`Self::client`] and
+/// [`Self::server`] - so the ids of streams they open don't clash.
+pub struct Session<U: UnixSocketInterface = DefaultUnixSocks> {
+    inner: Arc<Inner<U>>,
+}
+
+impl<U: UnixSocketInterface> Clone for Session<U> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<U: UnixSocketInterface> Debug for Session<U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.inner.state();
+        f.debug_struct("Session")
+            .field("streams", &state.streams.len())
+            .field("closed", &state.closed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<U: UnixSocketInterface> Session<U> {
+    /// Wrap the connecting side of a connection.
+    pub async fn client(stream: U::UnixStream) -> IoResult<Self> {
+        Self::new(stream, 1).await
+    }
+
+    /// Wrap the accepting side of a connection.
+    pub async fn server(stream: U::UnixStream) -> IoResult<Self> {
+        Self::new(stream, 2).await
+    }
+
+    /// Clients open odd stream ids, servers even ones.
+    async fn new(stream: U::UnixStream, first_id: u32) -> IoResult<Self> {
+        let (read_half, write_half) = split::split::<U>(stream).await?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                reader: async_lock::Mutex::new(FrameReader::new(read_half)),
+                writer: async_lock::Mutex::new(write_half),
+                state: Mutex::new(State {
+                    streams: HashMap::new(),
+                    incoming: VecDeque::new(),
+                    next_id: first_id,
+                    last_remote_id: 0,
+                    pending_fins: Vec::new(),
+                    closed: false,
+                    failure: None,
+                }),
+                changed: Event::new(),
+            }),
+        })
+    }
+
+    /// Open a new stream. The other side gets it from [`Self::accept`].
+    pub async fn open(&self) -> IoResult<LogicalStream<U>> {
+        let id = {
+            let mut state = self.inner.state();
+            state.check_open()?;
+            let id = state.next_id;
+            state.next_id = id
+                .checked_add(2)
+                .ok_or_else(|| io::Error::other("ran out of stream ids for the session"))?;
+            state.streams.insert(id, StreamState::default());
+            id
+        };
+        // Made before sending, so the stream is forgotten again if that fails.
+        let stream = LogicalStream {
+            session: self.clone(),
+            id,
+            write_closed: false,
+        };
+        self.inner.send_frame(id, DATA, &[]).await?;
+        Ok(stream)
+    }
+
+    /// Wait for the other side to open a stream, or `None` once it has closed the connection.
+    pub async fn accept(&self) -> IoResult<Option<LogicalStream<U>>> {
+        let id = self
+            .inner
+            .drive_until(|state| {
+                Ok(match state.incoming.pop_front() {
+                    Some(id) => Some(Some(id)),
+                    None if state.closed => Some(None),
+                    None => None,
+                })
+            })
+            .await?;
+        Ok(id.map(|id| LogicalStream {
+            session: self.clone(),
+            id,
+            write_closed: false,
+        }))
+    }
+
+    /// Shut the connection down, ending all streams on both sides - see
+    /// [`UnixSocketInterface::unix_stream_shutdown`].
+    pub async fn close(&self) -> IoResult<()> {
+        self.inner.writer.lock().await.shutdown().await
+    }
+}
+
+/// One of the streams of a [`Session`].
+///
+/// Dropping a stream without [`Self::close`]ing it only tells the other side once something else
+/// is sent on the session, and anything the other side still sends on it is discarded.
+pub struct LogicalStream<U: UnixSocketInterface = DefaultUnixSocks> {
+    session: Session<U>,
+    id: u32,
+    write_closed: bool,
+}
+
+impl<U: UnixSocketInterface> Debug for LogicalStream<U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogicalStream")
+            .field("id", &self.id)
+            .field("write_closed", &self.write_closed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<U: UnixSocketInterface> LogicalStream<U> {
+    /// Id of the stream within its session.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// The session the stream belongs to.
+    pub fn session(&self) -> &Session<U> {
+        &self.session
+    }
+
+    /// Read some bytes into `buf`, returning how many. Ok(0) means the other side closed the
+    /// stream, or the whole connection.
+    pub async fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let id = self.id;
+        let (read, unacknowledged) = self
+            .session
+            .inner
+            .drive_until(|state| {
+                let closed = state.closed;
+                let stream = state.stream(id);
+                if !stream.received.is_empty() {
+                    let read = stream.received.read(buf)?;
+                    stream.unacknowledged += read as u32;
+                    Ok(Some((read, stream.unacknowledged)))
+                } else if stream.fin_received || closed {
+                    Ok(Some((0, 0)))
+                } else {
+                    Ok(None)
+                }
+            })
+            .await?;
+        // Acknowledging in bulk, so reading a byte at a time doesn't mean a frame per byte.
+        if unacknowledged >= STREAM_WINDOW / 2 {
+            self.session
+                .inner
+                .send_frame(id, WINDOW, &unacknowledged.to_be_bytes())
+                .await?;
+            self.session.inner.state().stream(id).unacknowledged -= unacknowledged;
+        }
+        Ok(read)
+    }
+
+    /// Fill all of `buf`. The stream closing first is an [`io::ErrorKind::UnexpectedEof`] error.
+    pub async fn read_exact(&mut self, mut buf: &mut [u8]) -> IoResult<()> {
+        while !buf.is_empty() {
+            match self.read(buf).await? {
+                0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "stream closed before filling the buffer",
+                    ))
+                }
+                read => buf = &mut buf[read..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Read until the other side closes the stream.
+    pub async fn read_to_end(&mut self, buf: &mut Vec<u8>) -> IoResult<usize> {
+        let start = buf.len();
+        let mut chunk = vec![0; MAX_FRAME_SIZE];
+        loop {
+            match self.read(&mut chunk).await? {
+                0 => return Ok(buf.len() - start),
+                read => buf.extend_from_slice(&chunk[..read]),
+            }
+        }
+    }
+
+    /// Write some of `buf`, returning how much. This waits while the other side has as much
+    /// unread data on the stream as its window allows.
+    pub async fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        if self.write_closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "stream was closed for writing",
+            ));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let id = self.id;
+        let allowed = self
+            .session
+            .inner
+            .drive_until(|state| {
+                state.check_open()?;
+                let stream = state.stream(id);
+                if stream.send_window == 0 {
+                    return Ok(None);
+                }
+                let allowed = buf
+                    .len()
+                    .min(MAX_FRAME_SIZE)
+                    .min(stream.send_window as usize);
+                stream.send_window -= allowed as u32;
+                Ok(Some(allowed))
+            })
+            .await?;
+        self.session
+            .inner
+            .send_frame(id, DATA, &buf[..allowed])
+            .await?;
+        Ok(allowed)
+    }
+
+    /// Write all of `buf`.
+    pub async fn write_all(&mut self, mut buf: &[u8]) -> IoResult<()> {
+        while !buf.is_empty() {
+            let written = self.write(buf).await?;
+            buf = &buf[written..];
+        }
+        Ok(())
+    }
+
+    /// Tell the other side nothing more will be written, so its reads return Ok(0) once it has
+    /// read everything before. The stream can still be read from.
+    pub async fn close(&mut self) -> IoResult<()> {
+        if !self.write_closed {
+            self.session.inner.send_frame(self.id, FIN, &[]).await?;
+            self.write_closed = true;
+        }
+        Ok(())
+    }
+}
+
+impl<U: UnixSocketInterface> Drop for LogicalStream<U> {
+    fn drop(&mut self) {
+        let mut state = self.session.inner.state();
+        state.streams.remove(&self.id);
+        if !self.write_closed {
+            state.pending_fins.push(self.id);
+        }
+    }
+}
+
+struct Inner<U: UnixSocketInterface> {
+    reader: async_lock::Mutex<FrameReader<U>>,
+    writer: async_lock::Mutex<WriteHalf<U>>,
+    state: Mutex<State>,
+    /// Notified whenever a frame has been handled, or whoever was reading frames stops.
+    changed: Event,
+}
+
+impl<U: UnixSocketInterface> Inner<U> {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Wait until `ready` produces something, reading and handling frames - or waiting for
+    /// whoever is - until then.
+    async fn drive_until<T>(
+        &self,
+        mut ready: impl FnMut(&mut State) -> IoResult<Option<T>>,
+    ) -> IoResult<T> {
+        loop {
+            // Listening first, so frames handled in the meantime aren't missed.
+            let listener = self.changed.listen();
+            if let Some(ready) = self.check(&mut ready)? {
+                return Ok(ready);
+            }
+            let reader = future::or(
+                async {
+                    listener.await;
+                    None
+                },
+                async { Some(self.reader.lock().await) },
+            )
+            .await;
+            let Some(mut reader) = reader else {
+                continue;
+            };
+            // Whoever had the reader before may have handled what we were waiting for.
+            if let Some(ready) = self.check(&mut ready)? {
+                return Ok(ready);
+            }
+            // Waking the others even if this is dropped halfway through, so they see what was
+            // handled.
+            let _notify = NotifyOnDrop(&self.changed);
+            let frame = reader.next().await;
+            let mut state = self.state();
+            if let Err(e) = frame.and_then(|frame| state.handle(frame)) {
+                state.failure = Some(Failure::from(&e));
+                return Err(e);
+            }
+        }
+    }
+
+    fn check<T>(
+        &self,
+        ready: &mut impl FnMut(&mut State) -> IoResult<Option<T>>,
+    ) -> IoResult<Option<T>> {
+        let mut state = self.state();
+        if let Some(failure) = &state.failure {
+            return Err(failure.to_error());
+        }
+        match ready(&mut state)? {
+            None if state.closed => Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the other side closed the session",
+            )),
+            ready => Ok(ready),
+        }
+    }
+
+    /// Write a frame, and the FINs of streams dropped since the last one.
+    async fn send_frame(&self, id: u32, kind: u8, payload: &[u8]) -> IoResult<()> {
+        let mut writer = self.writer.lock().await;
+        let pending_fins = std::mem::take(&mut self.state().pending_fins);
+        let mut frames = Vec::with_capacity((pending_fins.len() + 1) * HEADER_LEN + payload.len());
+        for fin_id in pending_fins {
+            encode_frame(&mut frames, fin_id, FIN, &[]);
+        }
+        encode_frame(&mut frames, id, kind, payload);
+        // A frame that is only partly written leaves the connection unusable.
+        let mut poison = PoisonOnDrop {
+            state: &self.state,
+            armed: true,
+        };
+        let written = writer.write_all(&frames).await;
+        poison.armed = false;
+        written.inspect_err(|e| {
+            self.state().failure = Some(Failure::from(e));
+            self.changed.notify(usize::MAX);
+        })
+    }
+}
+
+fn encode_frame(frames: &mut Vec<u8>, id: u32, kind: u8, payload: &[u8]) {
+    frames.extend_from_slice(&id.to_be_bytes());
+    frames.push(kind);
+    frames.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frames.extend_from_slice(payload);
+}
+
+struct NotifyOnDrop<'a>(&'a Event);
+
+impl Drop for NotifyOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.notify(usize::MAX);
+    }
+}
+
+struct PoisonOnDrop<'a> {
+    state: &'a Mutex<State>,
+    armed: bool,
+}
+
+impl Drop for PoisonOnDrop<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            let mut state = self
+                .state
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            state.failure = Some(Failure {
+                kind: io::ErrorKind::Interrupted,
+                message: "a frame was only partly written".to_owned(),
+            });
+        }
+    }
+}
+
+/// Why a session stopped working - kept to give every later use the same error.
+#[derive(Debug)]
+struct Failure {
+    kind: io::ErrorKind,
+    message: String,
+}
+
+impl Failure {
+    fn to_error(&self) -> io::Error {
+        io::Error::new(self.kind, self.message.clone())
+    }
+}
+
+impl From<&io::Error> for Failure {
+    fn from(error: &io::Error) -> Self {
+        Self {
+            kind: error.kind(),
+            message: error.to_string(),
+        }
+    }
+}
+
+struct State {
+    streams: HashMap<u32, StreamState>,
+    /// Streams the other side opened that haven't been accepted yet.
+    incoming: VecDeque<u32>,
+    next_id: u32,
+    last_remote_id: u32,
+    pending_fins: Vec<u32>,
+    /// The other side closed the connection.
+    closed: bool,
+    failure: Option<Failure>,
+}
+
+impl State {
+    /// State of a stream that hasn't been dropped - streams are only forgotten when they are.
+    fn stream(&mut self, id: u32) -> &mut StreamState {
+        self.streams
+            .get_mut(&id)
+            .expect("streams are only forgotten when dropped")
+    }
+
+    fn check_open(&self) -> IoResult<()> {
+        match &self.failure {
+            Some(failure) => Err(failure.to_error()),
+            None if self.closed => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the other side closed the session",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn handle(&mut self, frame: Option<Frame>) -> IoResult<()> {
+        let Some(frame) = frame else {
+            self.closed = true;
+            return Ok(());
+        };
+        // Streams opened by the other side have the other parity, and ever increasing ids.
+        let opened_remotely = frame.id % 2 != self.next_id % 2 && frame.id > self.last_remote_id;
+        match frame.kind {
+            DATA => {
+                if opened_remotely {
+                    self.last_remote_id = frame.id;
+                    self.streams.insert(frame.id, StreamState::default());
+                    self.incoming.push_back(frame.id);
+                }
+                // Data for streams dropped here is discarded.
+                let Some(stream) = self.streams.get_mut(&frame.id) else {
+                    return Ok(());
+                };
+                let outstanding =
+                    stream.received.len() + stream.unacknowledged as usize + frame.payload.len();
+                if stream.fin_received || outstanding > STREAM_WINDOW as usize {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("stream {} was sent data it can't take", frame.id),
+                    ));
+                }
+                stream.received.extend(frame.payload);
+            }
+            FIN => {
+                if let Some(stream) = self.streams.get_mut(&frame.id) {
+                    stream.fin_received = true;
+                }
+            }
+            WINDOW => {
+                let increment = <[u8; 4]>::try_from(frame.payload.as_slice())
+                    .map(u32::from_be_bytes)
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "malformed window update")
+                    })?;
+                if let Some(stream) = self.streams.get_mut(&frame.id) {
+                    stream.send_window = stream.send_window.saturating_add(increment);
+                }
+            }
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown frame kind {kind}"),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+struct StreamState {
+    received: VecDeque<u8>,
+    /// Bytes read since the other side was last told about it.
+    unacknowledged: u32,
+    /// Bytes the other side can still take.
+    send_window: u32,
+    fin_received: bool,
+}
+
+impl Default for StreamState {
+    fn default() -> Self {
+        Self {
+            received: VecDeque::new(),
+            unacknowledged: 0,
+            send_window: STREAM_WINDOW,
+            fin_received: false,
+        }
+    }
+}
+
+struct Frame {
+    id: u32,
+    kind: u8,
+    payload: Vec<u8>,
+}
+
+/// Reads frames, keeping partly read ones when reading is abandoned so the next read picks up
+/// where it left off.
+struct FrameReader<U: UnixSocketInterface> {
+    half: ReadHalf<U>,
+    header: [u8; HEADER_LEN],
+    header_read: usize,
+    payload: Vec<u8>,
+    payload_read: usize,
+}
+
+impl<U: UnixSocketInterface> FrameReader<U> {
+    fn new(half: ReadHalf<U>) -> Self {
+        Self {
+            half,
+            header: [0; HEADER_LEN],
+            header_read: 0,
+            payload: Vec::new(),
+            payload_read: 0,
+        }
+    }
+
+    /// The next frame, or `None` if the other side closed the connection between frames.
+    async fn next(&mut self) -> IoResult<Option<Frame>> {
+        while self.header_read < HEADER_LEN {
+            match self.half.read(&mut self.header[self.header_read..]).await? {
+                0 if self.header_read == 0 => return Ok(None),
+                0 => return Err(truncated_frame()),
+                read => self.header_read += read,
+            }
+        }
+        let [i0, i1, i2, i3, kind, l0, l1, l2, l3] = self.header;
+        let len = u32::from_be_bytes([l0, l1, l2, l3]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {len} bytes is bigger than the {MAX_FRAME_SIZE} byte maximum"),
+            ));
+        }
+        self.payload.resize(len, 0);
+        while self.payload_read < len {
+            match self
+                .half
+                .read(&mut self.payload[self.payload_read..])
+                .await?
+            {
+                0 => return Err(truncated_frame()),
+                read => self.payload_read += read,
+            }
+        }
+        self.header_read = 0;
+        self.payload_read = 0;
+        Ok(Some(Frame {
+            id: u32::from_be_bytes([i0, i1, i2, i3]),
+            kind,
+            payload: std::mem::take(&mut self.payload),
+        }))
+    }
+}
+
+fn truncated_frame() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed in the middle of a frame",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use blocking::Unblock;
+    use futures_lite::future::{block_on, zip};
+
+    use super::{Session, STREAM_WINDOW};
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn multiplexing_test() {
+        let (a, b) = UnixStream::pair().unwrap();
+        block_on(async {
+            let client = Session::<StdThreadpoolUSocks>::client(Unblock::new(a))
+                .await
+                .unwrap();
+            let server = Session::<StdThreadpoolUSocks>::server(Unblock::new(b))
+                .await
+                .unwrap();
+
+            let mut first = client.open().await.unwrap();
+            let mut second = client.open().await.unwrap();
+            let mut first_accepted = server.accept().await.unwrap().unwrap();
+            let mut second_accepted = server.accept().await.unwrap().unwrap();
+            assert_eq!(first_accepted.id(), first.id());
+            assert_eq!(second_accepted.id(), second.id());
+
+            // Replies can come back in any order.
+            first.write_all(b"slow").await.unwrap();
+            second.write_all(b"quick").await.unwrap();
+            let mut request = [0; 5];
+            second_accepted.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"quick");
+            second_accepted.write_all(b"done").await.unwrap();
+            second_accepted.close().await.unwrap();
+            let mut reply = Vec::new();
+            second.read_to_end(&mut reply).await.unwrap();
+            assert_eq!(reply, b"done");
+
+            // Sending more than the window waits for the other side to read.
+            let big = vec![7; STREAM_WINDOW as usize * 3];
+            let (written, read) = zip(
+                async {
+                    first.write_all(&big).await?;
+                    first.close().await
+                },
+                async {
+                    let mut received = Vec::new();
+                    first_accepted.read_to_end(&mut received).await?;
+                    Ok::<_, std::io::Error>(received)
+                },
+            )
+            .await;
+            written.unwrap();
+            let mut expected = b"slow".to_vec();
+            expected.extend_from_slice(&big);
+            assert_eq!(read.unwrap(), expected);
+
+            // Servers can open streams too.
+            let mut pushed = server.open().await.unwrap();
+            pushed.write_all(b"push").await.unwrap();
+            let mut pushed_accepted = client.accept().await.unwrap().unwrap();
+            let mut push = [0; 4];
+            pushed_accepted.read_exact(&mut push).await.unwrap();
+            assert_eq!(&push, b"push");
+
+            client.close().await.unwrap();
+            assert!(server.accept().await.unwrap().is_none());
+            assert_eq!(pushed.read(&mut push).await.unwrap(), 0);
+        });
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,431 @@
+//! Encrypted, mutually authenticated connections with the Noise protocol - requires the `noise`
+//! feature. See [`NoiseConnection`] and [`ContextKey`].
+//!
+//! Sockets in a context directory are only as private as the directory permissions make them. On
+//! machines shared between users, that may not be considered enough - so connections can also be
+//! encrypted, with a key only clients and servers of the context have. That key is a pre-shared
+//! secret in the [`KEY_FILE_NAME`] file of the base context directory, made on first use and only
+//! readable by its owner. Connections run the `Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s` handshake
+//! with it, so each side proves it has the key before anything else is exchanged, and every
+//! connection gets keys of its own.
+//!
+//! The `noise` method of [`crate::declare_service`] finds the key by the socket the connection
+//! is on - see [`NoiseConnection::client_in_context`] - so it only works over unix sockets, not
+//! other [`crate::transport::Transport`]s.
+//!
+//! Encrypted messages are framed as their length as a big-endian `u16` followed by the message.
+
+use std::{
+    fmt::Debug,
+    fs::{OpenOptions, Permissions},
+    io::{self, Write},
+    os::unix::{
+        fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+};
+
+use snow::{HandshakeState, TransportState};
+use tracing::debug;
+
+use crate::{
+    split::{self, ReadHalf, WriteHalf},
+    DefaultUnixSocks, IoResult, UnixSocketInterface,
+};
+
+/// Name of the file in the base context directory holding the key of the context.
+pub const KEY_FILE_NAME: &str = ".suss-noise.key";
+
+/// Noise protocol connections are made with.
+const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise message, and the space its authentication tag takes in it.
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+
+/// Largest amount of data encrypted as one message.
+const MAX_PLAINTEXT_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+fn noise_error(error: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Key shared by the clients and servers of a context directory.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ContextKey([u8; 32]);
+
+impl Debug for ContextKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ContextKey").finish_non_exhaustive()
+    }
+}
+
+impl ContextKey {
+    /// Path of the key file of the base context directory.
+    pub fn path(base_context_directory: &Path) -> PathBuf {
+        base_context_directory.join(KEY_FILE_NAME)
+    }
+
+    /// Read the key of the base context directory. A key file others can read or write is an
+    /// [`io::ErrorKind::PermissionDenied`] error, as the key can't be trusted to be secret.
+    pub fn load(base_context_directory: &Path) -> IoResult<Self> {
+        let path = Self::path(base_context_directory);
+        let metadata = std::fs::metadata(&path)?;
+        if metadata.mode() & 0o077 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is accessible to others than its owner", path.display()),
+            ));
+        }
+        let key = std::fs::read(&path)?;
+        key.try_into().map(Self).map_err(|key: Vec<u8>| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} holds {} bytes rather than a 32 byte key",
+                    path.display(),
+                    key.len()
+                ),
+            )
+        })
+    }
+
+    /// Read the key of the base context directory, making one first if there is none yet.
+    pub fn load_or_create(base_context_directory: &Path) -> IoResult<Self> {
+        match Self::load(base_context_directory) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            loaded => return loaded,
+        }
+        use nanorand::rand::{chacha::ChaCha20, Rng};
+        let mut key = [0u8; 32];
+        ChaCha20::new().fill_bytes(&mut key);
+
+        // Written in full under another name first, so nobody reads half a key.
+        let path = Self::path(base_context_directory);
+        let mut staging_path = path.clone().into_os_string();
+        staging_path.push(format!(".{:016x}.tmp", ChaCha20::new().generate::<u64>()));
+        let mut staging = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&staging_path)?;
+        staging.set_permissions(Permissions::from_mode(0o600))?;
+        staging.write_all(&key)?;
+        staging.sync_all()?;
+        // Linking never replaces a key another process made in the meantime.
+        let linked = std::fs::hard_link(&staging_path, &path);
+        std::fs::remove_file(&staging_path)?;
+        match linked {
+            Ok(()) => Ok(Self(key)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                Self::load(base_context_directory)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn handshake(&self, initiator: bool) -> IoResult<HandshakeState> {
+        let params = NOISE_PARAMS.parse().map_err(noise_error)?;
+        let builder = snow::Builder::new(params).psk(0, &self.0);
+        match initiator {
+            true => builder.build_initiator(),
+            false => builder.build_responder(),
+        }
+        .map_err(noise_error)
+    }
+}
+
+/// Connection encrypting what is written to it, and decrypting what is read from it.
+pub struct NoiseConnection<U: UnixSocketInterface = DefaultUnixSocks> {
+    // Split, so writing after reading doesn't wait for a read of the socket interface.
+    read_half: ReadHalf<U>,
+    write_half: WriteHalf<U>,
+    transport: TransportState,
+    /// Decrypted data not read yet, from `read_position` on.
+    read_buffer: Vec<u8>,
+    read_position: usize,
+}
+
+impl<U: UnixSocketInterface> Debug for NoiseConnection<U>
+where
+    U::UnixStream: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NoiseConnection")
+            .field("read_half", &self.read_half)
+            .field("write_half", &self.write_half)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<U: UnixSocketInterface> NoiseConnection<U> {
+    /// Run the handshake as the connecting side, with the given key.
+    pub async fn client(stream: U::UnixStream, key: &ContextKey) -> IoResult<Self> {
+        let (mut read_half, mut write_half) = split::split::<U>(stream).await?;
+        let handshake = async {
+            let mut handshake = key.handshake(true)?;
+            let mut message = vec![0u8; MAX_MESSAGE_LEN];
+            let len = handshake
+                .write_message(&[], &mut message)
+                .map_err(noise_error)?;
+            write_message(&mut write_half, &message[..len]).await?;
+            let reply = read_message(&mut read_half)
+                .await?
+                .ok_or_else(handshake_cut_short)?;
+            handshake
+                .read_message(&reply, &mut message)
+                .map_err(noise_error)?;
+            Ok(handshake)
+        }
+        .await;
+        Self::new(read_half, write_half, handshake).await
+    }
+
+    /// Run the handshake as the accepting side, with the given key.
+    pub async fn server(stream: U::UnixStream, key: &ContextKey) -> IoResult<Self> {
+        let (mut read_half, mut write_half) = split::split::<U>(stream).await?;
+        let handshake = async {
+            let mut handshake = key.handshake(false)?;
+            let mut message = vec![0u8; MAX_MESSAGE_LEN];
+            let hello = read_message(&mut read_half)
+                .await?
+                .ok_or_else(handshake_cut_short)?;
+            handshake
+                .read_message(&hello, &mut message)
+                .map_err(noise_error)?;
+            let len = handshake
+                .write_message(&[], &mut message)
+                .map_err(noise_error)?;
+            write_message(&mut write_half, &message[..len]).await?;
+            Ok(handshake)
+        }
+        .await;
+        Self::new(read_half, write_half, handshake).await
+    }
+
+    /// Like [`Self::client`], with the key of the base context directory the service socket the
+    /// stream is connected to is in.
+    pub async fn client_in_context(mut stream: U::UnixStream) -> IoResult<Self> {
+        let socket = UnixStream::from(U::unix_stream_duplicate_fd(&mut stream).await?);
+        let key = load_key_next_to(socket.peer_addr()?.as_pathname()).await?;
+        Self::client(stream, &key).await
+    }
+
+    /// Like [`Self::server`], with the key of the base context directory the service socket the
+    /// stream was accepted on is in.
+    pub async fn server_in_context(mut stream: U::UnixStream) -> IoResult<Self> {
+        let socket = UnixStream::from(U::unix_stream_duplicate_fd(&mut stream).await?);
+        let key = load_key_next_to(socket.local_addr()?.as_pathname()).await?;
+        Self::server(stream, &key).await
+    }
+
+    async fn new(
+        read_half: ReadHalf<U>,
+        mut write_half: WriteHalf<U>,
+        handshake: IoResult<HandshakeState>,
+    ) -> IoResult<Self> {
+        let transport =
+            handshake.and_then(|handshake| handshake.into_transport_mode().map_err(noise_error));
+        match transport {
+            Ok(transport) => Ok(Self {
+                read_half,
+                write_half,
+                transport,
+                read_buffer: Vec::new(),
+                read_position: 0,
+            }),
+            Err(e) => {
+                // The read half may be left waiting on the socket, keeping it open - so tell the
+                // other side directly, rather than leaving it waiting for a reply.
+                if let Err(shutdown_error) = write_half.shutdown().await {
+                    debug!(
+                        "Failed to shut down connection after failed handshake - {}",
+                        shutdown_error
+                    );
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Write all of `buf`, encrypted.
+    pub async fn write_all(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut message = vec![0u8; MAX_MESSAGE_LEN];
+        for data in buf.chunks(MAX_PLAINTEXT_LEN) {
+            let len = self
+                .transport
+                .write_message(data, &mut message)
+                .map_err(noise_error)?;
+            write_message(&mut self.write_half, &message[..len]).await?;
+        }
+        Ok(())
+    }
+
+    /// Read some decrypted bytes into `buf`, returning how many. Ok(0) means the other side
+    /// closed the connection.
+    pub async fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.read_position == self.read_buffer.len() {
+            let Some(message) = read_message(&mut self.read_half).await? else {
+                return Ok(0);
+            };
+            let mut decrypted = vec![0u8; message.len()];
+            let len = self
+                .transport
+                .read_message(&message, &mut decrypted)
+                .map_err(noise_error)?;
+            decrypted.truncate(len);
+            self.read_buffer = decrypted;
+            self.read_position = 0;
+        }
+        let available = &self.read_buffer[self.read_position..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.read_position += read;
+        Ok(read)
+    }
+
+    /// Fill all of `buf` with decrypted bytes.
+    pub async fn read_exact(&mut self, mut buf: &mut [u8]) -> IoResult<()> {
+        while !buf.is_empty() {
+            match self.read(buf).await? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                read => buf = &mut buf[read..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the other side nothing more is coming - see [`WriteHalf::shutdown`].
+    pub async fn shutdown(&mut self) -> IoResult<()> {
+        self.write_half.shutdown().await
+    }
+}
+
+/// Load - or make - the key of the directory a socket is in.
+async fn load_key_next_to(socket_path: Option<&Path>) -> IoResult<ContextKey> {
+    let directory = socket_path
+        .and_then(Path::parent)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Encrypted connections need to be made over a unix socket with a path",
+            )
+        })?
+        .to_owned();
+    blocking::unblock(move || ContextKey::load_or_create(&directory)).await
+}
+
+fn handshake_cut_short() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Connection closed during the encryption handshake",
+    )
+}
+
+async fn write_message<U: UnixSocketInterface>(
+    write_half: &mut WriteHalf<U>,
+    message: &[u8],
+) -> IoResult<()> {
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    write_half.write_all(&frame).await
+}
+
+/// The next message, or `None` if the other side closed the connection between messages.
+async fn read_message<U: UnixSocketInterface>(
+    read_half: &mut ReadHalf<U>,
+) -> IoResult<Option<Vec<u8>>> {
+    let mut length = [0u8; 2];
+    let mut filled = 0;
+    while filled < length.len() {
+        match read_half.read(&mut length[filled..]).await? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    let mut message = vec![0u8; u16::from_be_bytes(length).into()];
+    read_half.read_exact(&mut message).await?;
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::{fs::PermissionsExt, net::UnixStream};
+
+    use blocking::Unblock;
+    use futures_lite::future::{block_on, zip};
+
+    use super::{ContextKey, NoiseConnection};
+    use crate::{socket_shims::StdThreadpoolUSocks, ContextDir};
+
+    #[test]
+    pub fn encrypted_round_trip_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let key = ContextKey::load_or_create(&context).unwrap();
+        assert_eq!(ContextKey::load_or_create(&context).unwrap(), key);
+        let key_path = ContextKey::path(&context);
+        let mode = std::fs::metadata(&key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let (client, server) = block_on(zip(
+            NoiseConnection::<StdThreadpoolUSocks>::client(Unblock::new(a), &key),
+            NoiseConnection::<StdThreadpoolUSocks>::server(Unblock::new(b), &key),
+        ));
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        let secret = vec![42; 100_000];
+        block_on(zip(
+            async {
+                client.write_all(&secret).await.unwrap();
+                client.shutdown().await.unwrap();
+            },
+            async {
+                let mut received = vec![0; secret.len()];
+                server.read_exact(&mut received).await.unwrap();
+                assert_eq!(received, secret);
+                assert_eq!(server.read(&mut [0; 1]).await.unwrap(), 0);
+            },
+        ));
+
+        // Another context has another key, which doesn't get through the handshake.
+        let other = ContextDir::temp_for_tests().unwrap();
+        let other_key = ContextKey::load_or_create(&other).unwrap();
+        let (a, b) = UnixStream::pair().unwrap();
+        let (client, server) = block_on(zip(
+            NoiseConnection::<StdThreadpoolUSocks>::client(Unblock::new(a), &key),
+            NoiseConnection::<StdThreadpoolUSocks>::server(Unblock::new(b), &other_key),
+        ));
+        assert!(client.is_err() || server.is_err());
+
+        // Keys others can read aren't used.
+        std::fs::set_permissions(&key_path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            ContextKey::load(&context).unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        std::fs::remove_dir_all(&context).unwrap();
+        std::fs::remove_dir_all(&other).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,309 @@
+//! A bounded pool of connections to a service - see [`ConnectionPool`].
+//!
+//! For a client with many requests in flight at once, a single connection serialises them all,
+//! while a connection per request churns through sockets. A pool sits in between: connections
+//! are checked out for a request, and checked back in for reuse once it is done.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Debug,
+    ops::{Deref, DerefMut},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use event_listener::Event;
+use tracing::debug;
+
+use crate::{
+    connection_cache::Cached, error, DefaultUnixSocks, ReifiedService, Service, ServiceStartable,
+    UnixSocketInterface,
+};
+
+/// Up to a fixed number of connections to a [`ReifiedService`], established on demand and
+/// reused once checked back in.
+///
+/// Connections that have been idle for longer than the idle timeout are closed, as are ones the
+/// server hung up on - both are noticed the next time the pool is used, rather than in the
+/// background. Once all connections are checked out, [`Self::checkout`] waits for one to be
+/// checked back in.
+pub struct ConnectionPool<
+    's,
+    'info,
+    S: Service<U>,
+    U: UnixSocketInterface = DefaultUnixSocks,
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug = OsString,
+> {
+    service: &'s ReifiedService<'info, S, U, ExecutorPrefixComponent>,
+    max_connections: usize,
+    liveness_timeout: Duration,
+    idle_timeout: Duration,
+    state: Mutex<PoolState<S::ServiceClientConnection>>,
+    /// Notified whenever a connection is checked in or discarded.
+    released: Event,
+}
+
+struct PoolState<C> {
+    /// Connections ready for reuse, along with when they were checked in - most recent last.
+    idle: Vec<(Cached<C>, Instant)>,
+    /// Connections checked out, or being established.
+    checked_out: usize,
+}
+
+impl<S: Service<U>, U: UnixSocketInterface, ExecutorPrefixComponent> Debug
+    for ConnectionPool<'_, '_, S, U, ExecutorPrefixComponent>
+where
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        f.debug_struct("ConnectionPool")
+            .field("service", &self.service)
+            .field("max_connections", &self.max_connections)
+            .field("liveness_timeout", &self.liveness_timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("idle_connections", &state.idle.len())
+            .field("checked_out", &state.checked_out)
+            .finish()
+    }
+}
+
+impl<'s, 'info, S, U, ExecutorPrefixComponent>
+    ConnectionPool<'s, 'info, S, U, ExecutorPrefixComponent>
+where
+    S: ServiceStartable<U>,
+    U: UnixSocketInterface,
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug,
+{
+    /// Idle connections are closed after this long by default.
+    pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Create an empty pool of up to `max_connections` connections, where a maximum of 0 is
+    /// treated as 1. The liveness timeout is for starting the service, as in
+    /// [`ReifiedService::connect_with_timeout`].
+    pub fn new(
+        service: &'s ReifiedService<'info, S, U, ExecutorPrefixComponent>,
+        max_connections: usize,
+        liveness_timeout: Duration,
+    ) -> Self {
+        Self {
+            service,
+            max_connections: max_connections.max(1),
+            liveness_timeout,
+            idle_timeout: Self::DEFAULT_IDLE_TIMEOUT,
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                checked_out: 0,
+            }),
+            released: Event::new(),
+        }
+    }
+
+    /// Close connections once they have been idle for this long.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Most connections the pool holds at once.
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    /// How long connections may be idle before they are closed.
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Number of connections waiting to be reused.
+    pub fn idle_connections(&self) -> usize {
+        self.lock_state().idle.len()
+    }
+
+    /// Number of connections checked out right now.
+    pub fn checked_out(&self) -> usize {
+        self.lock_state().checked_out
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, PoolState<S::ServiceClientConnection>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Check a connection out of the pool - reusing an idle one if there is a healthy one,
+    /// establishing a new one if the pool isn't full, and otherwise waiting until one is checked
+    /// back in. The connection is checked back in when the [`PooledConnection`] is dropped.
+    pub async fn checkout(
+        &self,
+    ) -> error::Result<PooledConnection<'_, 's, 'info, S, U, ExecutorPrefixComponent>> {
+        loop {
+            let listener = {
+                let mut state = self.lock_state();
+                let idle_timeout = self.idle_timeout;
+                state.idle.retain(|(connection, checked_in)| {
+                    let keep = checked_in.elapsed() < idle_timeout && connection.is_healthy();
+                    if !keep {
+                        debug!("Closing idle or hung up pooled connection");
+                    }
+                    keep
+                });
+                if let Some((connection, _)) = state.idle.pop() {
+                    state.checked_out += 1;
+                    return Ok(PooledConnection::new(self, connection));
+                }
+                if state.checked_out < self.max_connections {
+                    state.checked_out += 1;
+                    None
+                } else {
+                    Some(self.released.listen())
+                }
+            };
+            match listener {
+                Some(listener) => {
+                    debug!(
+                        "All {} pooled connections checked out - waiting for one",
+                        self.max_connections
+                    );
+                    listener.await;
+                }
+                None => {
+                    // Gives the slot back if connecting fails, or is given up on.
+                    let mut reserved = PooledConnection {
+                        pool: self,
+                        connection: None,
+                    };
+                    reserved.connection = Some(
+                        self.service
+                            .connect_cacheable(self.liveness_timeout)
+                            .await?,
+                    );
+                    return Ok(reserved);
+                }
+            }
+        }
+    }
+
+    /// Give up a checked out connection, keeping it for reuse if there is one.
+    fn release(&self, connection: Option<Cached<S::ServiceClientConnection>>) {
+        let mut state = self.lock_state();
+        state.checked_out -= 1;
+        if let Some(connection) = connection {
+            state.idle.push((connection, Instant::now()));
+        }
+        drop(state);
+        self.released.notify(1);
+    }
+}
+
+/// A connection checked out of a [`ConnectionPool`], which is checked back in when this is
+/// dropped.
+///
+/// While a connection is being established, this holds none - but is never handed out like that.
+pub struct PooledConnection<
+    'p,
+    's,
+    'info,
+    S: ServiceStartable<U>,
+    U: UnixSocketInterface = DefaultUnixSocks,
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug = OsString,
+> {
+    pool: &'p ConnectionPool<'s, 'info, S, U, ExecutorPrefixComponent>,
+    connection: Option<Cached<S::ServiceClientConnection>>,
+}
+
+impl<'p, 's, 'info, S, U, ExecutorPrefixComponent>
+    PooledConnection<'p, 's, 'info, S, U, ExecutorPrefixComponent>
+where
+    S: ServiceStartable<U>,
+    U: UnixSocketInterface,
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug,
+{
+    fn new(
+        pool: &'p ConnectionPool<'s, 'info, S, U, ExecutorPrefixComponent>,
+        connection: Cached<S::ServiceClientConnection>,
+    ) -> Self {
+        Self {
+            pool,
+            connection: Some(connection),
+        }
+    }
+
+    /// Close the connection rather than checking it back in - for instance because something
+    /// went wrong in the middle of an exchange, leaving it in an unknown state.
+    pub fn discard(mut self) {
+        self.connection = None;
+    }
+}
+
+impl<S, U, ExecutorPrefixComponent> Debug
+    for PooledConnection<'_, '_, '_, S, U, ExecutorPrefixComponent>
+where
+    S: ServiceStartable<U>,
+    U: UnixSocketInterface,
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PooledConnection").finish_non_exhaustive()
+    }
+}
+
+impl<S, U, ExecutorPrefixComponent> Deref
+    for PooledConnection<'_, '_, '_, S, U, ExecutorPrefixComponent>
+where
+    S: ServiceStartable<U>,
+    U: UnixSocketInterface,
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug,
+{
+    type Target = S::ServiceClientConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self
+            .connection
+            .as_ref()
+            .expect("handed out connected")
+            .connection
+    }
+}
+
+impl<S, U, ExecutorPrefixComponent> DerefMut
+    for PooledConnection<'_, '_, '_, S, U, ExecutorPrefixComponent>
+where
+    S: ServiceStartable<U>,
+    U: UnixSocketInterface,
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self
+            .connection
+            .as_mut()
+            .expect("handed out connected")
+            .connection
+    }
+}
+
+impl<S, U, ExecutorPrefixComponent> Drop
+    for PooledConnection<'_, '_, '_, S, U, ExecutorPrefixComponent>
+where
+    S: ServiceStartable<U>,
+    U: UnixSocketInterface,
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug,
+{
+    fn drop(&mut self) {
+        self.pool.release(self.connection.take());
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
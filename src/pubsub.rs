@@ -0,0 +1,264 @@
+//! Broadcasting messages to every connection subscribed to a service - see [`Hub`] and
+//! [`Subscription`].
+//!
+//! Notification-style services - file watchers, config change broadcasters - have clients that
+//! connect only to hear about things. Their servers hand each accepted connection to
+//! [`Hub::serve`], and [`Hub::publish`] messages to all of them at once. Clients read the
+//! messages one by one from a [`Subscription`]. The `pubsub` method of
+//! [`crate::declare_service`] wraps client connections in one, and leaves server connections
+//! bare for the hub.
+//!
+//! Messages are byte strings, framed as their length as a big-endian `u32` followed by the
+//! message itself - encode them however suits the service.
+
+use std::{
+    fmt::Debug,
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use async_channel::{Receiver, Sender};
+
+use crate::{DefaultUnixSocks, IoResult, UnixSocketInterface};
+
+/// Largest message accepted when receiving, to avoid allocating huge buffers for garbage lengths.
+pub const MAX_MESSAGE_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Fans messages out to every subscribed connection. Clones share the subscribers.
+///
+/// Each subscriber has a queue of messages that are published but not yet written to its
+/// connection. A subscriber whose queue is full when a message is published has fallen too far
+/// behind, and is dropped - its connection is closed once the queue has been written, and it has
+/// to subscribe again.
+#[derive(Clone)]
+pub struct Hub {
+    inner: Arc<HubInner>,
+}
+
+/// Queues of the subscribers of a hub - `None` once it is closed.
+type Subscribers = Option<Vec<Sender<Arc<[u8]>>>>;
+
+struct HubInner {
+    queue_capacity: usize,
+    subscribers: Mutex<Subscribers>,
+}
+
+impl Debug for Hub {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hub")
+            .field("queue_capacity", &self.inner.queue_capacity)
+            .field("subscribers", &self.subscriber_count())
+            .finish()
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hub {
+    /// How many messages each subscriber of a hub made with [`Self::new`] can fall behind by.
+    pub const DEFAULT_QUEUE_CAPACITY: usize = 64;
+
+    /// Hub whose subscribers can fall behind by [`Self::DEFAULT_QUEUE_CAPACITY`] messages.
+    pub fn new() -> Self {
+        Self::with_queue_capacity(Self::DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// Hub whose subscribers can fall behind by the given number of messages - a capacity of 0
+    /// is treated as 1.
+    pub fn with_queue_capacity(queue_capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(HubInner {
+                queue_capacity: queue_capacity.max(1),
+                subscribers: Mutex::new(Some(Vec::new())),
+            }),
+        }
+    }
+
+    fn subscribers(&self) -> MutexGuard<'_, Subscribers> {
+        self.inner
+            .subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// How many connections are subscribed. Connections that went away are only noticed when a
+    /// message is published.
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers().as_ref().map_or(0, Vec::len)
+    }
+
+    /// Queue a message for every subscriber, returning how many it was queued for. Subscribers
+    /// that are gone, or have fallen too far behind, are dropped.
+    pub fn publish(&self, message: impl Into<Arc<[u8]>>) -> usize {
+        let message = message.into();
+        let mut subscribers = self.subscribers();
+        let Some(senders) = subscribers.as_mut() else {
+            return 0;
+        };
+        senders.retain(|sender| sender.try_send(message.clone()).is_ok());
+        senders.len()
+    }
+
+    /// Write published messages to the connection until it goes away or the hub is closed,
+    /// shutting it down then. Only messages published after this is first polled are written.
+    pub async fn serve<U: UnixSocketInterface>(&self, mut stream: U::UnixStream) -> IoResult<()> {
+        let messages = self.subscribe();
+        while let Ok(message) = messages.recv().await {
+            let length = u32::try_from(message.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "message too long to be framed")
+            })?;
+            let mut frame = Vec::with_capacity(4 + message.len());
+            frame.extend_from_slice(&length.to_be_bytes());
+            frame.extend_from_slice(&message);
+            U::unix_stream_write_all(&mut stream, &frame).await?;
+        }
+        U::unix_stream_shutdown(&mut stream).await
+    }
+
+    fn subscribe(&self) -> Receiver<Arc<[u8]>> {
+        let (sender, receiver) = async_channel::bounded(self.inner.queue_capacity);
+        match self.subscribers().as_mut() {
+            Some(senders) => senders.push(sender),
+            // A closed hub ends the subscription straight away.
+            None => drop(sender),
+        }
+        receiver
+    }
+
+    /// End every subscription once what is already queued for it is written, and refuse new
+    /// ones.
+    pub fn close(&self) {
+        self.subscribers().take();
+    }
+}
+
+/// The client side of a subscription to a [`Hub`] - see [`Self::next`].
+pub struct Subscription<U: UnixSocketInterface = DefaultUnixSocks> {
+    stream: U::UnixStream,
+}
+
+impl<U: UnixSocketInterface> Debug for Subscription<U>
+where
+    U::UnixStream: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscription")
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl<U: UnixSocketInterface> Subscription<U> {
+    /// Wrap a bare stream connected to a server serving it with [`Hub::serve`].
+    pub fn new(stream: U::UnixStream) -> Self {
+        Self { stream }
+    }
+
+    /// Take back the bare stream.
+    pub fn into_inner(self) -> U::UnixStream {
+        self.stream
+    }
+
+    /// The next published message, or `None` once the server ended the subscription - because
+    /// the hub was closed, or this subscriber fell too far behind.
+    pub async fn next(&mut self) -> IoResult<Option<Vec<u8>>> {
+        let mut length = [0; 4];
+        match U::unix_stream_read_exact(&mut self.stream, &mut length).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let length = u32::from_be_bytes(length) as usize;
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "message of {} bytes exceeds the maximum of {}",
+                    length, MAX_MESSAGE_LENGTH
+                ),
+            ));
+        }
+        let mut message = vec![0; length];
+        U::unix_stream_read_exact(&mut self.stream, &mut message).await?;
+        Ok(Some(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use blocking::Unblock;
+    use futures_lite::future::{block_on, zip};
+
+    use super::{Hub, Subscription};
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn broadcast_test() {
+        let hub = Hub::new();
+        let (first_client, first_server) = UnixStream::pair().unwrap();
+        let (second_client, second_server) = UnixStream::pair().unwrap();
+        let mut first = Subscription::<StdThreadpoolUSocks>::new(Unblock::new(first_client));
+        let mut second = Subscription::<StdThreadpoolUSocks>::new(Unblock::new(second_client));
+        let (served, ()) = block_on(zip(
+            zip(
+                hub.serve::<StdThreadpoolUSocks>(Unblock::new(first_server)),
+                hub.serve::<StdThreadpoolUSocks>(Unblock::new(second_server)),
+            ),
+            async {
+                assert_eq!(hub.publish(&b"changed"[..]), 2);
+                assert_eq!(hub.publish(&b"changed again"[..]), 2);
+                hub.close();
+                for subscription in [&mut first, &mut second] {
+                    assert_eq!(subscription.next().await.unwrap().unwrap(), b"changed");
+                    assert_eq!(
+                        subscription.next().await.unwrap().unwrap(),
+                        b"changed again"
+                    );
+                    assert_eq!(subscription.next().await.unwrap(), None);
+                }
+            },
+        ));
+        served.0.unwrap();
+        served.1.unwrap();
+        assert_eq!(hub.publish(&b"nobody listens"[..]), 0);
+    }
+
+    #[test]
+    pub fn lagging_subscriber_test() {
+        let hub = Hub::with_queue_capacity(1);
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut subscription = Subscription::<StdThreadpoolUSocks>::new(Unblock::new(client));
+        let (served, ()) = block_on(zip(
+            hub.serve::<StdThreadpoolUSocks>(Unblock::new(server)),
+            async {
+                assert_eq!(hub.publish(&b"first"[..]), 1);
+                assert_eq!(hub.publish(&b"second"[..]), 0);
+                assert_eq!(subscription.next().await.unwrap().unwrap(), b"first");
+                assert_eq!(subscription.next().await.unwrap(), None);
+            },
+        ));
+        served.unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,148 @@
+//! Client connections that survive restarts of the service - see [`Reconnecting`].
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Debug,
+    future::Future,
+    io::ErrorKind,
+    pin::Pin,
+    time::Duration,
+};
+
+use tracing::warn;
+
+use crate::{
+    DefaultUnixSocks, IoResult, ReifiedService, Service, ServiceStartable, UnixSocketInterface,
+};
+
+/// Future of an operation run on a connection with [`Reconnecting::run`].
+pub type OperationFuture<'c, T> = Pin<Box<dyn Future<Output = IoResult<T>> + 'c>>;
+
+/// A client connection to a [`ReifiedService`] that is re-established when it is lost - for
+/// instance because the service was restarted - so long-lived clients don't need retry loops of
+/// their own.
+///
+/// Operations are run on the connection with [`Self::run`]. If one fails because the connection
+/// was lost (see [`is_connection_lost`]), the service is connected to again - starting it if it
+/// isn't running - and the operation is retried once on the new connection. Operations may
+/// therefore run twice, so they should be safe to repeat.
+pub struct Reconnecting<
+    's,
+    'info,
+    S: Service<U>,
+    U: UnixSocketInterface = DefaultUnixSocks,
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug = OsString,
+> {
+    service: &'s ReifiedService<'info, S, U, ExecutorPrefixComponent>,
+    liveness_timeout: Duration,
+    connection: Option<S::ServiceClientConnection>,
+}
+
+impl<S: Service<U>, U: UnixSocketInterface, ExecutorPrefixComponent> Debug
+    for Reconnecting<'_, '_, S, U, ExecutorPrefixComponent>
+where
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reconnecting")
+            .field("service", &self.service)
+            .field("liveness_timeout", &self.liveness_timeout)
+            .field("connected", &self.connection.is_some())
+            .finish()
+    }
+}
+
+impl<'s, 'info, S, U, ExecutorPrefixComponent>
+    Reconnecting<'s, 'info, S, U, ExecutorPrefixComponent>
+where
+    S: ServiceStartable<U>,
+    U: UnixSocketInterface,
+    ExecutorPrefixComponent: AsRef<OsStr> + Sized + Debug,
+{
+    /// Connect to the service on first use, with the given liveness timeout for starting it -
+    /// see [`ReifiedService::connect_with_timeout`].
+    pub fn new(
+        service: &'s ReifiedService<'info, S, U, ExecutorPrefixComponent>,
+        liveness_timeout: Duration,
+    ) -> Self {
+        Self {
+            service,
+            liveness_timeout,
+            connection: None,
+        }
+    }
+
+    /// Whether there is a connection to the service right now.
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// The current connection, connecting to the service first if there is none.
+    pub async fn connection(&mut self) -> IoResult<&mut S::ServiceClientConnection> {
+        if self.connection.is_none() {
+            self.connection = Some(
+                self.service
+                    .connect_with_timeout(self.liveness_timeout)
+                    .await?,
+            );
+        }
+        Ok(self.connection.as_mut().expect("connected above"))
+    }
+
+    /// Drop the current connection, so the next operation connects again.
+    pub fn disconnect(&mut self) {
+        self.connection = None;
+    }
+
+    /// Run an operation on the connection, reconnecting and retrying it once if the connection
+    /// turns out to be lost. Errors connecting to the service are converted into io errors.
+    pub async fn run<T>(
+        &mut self,
+        mut operation: impl for<'c> FnMut(&'c mut S::ServiceClientConnection) -> OperationFuture<'c, T>,
+    ) -> IoResult<T> {
+        let mut retried = false;
+        loop {
+            let result = operation(self.connection().await?).await;
+            match result {
+                Err(e) if is_connection_lost(&e) => {
+                    self.disconnect();
+                    if retried {
+                        return Err(e);
+                    }
+                    warn!("Connection to service lost - {} - reconnecting", e);
+                    retried = true;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether an io error means the connection is gone, rather than, say, the other side sending
+/// something unexpected.
+pub fn is_connection_lost(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::UnexpectedEof
+    )
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,364 @@
+//! Using services in a base context directory on another machine, through unix sockets forwarded
+//! into a local directory - see [`RemoteContext`] and [`crate::ReifiedService::with_remote_context`].
+//!
+//! Each service socket in the remote context directory is forwarded on first use to the socket
+//! of the same name in a local context directory, by a [`SocketForwarder`] - usually
+//! [`SshForwarder`], which runs `ssh -L <local socket>:<remote socket>`. Clients then connect to
+//! the local socket as if the service was running here, handshake and all. Remote services are
+//! never started by clients here, so they have to be started on their own machine - for
+//! instance by a client there.
+
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt::Debug,
+    io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    child::{ChildGuard, ServiceProcessHandle},
+    timefut,
+};
+
+/// Placeholder for the local socket path in [`SshForwarder`] command templates.
+pub const LOCAL_SOCKET_PLACEHOLDER: &str = "{local}";
+
+/// Placeholder for the remote socket path in [`SshForwarder`] command templates.
+pub const REMOTE_SOCKET_PLACEHOLDER: &str = "{remote}";
+
+/// Way of making a unix socket on another machine available at a local path.
+pub trait SocketForwarder: Debug + Send + Sync {
+    /// Start forwarding connections to `local_socket` on to `remote_socket`, producing the process
+    /// doing it. The local socket may only show up once the forwarding is set up - it is polled
+    /// for by [`RemoteContext`] - and should go away once the process exits.
+    fn forward(
+        &self,
+        local_socket: &Path,
+        remote_socket: &Path,
+    ) -> io::Result<Box<dyn ServiceProcessHandle>>;
+}
+
+/// Forward sockets with a command like `ssh`, built from a template where
+/// [`LOCAL_SOCKET_PLACEHOLDER`] and [`REMOTE_SOCKET_PLACEHOLDER`] stand for the socket paths.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SshForwarder {
+    template: Vec<OsString>,
+}
+
+impl SshForwarder {
+    /// Forward with plain `ssh` to the given destination - `host` or `user@host`, or the name of
+    /// a host in the ssh configuration.
+    pub fn new(destination: impl AsRef<OsStr>) -> Self {
+        let mut template: Vec<OsString> = [
+            "ssh",
+            "-N",
+            "-o",
+            "ExitOnForwardFailure=yes",
+            "-o",
+            "StreamLocalBindUnlink=yes",
+            "-L",
+            "{local}:{remote}",
+        ]
+        .map(OsString::from)
+        .into();
+        template.push(destination.as_ref().to_owned());
+        Self { template }
+    }
+
+    /// Forward with the given command template - for instance to pass extra ssh options, or to
+    /// go through another tool. The command must keep running while it forwards.
+    pub fn from_template(template: impl IntoIterator<Item = impl AsRef<OsStr>>) -> Self {
+        Self {
+            template: template
+                .into_iter()
+                .map(|part| part.as_ref().to_owned())
+                .collect(),
+        }
+    }
+
+    /// The command template.
+    pub fn template(&self) -> &[OsString] {
+        &self.template
+    }
+
+    /// The command forwarding the one socket to the other.
+    pub(crate) fn command(&self, local_socket: &Path, remote_socket: &Path) -> io::Result<Command> {
+        let mut parts = self
+            .template
+            .iter()
+            .map(|part| substitute(part, local_socket, remote_socket));
+        let program = parts.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "socket forwarding command template is empty",
+            )
+        })?;
+        let mut command = Command::new(program);
+        command.args(parts).stdin(Stdio::null());
+        Ok(command)
+    }
+}
+
+impl SocketForwarder for SshForwarder {
+    fn forward(
+        &self,
+        local_socket: &Path,
+        remote_socket: &Path,
+    ) -> io::Result<Box<dyn ServiceProcessHandle>> {
+        let child = self.command(local_socket, remote_socket)?.spawn()?;
+        Ok(Box::new(child))
+    }
+}
+
+/// Replace the placeholders in a part of a command template with the socket paths.
+fn substitute(part: &OsStr, local_socket: &Path, remote_socket: &Path) -> OsString {
+    let Some(mut rest) = part.to_str() else {
+        return part.to_owned();
+    };
+    let mut substituted = OsString::new();
+    loop {
+        let next = [
+            (LOCAL_SOCKET_PLACEHOLDER, local_socket),
+            (REMOTE_SOCKET_PLACEHOLDER, remote_socket),
+        ]
+        .into_iter()
+        .filter_map(|(placeholder, path)| rest.find(placeholder).map(|at| (at, placeholder, path)))
+        .min_by_key(|(at, ..)| *at);
+        let Some((at, placeholder, path)) = next else {
+            substituted.push(rest);
+            return substituted;
+        };
+        substituted.push(&rest[..at]);
+        substituted.push(path);
+        rest = &rest[at + placeholder.len()..];
+    }
+}
+
+/// A base context directory on another machine, whose service sockets are forwarded into a local
+/// directory on demand - see the [module documentation](self).
+///
+/// Forwarding processes are kept around and reused for later connections, and are terminated
+/// along with this. Their local sockets are removed then too.
+#[derive(Debug)]
+pub struct RemoteContext {
+    forwarder: Box<dyn SocketForwarder>,
+    remote_context_directory: PathBuf,
+    local_context_directory: PathBuf,
+    forward_timeout: Duration,
+    forwards: async_lock::Mutex<HashMap<OsString, ChildGuard>>,
+}
+
+impl RemoteContext {
+    /// How long to wait for a forwarded socket to show up, unless set with
+    /// [`Self::with_forward_timeout`].
+    pub const DEFAULT_FORWARD_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Forward sockets from the remote context directory into the local one with the forwarder.
+    /// The local directory should be private to this, so forwarded sockets don't clash with those
+    /// of local services.
+    pub fn new(
+        forwarder: impl SocketForwarder + 'static,
+        remote_context_directory: impl Into<PathBuf>,
+        local_context_directory: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            forwarder: Box::new(forwarder),
+            remote_context_directory: remote_context_directory.into(),
+            local_context_directory: local_context_directory.into(),
+            forward_timeout: Self::DEFAULT_FORWARD_TIMEOUT,
+            forwards: async_lock::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Forward the remote context directory with `ssh` to the destination - see
+    /// [`SshForwarder::new`].
+    pub fn over_ssh(
+        destination: impl AsRef<OsStr>,
+        remote_context_directory: impl Into<PathBuf>,
+        local_context_directory: impl Into<PathBuf>,
+    ) -> Self {
+        Self::new(
+            SshForwarder::new(destination),
+            remote_context_directory,
+            local_context_directory,
+        )
+    }
+
+    /// Wait this long for forwarded sockets to show up.
+    pub fn with_forward_timeout(mut self, forward_timeout: Duration) -> Self {
+        self.forward_timeout = forward_timeout;
+        self
+    }
+
+    /// The base context directory on the other machine.
+    pub fn remote_context_directory(&self) -> &Path {
+        &self.remote_context_directory
+    }
+
+    /// The local directory sockets are forwarded into - the base context directory of services
+    /// used through this.
+    pub fn local_context_directory(&self) -> &Path {
+        &self.local_context_directory
+    }
+
+    /// Make sure the socket of the given name is forwarded, producing its local path. Forwarding
+    /// that was set up before is reused as long as its process is running.
+    pub async fn forward(&self, socket_name: &OsStr) -> io::Result<PathBuf> {
+        let local_socket = self.local_context_directory.join(socket_name);
+        let remote_socket = self.remote_context_directory.join(socket_name);
+        let mut forwards = self.forwards.lock().await;
+        if let Some(existing) = forwards.get_mut(socket_name) {
+            match existing.try_wait() {
+                Ok(None) => return Ok(local_socket),
+                Ok(Some(status)) => warn!(
+                    "Forwarding of {} exited - {} - forwarding it again",
+                    remote_socket.display(),
+                    status
+                ),
+                Err(e) => warn!(
+                    "Couldn't check on forwarding of {} - {} - forwarding it again",
+                    remote_socket.display(),
+                    e
+                ),
+            }
+            forwards.remove(socket_name);
+        }
+        // Left over by a forwarding process that didn't clean up.
+        if std::fs::symlink_metadata(&local_socket).is_ok() {
+            debug!("Removing stale forwarded socket {}", local_socket.display());
+            std::fs::remove_file(&local_socket)?;
+        }
+        info!(
+            "Forwarding {} to {}",
+            remote_socket.display(),
+            local_socket.display()
+        );
+        let mut forwarding =
+            ChildGuard::new(self.forwarder.forward(&local_socket, &remote_socket)?);
+        let started = Instant::now();
+        while std::fs::symlink_metadata(&local_socket).is_err() {
+            if let Some(status) = forwarding.try_wait()? {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("forwarding process exited - {status}"),
+                ));
+            }
+            if started.elapsed() >= self.forward_timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "forwarded socket didn't show up within {}",
+                        humantime::format_duration(self.forward_timeout)
+                    ),
+                ));
+            }
+            timefut::sleep(Duration::from_millis(25)).await;
+        }
+        forwards.insert(socket_name.to_owned(), forwarding);
+        Ok(local_socket)
+    }
+}
+
+impl Drop for RemoteContext {
+    fn drop(&mut self) {
+        for (socket_name, forwarding) in self.forwards.get_mut().drain() {
+            drop(forwarding);
+            let _ = std::fs::remove_file(self.local_context_directory.join(socket_name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, path::Path};
+
+    use futures_lite::future::block_on;
+
+    use super::{RemoteContext, SshForwarder};
+    use crate::ContextDir;
+
+    #[test]
+    pub fn ssh_forwarder_command_test() {
+        let command = SshForwarder::new("user@host")
+            .command(
+                Path::new("/tmp/local/a.sock"),
+                Path::new("/run/remote/a.sock"),
+            )
+            .unwrap();
+        assert_eq!(command.get_program(), "ssh");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "-N",
+                "-o",
+                "ExitOnForwardFailure=yes",
+                "-o",
+                "StreamLocalBindUnlink=yes",
+                "-L",
+                "/tmp/local/a.sock:/run/remote/a.sock",
+                "user@host",
+            ]
+            .map(OsStr::new)
+        );
+        assert!(SshForwarder::from_template(Vec::<&str>::new())
+            .command(Path::new("a"), Path::new("b"))
+            .is_err());
+    }
+
+    #[test]
+    pub fn remote_context_forward_test() {
+        let remote = ContextDir::temp_for_tests().unwrap();
+        let local = ContextDir::temp_for_tests().unwrap();
+        std::fs::write(remote.join("forwarded.sock"), b"").unwrap();
+        // Stand in for ssh by linking the local socket to the remote one.
+        let forwarder = SshForwarder::from_template([
+            "sh",
+            "-c",
+            "ln -s \"$1\" \"$0\" && exec sleep 30",
+            "{local}",
+            "{remote}",
+        ]);
+        let context = RemoteContext::new(forwarder, remote.path(), local.path());
+        let local_socket = block_on(context.forward("forwarded.sock".as_ref())).unwrap();
+        assert_eq!(local_socket, local.join("forwarded.sock"));
+        assert_eq!(
+            std::fs::read_link(&local_socket).unwrap(),
+            remote.join("forwarded.sock")
+        );
+        // Reused rather than forwarded again.
+        assert_eq!(
+            block_on(context.forward("forwarded.sock".as_ref())).unwrap(),
+            local_socket
+        );
+        drop(context);
+        assert!(std::fs::symlink_metadata(&local_socket).is_err());
+
+        let failing = RemoteContext::new(
+            SshForwarder::from_template(["false"]),
+            remote.path(),
+            local.path(),
+        );
+        assert!(block_on(failing.forward("forwarded.sock".as_ref())).is_err());
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
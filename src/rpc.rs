@@ -0,0 +1,389 @@
+//! Request/response calls with many in flight at once over one connection - requires the
+//! `framed-serde` feature. See [`RpcConnection`] and [`RpcServer`].
+//!
+//! A [`crate::framed::TypedConnection`] only has one request in flight at a time, as replies
+//! come back in the order requests were sent. Here each request carries a correlation id, which
+//! the server copies onto its reply, so a server can answer in whatever order its handlers finish
+//! and callers sharing a connection each get their own reply.
+//!
+//! Messages are frames of the framed codec, whose contents are the correlation id as a
+//! big-endian `u64` followed by the message encoded as JSON. This is what the `rpc` method of
+//! [`crate::declare_service`] wraps streams in.
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard},
+    task::Poll,
+};
+
+use event_listener::Event;
+use futures_lite::future;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    framed::{self, FrameReader},
+    split::{self, WriteHalf},
+    DefaultUnixSocks, IoResult, UnixSocketInterface,
+};
+
+/// Length of the correlation id at the start of every message.
+const ID_LEN: usize = 8;
+
+fn encode<T: Serialize>(id: u64, message: &T) -> IoResult<Vec<u8>> {
+    let mut encoded = id.to_be_bytes().to_vec();
+    serde_json::to_writer(&mut encoded, message)?;
+    framed::frame(&encoded)
+}
+
+fn decode<T: DeserializeOwned>(encoded: &[u8]) -> IoResult<(u64, T)> {
+    if encoded.len() < ID_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Message too short to have a correlation id",
+        ));
+    }
+    let (id, message) = encoded.split_at(ID_LEN);
+    let id = u64::from_be_bytes(id.try_into().expect("split at the id length"));
+    Ok((id, serde_json::from_slice(message)?))
+}
+
+/// Client connection making calls with requests of type `Req`, answered with replies of type
+/// `Resp`. Clones share the connection, and can make calls at the same time.
+pub struct RpcConnection<Req, Resp, U: UnixSocketInterface = DefaultUnixSocks> {
+    inner: Arc<ClientInner<Resp, U>>,
+    _requests: PhantomData<fn(Req)>,
+}
+
+impl<Req, Resp, U: UnixSocketInterface> Clone for RpcConnection<Req, Resp, U> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            _requests: PhantomData,
+        }
+    }
+}
+
+impl<Req, Resp, U: UnixSocketInterface> Debug for RpcConnection<Req, Resp, U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcConnection")
+            .field("in_flight", &self.inner.calls().pending.len())
+            .finish_non_exhaustive()
+    }
+}
+
+struct ClientInner<Resp, U: UnixSocketInterface> {
+    reader: async_lock::Mutex<FrameReader<U>>,
+    writer: async_lock::Mutex<WriteHalf<U>>,
+    calls: Mutex<Calls<Resp>>,
+    /// Notified whenever a reply has been read, or whoever was reading replies stops.
+    replied: Event,
+}
+
+struct Calls<Resp> {
+    next_id: u64,
+    /// Calls waiting for their reply, and the reply once it's in.
+    pending: HashMap<u64, Option<Resp>>,
+    /// The server closed the connection.
+    closed: bool,
+    /// Why the connection stopped working, to give every later call the same error.
+    failure: Option<(io::ErrorKind, String)>,
+}
+
+impl<Resp> Calls<Resp> {
+    fn check(&self) -> IoResult<()> {
+        match &self.failure {
+            Some((kind, message)) => Err(io::Error::new(*kind, message.clone())),
+            None if self.closed => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the server closed the connection",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn fail(&mut self, error: &io::Error) {
+        self.failure = Some((error.kind(), error.to_string()));
+    }
+}
+
+impl<Resp, U: UnixSocketInterface> ClientInner<Resp, U> {
+    fn calls(&self) -> MutexGuard<'_, Calls<Resp>> {
+        self.calls
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Forgets a call when it's abandoned, so a reply that comes in later is discarded.
+struct PendingCall<'a, Resp, U: UnixSocketInterface> {
+    inner: &'a ClientInner<Resp, U>,
+    id: u64,
+}
+
+impl<Resp, U: UnixSocketInterface> Drop for PendingCall<'_, Resp, U> {
+    fn drop(&mut self) {
+        self.inner.calls().pending.remove(&self.id);
+    }
+}
+
+struct NotifyOnDrop<'a>(&'a Event);
+
+impl Drop for NotifyOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.notify(usize::MAX);
+    }
+}
+
+impl<Req, Resp, U: UnixSocketInterface> RpcConnection<Req, Resp, U> {
+    /// Wrap a bare stream.
+    pub async fn new(stream: U::UnixStream) -> IoResult<Self> {
+        let (read_half, write_half) = split::split::<U>(stream).await?;
+        Ok(Self {
+            inner: Arc::new(ClientInner {
+                reader: async_lock::Mutex::new(FrameReader::new(read_half)),
+                writer: async_lock::Mutex::new(write_half),
+                calls: Mutex::new(Calls {
+                    next_id: 0,
+                    pending: HashMap::new(),
+                    closed: false,
+                    failure: None,
+                }),
+                replied: Event::new(),
+            }),
+            _requests: PhantomData,
+        })
+    }
+
+    /// Shut the connection down, failing the calls still waiting for replies - see
+    /// [`UnixSocketInterface::unix_stream_shutdown`].
+    pub async fn close(&self) -> IoResult<()> {
+        self.inner.writer.lock().await.shutdown().await
+    }
+}
+
+impl<Req: Serialize, Resp: DeserializeOwned, U: UnixSocketInterface> RpcConnection<Req, Resp, U> {
+    /// Send a request and wait for its reply. The connection being closed before the reply
+    /// arrives is an [`io::ErrorKind::UnexpectedEof`] error.
+    ///
+    /// There is no background task reading replies - whichever call is waiting reads them and
+    /// hands them to the calls they belong to. Abandoning a call while its request is being
+    /// written leaves the connection unusable, as the server only got part of it.
+    pub async fn call(&self, request: &Req) -> IoResult<Resp> {
+        let inner = &*self.inner;
+        let id = {
+            let mut calls = inner.calls();
+            calls.check()?;
+            let id = calls.next_id;
+            calls.next_id += 1;
+            calls.pending.insert(id, None);
+            id
+        };
+        let _pending = PendingCall { inner, id };
+        let frame = encode(id, request)?;
+        {
+            let mut writer = inner.writer.lock().await;
+            if let Err(e) = writer.write_all(&frame).await {
+                inner.calls().fail(&e);
+                return Err(e);
+            }
+        }
+
+        loop {
+            // Listening first, so replies read in the meantime aren't missed.
+            let listener = inner.replied.listen();
+            if let Some(reply) = self.reply(id)? {
+                return Ok(reply);
+            }
+            let reader = future::or(
+                async {
+                    listener.await;
+                    None
+                },
+                async { Some(inner.reader.lock().await) },
+            )
+            .await;
+            let Some(mut reader) = reader else {
+                continue;
+            };
+            // Whoever had the reader before may have read our reply.
+            if let Some(reply) = self.reply(id)? {
+                return Ok(reply);
+            }
+            // Waking the others even if this is dropped halfway through, so they see what was
+            // read.
+            let _notify = NotifyOnDrop(&inner.replied);
+            let read = reader.next().await;
+            let mut calls = inner.calls();
+            match read.and_then(|frame| frame.map(|frame| decode(&frame)).transpose()) {
+                Ok(Some((reply_id, reply))) => {
+                    // Replies to abandoned calls are discarded.
+                    if let Some(pending) = calls.pending.get_mut(&reply_id) {
+                        *pending = Some(reply);
+                    }
+                }
+                Ok(None) => calls.closed = true,
+                Err(e) => {
+                    calls.fail(&e);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// The reply to the call, if it's in.
+    fn reply(&self, id: u64) -> IoResult<Option<Resp>> {
+        let mut calls = self.inner.calls();
+        if let Some(reply) = calls.pending.get_mut(&id).and_then(Option::take) {
+            return Ok(Some(reply));
+        }
+        calls.check().map(|()| None)
+    }
+}
+
+/// Server connection answering requests of type `Req` with replies of type `Resp` - see
+/// [`Self::serve`].
+pub struct RpcServer<Req, Resp, U: UnixSocketInterface = DefaultUnixSocks> {
+    reader: FrameReader<U>,
+    writer: async_lock::Mutex<WriteHalf<U>>,
+    _messages: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<Req, Resp, U: UnixSocketInterface> Debug for RpcServer<Req, Resp, U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcServer").finish_non_exhaustive()
+    }
+}
+
+/// A reply being worked out by a handler, then written.
+type InFlight<'a> = Pin<Box<dyn Future<Output = IoResult<()>> + 'a>>;
+
+impl<Req: DeserializeOwned, Resp: Serialize, U: UnixSocketInterface> RpcServer<Req, Resp, U> {
+    /// Wrap a bare stream.
+    pub async fn new(stream: U::UnixStream) -> IoResult<Self> {
+        let (read_half, write_half) = split::split::<U>(stream).await?;
+        Ok(Self {
+            reader: FrameReader::new(read_half),
+            writer: async_lock::Mutex::new(write_half),
+            _messages: PhantomData,
+        })
+    }
+
+    /// Answer every request with the reply `handler` produces for it, until the client closes
+    /// the connection, then wait for the replies still being worked out.
+    ///
+    /// Handlers run concurrently within this future - rather than as tasks of their own - and
+    /// replies are written as they are ready, so slow requests don't hold up quick ones.
+    pub async fn serve<H, HF>(mut self, mut handler: H) -> IoResult<()>
+    where
+        H: FnMut(Req) -> HF,
+        HF: Future<Output = Resp>,
+    {
+        let writer = &self.writer;
+        let mut in_flight: Vec<InFlight<'_>> = Vec::new();
+        let mut client_closed = false;
+        loop {
+            // Reading is picked up where it left off, so the read can be remade every time round.
+            let mut next_request = Box::pin(self.reader.next());
+            let request = future::poll_fn(|cx| {
+                let mut failed = None;
+                in_flight.retain_mut(|reply| match reply.as_mut().poll(cx) {
+                    Poll::Ready(written) => {
+                        if let Err(e) = written {
+                            failed.get_or_insert(e);
+                        }
+                        false
+                    }
+                    Poll::Pending => true,
+                });
+                if let Some(e) = failed {
+                    return Poll::Ready(Err(e));
+                }
+                if client_closed {
+                    return match in_flight.is_empty() {
+                        true => Poll::Ready(Ok(None)),
+                        false => Poll::Pending,
+                    };
+                }
+                next_request.as_mut().poll(cx).map(|read| read.map(Some))
+            })
+            .await?;
+            drop(next_request);
+            match request {
+                Some(Some(frame)) => {
+                    let (id, request) = decode::<Req>(&frame)?;
+                    let reply = handler(request);
+                    in_flight.push(Box::pin(async move {
+                        let frame = encode(id, &reply.await)?;
+                        writer.lock().await.write_all(&frame).await
+                    }));
+                }
+                Some(None) => client_closed = true,
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, os::unix::net::UnixStream, time::Duration};
+
+    use blocking::Unblock;
+    use futures_lite::future::{block_on, zip};
+
+    use super::{RpcConnection, RpcServer};
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn concurrent_calls_test() {
+        let (a, b) = UnixStream::pair().unwrap();
+        block_on(async {
+            let client = RpcConnection::<u64, String, StdThreadpoolUSocks>::new(Unblock::new(a))
+                .await
+                .unwrap();
+            let server = RpcServer::<u64, String, StdThreadpoolUSocks>::new(Unblock::new(b))
+                .await
+                .unwrap();
+            // Requests are delays in milliseconds, so the first request is answered last.
+            let served = server.serve(|delay| async move {
+                blocking::unblock(move || std::thread::sleep(Duration::from_millis(delay))).await;
+                format!("waited {delay}ms")
+            });
+            let answered = RefCell::new(Vec::new());
+            let calls = async {
+                let (client, answered) = (&client, &answered);
+                let call = |delay| async move {
+                    let reply = client.call(&delay).await.unwrap();
+                    answered.borrow_mut().push(reply);
+                };
+                zip(call(200), call(1)).await;
+                client.close().await.unwrap();
+                assert!(client.call(&1).await.is_err());
+            };
+            let (served, ()) = zip(served, calls).await;
+            served.unwrap();
+            assert_eq!(answered.into_inner(), ["waited 1ms", "waited 200ms"]);
+        });
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
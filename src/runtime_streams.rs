@@ -0,0 +1,115 @@
+//! Converting streams of any [`UnixSocketInterface`] into the stream types of particular async
+//! runtimes.
+//!
+//! Services generic over their socket interface still often want to hand out a concrete stream -
+//! say, to pass on to a library built on tokio. Each conversion takes over the socket of the
+//! stream and puts it into the mode the runtime expects, so it works no matter which interface
+//! the stream came from. These are what the `tokio`, `async_io` and `smol` methods of
+//! [`crate::declare_service`] wrap streams in, with the `tokio`, `async-io` and `smol` features.
+
+use std::os::unix::net::UnixStream;
+
+use crate::{IoResult, UnixSocketInterface};
+
+/// Stream handed out by [`into_tokio`].
+#[cfg(feature = "tokio")]
+pub type TokioStream = tokio::net::UnixStream;
+
+/// Stream handed out by [`into_async_io`].
+#[cfg(feature = "async-io")]
+pub type AsyncIoStream = async_io::Async<UnixStream>;
+
+/// Stream handed out by [`into_smol`].
+#[cfg(feature = "smol")]
+pub type SmolStream = async_net::unix::UnixStream;
+
+/// Take over the socket of a stream as a std stream, in whatever blocking mode it was in.
+///
+/// This needs [`UnixSocketInterface::unix_stream_duplicate_fd`].
+pub async fn into_std<U: UnixSocketInterface>(mut stream: U::UnixStream) -> IoResult<UnixStream> {
+    let fd = U::unix_stream_duplicate_fd(&mut stream).await?;
+    Ok(UnixStream::from(fd))
+}
+
+/// Take over the socket of a stream as a tokio stream. Like [`tokio::net::UnixStream::from_std`],
+/// this has to be called from within a tokio runtime. Requires the `tokio` feature.
+#[cfg(feature = "tokio")]
+pub async fn into_tokio<U: UnixSocketInterface>(stream: U::UnixStream) -> IoResult<TokioStream> {
+    let stream = into_std::<U>(stream).await?;
+    // Tokio expects the socket to be in non-blocking mode already.
+    stream.set_nonblocking(true)?;
+    TokioStream::from_std(stream)
+}
+
+/// Take over the socket of a stream as an [`async_io::Async`] stream. Requires the `async-io`
+/// feature.
+#[cfg(feature = "async-io")]
+pub async fn into_async_io<U: UnixSocketInterface>(
+    stream: U::UnixStream,
+) -> IoResult<AsyncIoStream> {
+    async_io::Async::new(into_std::<U>(stream).await?)
+}
+
+/// Take over the socket of a stream as a smol stream. Requires the `smol` feature.
+#[cfg(feature = "smol")]
+pub async fn into_smol<U: UnixSocketInterface>(stream: U::UnixStream) -> IoResult<SmolStream> {
+    SmolStream::try_from(into_std::<U>(stream).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use blocking::Unblock;
+    use futures_lite::future::block_on;
+
+    use super::*;
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn into_std_keeps_the_connection() {
+        let (a, mut b) = UnixStream::pair().unwrap();
+        let mut stream = block_on(into_std::<StdThreadpoolUSocks>(Unblock::new(a))).unwrap();
+        stream.write_all(b"ping").unwrap();
+        drop(stream);
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"ping");
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    pub fn into_smol_is_non_blocking() {
+        use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+        let (a, b) = UnixStream::pair().unwrap();
+        block_on(async {
+            let mut a = into_smol::<StdThreadpoolUSocks>(Unblock::new(a))
+                .await
+                .unwrap();
+            let mut b = into_smol::<StdThreadpoolUSocks>(Unblock::new(b))
+                .await
+                .unwrap();
+            a.write_all(b"ping").await.unwrap();
+            let mut received = [0u8; 4];
+            b.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"ping");
+        });
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
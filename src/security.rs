@@ -0,0 +1,168 @@
+//! Hardening against attacks on the file system paths of service sockets - like another user
+//! planting a symlink where a socket is expected, so a server binds, or a client removes, a file
+//! somewhere else. See [`SecurityPolicy`] and [`crate::Service::security_policy`].
+
+use std::{
+    io::{self, ErrorKind},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use tracing::error;
+
+use crate::{credentials, Error, IoResult, ServiceSocket};
+
+/// Checks made on the path of a service socket before binding, connecting to, or removing it. By
+/// default, no checks are made.
+///
+/// These don't make an unsafe directory safe - they just refuse to use one. The base context
+/// directory should be one only the user of the services can write to, like `$XDG_RUNTIME_DIR`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SecurityPolicy {
+    reject_symlinks: bool,
+    dir_owner: Option<u32>,
+    reject_world_writable_dir: bool,
+}
+
+impl SecurityPolicy {
+    /// Make no checks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make all checks - reject symlinks in place of sockets, and base context directories that
+    /// are world-writable or not owned by the user this process runs as.
+    pub fn strict() -> Self {
+        Self::new()
+            .with_symlink_check()
+            .with_dir_owner(credentials::current_uid())
+            .with_world_writable_check()
+    }
+
+    /// Reject socket paths that are symlinks.
+    pub fn with_symlink_check(mut self) -> Self {
+        self.reject_symlinks = true;
+        self
+    }
+
+    /// Reject base context directories not owned by this user.
+    pub fn with_dir_owner(mut self, uid: u32) -> Self {
+        self.dir_owner = Some(uid);
+        self
+    }
+
+    /// Reject base context directories anyone can write to - sticky ones like `/tmp` included.
+    pub fn with_world_writable_check(mut self) -> Self {
+        self.reject_world_writable_dir = true;
+        self
+    }
+
+    /// Check a socket path and the directory it is in against the policy, failing with
+    /// [`ErrorKind::PermissionDenied`] if they are unsafe to use. Socket paths that don't exist
+    /// are fine, but the directory has to.
+    pub fn check(&self, socket_path: &Path) -> IoResult<()> {
+        let denied = |reason: String| Err(io::Error::new(ErrorKind::PermissionDenied, reason));
+        if self.reject_symlinks {
+            match socket_path.symlink_metadata() {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    return denied(format!("{} is a symlink", socket_path.display()));
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.dir_owner.is_none() && !self.reject_world_writable_dir {
+            return Ok(());
+        }
+        let context_dir = match socket_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let metadata = context_dir.metadata()?;
+        if let Some(uid) = self.dir_owner {
+            if metadata.uid() != uid {
+                return denied(format!(
+                    "{} is owned by uid {}, not uid {}",
+                    context_dir.display(),
+                    metadata.uid(),
+                    uid
+                ));
+            }
+        }
+        if self.reject_world_writable_dir && metadata.mode() & 0o002 != 0 {
+            return denied(format!("{} is world-writable", context_dir.display()));
+        }
+        Ok(())
+    }
+
+    /// [`Self::check`] the socket of a service, producing an [`Error::InsecureSocketPath`].
+    pub(crate) fn check_socket(&self, service_socket: &ServiceSocket) -> crate::error::Result<()> {
+        self.check(&service_socket.path).map_err(|e| {
+            error!("Refusing to use socket @ {} - {}", service_socket, e);
+            Error::InsecureSocketPath {
+                socket: service_socket.clone(),
+                source: e,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, Permissions},
+        io::ErrorKind,
+        os::unix::fs::PermissionsExt,
+    };
+
+    use super::SecurityPolicy;
+    use crate::{credentials::current_uid, ContextDir};
+
+    #[test]
+    pub fn security_policy_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let socket_path = context.join("secure.sock");
+        fs::set_permissions(&context, Permissions::from_mode(0o700)).unwrap();
+        SecurityPolicy::strict().check(&socket_path).unwrap();
+
+        std::os::unix::fs::symlink("/nonexistent", &socket_path).unwrap();
+        SecurityPolicy::new().check(&socket_path).unwrap();
+        let symlink = SecurityPolicy::new()
+            .with_symlink_check()
+            .check(&socket_path);
+        assert_eq!(symlink.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        fs::remove_file(&socket_path).unwrap();
+
+        let wrong_owner = SecurityPolicy::new()
+            .with_dir_owner(current_uid().wrapping_add(1))
+            .check(&socket_path);
+        assert_eq!(wrong_owner.unwrap_err().kind(), ErrorKind::PermissionDenied);
+
+        fs::set_permissions(&context, Permissions::from_mode(0o777)).unwrap();
+        let world_writable = SecurityPolicy::new()
+            .with_world_writable_check()
+            .check(&socket_path);
+        assert_eq!(
+            world_writable.unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        fs::remove_dir_all(&context).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
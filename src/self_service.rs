@@ -0,0 +1,120 @@
+//! Servers living in the same binary as their clients - see [`crate::declare_self_service`].
+//!
+//! A self service is started by running the current executable again with a subcommand. The
+//! `main()` of that executable then checks for the subcommand with
+//! [`SelfServiceInvocation::from_env`] before doing anything else, something like:
+//! ```rust,ignore
+//! if let Some(invocation) = SelfServiceInvocation::from_env("serve") {
+//!     let environment = invocation.server_environment(context_dir);
+//!     return environment.start_and_run_server(&MyServer, &MyService).await;
+//! }
+//! // ... carry on being a client
+//! ```
+
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+use crate::{environment::ServerEnvironment, liveness};
+
+/// Arguments and liveness socket a self service was started with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfServiceInvocation {
+    /// Arguments after the subcommand.
+    pub args: Vec<OsString>,
+    /// Liveness socket to ping once the service socket exists, if the service was started by a
+    /// client - see [`liveness::retrieve_liveness_path`].
+    pub liveness_socket_path: Option<PathBuf>,
+}
+
+impl SelfServiceInvocation {
+    /// Check whether the current process was started with the given subcommand - see
+    /// [`Self::from_args`].
+    pub fn from_env(subcommand: impl AsRef<OsStr>) -> Option<Self> {
+        Self::from_args(subcommand, std::env::args_os())
+    }
+
+    /// Check whether the arguments - starting with the program name, like
+    /// [`std::env::args_os`] - start with the given subcommand. If they do, the liveness socket
+    /// path is taken out of the environment of the current process, as in
+    /// [`liveness::retrieve_liveness_path`].
+    pub fn from_args(
+        subcommand: impl AsRef<OsStr>,
+        args: impl IntoIterator<Item = OsString>,
+    ) -> Option<Self> {
+        let mut args = args.into_iter().skip(1);
+        if args.next()? != subcommand.as_ref() {
+            return None;
+        }
+        Some(Self {
+            args: args.collect(),
+            liveness_socket_path: liveness::retrieve_liveness_path(),
+        })
+    }
+
+    /// Arguments after the subcommand.
+    pub fn args(&self) -> &[OsString] {
+        &self.args
+    }
+
+    /// Liveness socket to ping once the service socket exists, if any.
+    pub fn liveness_socket_path(&self) -> Option<&Path> {
+        self.liveness_socket_path.as_deref()
+    }
+
+    /// Environment to run the server in, with the service socket in the given directory.
+    pub fn server_environment(
+        &self,
+        base_context_directory: impl Into<PathBuf>,
+    ) -> ServerEnvironment {
+        ServerEnvironment {
+            base_context_directory: base_context_directory.into(),
+            liveness_socket_path: self.liveness_socket_path.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn subcommand_is_matched() {
+        let args = |args: &[&str]| args.iter().map(OsString::from).collect::<Vec<_>>();
+        assert_eq!(
+            SelfServiceInvocation::from_args("serve", args(&["app", "status"])),
+            None
+        );
+        assert_eq!(
+            SelfServiceInvocation::from_args("serve", args(&["app"])),
+            None
+        );
+        let invocation =
+            SelfServiceInvocation::from_args("serve", args(&["app", "serve", "--verbose"]))
+                .unwrap();
+        assert_eq!(invocation.args(), args(&["--verbose"]));
+        assert_eq!(
+            invocation
+                .server_environment("/run/app")
+                .base_context_directory(),
+            Path::new("/run/app")
+        );
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,258 @@
+//! `SOCK_SEQPACKET` unix sockets, which preserve the boundaries of messages - see
+//! [`SeqPacketConnection`] and [`crate::socket_shims::SocketKind::SeqPacket`].
+//!
+//! Services of that kind bind a `SOCK_SEQPACKET` listener instead of a stream one, and clients
+//! connect to it accordingly. Their connections are still streams of the
+//! [`UnixSocketInterface`], which sends and receives whole messages over them with
+//! [`UnixSocketInterface::unix_seqpacket_send`] and [`UnixSocketInterface::unix_seqpacket_recv`].
+//! The `seqpacket` method of [`crate::declare_service`] wraps them in a [`SeqPacketConnection`],
+//! so protocols that are naturally message-oriented need no framing of their own.
+
+use std::{
+    fmt::Debug,
+    io,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+};
+
+use rustix::net::{AddressFamily, SocketAddrUnix, SocketFlags, SocketType};
+
+use crate::{DefaultUnixSocks, IoResult, UnixSocketInterface};
+
+/// Backlog of listening sockets - the same as the standard library uses.
+const LISTEN_BACKLOG: i32 = 128;
+
+/// Bind a std `SOCK_SEQPACKET` listener at the path.
+pub(crate) fn bind_std(socket_path: &Path) -> IoResult<UnixListener> {
+    let socket = rustix::net::socket_with(
+        AddressFamily::UNIX,
+        SocketType::SEQPACKET,
+        SocketFlags::CLOEXEC,
+        None,
+    )?;
+    rustix::net::bind(&socket, &SocketAddrUnix::new(socket_path)?)?;
+    rustix::net::listen(&socket, LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// Connect a std `SOCK_SEQPACKET` socket to the path.
+pub(crate) fn connect_std(socket_path: &Path) -> IoResult<UnixStream> {
+    let socket = rustix::net::socket_with(
+        AddressFamily::UNIX,
+        SocketType::SEQPACKET,
+        SocketFlags::CLOEXEC,
+        None,
+    )?;
+    rustix::net::connect(&socket, &SocketAddrUnix::new(socket_path)?)?;
+    Ok(socket.into())
+}
+
+/// A connected pair of `SOCK_SEQPACKET` sockets, like [`UnixStream::pair`].
+pub fn pair() -> IoResult<(UnixStream, UnixStream)> {
+    let (a, b) = rustix::net::socketpair(
+        AddressFamily::UNIX,
+        SocketType::SEQPACKET,
+        SocketFlags::CLOEXEC,
+        None,
+    )?;
+    Ok((a.into(), b.into()))
+}
+
+/// Whether connecting failed because the socket is of another kind than the one connecting.
+pub(crate) fn is_wrong_socket_kind(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(rustix::io::Errno::PROTOTYPE.raw_os_error())
+}
+
+/// Connect to whatever kind of socket is at the path - for probing sockets without knowing the
+/// service they belong to.
+pub(crate) async fn probe_connect<U: UnixSocketInterface>(
+    socket_path: &Path,
+) -> IoResult<U::UnixStream> {
+    match U::unix_stream_connect(socket_path).await {
+        Err(e) if is_wrong_socket_kind(&e) => U::unix_seqpacket_connect(socket_path).await,
+        probed => probed,
+    }
+}
+
+/// `SOCK_SEQPACKET` stream that sends and receives whole messages.
+///
+/// Messages can't be empty, as receiving nothing means the other side closed the connection.
+pub struct SeqPacketConnection<U: UnixSocketInterface = DefaultUnixSocks> {
+    stream: U::UnixStream,
+    max_message_size: usize,
+}
+
+impl<U: UnixSocketInterface> Debug for SeqPacketConnection<U>
+where
+    U::UnixStream: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SeqPacketConnection")
+            .field("stream", &self.stream)
+            .field("max_message_size", &self.max_message_size)
+            .finish()
+    }
+}
+
+impl<U: UnixSocketInterface> SeqPacketConnection<U> {
+    /// Largest message a connection made with [`Self::new`] sends or receives.
+    pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+    /// Wrap a bare `SOCK_SEQPACKET` stream, with messages of up to
+    /// [`Self::DEFAULT_MAX_MESSAGE_SIZE`].
+    pub fn new(stream: U::UnixStream) -> Self {
+        Self::with_max_message_size(Self::DEFAULT_MAX_MESSAGE_SIZE, stream)
+    }
+
+    /// Wrap a bare `SOCK_SEQPACKET` stream, with messages of up to the given size - a size of 0
+    /// is treated as 1.
+    pub fn with_max_message_size(max_message_size: usize, stream: U::UnixStream) -> Self {
+        Self {
+            stream,
+            max_message_size: max_message_size.max(1),
+        }
+    }
+
+    /// Largest message this sends or receives.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// The bare stream.
+    pub fn get_ref(&self) -> &U::UnixStream {
+        &self.stream
+    }
+
+    /// The bare stream, mutably.
+    pub fn get_mut(&mut self) -> &mut U::UnixStream {
+        &mut self.stream
+    }
+
+    /// Take back the bare stream.
+    pub fn into_inner(self) -> U::UnixStream {
+        self.stream
+    }
+
+    /// Send a message. Empty messages, and those bigger than [`Self::max_message_size`], are
+    /// [`io::ErrorKind::InvalidInput`] errors.
+    pub async fn send(&mut self, message: &[u8]) -> IoResult<()> {
+        if message.is_empty() || message.len() > self.max_message_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "messages must be between 1 and {} bytes, not {}",
+                    self.max_message_size,
+                    message.len()
+                ),
+            ));
+        }
+        let sent = U::unix_seqpacket_send(&mut self.stream, message).await?;
+        if sent != message.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                format!(
+                    "only sent {} bytes of a {} byte message",
+                    sent,
+                    message.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Receive a message, or `None` if the other side closed the connection. Messages bigger
+    /// than [`Self::max_message_size`] are [`io::ErrorKind::InvalidData`] errors, as the rest of
+    /// them is lost.
+    pub async fn recv(&mut self) -> IoResult<Option<Vec<u8>>> {
+        // One byte more than allowed, to tell messages that fit from those that were cut short.
+        let mut message = vec![0; self.max_message_size + 1];
+        let received = U::unix_seqpacket_recv(&mut self.stream, &mut message).await?;
+        if received == 0 {
+            return Ok(None);
+        }
+        if received > self.max_message_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "received a message bigger than {} bytes",
+                    self.max_message_size
+                ),
+            ));
+        }
+        message.truncate(received);
+        Ok(Some(message))
+    }
+
+    /// Shut the stream down - see [`UnixSocketInterface::unix_stream_shutdown`].
+    pub async fn shutdown(&mut self) -> IoResult<()> {
+        U::unix_stream_shutdown(&mut self.stream).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use blocking::Unblock;
+    use futures_lite::future::block_on;
+
+    use super::{bind_std, connect_std, probe_connect, SeqPacketConnection};
+    use crate::{socket_shims::StdThreadpoolUSocks, ContextDir};
+
+    #[test]
+    pub fn seqpacket_connection_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let socket_path = context.join("seqpacket.sock");
+        let listener = bind_std(&socket_path).unwrap();
+        let client = connect_std(&socket_path).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        block_on(async {
+            let mut client = SeqPacketConnection::<StdThreadpoolUSocks>::new(Unblock::new(client));
+            let mut server = SeqPacketConnection::<StdThreadpoolUSocks>::with_max_message_size(
+                8,
+                Unblock::new(server),
+            );
+            // Boundaries are kept, even when both messages are waiting.
+            client.send(b"first").await.unwrap();
+            client.send(b"second").await.unwrap();
+            assert_eq!(server.recv().await.unwrap().unwrap(), b"first");
+            assert_eq!(server.recv().await.unwrap().unwrap(), b"second");
+
+            assert_eq!(
+                client.send(b"").await.unwrap_err().kind(),
+                ErrorKind::InvalidInput
+            );
+            client.send(b"too long to receive").await.unwrap();
+            assert_eq!(
+                server.recv().await.unwrap_err().kind(),
+                ErrorKind::InvalidData
+            );
+
+            client.shutdown().await.unwrap();
+            assert_eq!(server.recv().await.unwrap(), None);
+
+            // Probing finds the socket whatever its kind.
+            let _probe = probe_connect::<StdThreadpoolUSocks>(&socket_path)
+                .await
+                .unwrap();
+            listener.accept().unwrap();
+        });
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
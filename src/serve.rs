@@ -0,0 +1,984 @@
+//! Helpers for implementing [`crate::Server::run_server`] - in particular, an accept loop that
+//! hands every incoming connection to a handler, and drains in-flight connections on shutdown or
+//! once the server has been idle for a while. It can also cap how many connections are handled at
+//! once - see [`ServeOptions`].
+
+use std::{
+    future::Future,
+    path::PathBuf,
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use event_listener::Event;
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    audit,
+    credentials::{self, CredentialPolicy, PeerCredentials},
+    events,
+    future::FutureExt,
+    mapfut::map_fut,
+    timefut, IoResult, ServiceEvent, ServiceSocket, UnixSocketInterface,
+};
+
+/// Keeps count of the connection handlers that are still running, so they can be waited on when
+/// shutting down.
+///
+/// Cloning this produces another handle to the same count.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionTracker {
+    inner: Arc<TrackerInner>,
+}
+
+#[derive(Debug, Default)]
+struct TrackerInner {
+    active: AtomicUsize,
+    idle: Event,
+    /// Notified whenever a connection handler finishes.
+    released: Event,
+    /// Service to emit connection [`crate::events`] for, if any.
+    socket: Option<ServiceSocket>,
+}
+
+impl ConnectionTracker {
+    /// Create a tracker with no active connections.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a tracker with no active connections, that emits [`ServiceEvent::Connected`] and
+    /// [`ServiceEvent::ConnectionClosed`] for the given service as connection handlers start and
+    /// finish.
+    pub fn for_service(service_socket: ServiceSocket) -> Self {
+        Self {
+            inner: Arc::new(TrackerInner {
+                socket: Some(service_socket),
+                ..TrackerInner::default()
+            }),
+        }
+    }
+
+    /// Number of tracked connection handlers that haven't finished yet.
+    pub fn active_connections(&self) -> usize {
+        self.inner.active.load(Ordering::Acquire)
+    }
+
+    /// Track a connection handler. It counts as active from now until the produced future
+    /// completes or is dropped.
+    pub fn track<F: Future>(&self, handler: F) -> TrackedConnection<F> {
+        self.inner.active.fetch_add(1, Ordering::AcqRel);
+        if let Some(socket) = &self.inner.socket {
+            events::emit(ServiceEvent::Connected {
+                socket: socket.clone(),
+            });
+        }
+        TrackedConnection {
+            handler: Box::pin(handler),
+            _guard: ActiveGuard(self.inner.clone()),
+        }
+    }
+
+    /// Wait until there are no active connection handlers.
+    pub async fn drained(&self) {
+        loop {
+            if self.active_connections() == 0 {
+                return;
+            }
+            let listener = self.inner.idle.listen();
+            // Re-check, in case the last handler finished before we started listening.
+            if self.active_connections() == 0 {
+                return;
+            }
+            listener.await;
+        }
+    }
+
+    /// Wait until fewer than `limit` connection handlers are active.
+    pub async fn below(&self, limit: usize) {
+        loop {
+            if self.active_connections() < limit {
+                return;
+            }
+            let listener = self.inner.released.listen();
+            // Re-check, in case a handler finished before we started listening.
+            if self.active_connections() < limit {
+                return;
+            }
+            listener.await;
+        }
+    }
+}
+
+/// Decrements the active count of a [`ConnectionTracker`] when dropped.
+#[derive(Debug)]
+struct ActiveGuard(Arc<TrackerInner>);
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        if let Some(socket) = &self.0.socket {
+            events::emit(ServiceEvent::ConnectionClosed {
+                socket: socket.clone(),
+            });
+        }
+        if self.0.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify(usize::MAX);
+        }
+        self.0.released.notify(usize::MAX);
+    }
+}
+
+/// Connection handler future tracked by a [`ConnectionTracker`] - this is what gets passed to the
+/// spawner in [`serve_connections`].
+#[derive(Debug)]
+pub struct TrackedConnection<F> {
+    handler: Pin<Box<F>>,
+    _guard: ActiveGuard,
+}
+
+impl<F: Future> Future for TrackedConnection<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.handler.as_mut().poll(cx)
+    }
+}
+
+/// What the accept loop does while as many connections are active as
+/// [`ServeOptions::with_max_concurrent_connections`] allows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AtCapacity {
+    /// Stop accepting until a connection finishes - new clients wait in the listen backlog of
+    /// the socket, and get refused once that is full.
+    #[default]
+    Queue,
+    /// Keep accepting, but shut new connections down straight away without handling them.
+    Reject,
+    /// Stop accepting, and move the socket aside to [`ServiceSocket::paused_path`] until a
+    /// connection finishes, so new clients find no socket at all rather than waiting on it.
+    ///
+    /// Clients that find no socket may well start another instance of the service, which then
+    /// takes over the socket path - if it does, this server stops accepting for good and drains,
+    /// as if shut down. This needs [`ServeOptions::for_service`] to know where the socket is,
+    /// and falls back to [`AtCapacity::Queue`] without it.
+    PauseAccepting,
+}
+
+/// How to run the accept loop of [`serve_with_options`] - which service the connections are for,
+/// who may connect, how many at once, and when to stop for lack of work.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServeOptions {
+    service_socket: Option<ServiceSocket>,
+    credential_policy: Option<CredentialPolicy>,
+    idle_timeout: Option<Duration>,
+    max_concurrent_connections: Option<usize>,
+    at_capacity: AtCapacity,
+}
+
+impl ServeOptions {
+    /// Accept everyone, and never stop for lack of work.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Emit connection [`crate::events`] for this service - see
+    /// [`ConnectionTracker::for_service`].
+    pub fn for_service(mut self, service_socket: ServiceSocket) -> Self {
+        self.service_socket = Some(service_socket);
+        self
+    }
+
+    /// Only let in peers whose credentials the policy allows - see
+    /// [`serve_connections_with_credentials`].
+    pub fn with_credential_policy(mut self, credential_policy: CredentialPolicy) -> Self {
+        self.credential_policy = Some(credential_policy);
+        self
+    }
+
+    /// Stop accepting connections once none have been active, and none have arrived, for this
+    /// long - so services started on demand release their resources again when they aren't
+    /// used. The accept loop then returns `Ok(())` as if shut down.
+    ///
+    /// With [`crate::ServerExt`], the service socket is removed as soon as
+    /// [`crate::Server::run_server`] returns, so the next client starts a new instance - return
+    /// from it promptly after the accept loop finishes.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Handle at most this many connections at once, doing what `at_capacity` says with any more
+    /// - so a flood of clients can't exhaust the server. A limit of 0 is treated as 1.
+    pub fn with_max_concurrent_connections(
+        mut self,
+        max_concurrent_connections: usize,
+        at_capacity: AtCapacity,
+    ) -> Self {
+        self.max_concurrent_connections = Some(max_concurrent_connections.max(1));
+        self.at_capacity = at_capacity;
+        self
+    }
+
+    /// Service connection events are emitted for, if any.
+    pub fn service_socket(&self) -> Option<&ServiceSocket> {
+        self.service_socket.as_ref()
+    }
+
+    /// Policy peers are checked against, if any.
+    pub fn credential_policy(&self) -> Option<&CredentialPolicy> {
+        self.credential_policy.as_ref()
+    }
+
+    /// How long the server may be idle before it stops, if at all.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+
+    /// Most connections handled at once, if limited.
+    pub fn max_concurrent_connections(&self) -> Option<usize> {
+        self.max_concurrent_connections
+    }
+
+    /// What happens to connections beyond [`ServeOptions::max_concurrent_connections`].
+    pub fn at_capacity(&self) -> AtCapacity {
+        self.at_capacity
+    }
+}
+
+/// Accept connections on a service listener until `shutdown` completes, running `handler` for
+/// each one.
+///
+/// The future produced by the handler is wrapped in a [`TrackedConnection`] and passed to
+/// `spawner`, which should spawn it as a task on whatever runtime you use - for instance
+/// `|connection| { tokio::spawn(connection); }`, or a [`crate::TaskSpawner`] turned into one with
+/// [`crate::task::connection_spawner`]. This keeps the accept loop itself independent of any
+/// particular runtime.
+///
+/// Once `shutdown` completes, no more connections are accepted, and this waits for all handlers
+/// still in flight to finish before returning. If accepting a connection fails with an
+/// unrecoverable error, in-flight handlers are drained in the same way and the error is returned.
+#[instrument(skip_all)]
+pub async fn serve_connections<U, H, HF, Sp>(
+    listener: &mut U::UnixListener,
+    handler: H,
+    spawner: Sp,
+    shutdown: impl Future<Output = ()>,
+) -> IoResult<()>
+where
+    U: UnixSocketInterface,
+    H: FnMut(U::UnixStream, U::SocketAddr) -> HF,
+    HF: Future<Output = ()>,
+    Sp: FnMut(TrackedConnection<HF>),
+{
+    let mut handler = handler;
+    accept_loop::<U, _, _, _>(
+        listener,
+        &ServeOptions::new(),
+        |stream, addr, _| handler(stream, addr),
+        spawner,
+        shutdown,
+    )
+    .await
+}
+
+/// Like [`serve_connections`], but only let in peers whose credentials the policy allows - see
+/// [`crate::credentials`]. The credentials of each peer are passed on to the handler.
+///
+/// Connections from peers that aren't allowed, or whose credentials can't be read, are logged and
+/// shut down without ever reaching the handler.
+#[instrument(skip_all)]
+pub async fn serve_connections_with_credentials<U, H, HF, Sp>(
+    listener: &mut U::UnixListener,
+    policy: &CredentialPolicy,
+    handler: H,
+    spawner: Sp,
+    shutdown: impl Future<Output = ()>,
+) -> IoResult<()>
+where
+    U: UnixSocketInterface,
+    H: FnMut(U::UnixStream, U::SocketAddr, PeerCredentials) -> HF,
+    HF: Future<Output = ()>,
+    Sp: FnMut(TrackedConnection<HF>),
+{
+    let mut handler = handler;
+    accept_loop::<U, _, _, _>(
+        listener,
+        &ServeOptions::new().with_credential_policy(policy.clone()),
+        |stream, addr, credentials| {
+            handler(
+                stream,
+                addr,
+                credentials.expect("credentials are checked when there is a policy"),
+            )
+        },
+        spawner,
+        shutdown,
+    )
+    .await
+}
+
+/// Like [`serve_connections`], but also emit [`ServiceEvent::Connected`] and
+/// [`ServiceEvent::ConnectionClosed`] for the service on the given socket as connections come and
+/// go - see [`ConnectionTracker::for_service`].
+#[instrument(skip_all, fields(socket = %service_socket))]
+pub async fn serve_service_connections<U, H, HF, Sp>(
+    service_socket: &ServiceSocket,
+    listener: &mut U::UnixListener,
+    handler: H,
+    spawner: Sp,
+    shutdown: impl Future<Output = ()>,
+) -> IoResult<()>
+where
+    U: UnixSocketInterface,
+    H: FnMut(U::UnixStream, U::SocketAddr) -> HF,
+    HF: Future<Output = ()>,
+    Sp: FnMut(TrackedConnection<HF>),
+{
+    let mut handler = handler;
+    accept_loop::<U, _, _, _>(
+        listener,
+        &ServeOptions::new().for_service(service_socket.clone()),
+        |stream, addr, _| handler(stream, addr),
+        spawner,
+        shutdown,
+    )
+    .await
+}
+
+/// Like [`serve_connections`], but configured with [`ServeOptions`] - which can combine
+/// connection events, a credential policy, a limit on concurrent connections, and stopping once
+/// the server is idle. The credentials of each peer are passed on to the handler if there is a
+/// policy.
+#[instrument(skip_all)]
+pub async fn serve_with_options<U, H, HF, Sp>(
+    listener: &mut U::UnixListener,
+    options: &ServeOptions,
+    handler: H,
+    spawner: Sp,
+    shutdown: impl Future<Output = ()>,
+) -> IoResult<()>
+where
+    U: UnixSocketInterface,
+    H: FnMut(U::UnixStream, U::SocketAddr, Option<PeerCredentials>) -> HF,
+    HF: Future<Output = ()>,
+    Sp: FnMut(TrackedConnection<HF>),
+{
+    accept_loop::<U, _, _, _>(listener, options, handler, spawner, shutdown).await
+}
+
+/// Complete once no connection has been active, or accepted since `last_accept`, for the idle
+/// timeout.
+async fn idle(tracker: &ConnectionTracker, last_accept: &Mutex<Instant>, idle_timeout: Duration) {
+    loop {
+        tracker.drained().await;
+        let idle_since = Instant::now();
+        timefut::sleep(idle_timeout).await;
+        let accepted_since = *last_accept.lock().unwrap_or_else(|e| e.into_inner()) > idle_since;
+        if tracker.active_connections() == 0 && !accepted_since {
+            return;
+        }
+    }
+}
+
+/// Move the socket aside to its paused path, so new clients don't find it.
+async fn pause_socket(service_socket: &ServiceSocket) -> IoResult<()> {
+    let (path, paused_path) = (service_socket.path.clone(), service_socket.paused_path());
+    blocking::unblock(move || std::fs::rename(path, paused_path)).await
+}
+
+/// Move the socket back from its paused path, producing whether it could be - it can't if
+/// another server has bound the socket path in the meantime, in which case the paused socket is
+/// removed.
+async fn resume_socket(service_socket: &ServiceSocket) -> IoResult<bool> {
+    restore_socket(service_socket.path.clone(), service_socket.paused_path()).await
+}
+
+/// Move the socket at `aside_path` back to `path` - see [`resume_socket`].
+async fn restore_socket(path: PathBuf, aside_path: PathBuf) -> IoResult<bool> {
+    blocking::unblock(move || {
+        // Unlike renaming, linking never replaces the socket of another server.
+        let resumed = match std::fs::hard_link(&aside_path, path) {
+            Ok(()) => true,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => false,
+            Err(e) => return Err(e),
+        };
+        std::fs::remove_file(aside_path)?;
+        Ok(resumed)
+    })
+    .await
+}
+
+/// Whether the socket of a server is where clients find it - see [`PauseHandle`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Publication {
+    Published,
+    Paused,
+    /// The server stopped, handed its listener off, or another server took over the socket path
+    /// while it was paused.
+    Gone,
+}
+
+/// Takes the socket of a running server out of sight of new clients, and puts it back - for
+/// servers that are overloaded or reconfiguring to shed new clients for a while. Get one with
+/// [`crate::PublishedServer::pause_handle`]. Clones control the same server.
+///
+/// While paused, the socket is moved aside to [`ServiceSocket::hidden_path`], so the listener
+/// stays bound and connections already accepted are unaffected. Clients that find no socket may
+/// well start another instance of the service - if it takes over the socket path, the paused
+/// server can't resume.
+#[derive(Debug, Clone)]
+pub struct PauseHandle {
+    service_socket: ServiceSocket,
+    publication: Arc<async_lock::Mutex<Publication>>,
+}
+
+impl PauseHandle {
+    pub(crate) fn new(service_socket: ServiceSocket) -> Self {
+        Self {
+            service_socket,
+            publication: Arc::new(async_lock::Mutex::new(Publication::Published)),
+        }
+    }
+
+    /// Move the socket aside, so new clients don't find it. Pausing a paused server does nothing.
+    #[instrument]
+    pub async fn pause(&self) -> IoResult<()> {
+        let mut publication = self.publication.lock().await;
+        match *publication {
+            Publication::Published => {}
+            Publication::Paused => return Ok(()),
+            Publication::Gone => return Err(self.gone()),
+        }
+        let (path, hidden_path) = (
+            self.service_socket.path.clone(),
+            self.service_socket.hidden_path(),
+        );
+        // Linking never replaces the socket of a server that is initializing there.
+        blocking::unblock(move || {
+            std::fs::hard_link(&path, &hidden_path)?;
+            std::fs::remove_file(path)
+        })
+        .await?;
+        info!("Paused accepting @ {}", self.service_socket);
+        *publication = Publication::Paused;
+        Ok(())
+    }
+
+    /// Move the socket back, so clients find it again, producing whether it could be - it can't
+    /// if another server has taken over the socket path in the meantime, in which case the
+    /// paused socket is removed, and the server should shut down. Resuming a server that isn't
+    /// paused does nothing.
+    #[instrument]
+    pub async fn resume(&self) -> IoResult<bool> {
+        let mut publication = self.publication.lock().await;
+        match *publication {
+            Publication::Paused => {}
+            Publication::Published => return Ok(true),
+            Publication::Gone => return Ok(false),
+        }
+        let resumed = restore_socket(
+            self.service_socket.path.clone(),
+            self.service_socket.hidden_path(),
+        )
+        .await?;
+        if resumed {
+            info!("Resumed accepting @ {}", self.service_socket);
+            *publication = Publication::Published;
+        } else {
+            warn!(
+                "Another server took over socket @ {}, can't resume accepting",
+                self.service_socket
+            );
+            *publication = Publication::Gone;
+        }
+        Ok(resumed)
+    }
+
+    /// Whether the server is paused.
+    pub async fn is_paused(&self) -> bool {
+        *self.publication.lock().await == Publication::Paused
+    }
+
+    /// Note that the listener was handed off to a new instance of the service - see
+    /// [`crate::control::ControlCommand::Handoff`] - so the socket is the new instance's to
+    /// remove, and can't be paused anymore. A paused socket is moved back for the new instance.
+    pub(crate) async fn handed_off(&self) {
+        let mut publication = self.publication.lock().await;
+        if *publication == Publication::Paused {
+            let restored = restore_socket(
+                self.service_socket.path.clone(),
+                self.service_socket.hidden_path(),
+            )
+            .await;
+            if !matches!(restored, Ok(true)) {
+                warn!(
+                    "Couldn't move socket @ {} back for the new instance",
+                    self.service_socket
+                );
+            }
+        }
+        *publication = Publication::Gone;
+    }
+
+    /// Note that the server stopped, removing its socket if it was moved aside - producing
+    /// whether the socket at the service socket path is still its own to remove.
+    pub(crate) async fn stopped(&self) -> bool {
+        let mut publication = self.publication.lock().await;
+        let published = *publication == Publication::Published;
+        if *publication == Publication::Paused {
+            let _ = std::fs::remove_file(self.service_socket.hidden_path());
+        }
+        *publication = Publication::Gone;
+        published
+    }
+
+    fn gone(&self) -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("Server @ {} is no longer running", self.service_socket),
+        )
+    }
+}
+
+/// Accept loop behind [`serve_connections`] and friends. With a policy, the credentials of every
+/// peer are checked before handling it, and handed to the handler.
+async fn accept_loop<U, H, HF, Sp>(
+    listener: &mut U::UnixListener,
+    options: &ServeOptions,
+    mut handler: H,
+    mut spawner: Sp,
+    shutdown: impl Future<Output = ()>,
+) -> IoResult<()>
+where
+    U: UnixSocketInterface,
+    H: FnMut(U::UnixStream, U::SocketAddr, Option<PeerCredentials>) -> HF,
+    HF: Future<Output = ()>,
+    Sp: FnMut(TrackedConnection<HF>),
+{
+    let tracker = match options.service_socket() {
+        Some(service_socket) => ConnectionTracker::for_service(service_socket.clone()),
+        None => ConnectionTracker::new(),
+    };
+    let policy = options.credential_policy();
+    let last_accept = Mutex::new(Instant::now());
+    let shutdown = async {
+        match options.idle_timeout() {
+            Some(idle_timeout) => {
+                let idle = async {
+                    idle(&tracker, &last_accept, idle_timeout).await;
+                    info!(
+                        "Idle for {}, shutting down",
+                        humantime::format_duration(idle_timeout)
+                    );
+                };
+                shutdown.or(idle).await
+            }
+            None => shutdown.await,
+        }
+    };
+    let mut shutdown = pin!(shutdown);
+    let result = loop {
+        let at_limit = options.max_concurrent_connections().filter(|&limit| {
+            options.at_capacity() != AtCapacity::Reject && tracker.active_connections() >= limit
+        });
+        if let Some(limit) = at_limit {
+            info!(
+                "At {} active connection(s), waiting for one to finish",
+                limit
+            );
+            let paused = match (options.at_capacity(), options.service_socket()) {
+                (AtCapacity::PauseAccepting, Some(service_socket)) => {
+                    match pause_socket(service_socket).await {
+                        Ok(()) => Some(service_socket),
+                        Err(e) => {
+                            warn!("Couldn't pause socket @ {} - {}", service_socket, e);
+                            None
+                        }
+                    }
+                }
+                (AtCapacity::PauseAccepting, None) => {
+                    warn!("No service socket to pause, queueing connections instead");
+                    None
+                }
+                _ => None,
+            };
+            // false means shutdown was requested.
+            let freed = map_fut(tracker.below(limit), |_| true)
+                .or(map_fut(shutdown.as_mut(), |_| false))
+                .await;
+            if let Some(service_socket) = paused {
+                match resume_socket(service_socket).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        info!(
+                            "Another server took over socket @ {}, no longer accepting connections",
+                            service_socket
+                        );
+                        break Ok(());
+                    }
+                    Err(e) => {
+                        error!("Couldn't resume socket @ {} - {}", service_socket, e);
+                        break Err(e);
+                    }
+                }
+            }
+            if !freed {
+                info!("Shutdown requested, no longer accepting connections");
+                break Ok(());
+            }
+        }
+        // None means shutdown was requested.
+        let maybe_accepted = map_fut(U::unix_listener_accept(listener), Some)
+            .or(map_fut(shutdown.as_mut(), |_| None))
+            .await;
+        match maybe_accepted {
+            Some(Ok((mut stream, addr))) => {
+                debug!("Accepted connection");
+                *last_accept.lock().unwrap_or_else(|e| e.into_inner()) = Instant::now();
+                if options
+                    .max_concurrent_connections()
+                    .is_some_and(|limit| tracker.active_connections() >= limit)
+                {
+                    warn!("Refusing connection - too many active connections");
+                    let _ = U::unix_stream_shutdown(&mut stream).await;
+                    continue;
+                }
+                let credentials = match policy {
+                    Some(policy) => match credentials::check_peer::<U>(&mut stream, policy).await {
+                        Ok(credentials) => Some(credentials),
+                        Err(e) => {
+                            warn!("Refusing connection - {}", e);
+                            if audit::is_enabled() {
+                                let peer = U::unix_stream_peer_credentials(&mut stream).await.ok();
+                                let reason = e.to_string();
+                                let denied =
+                                    audit::AuditEvent::AuthorizationDenied { peer, reason };
+                                audit::record(options.service_socket(), denied);
+                            }
+                            let _ = U::unix_stream_shutdown(&mut stream).await;
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+                if audit::is_enabled() {
+                    let peer = match credentials {
+                        Some(credentials) => Some(credentials),
+                        None => U::unix_stream_peer_credentials(&mut stream).await.ok(),
+                    };
+                    let accepted = audit::AuditEvent::ConnectionAccepted { peer };
+                    audit::record(options.service_socket(), accepted);
+                }
+                spawner(tracker.track(handler(stream, addr, credentials)));
+            }
+            Some(Err(e))
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::Interrupted
+                ) =>
+            {
+                warn!("Transient error accepting connection - {}", e);
+            }
+            Some(Err(e)) => {
+                error!("Failed to accept connection - {}", e);
+                break Err(e);
+            }
+            None => {
+                info!("Shutdown requested, no longer accepting connections");
+                break Ok(());
+            }
+        }
+    };
+    info!(
+        "Draining {} active connection(s)",
+        tracker.active_connections()
+    );
+    tracker.drained().await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+        time::{Duration, Instant},
+    };
+
+    use futures_lite::{future::block_on, StreamExt};
+
+    use super::*;
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn accept_loop_handles_and_drains_connections() {
+        let socket_path = temp_dir().join(format!("serve-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        let mut listener = block_on(StdThreadpoolUSocks::unix_listener_bind(&socket_path)).unwrap();
+
+        let handled = Arc::new(AtomicUsize::new(0));
+        let client_socket_path = socket_path.clone();
+        // Shut down once the client is done.
+        let client = blocking::unblock(move || {
+            let mut stream = UnixStream::connect(client_socket_path).unwrap();
+            stream.write_all(b"x").unwrap();
+            let mut echoed = [0u8];
+            stream.read_exact(&mut echoed).unwrap();
+            assert_eq!(&echoed, b"x");
+        });
+
+        let handler_count = handled.clone();
+        block_on(serve_connections::<StdThreadpoolUSocks, _, _, _>(
+            &mut listener,
+            |stream, _addr| {
+                let handler_count = handler_count.clone();
+                async move {
+                    let mut stream = stream.into_inner().await;
+                    blocking::unblock(move || {
+                        let mut buf = [0u8];
+                        stream.read_exact(&mut buf).unwrap();
+                        stream.write_all(&buf).unwrap();
+                    })
+                    .await;
+                    handler_count.fetch_add(1, Ordering::AcqRel);
+                }
+            },
+            |connection| {
+                std::thread::spawn(move || block_on(connection));
+            },
+            client,
+        ))
+        .unwrap();
+
+        assert_eq!(handled.load(Ordering::Acquire), 1);
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+
+    #[test]
+    pub fn idle_timeout_test() {
+        let socket_path = temp_dir().join(format!("serve-idle-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        let mut listener = block_on(StdThreadpoolUSocks::unix_listener_bind(&socket_path)).unwrap();
+        let options = ServeOptions::new().with_idle_timeout(Duration::from_millis(300));
+
+        // A client keeps the server busy for a while, then it goes idle.
+        let client_socket_path = socket_path.clone();
+        let client = std::thread::spawn(move || {
+            let mut stream = UnixStream::connect(client_socket_path).unwrap();
+            std::thread::sleep(Duration::from_millis(500));
+            stream.write_all(b"x").unwrap();
+        });
+        let started = Instant::now();
+        block_on(serve_with_options::<StdThreadpoolUSocks, _, _, _>(
+            &mut listener,
+            &options,
+            |stream, _addr, credentials| {
+                assert_eq!(credentials, None);
+                async move {
+                    let mut stream = stream.into_inner().await;
+                    blocking::unblock(move || stream.read_exact(&mut [0u8]).unwrap()).await;
+                }
+            },
+            |connection| {
+                std::thread::spawn(move || block_on(connection));
+            },
+            std::future::pending(),
+        ))
+        .unwrap();
+        // Not while the connection was active, and only once idle for the timeout after it.
+        assert!(started.elapsed() >= Duration::from_millis(800));
+        client.join().unwrap();
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+
+    #[test]
+    pub fn service_connection_events_test() {
+        let service_socket = ServiceSocket::new(
+            std::ffi::OsStr::new(&format!("serve-events-test-{}.sock", std::process::id())),
+            &temp_dir(),
+        );
+        let _ = std::fs::remove_file(&service_socket.path);
+        let mut listener = block_on(StdThreadpoolUSocks::unix_listener_bind(
+            &service_socket.path,
+        ))
+        .unwrap();
+        let mut events = crate::events::subscribe(&service_socket);
+
+        let client_socket_path = service_socket.path.clone();
+        let client = blocking::unblock(move || {
+            let mut stream = UnixStream::connect(client_socket_path).unwrap();
+            // Wait for the server to close the connection.
+            assert_eq!(stream.read(&mut [0u8]).unwrap(), 0);
+        });
+        block_on(serve_service_connections::<StdThreadpoolUSocks, _, _, _>(
+            &service_socket,
+            &mut listener,
+            |stream, _addr| async move { drop(stream) },
+            |connection| {
+                std::thread::spawn(move || block_on(connection));
+            },
+            client,
+        ))
+        .unwrap();
+
+        assert_eq!(
+            block_on(events.next()),
+            Some(ServiceEvent::Connected {
+                socket: service_socket.clone()
+            })
+        );
+        assert_eq!(
+            block_on(events.next()),
+            Some(ServiceEvent::ConnectionClosed {
+                socket: service_socket.clone()
+            })
+        );
+        std::fs::remove_file(&service_socket.path).unwrap();
+    }
+
+    #[test]
+    pub fn max_concurrent_connections_test() {
+        let service_socket = ServiceSocket::new(
+            std::ffi::OsStr::new(&format!("serve-limit-test-{}.sock", std::process::id())),
+            &temp_dir(),
+        );
+        let serve = |at_capacity, client: Box<dyn FnOnce() + Send>| {
+            let _ = std::fs::remove_file(&service_socket.path);
+            let mut listener = block_on(StdThreadpoolUSocks::unix_listener_bind(
+                &service_socket.path,
+            ))
+            .unwrap();
+            let options = ServeOptions::new()
+                .for_service(service_socket.clone())
+                .with_max_concurrent_connections(1, at_capacity);
+            block_on(serve_with_options::<StdThreadpoolUSocks, _, _, _>(
+                &mut listener,
+                &options,
+                |stream, _addr, _| async move {
+                    // Hold on to the connection until the client writes something.
+                    let mut stream = stream.into_inner().await;
+                    blocking::unblock(move || stream.read_exact(&mut [0u8]).unwrap()).await;
+                },
+                |connection| {
+                    std::thread::spawn(move || block_on(connection));
+                },
+                blocking::unblock(client),
+            ))
+            .unwrap();
+            std::fs::remove_file(&service_socket.path).unwrap();
+        };
+        let finish = |mut stream: UnixStream| {
+            stream.write_all(b"x").unwrap();
+            assert_eq!(stream.read(&mut [0u8]).unwrap(), 0);
+        };
+
+        // Connections beyond the limit are shut down straight away.
+        let socket_path = service_socket.path.clone();
+        serve(
+            AtCapacity::Reject,
+            Box::new(move || {
+                let first = UnixStream::connect(&socket_path).unwrap();
+                std::thread::sleep(Duration::from_millis(200));
+                let mut second = UnixStream::connect(&socket_path).unwrap();
+                assert_eq!(second.read(&mut [0u8]).unwrap(), 0);
+                finish(first);
+            }),
+        );
+
+        // The socket is moved aside until the active connection finishes.
+        let socket_path = service_socket.path.clone();
+        let paused_path = service_socket.paused_path();
+        serve(
+            AtCapacity::PauseAccepting,
+            Box::new(move || {
+                let first = UnixStream::connect(&socket_path).unwrap();
+                std::thread::sleep(Duration::from_millis(200));
+                assert_eq!(
+                    UnixStream::connect(&socket_path).unwrap_err().kind(),
+                    std::io::ErrorKind::NotFound
+                );
+                assert!(paused_path.exists());
+                finish(first);
+                let started = Instant::now();
+                while !socket_path.exists() {
+                    assert!(started.elapsed() < Duration::from_secs(10));
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                assert!(!paused_path.exists());
+                finish(UnixStream::connect(&socket_path).unwrap());
+            }),
+        );
+    }
+
+    #[test]
+    pub fn credential_policy_rejects_and_admits_test() {
+        let socket_path = temp_dir().join(format!(
+            "serve-credentials-test-{}.sock",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&socket_path);
+
+        for (policy, admitted) in [
+            (CredentialPolicy::current_user(), true),
+            (CredentialPolicy::new(), false),
+        ] {
+            let mut listener =
+                block_on(StdThreadpoolUSocks::unix_listener_bind(&socket_path)).unwrap();
+            let client_socket_path = socket_path.clone();
+            let client = blocking::unblock(move || {
+                let mut stream = UnixStream::connect(client_socket_path).unwrap();
+                // Either the handler closes the connection, or the accept loop refuses it.
+                assert_eq!(stream.read(&mut [0u8]).unwrap(), 0);
+            });
+            let seen = Arc::new(std::sync::Mutex::new(None));
+            let handler_seen = seen.clone();
+            block_on(serve_connections_with_credentials::<
+                StdThreadpoolUSocks,
+                _,
+                _,
+                _,
+            >(
+                &mut listener,
+                &policy,
+                |stream, _addr, credentials| {
+                    *handler_seen.lock().unwrap() = Some(credentials);
+                    async move { drop(stream) }
+                },
+                |connection| {
+                    std::thread::spawn(move || block_on(connection));
+                },
+                client,
+            ))
+            .unwrap();
+
+            let seen = seen.lock().unwrap().take();
+            assert_eq!(seen.is_some(), admitted);
+            if let Some(credentials) = seen {
+                assert_eq!(credentials.pid, std::process::id());
+            }
+            std::fs::remove_file(&socket_path).unwrap();
+        }
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,348 @@
+//! Shared memory handed out over the stream of a service, for data planes that need more
+//! throughput than the socket - see [`SharedMemory`] and [`SharedMapping`].
+//!
+//! The server creates a [`SharedMemory`] region - a sealed memfd - and sends it to clients with
+//! [`send_region`], which passes its file descriptor (see [`crate::fdpass`]). Clients receive it
+//! with [`recv_region`], and both sides [`SharedMemory::map`] it. The socket stays the control
+//! channel - for telling the other side what was written where, say.
+//!
+//! Regions are sealed against resizing, and clients refuse ones that aren't, so neither side can
+//! pull the memory out from under the mapping of the other. The other side can still write to
+//! the memory at any time, though, so [`SharedMapping`] never hands out references to it - only
+//! copies of [`Pod`] values and bytes, and atomics to coordinate with.
+
+use std::{
+    fmt::Debug,
+    io::{self, ErrorKind},
+    mem::{align_of, size_of},
+    os::fd::{AsFd, BorrowedFd, OwnedFd},
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicU64},
+};
+
+use rustix::{
+    fs::{MemfdFlags, SealFlags},
+    mm::{MapFlags, ProtFlags},
+};
+
+use crate::{fdpass, IoResult, UnixSocketInterface};
+
+/// Types that are valid for every bit pattern of their size, and have no padding - so they can be
+/// read from memory another process writes to.
+///
+/// # Safety
+/// Implementors must have no padding bytes, no invalid bit patterns, and no pointers or other
+/// references - like the integer and floating point types, and arrays of them.
+pub unsafe trait Pod: Copy + Send + Sync + 'static {}
+
+macro_rules! impl_pod {
+    ($($pod:ty),*) => {$(
+        // SAFETY: primitive numbers are valid for every bit pattern and have no padding.
+        unsafe impl Pod for $pod {}
+    )*};
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+// SAFETY: arrays have no padding between elements, and are valid if every element is.
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
+
+/// Seals every region is given, and that received regions need.
+const REQUIRED_SEALS: SealFlags = SealFlags::SHRINK.union(SealFlags::GROW);
+
+/// A region of shared memory, held as the file descriptor of a sealed memfd.
+#[derive(Debug)]
+pub struct SharedMemory {
+    fd: OwnedFd,
+    len: usize,
+}
+
+impl SharedMemory {
+    /// Create a zeroed region of the given size. The name is only for debugging - it shows up in
+    /// `/proc/<pid>/fd`.
+    pub fn create(name: &str, len: usize) -> IoResult<Self> {
+        if len == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "shared memory regions can't be empty",
+            ));
+        }
+        let fd = rustix::fs::memfd_create(name, MemfdFlags::CLOEXEC | MemfdFlags::ALLOW_SEALING)?;
+        rustix::fs::ftruncate(&fd, len as u64)?;
+        rustix::fs::fcntl_add_seals(&fd, REQUIRED_SEALS | SealFlags::SEAL)?;
+        Ok(Self { fd, len })
+    }
+
+    /// Take over a region received from elsewhere. Regions that could still be resized are
+    /// [`io::ErrorKind::InvalidData`] errors.
+    pub fn from_fd(fd: OwnedFd) -> IoResult<Self> {
+        let seals = rustix::fs::fcntl_get_seals(&fd)?;
+        if !seals.contains(REQUIRED_SEALS) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "shared memory region isn't sealed against resizing",
+            ));
+        }
+        let len = usize::try_from(rustix::fs::fstat(&fd)?.st_size)
+            .ok()
+            .filter(|len| *len > 0)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "invalid shared memory size"))?;
+        Ok(Self { fd, len })
+    }
+
+    /// Size of the region in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the region is empty - never, as empty regions can't be made.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Map the region into this process, readable and writable.
+    pub fn map(&self) -> IoResult<SharedMapping> {
+        // SAFETY: a fresh mapping is made - nothing else is placed at the address - and the
+        // region is sealed against shrinking, so the whole mapping stays backed.
+        let ptr = unsafe {
+            rustix::mm::mmap(
+                std::ptr::null_mut(),
+                self.len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+                &self.fd,
+                0,
+            )?
+        };
+        let ptr = NonNull::new(ptr.cast()).ok_or_else(|| io::Error::from(ErrorKind::Other))?;
+        Ok(SharedMapping { ptr, len: self.len })
+    }
+}
+
+impl AsFd for SharedMemory {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl From<SharedMemory> for OwnedFd {
+    fn from(region: SharedMemory) -> Self {
+        region.fd
+    }
+}
+
+/// A [`SharedMemory`] region mapped into this process. It stays mapped until this is dropped,
+/// even if the region itself is dropped first.
+///
+/// Offsets are in bytes from the start of the region. Accesses outside of it are
+/// [`io::ErrorKind::InvalidInput`] errors, as are typed accesses at offsets not aligned for the
+/// type.
+pub struct SharedMapping {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the mapping is only ever accessed through copies and atomics, which are fine from any
+// thread - and other processes write to it concurrently anyway.
+unsafe impl Send for SharedMapping {}
+// SAFETY: as above.
+unsafe impl Sync for SharedMapping {}
+
+impl Debug for SharedMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedMapping")
+            .field("ptr", &self.ptr)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl SharedMapping {
+    /// Size of the mapping in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the mapping is empty - never, as empty regions can't be made.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Pointer to `size` bytes at the offset, aligned to `align`.
+    fn at(&self, offset: usize, size: usize, align: usize) -> IoResult<*mut u8> {
+        let in_bounds = offset.checked_add(size).is_some_and(|end| end <= self.len);
+        if !in_bounds {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes at offset {} are outside of the {} byte region",
+                    size, offset, self.len
+                ),
+            ));
+        }
+        // SAFETY: the offset is within the mapping, as just checked.
+        let ptr = unsafe { self.ptr.as_ptr().add(offset) };
+        // Alignments are powers of two.
+        if ptr as usize & (align - 1) != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("offset {} isn't aligned to {} bytes", offset, align),
+            ));
+        }
+        Ok(ptr)
+    }
+
+    /// Read a value at the offset.
+    pub fn read<T: Pod>(&self, offset: usize) -> IoResult<T> {
+        let ptr = self.at(offset, size_of::<T>(), align_of::<T>())?;
+        // SAFETY: the pointer is in bounds and aligned, and any bits are a valid `T`.
+        Ok(unsafe { ptr.cast::<T>().read_volatile() })
+    }
+
+    /// Write a value at the offset.
+    pub fn write<T: Pod>(&self, offset: usize, value: T) -> IoResult<()> {
+        let ptr = self.at(offset, size_of::<T>(), align_of::<T>())?;
+        // SAFETY: the pointer is in bounds and aligned, and the mapping is writable.
+        unsafe { ptr.cast::<T>().write_volatile(value) };
+        Ok(())
+    }
+
+    /// Copy bytes from the offset into `buf`.
+    pub fn read_bytes(&self, offset: usize, buf: &mut [u8]) -> IoResult<()> {
+        let ptr = self.at(offset, buf.len(), 1)?;
+        // SAFETY: the bytes are in bounds, and can't overlap a buffer of this process.
+        unsafe { ptr.copy_to_nonoverlapping(buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// Copy `bytes` to the offset.
+    pub fn write_bytes(&self, offset: usize, bytes: &[u8]) -> IoResult<()> {
+        let ptr = self.at(offset, bytes.len(), 1)?;
+        // SAFETY: the bytes are in bounds, and can't overlap a buffer of this process.
+        unsafe { ptr.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len()) };
+        Ok(())
+    }
+
+    /// Atomic at the offset, for coordinating with the other side - like a sequence number
+    /// bumped after writing.
+    pub fn atomic_u32(&self, offset: usize) -> IoResult<&AtomicU32> {
+        let ptr = self.at(offset, size_of::<AtomicU32>(), align_of::<AtomicU32>())?;
+        // SAFETY: the pointer is in bounds and aligned, lives as long as the mapping, and is only
+        // ever accessed atomically through the reference.
+        Ok(unsafe { AtomicU32::from_ptr(ptr.cast()) })
+    }
+
+    /// 64 bit [`Self::atomic_u32`].
+    pub fn atomic_u64(&self, offset: usize) -> IoResult<&AtomicU64> {
+        let ptr = self.at(offset, size_of::<AtomicU64>(), align_of::<AtomicU64>())?;
+        // SAFETY: as in `atomic_u32`.
+        Ok(unsafe { AtomicU64::from_ptr(ptr.cast()) })
+    }
+}
+
+impl Drop for SharedMapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was made by `SharedMemory::map`, and nothing borrows from it any
+        // more.
+        let _ = unsafe { rustix::mm::munmap(self.ptr.as_ptr().cast(), self.len) };
+    }
+}
+
+/// Send a region to the other side of the stream - see [`fdpass::send_fd`].
+pub async fn send_region<U: UnixSocketInterface>(
+    stream: &mut U::UnixStream,
+    region: &SharedMemory,
+) -> IoResult<()> {
+    fdpass::send_fd::<U>(stream, region.as_fd()).await
+}
+
+/// Receive a region sent by [`send_region`], which must be the next thing on the stream - see
+/// [`SharedMemory::from_fd`].
+pub async fn recv_region<U: UnixSocketInterface>(
+    stream: &mut U::UnixStream,
+) -> IoResult<SharedMemory> {
+    SharedMemory::from_fd(fdpass::recv_fd::<U>(stream).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::ErrorKind, os::unix::net::UnixStream, sync::atomic::Ordering};
+
+    use blocking::Unblock;
+    use futures_lite::future::block_on;
+    use rustix::fs::MemfdFlags;
+
+    use super::{recv_region, send_region, SharedMemory};
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn shared_memory_test() {
+        let region = SharedMemory::create("suss-shm-test", 4096).unwrap();
+        let (a, b) = UnixStream::pair().unwrap();
+        let (mut server, mut client) = (Unblock::new(a), Unblock::new(b));
+        let received = block_on(async {
+            send_region::<StdThreadpoolUSocks>(&mut server, &region)
+                .await
+                .unwrap();
+            recv_region::<StdThreadpoolUSocks>(&mut client)
+                .await
+                .unwrap()
+        });
+        assert_eq!(received.len(), 4096);
+
+        let server_mapping = region.map().unwrap();
+        drop(region);
+        let client_mapping = received.map().unwrap();
+        server_mapping.write(8, 0xdead_beef_u64).unwrap();
+        server_mapping.write_bytes(16, b"shared").unwrap();
+        server_mapping
+            .atomic_u64(0)
+            .unwrap()
+            .fetch_add(1, Ordering::Release);
+        assert_eq!(
+            client_mapping
+                .atomic_u64(0)
+                .unwrap()
+                .load(Ordering::Acquire),
+            1
+        );
+        assert_eq!(client_mapping.read::<u64>(8).unwrap(), 0xdead_beef);
+        let mut shared = [0u8; 6];
+        client_mapping.read_bytes(16, &mut shared).unwrap();
+        assert_eq!(&shared, b"shared");
+
+        // Out of bounds and misaligned accesses are refused.
+        assert_eq!(
+            client_mapping.read::<u32>(4094).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            client_mapping.read::<u64>(4).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+
+        // So are regions that could be shrunk underneath the mapping.
+        let unsealed = rustix::fs::memfd_create("suss-shm-unsealed", MemfdFlags::CLOEXEC).unwrap();
+        rustix::fs::ftruncate(&unsealed, 4096).unwrap();
+        assert_eq!(
+            SharedMemory::from_fd(unsealed).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
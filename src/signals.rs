@@ -0,0 +1,103 @@
+//! Termination signal handling for servers - requires the `signals` feature.
+//!
+//! [`termination_signal`] installs handlers for SIGTERM and SIGINT, and produces a future that
+//! completes when either arrives. Pass it as the shutdown future of
+//! [`crate::ServerExt::start_and_run_server_with_shutdown`], so that when the system stops your
+//! service it stops serving, unlinks its socket, and drains in-flight work instead of being
+//! killed outright.
+
+use std::{
+    future::Future,
+    os::raw::c_int,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::{Handle, Signals},
+};
+use tracing::{info, warn};
+
+use crate::IoResult;
+
+/// Signals treated as a request to terminate the service.
+pub const TERMINATION_SIGNALS: &[c_int] = &[SIGTERM, SIGINT];
+
+/// Install handlers for [`TERMINATION_SIGNALS`], returning a future that completes when one of them
+/// is received.
+///
+/// The handlers are installed immediately, so signals arriving before the future is first polled
+/// are not lost. While they are installed, these signals no longer terminate the process by
+/// default. Waiting is done on the [`blocking`] threadpool, so this works with any async runtime.
+pub fn termination_signal() -> IoResult<TerminationSignal> {
+    let mut signals = Signals::new(TERMINATION_SIGNALS)?;
+    let handle = signals.handle();
+    let waiter = blocking::unblock(move || signals.forever().next());
+    Ok(TerminationSignal {
+        waiter: Box::pin(waiter),
+        handle,
+    })
+}
+
+/// Future completing when a termination signal is received - see [`termination_signal`].
+///
+/// Dropping this uninstalls the signal handlers.
+pub struct TerminationSignal {
+    waiter: Pin<Box<dyn Future<Output = Option<c_int>> + Send>>,
+    handle: Handle,
+}
+
+impl std::fmt::Debug for TerminationSignal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TerminationSignal").finish_non_exhaustive()
+    }
+}
+
+impl Future for TerminationSignal {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.waiter.as_mut().poll(cx).map(|signal| match signal {
+            Some(signal) => info!("Received termination signal {}", signal),
+            None => warn!("Termination signal handling closed without receiving a signal"),
+        })
+    }
+}
+
+impl Drop for TerminationSignal {
+    fn drop(&mut self) {
+        // Stops the waiting thread, which then unregisters the handlers.
+        self.handle.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future::block_on;
+
+    use super::*;
+
+    #[test]
+    pub fn termination_signal_completes_on_sigterm() {
+        let signal = termination_signal().unwrap();
+        signal_hook::low_level::raise(SIGTERM).unwrap();
+        block_on(signal);
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,229 @@
+//! Control over who can reach the socket of a server, by way of the file mode and group of the
+//! socket file, and of the directory it lives in - see [`SocketPermissions`] and
+//! [`crate::Server::socket_permissions`].
+
+use std::{
+    fs::{DirBuilder, Permissions},
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        net::{UnixDatagram, UnixListener},
+    },
+    path::{Path, PathBuf},
+};
+
+use tracing::{debug, info};
+
+use crate::{
+    cleanable_path::CleanablePathBuf, socket_shims::SocketKind, IoResult, UnixSocketInterface,
+};
+
+/// Mode of base context directories created by [`SocketPermissions::with_private_context_dir`].
+const PRIVATE_DIR_MODE: u32 = 0o700;
+
+/// File permissions and ownership for the socket of a server. By default, nothing is changed, so
+/// the socket gets whatever mode the umask of the server process gives it.
+///
+/// When a mode or group is set, the socket is bound under a temporary name next to the final one
+/// and only linked into place once its permissions are set - so no client can ever connect while
+/// it still has the permissions the umask gave it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketPermissions {
+    mode: Option<u32>,
+    group: Option<u32>,
+    private_context_dir: bool,
+}
+
+impl SocketPermissions {
+    /// Leave the permissions of the socket to the umask.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give the socket this file mode, like `0o600` for only the owner or `0o660` for the owner
+    /// and the group of the socket. Connecting to a unix socket needs write permission on it.
+    pub fn with_mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Give the socket to this group - which the server process has to be a member of. Combine
+    /// with a mode like `0o660` to let the members of the group connect.
+    pub fn with_group(mut self, gid: u32) -> Self {
+        self.group = Some(gid);
+        self
+    }
+
+    /// Create the base context directory - and any missing parents - with mode `0o700` if it
+    /// doesn't exist yet, so only the user of the server can reach sockets inside it. Existing
+    /// directories are left as they are.
+    pub fn with_private_context_dir(mut self) -> Self {
+        self.private_context_dir = true;
+        self
+    }
+
+    /// File mode the socket is given, if any.
+    pub fn mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    /// Group the socket is given, if any.
+    pub fn group(&self) -> Option<u32> {
+        self.group
+    }
+
+    /// Create the directory the socket goes in, if asked to.
+    pub(crate) fn prepare_context_dir(&self, socket_path: &Path) -> IoResult<()> {
+        let Some(context_dir) = socket_path.parent() else {
+            return Ok(());
+        };
+        if !self.private_context_dir || context_dir.exists() {
+            return Ok(());
+        }
+        info!(
+            "Creating private context directory @ {}",
+            context_dir.display()
+        );
+        DirBuilder::new()
+            .recursive(true)
+            .mode(PRIVATE_DIR_MODE)
+            .create(context_dir)
+    }
+
+    /// Bind a listener socket of the given kind at the path, with these permissions already
+    /// applied by the time anything can connect to it. Fails with
+    /// [`std::io::ErrorKind::AddrInUse`] if the path is taken, like a plain bind.
+    pub(crate) async fn bind<U: UnixSocketInterface>(
+        &self,
+        socket_path: &Path,
+        kind: SocketKind,
+    ) -> IoResult<U::UnixListener> {
+        if self.mode.is_none() && self.group.is_none() {
+            return kind.bind::<U>(socket_path).await;
+        }
+        let staging_path = CleanablePathBuf::new(staging_path(socket_path));
+        let listener = kind.bind::<U>(staging_path.as_ref()).await?;
+        self.link_into_place(staging_path.as_ref(), socket_path)?;
+        Ok(listener)
+    }
+
+    /// Bind a datagram socket at the path, like [`Self::bind`] does listeners - see
+    /// [`crate::datagram`].
+    pub(crate) fn bind_datagram(&self, socket_path: &Path) -> IoResult<UnixDatagram> {
+        if self.mode.is_none() && self.group.is_none() {
+            return UnixDatagram::bind(socket_path);
+        }
+        let staging_path = CleanablePathBuf::new(staging_path(socket_path));
+        let socket = UnixDatagram::bind(&staging_path)?;
+        self.link_into_place(staging_path.as_ref(), socket_path)?;
+        Ok(socket)
+    }
+
+    /// Bind a std listener at the path, like [`Self::bind`] does those of the socket interface -
+    /// see [`crate::control`].
+    pub(crate) fn bind_std_listener(&self, socket_path: &Path) -> IoResult<UnixListener> {
+        if self.mode.is_none() && self.group.is_none() {
+            return UnixListener::bind(socket_path);
+        }
+        let staging_path = CleanablePathBuf::new(staging_path(socket_path));
+        let listener = UnixListener::bind(&staging_path)?;
+        self.link_into_place(staging_path.as_ref(), socket_path)?;
+        Ok(listener)
+    }
+
+    /// Apply these permissions to a socket bound at the staging path, and link it into place at
+    /// the socket path.
+    fn link_into_place(&self, staging_path: &Path, socket_path: &Path) -> IoResult<()> {
+        if let Some(gid) = self.group {
+            std::os::unix::fs::chown(staging_path, None, Some(gid))?;
+        }
+        if let Some(mode) = self.mode {
+            std::fs::set_permissions(staging_path, Permissions::from_mode(mode))?;
+        }
+        // Linking never replaces an existing file, unlike renaming.
+        std::fs::hard_link(staging_path, socket_path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::AlreadyExists => std::io::ErrorKind::AddrInUse.into(),
+            _ => e,
+        })?;
+        debug!(
+            "Linked socket with {:?} into place @ {}",
+            self,
+            socket_path.display()
+        );
+        Ok(())
+    }
+}
+
+/// Temporary name to bind a socket under before linking it into place - a hidden file in the same
+/// directory, as links can't cross file systems.
+fn staging_path(socket_path: &Path) -> PathBuf {
+    use nanorand::rand::{chacha::ChaCha20, Rng};
+    let mut name = std::ffi::OsString::from(".");
+    name.push(socket_path.file_name().unwrap_or_default());
+    name.push(format!(".{:016x}.bind", ChaCha20::new().generate::<u64>()));
+    socket_path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::ErrorKind,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+    };
+
+    use futures_lite::future::block_on;
+
+    use super::SocketPermissions;
+    use crate::{
+        socket_shims::{SocketKind, StdThreadpoolUSocks},
+        ContextDir,
+    };
+
+    #[test]
+    pub fn socket_permissions_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let context_dir = context.join("nested").join("private");
+        let socket_path = context_dir.join("permissions.sock");
+        let gid = fs::metadata(&context).unwrap().gid();
+        let permissions = SocketPermissions::new()
+            .with_mode(0o660)
+            .with_group(gid)
+            .with_private_context_dir();
+
+        permissions.prepare_context_dir(&socket_path).unwrap();
+        assert_eq!(
+            fs::metadata(&context_dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        let _listener =
+            block_on(permissions.bind::<StdThreadpoolUSocks>(&socket_path, SocketKind::Stream))
+                .unwrap();
+        let metadata = fs::metadata(&socket_path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o660);
+        assert_eq!(metadata.gid(), gid);
+        // Only the socket itself is left, not the name it was bound under.
+        assert_eq!(fs::read_dir(&context_dir).unwrap().count(), 1);
+
+        let taken =
+            block_on(permissions.bind::<StdThreadpoolUSocks>(&socket_path, SocketKind::Stream));
+        assert_eq!(taken.unwrap_err().kind(), ErrorKind::AddrInUse);
+        assert_eq!(fs::read_dir(&context_dir).unwrap().count(), 1);
+        fs::remove_dir_all(&context).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
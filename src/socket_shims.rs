@@ -1,23 +1,31 @@
 //! Provide semi-unified interface to unix sockets api for arbitrary different async runtimes or
 //! perhaps actually-sync-under-the-hood interfaces.
-use std::{net::Shutdown, path::Path};
+use std::{
+    net::Shutdown,
+    os::fd::{BorrowedFd, OwnedFd},
+    path::Path,
+};
 
 use super::IoResult;
-use async_trait::async_trait;
+use crate::credentials::{peer_credentials, PeerCredentials};
 use blocking::{unblock, Unblock};
 
-#[async_trait(?Send)]
 /// Provide a unified interface to unix sockets in various points of existence. You can provide
 /// your own version of this in future if you have a runtime that is not supported.
 ///
-/// Note that this is very unideal... In future, I am likely to do a couple things:
-/// * Move the common interface out into a crate
-/// * Make the trait delegate to `poll` functions and add a crapton of Future structs for the
-///   various functions, to avoid the allocations involved in [`macro@async_trait`]. Maybe even see if I
-///   can't make a macro for that.
+/// Note that this is very unideal... In future, I am likely to move the common interface out into
+/// a crate.
+///
+/// The functions are native `async fn`s, unless the `async-trait-compat` feature is enabled - in
+/// which case they are implemented with [`macro@crate::async_trait`] (`?Send`) for older
+/// compilers, and your own implementations need that attribute too.
+#[cfg_attr(feature = "async-trait-compat", async_trait::async_trait(?Send))]
+// Futures of these functions are deliberately not required to be `Send`, so single threaded
+// runtimes can be used.
+#[allow(async_fn_in_trait)]
 pub trait UnixSocketInterface {
     /// Unix stream type - equivalent to [`std::os::unix::net::UnixStream`]
-    type UnixStream;
+    type UnixStream: 'static;
     /// Unix listener type - equivalent to [`std::os::unix::net::UnixListener`]
     type UnixListener;
     /// Unix socket address type - equivalent to [`std::os::unix::net::SocketAddr`]
@@ -56,18 +64,182 @@ pub trait UnixSocketInterface {
     async fn unix_listener_accept(
         s: &mut Self::UnixListener,
     ) -> IoResult<(Self::UnixStream, Self::SocketAddr)>;
+
+    /// Take over a std listener socket - like one inherited from the process that started this one,
+    /// see [`crate::liveness::LivenessTransport::InheritedListener`].
+    ///
+    /// By default this fails with [`std::io::ErrorKind::Unsupported`], so implementations that
+    /// predate it keep working - servers just bind their socket themselves.
+    async fn unix_listener_from_std(
+        listener: std::os::unix::net::UnixListener,
+    ) -> IoResult<Self::UnixListener> {
+        let _ = listener;
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Take over a std stream socket - like a duplicate of another stream, see [`crate::split`].
+    ///
+    /// By default this fails with [`std::io::ErrorKind::Unsupported`], so implementations that
+    /// predate it keep working - their streams just can't be split.
+    async fn unix_stream_from_std(
+        stream: std::os::unix::net::UnixStream,
+    ) -> IoResult<Self::UnixStream> {
+        let _ = stream;
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Read the credentials of the process on the other end of the stream - see
+    /// [`crate::credentials`].
+    ///
+    /// By default this fails with [`std::io::ErrorKind::Unsupported`], so implementations that
+    /// predate it keep working - servers just can't check who connects to them.
+    async fn unix_stream_peer_credentials(s: &mut Self::UnixStream) -> IoResult<PeerCredentials> {
+        let _ = s;
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Duplicate the file descriptor of the stream, so the socket can be checked on while the
+    /// stream itself is in use - see [`crate::ReifiedService::connection`].
+    ///
+    /// By default this fails with [`std::io::ErrorKind::Unsupported`], so implementations that
+    /// predate it keep working - connections just can't be health-checked.
+    async fn unix_stream_duplicate_fd(s: &mut Self::UnixStream) -> IoResult<OwnedFd> {
+        let _ = s;
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Duplicate the file descriptor of the listener, so it can be handed to a new instance of
+    /// the service while this one keeps running - see [`crate::control::ControlCommand::Handoff`].
+    ///
+    /// By default this fails with [`std::io::ErrorKind::Unsupported`], so implementations that
+    /// predate it keep working - their listeners just can't be handed off.
+    async fn unix_listener_duplicate_fd(l: &mut Self::UnixListener) -> IoResult<OwnedFd> {
+        let _ = l;
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Connect to a `SOCK_SEQPACKET` socket, for services of [`SocketKind::SeqPacket`] - see
+    /// [`crate::seqpacket`]. The connection is a stream of this interface, each write of which
+    /// is sent as one message, and each read of which receives one.
+    ///
+    /// By default this connects a std socket and takes it over with
+    /// [`Self::unix_stream_from_std`].
+    async fn unix_seqpacket_connect(socket_path: impl AsRef<Path>) -> IoResult<Self::UnixStream> {
+        let socket_path = socket_path.as_ref().to_owned();
+        let stream = unblock(move || crate::seqpacket::connect_std(&socket_path)).await?;
+        Self::unix_stream_from_std(stream).await
+    }
+
+    /// Bind a `SOCK_SEQPACKET` listening socket at the path, for services of
+    /// [`SocketKind::SeqPacket`] - see [`crate::seqpacket`].
+    ///
+    /// By default this binds a std socket and takes it over with
+    /// [`Self::unix_listener_from_std`].
+    async fn unix_seqpacket_bind(path: impl AsRef<Path>) -> IoResult<Self::UnixListener> {
+        Self::unix_listener_from_std(crate::seqpacket::bind_std(path.as_ref())?).await
+    }
+
+    /// Send the whole buffer as one message over a `SOCK_SEQPACKET` stream, returning how much
+    /// was sent.
+    ///
+    /// By default this is [`Self::unix_stream_write`], which is right for interfaces that write
+    /// straight to the socket.
+    async fn unix_seqpacket_send(s: &mut Self::UnixStream, message: &[u8]) -> IoResult<usize> {
+        Self::unix_stream_write(s, message).await
+    }
+
+    /// Receive one message from a `SOCK_SEQPACKET` stream into the buffer, returning its size -
+    /// the rest of messages that don't fit is lost.
+    ///
+    /// By default this is [`Self::unix_stream_read`], which is right for interfaces that read
+    /// straight from the socket.
+    async fn unix_seqpacket_recv(s: &mut Self::UnixStream, buf: &mut [u8]) -> IoResult<usize> {
+        Self::unix_stream_read(s, buf).await
+    }
+
+    /// Write some bytes from `buf` along with the file descriptors, returning how many bytes were
+    /// written - see [`crate::fdpass`].
+    ///
+    /// By default this sends over a duplicate of the socket from
+    /// [`Self::unix_stream_duplicate_fd`], which is right for interfaces that write straight to
+    /// the socket.
+    async fn unix_stream_send_with_fds(
+        s: &mut Self::UnixStream,
+        buf: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> IoResult<usize> {
+        let socket = Self::unix_stream_duplicate_fd(s).await?;
+        let fds = fds
+            .iter()
+            .map(BorrowedFd::try_clone_to_owned)
+            .collect::<IoResult<Vec<_>>>()?;
+        crate::fdpass::send_through_duplicate(socket, buf.to_vec(), fds).await
+    }
+
+    /// Read some bytes into `buf`, returning how many, and add the file descriptors that arrived
+    /// with them to `fds` - see [`crate::fdpass`].
+    ///
+    /// By default this receives from a duplicate of the socket from
+    /// [`Self::unix_stream_duplicate_fd`], which is right for interfaces that read straight from
+    /// the socket.
+    async fn unix_stream_recv_with_fds(
+        s: &mut Self::UnixStream,
+        buf: &mut [u8],
+        fds: &mut Vec<OwnedFd>,
+    ) -> IoResult<usize> {
+        let socket = Self::unix_stream_duplicate_fd(s).await?;
+        crate::fdpass::recv_through_duplicate(socket, buf, fds).await
+    }
+}
+
+/// Kind of unix socket a service uses - see [`crate::Service::socket_kind`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SocketKind {
+    /// `SOCK_STREAM` - a plain byte stream.
+    #[default]
+    Stream,
+    /// `SOCK_SEQPACKET` - a connection that preserves the boundaries of messages, see
+    /// [`crate::seqpacket`].
+    SeqPacket,
+}
+
+impl SocketKind {
+    /// Connect to a socket of this kind at the path.
+    pub(crate) async fn connect<U: UnixSocketInterface>(
+        self,
+        socket_path: &Path,
+    ) -> IoResult<U::UnixStream> {
+        match self {
+            SocketKind::Stream => U::unix_stream_connect(socket_path).await,
+            SocketKind::SeqPacket => U::unix_seqpacket_connect(socket_path).await,
+        }
+    }
+
+    /// Bind a listening socket of this kind at the path.
+    pub(crate) async fn bind<U: UnixSocketInterface>(
+        self,
+        socket_path: &Path,
+    ) -> IoResult<U::UnixListener> {
+        match self {
+            SocketKind::Stream => U::unix_listener_bind(socket_path).await,
+            SocketKind::SeqPacket => U::unix_seqpacket_bind(socket_path).await,
+        }
+    }
 }
 
 #[cfg(feature = "async-std")]
 #[derive(Debug, Clone, Copy)]
 /// Implementation of unix sockets using the [`async_std`] primitives.
+///
+/// Available with the `async-std` feature, in which case it is also selected as
+/// [`DefaultUnixSocks`].
 pub struct AsyncStdUSocks;
 
 #[cfg(feature = "async-std")]
 use async_std::os::unix::net as async_std_us;
 
 #[cfg(feature = "async-std")]
-#[async_trait(?Send)]
+#[cfg_attr(feature = "async-trait-compat", async_trait::async_trait(?Send))]
 impl UnixSocketInterface for AsyncStdUSocks {
     type UnixStream = async_std_us::UnixStream;
     type UnixListener = async_std_us::UnixListener;
@@ -110,6 +282,37 @@ impl UnixSocketInterface for AsyncStdUSocks {
     ) -> IoResult<(Self::UnixStream, Self::SocketAddr)> {
         s.accept().await
     }
+
+    async fn unix_listener_from_std(
+        listener: std::os::unix::net::UnixListener,
+    ) -> IoResult<Self::UnixListener> {
+        Ok(listener.into())
+    }
+
+    async fn unix_stream_from_std(
+        stream: std::os::unix::net::UnixStream,
+    ) -> IoResult<Self::UnixStream> {
+        Ok(stream.into())
+    }
+
+    async fn unix_stream_peer_credentials(s: &mut Self::UnixStream) -> IoResult<PeerCredentials> {
+        use std::os::fd::{AsRawFd, BorrowedFd};
+        // async-std streams only expose their raw fd.
+        // SAFETY: the fd stays open for as long as the stream is borrowed.
+        peer_credentials(&unsafe { BorrowedFd::borrow_raw(s.as_raw_fd()) })
+    }
+
+    async fn unix_stream_duplicate_fd(s: &mut Self::UnixStream) -> IoResult<OwnedFd> {
+        use std::os::fd::{AsRawFd, BorrowedFd};
+        // SAFETY: the fd stays open for as long as the stream is borrowed.
+        unsafe { BorrowedFd::borrow_raw(s.as_raw_fd()) }.try_clone_to_owned()
+    }
+
+    async fn unix_listener_duplicate_fd(l: &mut Self::UnixListener) -> IoResult<OwnedFd> {
+        use std::os::fd::{AsRawFd, BorrowedFd};
+        // SAFETY: the fd stays open for as long as the listener is borrowed.
+        unsafe { BorrowedFd::borrow_raw(l.as_raw_fd()) }.try_clone_to_owned()
+    }
 }
 
 #[cfg(feature = "tokio")]
@@ -119,7 +322,7 @@ pub struct TokioUSocks;
 use tokio::net as tokio_us;
 
 #[cfg(feature = "tokio")]
-#[async_trait(?Send)]
+#[cfg_attr(feature = "async-trait-compat", async_trait::async_trait(?Send))]
 impl UnixSocketInterface for TokioUSocks {
     type UnixStream = tokio_us::UnixStream;
     type UnixListener = tokio_us::UnixListener;
@@ -165,6 +368,35 @@ impl UnixSocketInterface for TokioUSocks {
     ) -> IoResult<(Self::UnixStream, Self::SocketAddr)> {
         s.accept().await
     }
+
+    async fn unix_listener_from_std(
+        listener: std::os::unix::net::UnixListener,
+    ) -> IoResult<Self::UnixListener> {
+        // Tokio needs the socket in non-blocking mode, which inherited ones may not be in.
+        listener.set_nonblocking(true)?;
+        Self::UnixListener::from_std(listener)
+    }
+
+    async fn unix_stream_from_std(
+        stream: std::os::unix::net::UnixStream,
+    ) -> IoResult<Self::UnixStream> {
+        stream.set_nonblocking(true)?;
+        Self::UnixStream::from_std(stream)
+    }
+
+    async fn unix_stream_peer_credentials(s: &mut Self::UnixStream) -> IoResult<PeerCredentials> {
+        peer_credentials(s)
+    }
+
+    async fn unix_stream_duplicate_fd(s: &mut Self::UnixStream) -> IoResult<OwnedFd> {
+        use std::os::fd::AsFd;
+        s.as_fd().try_clone_to_owned()
+    }
+
+    async fn unix_listener_duplicate_fd(l: &mut Self::UnixListener) -> IoResult<OwnedFd> {
+        use std::os::fd::AsFd;
+        l.as_fd().try_clone_to_owned()
+    }
 }
 
 /// Uses [`blocking::unblock`] and [`blocking::Unblock`] to avoid blocking async threads. This is
@@ -176,7 +408,7 @@ pub struct StdThreadpoolUSocks;
 
 use std::os::unix::net as std_us;
 
-#[async_trait(?Send)]
+#[cfg_attr(feature = "async-trait-compat", async_trait::async_trait(?Send))]
 impl UnixSocketInterface for StdThreadpoolUSocks {
     type UnixStream = Unblock<std_us::UnixStream>;
     type UnixListener = Unblock<std_us::UnixListener>;
@@ -229,6 +461,100 @@ impl UnixSocketInterface for StdThreadpoolUSocks {
             .await
             .map(|(connection, addr)| (Unblock::new(connection), addr))
     }
+
+    async fn unix_listener_from_std(
+        listener: std_us::UnixListener,
+    ) -> IoResult<Self::UnixListener> {
+        // Accepting happens on the threadpool, so the socket has to block.
+        listener.set_nonblocking(false)?;
+        Ok(Unblock::new(listener))
+    }
+
+    async fn unix_stream_from_std(stream: std_us::UnixStream) -> IoResult<Self::UnixStream> {
+        // Reading and writing happen on the threadpool, so the socket has to block.
+        stream.set_nonblocking(false)?;
+        Ok(Unblock::new(stream))
+    }
+
+    async fn unix_stream_peer_credentials(s: &mut Self::UnixStream) -> IoResult<PeerCredentials> {
+        s.with_mut(|inner_sock| peer_credentials(inner_sock)).await
+    }
+
+    async fn unix_stream_duplicate_fd(s: &mut Self::UnixStream) -> IoResult<OwnedFd> {
+        s.with_mut(|inner_sock| inner_sock.try_clone().map(OwnedFd::from))
+            .await
+    }
+
+    async fn unix_listener_duplicate_fd(l: &mut Self::UnixListener) -> IoResult<OwnedFd> {
+        l.with_mut(|inner_sock| inner_sock.try_clone().map(OwnedFd::from))
+            .await
+    }
+
+    // Reads and writes through `Unblock` go through a buffer, which would merge and split
+    // messages - so these use the socket directly.
+    async fn unix_seqpacket_send(s: &mut Self::UnixStream, message: &[u8]) -> IoResult<usize> {
+        use std::io::Write;
+        let message = message.to_vec();
+        s.with_mut(move |inner_sock| inner_sock.write(&message))
+            .await
+    }
+
+    async fn unix_seqpacket_recv(s: &mut Self::UnixStream, buf: &mut [u8]) -> IoResult<usize> {
+        use std::io::Read;
+        let mut message = vec![0; buf.len()];
+        let (message, received) = s
+            .with_mut(move |inner_sock| {
+                let received = inner_sock.read(&mut message);
+                (message, received)
+            })
+            .await;
+        let received = received?;
+        buf[..received].copy_from_slice(&message[..received]);
+        Ok(received)
+    }
+
+    async fn unix_stream_send_with_fds(
+        s: &mut Self::UnixStream,
+        buf: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> IoResult<usize> {
+        use std::os::fd::AsFd;
+        let buf = buf.to_vec();
+        let fds = fds
+            .iter()
+            .map(BorrowedFd::try_clone_to_owned)
+            .collect::<IoResult<Vec<_>>>()?;
+        s.with_mut(move |inner_sock| {
+            let fds: Vec<_> = fds.iter().map(AsFd::as_fd).collect();
+            crate::fdpass::send_with_fds(inner_sock.as_fd(), &buf, &fds)
+        })
+        .await
+    }
+
+    async fn unix_stream_recv_with_fds(
+        s: &mut Self::UnixStream,
+        buf: &mut [u8],
+        fds: &mut Vec<OwnedFd>,
+    ) -> IoResult<usize> {
+        use std::os::fd::AsFd;
+        let mut message = vec![0; buf.len()];
+        let (message, received_fds, received) = s
+            .with_mut(move |inner_sock| {
+                let mut received_fds = Vec::new();
+                let received = crate::fdpass::recv_with_fds(
+                    inner_sock.as_fd(),
+                    &mut message,
+                    &mut received_fds,
+                    rustix::net::RecvFlags::empty(),
+                );
+                (message, received_fds, received)
+            })
+            .await;
+        fds.extend(received_fds);
+        let received = received?;
+        buf[..received].copy_from_slice(&message[..received]);
+        Ok(received)
+    }
 }
 
 // The part where we select the "default" unix socks barebones common interface.
@@ -237,10 +563,14 @@ impl UnixSocketInterface for StdThreadpoolUSocks {
 // * tokio
 // * std fallback
 #[cfg(feature = "async-std")]
+/// Default unix socket interface - [`AsyncStdUSocks`], as the `async-std` feature is enabled.
 pub type DefaultUnixSocks = AsyncStdUSocks;
 #[cfg(all(feature = "tokio", not(feature = "async-std")))]
+/// Default unix socket interface - [`TokioUSocks`], as the `tokio` feature is enabled.
 pub type DefaultUnixSocks = TokioUSocks;
 #[cfg(not(any(feature = "tokio", feature = "async-std")))]
+/// Default unix socket interface - [`StdThreadpoolUSocks`], as no async runtime feature is
+/// enabled.
 pub type DefaultUnixSocks = StdThreadpoolUSocks;
 
 // suss - library for creating single, directory namespaced unix socket servers in a network
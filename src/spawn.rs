@@ -0,0 +1,711 @@
+//! Options for spawning the processes of services started on demand - where their standard input
+//! and output go, which directory they run in and which user they run as, what resources they may
+//! use, and whether they die with the process that started them or outlive its session. See
+//! [`SpawnOptions`] and [`crate::ServiceStartable::spawn_options`].
+
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Read},
+    os::{
+        fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+        unix::{net::UnixStream, process::CommandExt},
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{Once, OnceLock},
+};
+
+use tracing::{info, warn};
+
+use crate::{child::ServiceProcessHandle, IoResult, ServiceSocket};
+
+/// Environment variable naming the file descriptor that services spawned with
+/// [`SpawnOptions::with_die_with_parent`] inherit, to find out when the process that started them
+/// exits - see [`watch_parent`].
+pub const PARENT_WATCH_FD_ENV_VAR: &str = "SUSS_PARENT_WATCH_FD";
+
+/// Socketpair shared by all services spawned by this process with
+/// [`SpawnOptions::with_die_with_parent`] - this process holds on to one end until it exits, and
+/// the services inherit the other, so they read end of file once it does.
+static PARENT_WATCH: OnceLock<(UnixStream, OwnedFd)> = OnceLock::new();
+
+/// Where the standard input of a spawned service process comes from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputSource {
+    /// The standard input of the process starting the service.
+    #[default]
+    Inherit,
+    /// Nothing - reading gives end of file straight away.
+    Null,
+}
+
+/// Where the standard output or error of a spawned service process goes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputTarget {
+    /// The same place as for the process starting the service.
+    #[default]
+    Inherit,
+    /// Nowhere.
+    Null,
+    /// Appended to the log file next to the service socket - see [`ServiceSocket::log_path`].
+    LogFile,
+    /// Piped back to the process starting the service, and logged there line by line as
+    /// [`tracing`] events with the socket name of the service in a `service` field - at info
+    /// level for standard output, and warn level for standard error.
+    Tracing,
+}
+
+/// I/O scheduling class and priority of a spawned service process - see `ioprio_set(2)`. Only
+/// supported on Linux.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    /// Served before everything else, at priority 0 (highest) to 7. Needs `CAP_SYS_ADMIN`.
+    Realtime(u8),
+    /// Served in turn with other processes, at priority 0 (highest) to 7.
+    BestEffort(u8),
+    /// Only served when nothing else needs the disk.
+    Idle,
+}
+
+impl IoPriority {
+    /// The raw value passed to `ioprio_set(2)`, with the priority clamped to 0..=7.
+    pub fn as_raw(&self) -> i32 {
+        const CLASS_SHIFT: i32 = 13;
+        let (class, level) = match *self {
+            IoPriority::Realtime(level) => (1, level),
+            IoPriority::BestEffort(level) => (2, level),
+            IoPriority::Idle => (3, 0),
+        };
+        (class << CLASS_SHIFT) | i32::from(level.min(7))
+    }
+}
+
+/// How to set up spawned service processes. By default, everything is inherited from the process
+/// starting the service.
+///
+/// Output piped to [`OutputTarget::Tracing`] is forwarded by a background thread for as long as
+/// the service process keeps it open - so for the whole life of the service, unless it
+/// redirects its output once it is running.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SpawnOptions {
+    stdin: InputSource,
+    stdout: OutputTarget,
+    stderr: OutputTarget,
+    die_with_parent: bool,
+    detach: bool,
+    user: Option<(u32, u32)>,
+    nofile_limit: Option<u64>,
+    core_limit: Option<u64>,
+    address_space_limit: Option<u64>,
+    nice: Option<i32>,
+    io_priority: Option<IoPriority>,
+    current_dir: Option<PathBuf>,
+}
+
+impl SpawnOptions {
+    /// Inherit everything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take standard input from this source.
+    pub fn with_stdin(mut self, stdin: InputSource) -> Self {
+        self.stdin = stdin;
+        self
+    }
+
+    /// Send standard output to this target.
+    pub fn with_stdout(mut self, stdout: OutputTarget) -> Self {
+        self.stdout = stdout;
+        self
+    }
+
+    /// Send standard error to this target.
+    pub fn with_stderr(mut self, stderr: OutputTarget) -> Self {
+        self.stderr = stderr;
+        self
+    }
+
+    /// Make the service process exit when the process that started it does, rather than keeping
+    /// it running in the background.
+    ///
+    /// On Linux, the service process gets `SIGTERM` as its parent-death signal. This is sent when
+    /// the *thread* that spawned it exits, so only use this if services are started from threads
+    /// that live as long as the process - not from the threads of a pool that shrinks.
+    ///
+    /// Everywhere, the service process also inherits a socket - named in
+    /// [`PARENT_WATCH_FD_ENV_VAR`] - that reaches end of file once the process that started it
+    /// exits. The servers of this library watch it and send themselves `SIGTERM` - see
+    /// [`watch_parent`].
+    pub fn with_die_with_parent(mut self) -> Self {
+        self.die_with_parent = true;
+        self
+    }
+
+    /// Start the service process in a new session, without a controlling terminal, so it keeps
+    /// running once the terminal or session of the process that started it goes away - for
+    /// services meant to persist once started on demand.
+    ///
+    /// The process is not forked again after that, so its process id stays the one the client
+    /// watches while waiting for it to become live. Combine this with
+    /// [`OutputTarget::LogFile`] or [`OutputTarget::Null`] - output inherited from a terminal
+    /// that goes away fails to write.
+    pub fn with_detach(mut self) -> Self {
+        self.detach = true;
+        self
+    }
+
+    /// Run the service process as this user id and group id, with no supplementary groups - so a
+    /// privileged launcher can start services for other users, or as an unprivileged sandbox
+    /// user.
+    ///
+    /// Switching to another user needs the process starting the service to run as root - if it
+    /// doesn't, spawning fails with [`std::io::ErrorKind::PermissionDenied`] before anything is
+    /// run. The user needs access to the context directory to bind the service socket there.
+    pub fn with_user(mut self, uid: u32, gid: u32) -> Self {
+        self.user = Some((uid, gid));
+        self
+    }
+
+    /// Limit the number of files the service process can have open (`RLIMIT_NOFILE`).
+    ///
+    /// Like the other limits, this sets both the soft and the hard limit, so the service can't
+    /// raise it again. Raising a hard limit above that of the process starting the service needs
+    /// root. Limits are applied after switching user.
+    pub fn with_nofile_limit(mut self, limit: u64) -> Self {
+        self.nofile_limit = Some(limit);
+        self
+    }
+
+    /// Limit the size of core dumps of the service process in bytes (`RLIMIT_CORE`) - 0 disables
+    /// them.
+    pub fn with_core_limit(mut self, limit: u64) -> Self {
+        self.core_limit = Some(limit);
+        self
+    }
+
+    /// Limit the size of the virtual memory of the service process in bytes (`RLIMIT_AS`).
+    pub fn with_address_space_limit(mut self, limit: u64) -> Self {
+        self.address_space_limit = Some(limit);
+        self
+    }
+
+    /// Run the service process at this niceness, from -20 (most favourable scheduling) to 19
+    /// (least favourable). Going below the niceness of the process starting the service needs
+    /// privileges, which are gone once the service is switched to another user.
+    pub fn with_nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Run the service process with this I/O scheduling class and priority. Spawning fails with
+    /// [`std::io::ErrorKind::Unsupported`] on anything but Linux.
+    pub fn with_io_priority(mut self, io_priority: IoPriority) -> Self {
+        self.io_priority = Some(io_priority);
+        self
+    }
+
+    /// Run the service process in this working directory, rather than that of the process
+    /// starting it - which is anywhere a client happens to be. Relative paths are relative to the
+    /// working directory of the process starting the service.
+    pub fn with_current_dir(mut self, current_dir: impl Into<PathBuf>) -> Self {
+        self.current_dir = Some(current_dir.into());
+        self
+    }
+
+    /// Source of standard input.
+    pub fn stdin(&self) -> InputSource {
+        self.stdin
+    }
+
+    /// Target of standard output.
+    pub fn stdout(&self) -> OutputTarget {
+        self.stdout
+    }
+
+    /// Target of standard error.
+    pub fn stderr(&self) -> OutputTarget {
+        self.stderr
+    }
+
+    /// Whether the service process exits when the process that started it does.
+    pub fn die_with_parent(&self) -> bool {
+        self.die_with_parent
+    }
+
+    /// Whether the service process is started in a new session.
+    pub fn detach(&self) -> bool {
+        self.detach
+    }
+
+    /// User id and group id to run the service process as, if not the ones of the process
+    /// starting it.
+    pub fn user(&self) -> Option<(u32, u32)> {
+        self.user
+    }
+
+    /// Limit on the number of open files of the service process, if any.
+    pub fn nofile_limit(&self) -> Option<u64> {
+        self.nofile_limit
+    }
+
+    /// Limit on the size of core dumps of the service process, if any.
+    pub fn core_limit(&self) -> Option<u64> {
+        self.core_limit
+    }
+
+    /// Limit on the size of the virtual memory of the service process, if any.
+    pub fn address_space_limit(&self) -> Option<u64> {
+        self.address_space_limit
+    }
+
+    /// Niceness of the service process, if not inherited.
+    pub fn nice(&self) -> Option<i32> {
+        self.nice
+    }
+
+    /// I/O scheduling class and priority of the service process, if not inherited.
+    pub fn io_priority(&self) -> Option<IoPriority> {
+        self.io_priority
+    }
+
+    /// Working directory of the service process, if not inherited.
+    pub fn current_dir(&self) -> Option<&Path> {
+        self.current_dir.as_deref()
+    }
+
+    /// Open whatever a process for the service needs, creating the log file if it is used.
+    pub fn prepare(&self, service_socket: &ServiceSocket) -> IoResult<PreparedSpawn> {
+        let output = |target: OutputTarget| -> IoResult<Stdio> {
+            Ok(match target {
+                OutputTarget::Inherit => Stdio::inherit(),
+                OutputTarget::Null => Stdio::null(),
+                OutputTarget::LogFile => OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(service_socket.log_path())?
+                    .into(),
+                OutputTarget::Tracing => Stdio::piped(),
+            })
+        };
+        if let Some((uid, gid)) = self.user {
+            check_can_switch_user(uid, gid)?;
+        }
+        if self.io_priority.is_some() && !cfg!(any(target_os = "linux", target_os = "android")) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "I/O priorities are only supported on Linux",
+            ));
+        }
+        let parent_watch_fd = match self.die_with_parent {
+            true => Some(parent_watch_fd()?),
+            false => None,
+        };
+        Ok(PreparedSpawn {
+            stdin: match self.stdin {
+                InputSource::Inherit => Stdio::inherit(),
+                InputSource::Null => Stdio::null(),
+            },
+            stdout: output(self.stdout)?,
+            stderr: output(self.stderr)?,
+            parent_watch_fd,
+            context_dir: Some(service_socket.base_context_directory().to_owned()),
+            options: self.clone(),
+        })
+    }
+}
+
+/// Fail with a clear error if this process can't switch to the given user and group.
+fn check_can_switch_user(uid: u32, gid: u32) -> IoResult<()> {
+    let euid = rustix::process::geteuid().as_raw();
+    let egid = rustix::process::getegid().as_raw();
+    if euid == 0 || (uid == euid && gid == egid) {
+        return Ok(());
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        format!(
+            "can't run the service as user {}, group {} - this needs root, but running as user {}",
+            uid, gid, euid
+        ),
+    ))
+}
+
+/// The end of the shared parent watch socketpair that spawned services inherit.
+fn parent_watch_fd() -> IoResult<RawFd> {
+    if let Some((_, theirs)) = PARENT_WATCH.get() {
+        return Ok(theirs.as_raw_fd());
+    }
+    let (ours, theirs) = UnixStream::pair()?;
+    let (_, theirs) = PARENT_WATCH.get_or_init(|| (ours, theirs.into()));
+    Ok(theirs.as_raw_fd())
+}
+
+/// A spawned service process, as prepared from [`SpawnOptions`] - passed to
+/// [`crate::ServiceStartable::run_service_command_raw`].
+#[derive(Debug)]
+pub struct PreparedSpawn {
+    stdin: Stdio,
+    stdout: Stdio,
+    stderr: Stdio,
+    parent_watch_fd: Option<RawFd>,
+    context_dir: Option<PathBuf>,
+    options: SpawnOptions,
+}
+
+impl PreparedSpawn {
+    /// Inherit standard input and output, the user and the session from the process starting the
+    /// service, and leave the service running once that exits.
+    pub fn inherit() -> Self {
+        Self {
+            stdin: Stdio::inherit(),
+            stdout: Stdio::inherit(),
+            stderr: Stdio::inherit(),
+            parent_watch_fd: None,
+            context_dir: None,
+            options: SpawnOptions::new(),
+        }
+    }
+
+    /// Base context directory of the service being spawned, if known - for passing on to the
+    /// service, as with `env SUSS_CONTEXT_DIR` in [`crate::declare_service`].
+    pub fn context_dir(&self) -> Option<&Path> {
+        self.context_dir.as_deref()
+    }
+
+    /// Set up the command accordingly. This is automatically used with
+    /// [`crate::declare_service`].
+    pub fn apply(self, command: &mut Command) -> &mut Command {
+        command
+            .stdin(self.stdin)
+            .stdout(self.stdout)
+            .stderr(self.stderr);
+        if let Some(current_dir) = &self.options.current_dir {
+            command.current_dir(current_dir);
+        }
+        if let Some((uid, gid)) = self.options.user {
+            // This drops supplementary groups when running as root, then sets the group id before
+            // the user id.
+            command.uid(uid).gid(gid);
+        }
+        if self.options.detach {
+            // SAFETY: setsid is async-signal-safe.
+            unsafe {
+                command.pre_exec(|| {
+                    rustix::process::setsid()?;
+                    Ok(())
+                });
+            }
+        }
+        let options = self.options;
+        let limits = [
+            options.nofile_limit,
+            options.core_limit,
+            options.address_space_limit,
+        ];
+        if limits.iter().any(Option::is_some)
+            || options.nice.is_some()
+            || options.io_priority.is_some()
+        {
+            // SAFETY: only async-signal-safe system calls are made between fork and exec.
+            unsafe {
+                command.pre_exec(move || apply_resource_limits(&options));
+            }
+        }
+        let Some(parent_watch_fd) = self.parent_watch_fd else {
+            return command.env_remove(PARENT_WATCH_FD_ENV_VAR);
+        };
+        let parent_pid = rustix::process::getpid();
+        command.env(PARENT_WATCH_FD_ENV_VAR, parent_watch_fd.to_string());
+        // SAFETY: only async-signal-safe system calls are made between fork and exec.
+        unsafe {
+            command.pre_exec(move || {
+                // SAFETY: the parent watch socketpair is never closed.
+                let fd = BorrowedFd::borrow_raw(parent_watch_fd);
+                rustix::io::fcntl_setfd(fd, rustix::io::FdFlags::empty())?;
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                rustix::process::set_parent_process_death_signal(Some(
+                    rustix::process::Signal::TERM,
+                ))?;
+                // The parent may have exited before the death signal was set.
+                if rustix::process::getppid() != Some(parent_pid) {
+                    return Err(std::io::Error::other("process starting the service exited"));
+                }
+                Ok(())
+            })
+        }
+    }
+}
+
+/// Apply the resource limits, niceness and I/O priority of the options to this process.
+fn apply_resource_limits(options: &SpawnOptions) -> IoResult<()> {
+    use rustix::process::{setrlimit, Resource, Rlimit};
+    let limits = [
+        (Resource::Nofile, options.nofile_limit),
+        (Resource::Core, options.core_limit),
+        (Resource::As, options.address_space_limit),
+    ];
+    for (resource, limit) in limits {
+        if let Some(limit) = limit {
+            setrlimit(
+                resource,
+                Rlimit {
+                    current: Some(limit),
+                    maximum: Some(limit),
+                },
+            )?;
+        }
+    }
+    if let Some(nice) = options.nice {
+        rustix::process::setpriority_process(None, nice)?;
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(io_priority) = options.io_priority {
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        // SAFETY: ioprio_set takes three integers.
+        let set = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                0,
+                io_priority.as_raw(),
+            )
+        };
+        if set == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// In a service process spawned with [`SpawnOptions::with_die_with_parent`], start a background
+/// thread that sends this process `SIGTERM` once the process that started it exits. The inherited
+/// file descriptor is made close-on-exec, so child processes of the service don't inherit it. This
+/// does nothing if there is no such file descriptor, or if it was already done.
+///
+/// The environment is left as it is, as other threads may be reading it - services this process
+/// spawns get [`PARENT_WATCH_FD_ENV_VAR`] cleared, or set to a descriptor of their own.
+///
+/// This is automatically used by the servers of this library - which shut down cleanly on
+/// `SIGTERM` if they handle it, see `signals` - once their socket is bound.
+pub fn watch_parent() {
+    static WATCHING: Once = Once::new();
+    WATCHING.call_once(|| {
+        let fd = std::env::var(PARENT_WATCH_FD_ENV_VAR)
+            .ok()
+            .and_then(|fd| fd.parse::<RawFd>().ok());
+        let Some(fd) = fd else { return };
+        info!("Watching for the exit of the process that started this one");
+        // SAFETY: the file descriptor was inherited for this, and is only taken over once.
+        let mut parent_watch = UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        if let Err(e) = rustix::io::fcntl_setfd(&parent_watch, rustix::io::FdFlags::CLOEXEC) {
+            warn!(
+                "Couldn't keep the parent watch socket from child processes - {}",
+                e
+            );
+        }
+        let spawned = std::thread::Builder::new()
+            .name("suss-parent-watch".to_owned())
+            .spawn(move || {
+                let _ = std::io::copy(&mut parent_watch, &mut std::io::sink());
+                warn!("The process that started this one exited - terminating");
+                let _ = rustix::process::kill_process(
+                    rustix::process::getpid(),
+                    rustix::process::Signal::TERM,
+                );
+            });
+        if let Err(e) = spawned {
+            warn!("Couldn't watch the process that started this one - {}", e);
+        }
+    });
+}
+
+/// Forward the piped output of a spawned service process - see [`OutputTarget::Tracing`] - as
+/// [`tracing`] events, from background threads.
+pub(crate) fn forward_output(
+    child: &mut impl ServiceProcessHandle,
+    service_socket: &ServiceSocket,
+) {
+    let service = service_socket
+        .path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    if let Some(stdout) = child.take_stdout() {
+        spawn_forwarder(stdout, service.clone(), false);
+    }
+    if let Some(stderr) = child.take_stderr() {
+        spawn_forwarder(stderr, service, true);
+    }
+}
+
+fn spawn_forwarder(output: impl Read + Send + 'static, service: String, is_stderr: bool) {
+    let spawned = std::thread::Builder::new()
+        .name(format!("suss-output-{service}"))
+        .spawn(move || {
+            for line in BufReader::new(output).split(b'\n') {
+                let Ok(line) = line else { break };
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches('\r');
+                if is_stderr {
+                    warn!(service = %service, stream = "stderr", "{}", line);
+                } else {
+                    info!(service = %service, stream = "stdout", "{}", line);
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        warn!("Couldn't forward the output of a service process - {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, process::Command};
+
+    use super::{IoPriority, OutputTarget, SpawnOptions};
+    use crate::{ContextDir, ServiceSocket};
+
+    #[test]
+    pub fn spawn_options_log_file_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("logging.sock"), &context);
+        let options = SpawnOptions::new()
+            .with_stdout(OutputTarget::LogFile)
+            .with_stderr(OutputTarget::LogFile);
+        for _ in 0..2 {
+            let mut command = Command::new("sh");
+            command.args(["-c", "echo out; echo err >&2"]);
+            options
+                .prepare(&service_socket)
+                .unwrap()
+                .apply(&mut command);
+            assert!(command.status().unwrap().success());
+        }
+        // Later runs append to the log of earlier ones.
+        assert_eq!(
+            std::fs::read_to_string(context.join("logging.log")).unwrap(),
+            "out\nerr\nout\nerr\n"
+        );
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    pub fn die_with_parent_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("orphan.sock"), &context);
+        let run = |options: SpawnOptions, script: &str| {
+            let mut command = Command::new("sh");
+            command.args(["-c", script]);
+            options
+                .prepare(&service_socket)
+                .unwrap()
+                .apply(&mut command);
+            command.status().unwrap().success()
+        };
+        // The parent watch socket is inherited, and named in the environment.
+        let inherited =
+            "[ -n \"$SUSS_PARENT_WATCH_FD\" ] && [ -S /proc/self/fd/$SUSS_PARENT_WATCH_FD ]";
+        assert!(run(SpawnOptions::new().with_die_with_parent(), inherited));
+        assert!(run(SpawnOptions::new().with_die_with_parent(), inherited));
+        assert!(run(SpawnOptions::new(), "[ -z \"$SUSS_PARENT_WATCH_FD\" ]"));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    pub fn detach_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("detached.sock"), &context);
+        let leads_session = |options: SpawnOptions| {
+            let mut command = Command::new("sh");
+            // The sixth field of the process status is its session id.
+            command.args(["-c", "set -- $(cat /proc/$$/stat); [ \"$6\" = \"$$\" ]"]);
+            options
+                .prepare(&service_socket)
+                .unwrap()
+                .apply(&mut command);
+            command.status().unwrap().success()
+        };
+        assert!(!leads_session(SpawnOptions::new()));
+        assert!(leads_session(SpawnOptions::new().with_detach()));
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn user_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("sandboxed.sock"), &context);
+        let euid = rustix::process::geteuid().as_raw();
+        let egid = rustix::process::getegid().as_raw();
+        let run_as = |uid: u32, gid: u32| {
+            let mut command = Command::new("sh");
+            command.args([
+                "-c",
+                &format!("[ $(id -u) = {} ] && [ $(id -g) = {} ]", uid, gid),
+            ]);
+            SpawnOptions::new()
+                .with_user(uid, gid)
+                .prepare(&service_socket)
+                .map(|prepared| prepared.apply(&mut command).status().unwrap().success())
+        };
+        // Running as ourselves always works.
+        assert!(run_as(euid, egid).unwrap());
+        // The conventional nobody user and group.
+        match run_as(65534, 65534) {
+            Ok(ran) => assert!(euid == 0 && ran),
+            Err(e) => {
+                assert_ne!(euid, 0);
+                assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+            }
+        }
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    pub fn resource_limits_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("limited.sock"), &context);
+        let options = SpawnOptions::new()
+            .with_nofile_limit(64)
+            .with_core_limit(0)
+            .with_address_space_limit(1 << 32)
+            .with_nice(7)
+            .with_io_priority(IoPriority::Idle);
+        let mut command = Command::new("sh");
+        // The nineteenth field of the process status is its niceness.
+        command.args([
+            "-c",
+            "[ $(ulimit -n) = 64 ] && [ $(ulimit -c) = 0 ] && [ $(ulimit -v) = 4194304 ] \
+                && { ! command -v ionice >/dev/null || [ \"$(ionice -p $$)\" = idle ]; } \
+                && set -- $(cat /proc/$$/stat) && [ \"${19}\" = 7 ]",
+        ]);
+        options
+            .prepare(&service_socket)
+            .unwrap()
+            .apply(&mut command);
+        assert!(command.status().unwrap().success());
+        assert_eq!(IoPriority::BestEffort(4).as_raw(), (2 << 13) | 4);
+        assert_eq!(IoPriority::Realtime(9).as_raw(), (1 << 13) | 7);
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
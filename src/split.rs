@@ -0,0 +1,153 @@
+//! Connections split into independently owned read and write halves - see [`split`].
+//!
+//! Reading and writing a stream at the same time - say, from separate tasks - needs two handles
+//! on it. The halves are separate streams over duplicates of the same socket, so this works with
+//! any [`UnixSocketInterface`] that implements [`UnixSocketInterface::unix_stream_duplicate_fd`]
+//! and [`UnixSocketInterface::unix_stream_from_std`]. This is what the `split` method of
+//! [`crate::declare_service`] wraps streams in.
+
+use std::{fmt::Debug, os::unix::net::UnixStream};
+
+use crate::{DefaultUnixSocks, IoResult, UnixSocketInterface};
+
+/// Split a stream into a read half and a write half.
+pub async fn split<U: UnixSocketInterface>(
+    mut stream: U::UnixStream,
+) -> IoResult<(ReadHalf<U>, WriteHalf<U>)> {
+    let duplicate = U::unix_stream_duplicate_fd(&mut stream).await?;
+    let write_stream = U::unix_stream_from_std(UnixStream::from(duplicate)).await?;
+    Ok((
+        ReadHalf { stream },
+        WriteHalf {
+            stream: write_stream,
+        },
+    ))
+}
+
+/// Reading half of a stream - see [`split`].
+pub struct ReadHalf<U: UnixSocketInterface = DefaultUnixSocks> {
+    stream: U::UnixStream,
+}
+
+impl<U: UnixSocketInterface> Debug for ReadHalf<U>
+where
+    U::UnixStream: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReadHalf")
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl<U: UnixSocketInterface> ReadHalf<U> {
+    /// Read some bytes into `buf`, returning how many. Ok(0) means the other side closed the
+    /// connection, or shut down its writing direction.
+    pub async fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        U::unix_stream_read(&mut self.stream, buf).await
+    }
+
+    /// Fill all of `buf`.
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> IoResult<()> {
+        U::unix_stream_read_exact(&mut self.stream, buf).await
+    }
+
+    /// Take back the stream this half reads from. Writing to it writes to the same connection as
+    /// the write half.
+    pub fn into_inner(self) -> U::UnixStream {
+        self.stream
+    }
+}
+
+/// Writing half of a stream - see [`split`].
+///
+/// The connection stays open until both halves are dropped, so use [`Self::shutdown`] to tell the
+/// other side nothing more is coming.
+pub struct WriteHalf<U: UnixSocketInterface = DefaultUnixSocks> {
+    stream: U::UnixStream,
+}
+
+impl<U: UnixSocketInterface> Debug for WriteHalf<U>
+where
+    U::UnixStream: Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WriteHalf")
+            .field("stream", &self.stream)
+            .finish()
+    }
+}
+
+impl<U: UnixSocketInterface> WriteHalf<U> {
+    /// Write some bytes from `buf`, returning how many.
+    pub async fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        U::unix_stream_write(&mut self.stream, buf).await
+    }
+
+    /// Write all of `buf`.
+    pub async fn write_all(&mut self, buf: &[u8]) -> IoResult<()> {
+        U::unix_stream_write_all(&mut self.stream, buf).await
+    }
+
+    /// Shut down the writing direction of the connection, leaving the read half usable.
+    pub async fn shutdown(&mut self) -> IoResult<()> {
+        // The socket interface may only shut down both directions at once.
+        let fd = U::unix_stream_duplicate_fd(&mut self.stream).await?;
+        rustix::net::shutdown(&fd, rustix::net::Shutdown::Write)?;
+        Ok(())
+    }
+
+    /// Take back the stream this half writes to. Reading from it reads from the same connection
+    /// as the read half.
+    pub fn into_inner(self) -> U::UnixStream {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use blocking::Unblock;
+    use futures_lite::future::block_on;
+
+    use super::*;
+    use crate::socket_shims::StdThreadpoolUSocks;
+
+    #[test]
+    pub fn halves_share_the_connection() {
+        let (a, mut raw_server) = UnixStream::pair().unwrap();
+        block_on(async {
+            let (mut read_half, mut write_half) =
+                split::<StdThreadpoolUSocks>(Unblock::new(a)).await.unwrap();
+            write_half.write_all(b"ping").await.unwrap();
+            write_half.shutdown().await.unwrap();
+            let mut received = Vec::new();
+            raw_server.read_to_end(&mut received).unwrap();
+            assert_eq!(received, b"ping");
+
+            raw_server.write_all(b"pong").unwrap();
+            drop(raw_server);
+            let mut received = [0u8; 4];
+            read_half.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"pong");
+            assert_eq!(read_half.read(&mut received).await.unwrap(), 0);
+        });
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
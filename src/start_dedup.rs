@@ -0,0 +1,102 @@
+//! Deduplication of concurrent attempts to start the same service within this process.
+//!
+//! Without this, many tasks connecting to a service that isn't running at the same time would
+//! each spawn a service process - of which all but one would fail to bind the socket. Instead, the
+//! first task to claim the start of a service does it, and the others wait for it to finish.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use event_listener::{Event, EventListener};
+
+/// Starts in progress in this process, keyed by the socket path of the service being started.
+static STARTS_IN_PROGRESS: Mutex<BTreeMap<PathBuf, Arc<Event>>> = Mutex::new(BTreeMap::new());
+
+/// Outcome of trying to claim the start of a service.
+pub(crate) enum StartClaim {
+    /// Nothing else in this process is starting the service, so it's up to us. Other claims wait
+    /// until this is dropped.
+    Starter(StartGuard),
+    /// Something else in this process is starting the service - this completes when it's done,
+    /// whether it succeeded or not.
+    Waiter(EventListener),
+}
+
+/// Claim the start of the service with the given socket path.
+pub(crate) fn claim_start(socket_path: &Path) -> StartClaim {
+    let mut starts = STARTS_IN_PROGRESS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    match starts.get(socket_path) {
+        // Listening while holding the lock means the notification can't be missed.
+        Some(finished) => StartClaim::Waiter(finished.listen()),
+        None => {
+            starts.insert(socket_path.to_owned(), Arc::new(Event::new()));
+            StartClaim::Starter(StartGuard {
+                socket_path: socket_path.to_owned(),
+            })
+        }
+    }
+}
+
+/// Releases a claim on starting a service when dropped, waking everything waiting on it.
+#[derive(Debug)]
+pub(crate) struct StartGuard {
+    socket_path: PathBuf,
+}
+
+impl Drop for StartGuard {
+    fn drop(&mut self) {
+        let finished = STARTS_IN_PROGRESS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&self.socket_path);
+        if let Some(finished) = finished {
+            finished.notify(usize::MAX);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use futures_lite::future::block_on;
+
+    use super::{claim_start, StartClaim};
+
+    #[test]
+    pub fn claim_start_test() {
+        let path = Path::new("/nonexistent/start-dedup-test.sock");
+        let guard = match claim_start(path) {
+            StartClaim::Starter(guard) => guard,
+            StartClaim::Waiter(_) => panic!("first claim should be the starter"),
+        };
+        let waiter = match claim_start(path) {
+            StartClaim::Waiter(listener) => listener,
+            StartClaim::Starter(_) => panic!("second claim should wait"),
+        };
+        drop(guard);
+        block_on(waiter);
+        assert!(matches!(claim_start(path), StartClaim::Starter(_)));
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,556 @@
+//! Inspecting whether a service is running, for supervisors and health dashboards - see
+//! [`crate::ReifiedService::status`].
+//!
+//! Servers started with [`crate::ServerExt`] leave a *state file* next to their socket while they
+//! run - [`ServiceSocket::state_path`] - describing the server process as a [`ServiceState`]. It
+//! can be read without connecting to the service - see [`read_state`] - and is otherwise only used
+//! to report the process ID of a running service, so services run some other way still show up as
+//! running, just without one.
+//!
+//! To find out about all the services in a base context directory, rather than a particular one,
+//! use [`list_services`].
+//!
+//! Servers with a [`Heartbeat`] - see [`crate::Server::heartbeat`] - also bump the modification
+//! time of their state file periodically, from the same runtime as the server itself. A server
+//! whose socket still accepts connections, but which has stopped doing that, shows up as
+//! [`ServiceStatus::Unresponsive`].
+//!
+//! The state file is a JSON object like the following, where the versions are `null` if the
+//! service doesn't declare them, and the heartbeat is `null` if the server has none:
+//!
+//! ```json
+//! {
+//!   "pid": 1234,
+//!   "started_at": "2022-07-01T12:00:00.000Z",
+//!   "suss_version": "0.0.5",
+//!   "service_version": "1.2.0",
+//!   "protocol_versions": [1, 2],
+//!   "heartbeat": { "interval_ms": 5000, "tolerance_ms": 5000 }
+//! }
+//! ```
+
+use std::{
+    fmt::Display,
+    fs,
+    future::Future,
+    io::ErrorKind,
+    ops::RangeInclusive,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use nix::{
+    errno::Errno,
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::{
+    cleanable_path::CleanablePathBuf, datagram, future::FutureExt, gc, seqpacket,
+    socket_shims::StdThreadpoolUSocks, timefut, IoResult, ServiceSocket, UnixSocketInterface,
+};
+
+/// How often a killed process is checked for having exited.
+const KILL_POLL_INTERVAL: Duration = Duration::from_millis(25);
+
+/// Whether a service is running, as determined by [`crate::ReifiedService::status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServiceStatus {
+    /// The service socket accepts connections. The process ID of the server is included if it
+    /// left a state file.
+    Running { pid: Option<u32> },
+    /// The service socket exists, but nothing accepts connections on it - it was most likely left
+    /// behind by a server that crashed.
+    StaleSocket,
+    /// The service socket accepts connections, but the server has missed its [`Heartbeat`] - it
+    /// is most likely hung.
+    Unresponsive { pid: u32 },
+    /// There is no service socket to connect to.
+    NotRunning,
+}
+
+impl Display for ServiceStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceStatus::Running { pid: Some(pid) } => write!(f, "running (pid {pid})"),
+            ServiceStatus::Running { pid: None } => write!(f, "running"),
+            ServiceStatus::StaleSocket => write!(f, "stale socket"),
+            ServiceStatus::Unresponsive { pid } => write!(f, "unresponsive (pid {pid})"),
+            ServiceStatus::NotRunning => write!(f, "not running"),
+        }
+    }
+}
+
+/// How often a server bumps the modification time of its state file, and how late it may be
+/// before it is taken to be unresponsive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    interval: Duration,
+    tolerance: Duration,
+}
+
+impl Heartbeat {
+    /// Beat at this interval. The heartbeat may be late by up to one more interval, by default.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            tolerance: interval,
+        }
+    }
+
+    /// Let the heartbeat be up to this late before the server is taken to be unresponsive.
+    pub fn with_tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Interval between beats.
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// How late a beat may be.
+    pub fn tolerance(&self) -> Duration {
+        self.tolerance
+    }
+
+    /// Whether a server that last beat at the given time has missed its heartbeat.
+    pub fn is_stale(&self, last_beat: SystemTime) -> bool {
+        SystemTime::now()
+            .duration_since(last_beat)
+            .is_ok_and(|since| since > self.interval + self.tolerance)
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "interval_ms": u64::try_from(self.interval.as_millis()).unwrap_or(u64::MAX),
+            "tolerance_ms": u64::try_from(self.tolerance.as_millis()).unwrap_or(u64::MAX),
+        })
+    }
+
+    fn from_json(heartbeat: &Value) -> Option<Self> {
+        Some(Self {
+            interval: Duration::from_millis(heartbeat["interval_ms"].as_u64()?),
+            tolerance: Duration::from_millis(heartbeat["tolerance_ms"].as_u64()?),
+        })
+    }
+}
+
+/// Description of a running server process, as recorded in its state file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceState {
+    /// Process ID of the server.
+    pub pid: u32,
+    /// When the server started listening on the service socket, to millisecond precision.
+    pub started_at: SystemTime,
+    /// Version of suss the server was built with.
+    pub suss_version: String,
+    /// Version of the service - see [`crate::Service::service_version`].
+    pub service_version: Option<String>,
+    /// Protocol versions supported by the server - see
+    /// [`crate::Service::handshake_protocol_versions`].
+    pub protocol_versions: Option<RangeInclusive<u32>>,
+    /// Heartbeat of the server, if it has one - see [`crate::Server::heartbeat`].
+    pub heartbeat: Option<Heartbeat>,
+}
+
+impl ServiceState {
+    /// State of a server running in this process, starting now.
+    pub fn for_this_process(
+        service_version: Option<&str>,
+        protocol_versions: Option<RangeInclusive<u32>>,
+    ) -> Self {
+        Self {
+            pid: std::process::id(),
+            started_at: SystemTime::now(),
+            suss_version: env!("CARGO_PKG_VERSION").to_owned(),
+            service_version: service_version.map(str::to_owned),
+            protocol_versions,
+            heartbeat: None,
+        }
+    }
+
+    /// The same state, for a server with this heartbeat.
+    pub fn with_heartbeat(mut self, heartbeat: Option<Heartbeat>) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "pid": self.pid,
+            "started_at": humantime::format_rfc3339_millis(self.started_at).to_string(),
+            "suss_version": self.suss_version,
+            "service_version": self.service_version,
+            "protocol_versions": self
+                .protocol_versions
+                .as_ref()
+                .map(|versions| [*versions.start(), *versions.end()]),
+            "heartbeat": self.heartbeat.map(Heartbeat::to_json),
+        })
+    }
+
+    fn from_json(state: &Value) -> Option<Self> {
+        let protocol_versions = match &state["protocol_versions"] {
+            Value::Null => None,
+            versions => {
+                let start = versions[0].as_u64()?.try_into().ok()?;
+                let end = versions[1].as_u64()?.try_into().ok()?;
+                Some(start..=end)
+            }
+        };
+        Some(Self {
+            pid: state["pid"].as_u64()?.try_into().ok()?,
+            started_at: humantime::parse_rfc3339_weak(state["started_at"].as_str()?).ok()?,
+            suss_version: state["suss_version"].as_str()?.to_owned(),
+            service_version: match &state["service_version"] {
+                Value::Null => None,
+                version => Some(version.as_str()?.to_owned()),
+            },
+            protocol_versions,
+            heartbeat: match &state["heartbeat"] {
+                Value::Null => None,
+                heartbeat => Some(Heartbeat::from_json(heartbeat)?),
+            },
+        })
+    }
+}
+
+/// Write the state file for a server. It is removed again when the produced path is dropped.
+pub(crate) fn write_state_file(
+    service_socket: &ServiceSocket,
+    state: &ServiceState,
+) -> IoResult<CleanablePathBuf> {
+    let path = service_socket.state_path();
+    fs::write(&path, format!("{:#}\n", state.to_json()))?;
+    debug!("Wrote state file @ {}", path.display());
+    Ok(path.into())
+}
+
+/// Bump the modification time of the state file at the heartbeat interval for as long as the
+/// server future runs, producing its output. Heartbeats are driven by the same runtime as the
+/// server, so they stop if it hangs.
+pub(crate) async fn with_heartbeat<F: Future>(
+    server: F,
+    state_path: Option<PathBuf>,
+    heartbeat: Option<Heartbeat>,
+) -> F::Output {
+    let (Some(state_path), Some(heartbeat)) = (state_path, heartbeat) else {
+        return server.await;
+    };
+    let beat = async {
+        loop {
+            timefut::sleep(heartbeat.interval()).await;
+            let beaten = fs::File::options()
+                .write(true)
+                .open(&state_path)
+                .and_then(|state_file| state_file.set_modified(SystemTime::now()));
+            if let Err(e) = beaten {
+                debug!("Couldn't beat heartbeat @ {} - {}", state_path.display(), e);
+            }
+        }
+    };
+    server.or(beat).await
+}
+
+/// Whether the server described by the state file of a service socket has missed its heartbeat.
+/// Servers without a heartbeat never miss it.
+pub fn missed_heartbeat(service_socket: &ServiceSocket, state: &ServiceState) -> bool {
+    let Some(heartbeat) = state.heartbeat else {
+        return false;
+    };
+    fs::metadata(service_socket.state_path())
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|last_beat| heartbeat.is_stale(last_beat))
+}
+
+/// Status of a service whose socket accepts connections, given its state file if any.
+pub(crate) fn running_status(
+    service_socket: &ServiceSocket,
+    state: Option<&ServiceState>,
+) -> ServiceStatus {
+    match state {
+        Some(state) if missed_heartbeat(service_socket, state) => {
+            ServiceStatus::Unresponsive { pid: state.pid }
+        }
+        state => ServiceStatus::Running {
+            pid: state.map(|state| state.pid),
+        },
+    }
+}
+
+/// Read the state file of a service socket, if there is a valid one. This doesn't check whether
+/// the server it describes is still running - see [`crate::ReifiedService::status`] for that.
+pub fn read_state(service_socket: &ServiceSocket) -> Option<ServiceState> {
+    let path = service_socket.state_path();
+    let contents = fs::read(&path).ok()?;
+    let state = serde_json::from_slice(&contents)
+        .ok()
+        .and_then(|state| ServiceState::from_json(&state));
+    if state.is_none() {
+        warn!("Ignoring invalid state file @ {}", path.display());
+    }
+    state
+}
+
+/// Check whether a server is running on a socket, like [`crate::ReifiedService::status`] does for
+/// a known service. The process ID of the server is read from its state file, if it has one.
+pub async fn socket_status(service_socket: &ServiceSocket) -> ServiceStatus {
+    probe_socket(service_socket, read_state(service_socket).as_ref()).await
+}
+
+/// Make a test connection to a socket, which is closed straight away, to find out whether a
+/// server is running on it.
+async fn probe_socket(
+    service_socket: &ServiceSocket,
+    state: Option<&ServiceState>,
+) -> ServiceStatus {
+    let probed = match seqpacket::probe_connect::<StdThreadpoolUSocks>(&service_socket.path).await {
+        Ok(mut probe) => {
+            let _ = StdThreadpoolUSocks::unix_stream_shutdown(&mut probe).await;
+            Ok(())
+        }
+        // Datagram sockets can't be connected to as streams.
+        Err(e) if seqpacket::is_wrong_socket_kind(&e) => datagram::probe(&service_socket.path),
+        Err(e) => Err(e),
+    };
+    match probed {
+        Ok(()) => running_status(service_socket, state),
+        Err(e) if e.kind() == ErrorKind::ConnectionRefused => ServiceStatus::StaleSocket,
+        Err(e) => {
+            debug!("Couldn't probe socket @ {} - {}", service_socket, e);
+            ServiceStatus::NotRunning
+        }
+    }
+}
+
+/// Service socket found in a base context directory by [`list_services`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredService {
+    /// The socket of the service.
+    pub socket: ServiceSocket,
+    /// Whether a server answered on the socket when it was probed. This is
+    /// [`ServiceStatus::NotRunning`] if the socket vanished before it could be probed.
+    pub status: ServiceStatus,
+    /// The state file of the service, if there is a valid one - see [`read_state`].
+    pub state: Option<ServiceState>,
+}
+
+/// Find the service sockets in a base context directory, probing each of them like
+/// [`crate::ReifiedService::status`] does and reading their state files, sorted by socket name.
+///
+/// Control sockets are skipped - see [`ServiceSocket::control_path`]. As the services aren't
+/// known, no handshake is made, so anything else listening on a socket in the directory shows up
+/// too.
+pub async fn list_services(
+    context_dir: &Path,
+) -> IoResult<impl Iterator<Item = DiscoveredService>> {
+    let mut services = Vec::new();
+    for name in gc::list_entries(context_dir, true).await? {
+        if name
+            .as_bytes()
+            .ends_with(gc::CONTROL_SOCKET_SUFFIX.as_bytes())
+        {
+            continue;
+        }
+        let socket = ServiceSocket::new(&name, context_dir);
+        let state = read_state(&socket);
+        let status = probe_socket(&socket, state.as_ref()).await;
+        services.push(DiscoveredService {
+            socket,
+            status,
+            state,
+        });
+    }
+    Ok(services.into_iter())
+}
+
+/// Kill a process, as recorded in a state file - first with `SIGTERM`, then `SIGKILL` if it
+/// hasn't exited within the timeout. This waits for up to the timeout again for the process to be
+/// gone after `SIGKILL`, failing with [`std::io::ErrorKind::TimedOut`] otherwise.
+pub(crate) async fn kill_process(pid: u32, timeout: Duration) -> IoResult<()> {
+    let pid = Pid::from_raw(
+        pid.try_into()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+    );
+    if pid == Pid::this() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Refusing to kill our own process",
+        ));
+    }
+    for signal in [Signal::SIGTERM, Signal::SIGKILL] {
+        warn!("Sending {} to process {}", signal, pid);
+        match kill(pid, signal) {
+            Ok(()) => {}
+            Err(Errno::ESRCH) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        let started = Instant::now();
+        while started.elapsed() < timeout {
+            // Signal 0 only checks whether the process exists.
+            match kill(pid, None) {
+                Err(Errno::ESRCH) => return Ok(()),
+                Ok(()) | Err(_) => timefut::sleep(KILL_POLL_INTERVAL).await,
+            }
+        }
+    }
+    Err(std::io::ErrorKind::TimedOut.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env::temp_dir,
+        ffi::OsStr,
+        os::unix::net::UnixListener,
+        process::Command,
+        time::{Duration, Instant},
+    };
+
+    use futures_lite::future::block_on;
+
+    use super::{
+        kill_process, list_services, read_state, socket_status, write_state_file, Heartbeat,
+        ServiceState, ServiceStatus,
+    };
+    use crate::{ContextDir, ServiceSocket};
+
+    #[test]
+    pub fn state_file_test() {
+        let service_socket = ServiceSocket::new(OsStr::new("state-file-test.sock"), &temp_dir());
+        assert_eq!(read_state(&service_socket), None);
+        for state in [
+            ServiceState::for_this_process(Some("1.2.0"), Some(1..=2)),
+            ServiceState::for_this_process(None, None),
+        ] {
+            let state_file = write_state_file(&service_socket, &state).unwrap();
+            let read = read_state(&service_socket).unwrap();
+            assert_eq!(read.pid, std::process::id());
+            assert_eq!(read.suss_version, env!("CARGO_PKG_VERSION"));
+            assert_eq!(read.service_version, state.service_version);
+            assert_eq!(read.protocol_versions, state.protocol_versions);
+            let since_start = state.started_at.duration_since(read.started_at).unwrap();
+            assert!(since_start < Duration::from_millis(1));
+            drop(state_file);
+            assert!(!service_socket.state_path().exists());
+        }
+
+        std::fs::write(service_socket.state_path(), "{\"pid\": \"garbage\"}").unwrap();
+        assert_eq!(read_state(&service_socket), None);
+        std::fs::remove_file(service_socket.state_path()).unwrap();
+    }
+
+    #[test]
+    pub fn list_services_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let live = ServiceSocket::new(OsStr::new("live.sock"), &context);
+        let _listener = UnixListener::bind(&live.path).unwrap();
+        let _control = UnixListener::bind(live.control_path()).unwrap();
+        let state = ServiceState::for_this_process(Some("0.1.0"), None);
+        let _state_file = write_state_file(&live, &state).unwrap();
+        drop(UnixListener::bind(context.join("dead.sock")).unwrap());
+
+        let services = block_on(list_services(&context))
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0].socket.name, "dead.sock");
+        assert_eq!(services[0].status, ServiceStatus::StaleSocket);
+        assert_eq!(services[0].state, None);
+        assert_eq!(services[1].socket, live);
+        assert_eq!(
+            services[1].status,
+            ServiceStatus::Running {
+                pid: Some(std::process::id())
+            }
+        );
+        assert_eq!(
+            services[1]
+                .state
+                .as_ref()
+                .unwrap()
+                .service_version
+                .as_deref(),
+            Some("0.1.0")
+        );
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn heartbeat_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let service_socket = ServiceSocket::new(OsStr::new("heartbeat.sock"), &context);
+        let _listener = UnixListener::bind(&service_socket.path).unwrap();
+        let heartbeat =
+            Heartbeat::new(Duration::from_secs(1)).with_tolerance(Duration::from_secs(2));
+        let state = ServiceState::for_this_process(None, None).with_heartbeat(Some(heartbeat));
+        let _state_file = write_state_file(&service_socket, &state).unwrap();
+        assert_eq!(
+            read_state(&service_socket).unwrap().heartbeat,
+            Some(heartbeat)
+        );
+        let pid = std::process::id();
+        assert_eq!(
+            block_on(socket_status(&service_socket)),
+            ServiceStatus::Running { pid: Some(pid) }
+        );
+
+        // The state file hasn't been touched for longer than the interval and tolerance.
+        std::fs::File::options()
+            .write(true)
+            .open(service_socket.state_path())
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - Duration::from_secs(4))
+            .unwrap();
+        assert_eq!(
+            block_on(socket_status(&service_socket)),
+            ServiceStatus::Unresponsive { pid }
+        );
+
+        // Beating brings it back.
+        block_on(super::with_heartbeat(
+            crate::timefut::sleep(Duration::from_millis(1500)),
+            Some(service_socket.state_path()),
+            Some(heartbeat),
+        ));
+        assert_eq!(
+            block_on(socket_status(&service_socket)),
+            ServiceStatus::Running { pid: Some(pid) }
+        );
+        std::fs::remove_dir_all(&context).unwrap();
+    }
+
+    #[test]
+    pub fn kill_process_test() {
+        let mut child = Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id();
+        // Reap the child as soon as it dies, so it doesn't linger as a zombie.
+        let reaper = std::thread::spawn(move || child.wait().unwrap());
+        let started = Instant::now();
+        block_on(kill_process(pid, Duration::from_secs(5))).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!reaper.join().unwrap().success());
+
+        assert!(block_on(kill_process(std::process::id(), Duration::from_secs(1))).is_err());
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
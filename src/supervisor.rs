@@ -0,0 +1,552 @@
+//! Keeping services running - a [`Supervisor`] starts a set of services, checks on them
+//! periodically, and restarts them according to their [`RestartPolicy`] when they go down.
+//!
+//! Services are usually started as orphaned processes (see
+//! [`crate::ServiceStartable::after_post_liveness_subprocess`]), so the supervisor can't wait on
+//! them to find out how they exited. Instead, it goes by what they left behind - servers run with
+//! [`crate::ServerExt`] remove their socket and state file when they shut down cleanly, so a
+//! service that went down leaving either of them behind is taken to have crashed. Services that
+//! have missed their [`crate::Server::heartbeat`] are killed, and taken to have crashed too.
+
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+use tracing::{info, instrument, warn};
+
+use crate::{
+    bundle::{run_bounded, BundleOperation},
+    connect_options::exponential_backoff,
+    error, status, timefut, ReifiedService, ServiceSocket, ServiceStartable, ServiceStatus,
+    UnixSocketInterface,
+};
+
+/// How long to wait for an unresponsive service to exit once it is sent `SIGTERM`, and then
+/// `SIGKILL`.
+const UNRESPONSIVE_KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// When a service should be restarted after going down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restart {
+    /// Never restart the service. It is still started once when the supervisor starts.
+    Never,
+    /// Restart the service if it crashed or failed to start, but not if it shut down cleanly.
+    OnFailure,
+    /// Always restart the service.
+    Always,
+}
+
+/// How a [`Supervisor`] restarts a service - when, and with what exponential backoff between
+/// consecutive restarts.
+///
+/// Restarts count as consecutive until the service has stayed up for the maximum backoff.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RestartPolicy {
+    restart: Restart,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_restarts: Option<u32>,
+}
+
+impl RestartPolicy {
+    /// Default delay before the first restart.
+    pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+    /// Default cap on the delay before a restart.
+    pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+    /// Restart services as given by `restart`, with the default backoff and no limit on consecutive
+    /// restarts.
+    pub fn new(restart: Restart) -> Self {
+        Self {
+            restart,
+            initial_backoff: Self::DEFAULT_INITIAL_BACKOFF,
+            max_backoff: Self::DEFAULT_MAX_BACKOFF,
+            max_restarts: None,
+        }
+    }
+
+    /// Wait this long before the first restart. The delay doubles with every consecutive restart
+    /// after that.
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// Never wait longer than this before a restart.
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Give up on the service after this many consecutive restarts.
+    pub fn with_max_restarts(mut self, max_restarts: u32) -> Self {
+        self.max_restarts = Some(max_restarts);
+        self
+    }
+
+    /// When the service is restarted.
+    pub fn restart(&self) -> Restart {
+        self.restart
+    }
+
+    /// Delay before the given consecutive restart (counting from 0).
+    pub fn backoff(&self, restart: u32) -> Duration {
+        exponential_backoff(self.initial_backoff, self.max_backoff, restart)
+    }
+}
+
+/// Something that happened to a service run by a [`Supervisor`].
+#[derive(Debug)]
+pub enum SupervisorEvent {
+    /// The service was found running, without the supervisor having started it.
+    Running { socket: ServiceSocket },
+    /// The supervisor started the service, after the given number of consecutive restarts.
+    Started {
+        socket: ServiceSocket,
+        restarts: u32,
+    },
+    /// The service went down, leaving its socket or state file behind.
+    Crashed { socket: ServiceSocket },
+    /// The service shut down cleanly.
+    Exited { socket: ServiceSocket },
+    /// The service couldn't be started.
+    StartFailed { error: error::Error },
+    /// The service will be restarted after the given delay.
+    RestartScheduled {
+        socket: ServiceSocket,
+        delay: Duration,
+    },
+    /// The service won't be restarted any more, as per its restart policy.
+    GaveUp { socket: ServiceSocket },
+}
+
+/// Runs a set of services, restarting them according to their [`RestartPolicy`] when they go
+/// down.
+#[derive(Debug)]
+pub struct Supervisor<'a> {
+    services: Vec<Supervisee<'a>>,
+    liveness_timeout: Duration,
+    check_interval: Duration,
+}
+
+impl<'a> Supervisor<'a> {
+    /// Default interval between checks on the services.
+    pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Supervise no services yet, starting them with the given liveness timeout.
+    pub fn new(liveness_timeout: Duration) -> Self {
+        Self {
+            services: Vec::new(),
+            liveness_timeout,
+            check_interval: Self::DEFAULT_CHECK_INTERVAL,
+        }
+    }
+
+    /// Check on the services this often.
+    pub fn with_check_interval(mut self, check_interval: Duration) -> Self {
+        self.check_interval = check_interval;
+        self
+    }
+
+    /// Supervise a service - for instance one produced by a [`crate::ServiceBundle`] - with the
+    /// given restart policy.
+    pub fn with_service<S, U, ExecutorPrefixComponent>(
+        self,
+        service: ReifiedService<'a, S, U, ExecutorPrefixComponent>,
+        policy: RestartPolicy,
+    ) -> Self
+    where
+        S: ServiceStartable<U> + 'a,
+        U: UnixSocketInterface + 'a,
+        ExecutorPrefixComponent: AsRef<std::ffi::OsStr> + Sized + Debug + 'a,
+    {
+        self.with_supervised(Box::new(service), policy)
+    }
+
+    fn with_supervised(mut self, service: Box<dyn Supervised + 'a>, policy: RestartPolicy) -> Self {
+        self.services.push(Supervisee {
+            service,
+            policy,
+            phase: Phase::Unchecked,
+            restarts: 0,
+        });
+        self
+    }
+
+    /// Start the services, and keep checking on them and restarting them until the supervisor
+    /// has given up on all of them - which, for services that are always restarted without a
+    /// limit, is never. Everything that happens to them is passed to `on_event`.
+    ///
+    /// The services are checked and started concurrently, on the current task. To stop
+    /// supervising, stop polling this - for instance by racing it against a shutdown signal.
+    #[instrument(skip_all)]
+    pub async fn run(&mut self, mut on_event: impl FnMut(SupervisorEvent)) {
+        loop {
+            if self
+                .services
+                .iter()
+                .all(|supervisee| matches!(supervisee.phase, Phase::Finished))
+            {
+                info!("Gave up on all supervised services");
+                return;
+            }
+            let liveness_timeout = self.liveness_timeout;
+            let checks = self
+                .services
+                .iter_mut()
+                .enumerate()
+                .map(|(index, supervisee)| {
+                    let check: BundleOperation<'_, _> =
+                        Box::pin(supervisee.check(liveness_timeout));
+                    (index, check)
+                })
+                .collect::<Vec<_>>();
+            let parallelism = checks.len();
+            for events in run_bounded(checks, parallelism).await.into_values() {
+                events.into_iter().for_each(&mut on_event);
+            }
+            timefut::sleep(self.check_interval).await;
+        }
+    }
+}
+
+/// How a supervised service went, as far as the supervisor can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Health {
+    Running,
+    Crashed,
+    Exited,
+}
+
+/// Object-safe view of a [`ReifiedService`] for the supervisor.
+trait Supervised: Debug {
+    fn service_socket(&self) -> ServiceSocket;
+
+    fn health(&self) -> BundleOperation<'_, Health>;
+
+    fn ensure_started(&self, liveness_timeout: Duration) -> BundleOperation<'_, error::Result<()>>;
+}
+
+impl<S, U, ExecutorPrefixComponent> Supervised for ReifiedService<'_, S, U, ExecutorPrefixComponent>
+where
+    S: ServiceStartable<U>,
+    U: UnixSocketInterface,
+    ExecutorPrefixComponent: AsRef<std::ffi::OsStr> + Sized + Debug,
+{
+    fn service_socket(&self) -> ServiceSocket {
+        ReifiedService::service_socket(self)
+    }
+
+    fn health(&self) -> BundleOperation<'_, Health> {
+        Box::pin(async move {
+            match self.status().await {
+                ServiceStatus::Running { .. } => Health::Running,
+                ServiceStatus::StaleSocket => Health::Crashed,
+                // It can't be started again while it still holds on to its socket.
+                ServiceStatus::Unresponsive { pid } => {
+                    warn!("Service is unresponsive - killing process {}", pid);
+                    if let Err(e) = status::kill_process(pid, UNRESPONSIVE_KILL_TIMEOUT).await {
+                        warn!("Couldn't kill process {} - {}", pid, e);
+                    }
+                    Health::Crashed
+                }
+                ServiceStatus::NotRunning if self.service_socket().state_path().exists() => {
+                    Health::Crashed
+                }
+                ServiceStatus::NotRunning => Health::Exited,
+            }
+        })
+    }
+
+    fn ensure_started(&self, liveness_timeout: Duration) -> BundleOperation<'_, error::Result<()>> {
+        Box::pin(ReifiedService::ensure_started(self, liveness_timeout))
+    }
+}
+
+/// Where a supervised service is at.
+#[derive(Debug, Clone, Copy)]
+enum Phase {
+    /// The service hasn't been checked on yet.
+    Unchecked,
+    /// The service was running at the last check.
+    Up { since: Instant },
+    /// The service is down, and should be restarted at the given time.
+    Down { restart_at: Instant },
+    /// The service is down, and won't be restarted.
+    Finished,
+}
+
+/// A service run by a [`Supervisor`], along with how it is doing.
+#[derive(Debug)]
+struct Supervisee<'a> {
+    service: Box<dyn Supervised + 'a>,
+    policy: RestartPolicy,
+    phase: Phase,
+    /// Number of consecutive restarts so far.
+    restarts: u32,
+}
+
+impl Supervisee<'_> {
+    /// Check on the service, starting it if it is due, producing what happened.
+    async fn check(&mut self, liveness_timeout: Duration) -> Vec<SupervisorEvent> {
+        let socket = self.service.service_socket();
+        let mut events = Vec::new();
+        match self.phase {
+            Phase::Finished => {}
+            Phase::Down { restart_at } if Instant::now() < restart_at => {}
+            Phase::Down { .. } => self.start(liveness_timeout, &mut events).await,
+            Phase::Unchecked | Phase::Up { .. } => {
+                match (self.phase, self.service.health().await) {
+                    (Phase::Unchecked, Health::Running) => {
+                        self.phase = Phase::Up {
+                            since: Instant::now(),
+                        };
+                        events.push(SupervisorEvent::Running { socket });
+                    }
+                    (Phase::Up { since }, Health::Running) => {
+                        if since.elapsed() >= self.policy.max_backoff {
+                            self.restarts = 0;
+                        }
+                    }
+                    (Phase::Unchecked, _) => self.start(liveness_timeout, &mut events).await,
+                    (_, health) => {
+                        let crashed = health == Health::Crashed;
+                        match crashed {
+                            true => warn!("Supervised service @ {} crashed", socket),
+                            false => info!("Supervised service @ {} exited", socket),
+                        }
+                        events.push(match crashed {
+                            true => SupervisorEvent::Crashed {
+                                socket: socket.clone(),
+                            },
+                            false => SupervisorEvent::Exited {
+                                socket: socket.clone(),
+                            },
+                        });
+                        self.schedule_restart(crashed, socket, &mut events);
+                    }
+                }
+            }
+        }
+        events
+    }
+
+    /// Start the service, scheduling a restart if that fails.
+    async fn start(&mut self, liveness_timeout: Duration, events: &mut Vec<SupervisorEvent>) {
+        let socket = self.service.service_socket();
+        match self.service.ensure_started(liveness_timeout).await {
+            Ok(()) => {
+                info!("Started supervised service @ {}", socket);
+                self.phase = Phase::Up {
+                    since: Instant::now(),
+                };
+                events.push(SupervisorEvent::Started {
+                    socket,
+                    restarts: self.restarts,
+                });
+            }
+            Err(e) => {
+                warn!("Couldn't start supervised service @ {} - {}", socket, e);
+                events.push(SupervisorEvent::StartFailed { error: e });
+                self.schedule_restart(true, socket, events);
+            }
+        }
+    }
+
+    /// Schedule a restart of the service after it went down, if its policy says so.
+    fn schedule_restart(
+        &mut self,
+        failed: bool,
+        socket: ServiceSocket,
+        events: &mut Vec<SupervisorEvent>,
+    ) {
+        let restart = match self.policy.restart {
+            Restart::Never => false,
+            Restart::OnFailure => failed,
+            Restart::Always => true,
+        };
+        let exhausted = self
+            .policy
+            .max_restarts
+            .is_some_and(|max_restarts| self.restarts >= max_restarts);
+        if !restart || exhausted {
+            warn!("Giving up on supervised service @ {}", socket);
+            self.phase = Phase::Finished;
+            events.push(SupervisorEvent::GaveUp { socket });
+            return;
+        }
+        let delay = self.policy.backoff(self.restarts);
+        self.restarts += 1;
+        self.phase = Phase::Down {
+            restart_at: Instant::now() + delay,
+        };
+        events.push(SupervisorEvent::RestartScheduled { socket, delay });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, ffi::OsStr, path::Path, time::Duration};
+
+    use futures_lite::future::block_on;
+
+    use super::{Health, Restart, RestartPolicy, Supervised, Supervisor, SupervisorEvent};
+    use crate::{bundle::BundleOperation, error, Error, ServiceSocket};
+
+    /// Service that is always in the same health, and may fail to start.
+    #[derive(Debug)]
+    struct FakeService {
+        health: Health,
+        start_fails: bool,
+        starts: Cell<u32>,
+    }
+
+    impl Supervised for &FakeService {
+        fn service_socket(&self) -> ServiceSocket {
+            ServiceSocket::new(OsStr::new("fake.sock"), Path::new("/nonexistent"))
+        }
+
+        fn health(&self) -> BundleOperation<'_, Health> {
+            Box::pin(async move { self.health })
+        }
+
+        fn ensure_started(&self, _: Duration) -> BundleOperation<'_, error::Result<()>> {
+            self.starts.set(self.starts.get() + 1);
+            let result = match self.start_fails {
+                true => Err(Error::ConnectFailed {
+                    socket: self.service_socket(),
+                    source: std::io::ErrorKind::ConnectionRefused.into(),
+                }),
+                false => Ok(()),
+            };
+            Box::pin(async move { result })
+        }
+    }
+
+    /// Supervise a fake service until the supervisor gives up on it, producing the names of the
+    /// events that happened.
+    fn supervise(service: &FakeService, policy: RestartPolicy) -> Vec<String> {
+        let mut events = Vec::new();
+        let policy = policy
+            .with_initial_backoff(Duration::from_millis(1))
+            .with_max_backoff(Duration::from_millis(2));
+        let mut supervisor = Supervisor::new(Duration::from_secs(1))
+            .with_check_interval(Duration::from_millis(1))
+            .with_supervised(Box::new(service), policy);
+        block_on(supervisor.run(|event| {
+            let name = match event {
+                SupervisorEvent::Running { .. } => "running".to_owned(),
+                SupervisorEvent::Started { restarts, .. } => format!("started {restarts}"),
+                SupervisorEvent::Crashed { .. } => "crashed".to_owned(),
+                SupervisorEvent::Exited { .. } => "exited".to_owned(),
+                SupervisorEvent::StartFailed { .. } => "start failed".to_owned(),
+                SupervisorEvent::RestartScheduled { .. } => "restart scheduled".to_owned(),
+                SupervisorEvent::GaveUp { .. } => "gave up".to_owned(),
+            };
+            events.push(name);
+        }));
+        events
+    }
+
+    #[test]
+    pub fn supervisor_test() {
+        let crashing = FakeService {
+            health: Health::Crashed,
+            start_fails: false,
+            starts: Cell::new(0),
+        };
+        assert_eq!(
+            supervise(
+                &crashing,
+                RestartPolicy::new(Restart::Always).with_max_restarts(2)
+            ),
+            vec![
+                "started 0",
+                "crashed",
+                "restart scheduled",
+                "started 1",
+                "crashed",
+                "restart scheduled",
+                "started 2",
+                "crashed",
+                "gave up"
+            ]
+        );
+        assert_eq!(crashing.starts.get(), 3);
+
+        let exiting = FakeService {
+            health: Health::Exited,
+            start_fails: false,
+            starts: Cell::new(0),
+        };
+        assert_eq!(
+            supervise(&exiting, RestartPolicy::new(Restart::OnFailure)),
+            vec!["started 0", "exited", "gave up"]
+        );
+
+        let broken = FakeService {
+            health: Health::Exited,
+            start_fails: true,
+            starts: Cell::new(0),
+        };
+        assert_eq!(
+            supervise(&broken, RestartPolicy::new(Restart::Never)),
+            vec!["start failed", "gave up"]
+        );
+        assert_eq!(
+            supervise(
+                &broken,
+                RestartPolicy::new(Restart::OnFailure).with_max_restarts(1)
+            ),
+            vec![
+                "start failed",
+                "restart scheduled",
+                "start failed",
+                "gave up"
+            ]
+        );
+
+        let running = FakeService {
+            health: Health::Running,
+            start_fails: false,
+            starts: Cell::new(0),
+        };
+        let mut supervisor = Supervisor::new(Duration::from_secs(1))
+            .with_supervised(Box::new(&running), RestartPolicy::new(Restart::Never));
+        let mut events = Vec::new();
+        block_on(futures_lite::future::or(
+            supervisor.run(|event| events.push(event)),
+            crate::timefut::sleep(Duration::from_millis(50)),
+        ));
+        assert!(matches!(events[..], [SupervisorEvent::Running { .. }]));
+        assert_eq!(running.starts.get(), 0);
+    }
+
+    #[test]
+    pub fn restart_policy_test() {
+        let policy = RestartPolicy::new(Restart::Always)
+            .with_initial_backoff(Duration::from_millis(100))
+            .with_max_backoff(Duration::from_millis(300));
+        assert_eq!(policy.restart(), Restart::Always);
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(2), Duration::from_millis(300));
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,183 @@
+//! Spawning background tasks on whatever runtime you use - see [`TaskSpawner`].
+//!
+//! With a spawner in [`crate::ConnectOptions::with_task_spawner`], the library reaps the
+//! processes of services it starts itself, rather than leaving that to
+//! [`crate::ServiceStartable::after_post_liveness_subprocess`]. A spawner can also run the
+//! connection handlers of the accept loops in [`crate::serve`] - see [`connection_spawner`].
+
+use std::{fmt::Debug, future::Future, pin::Pin, sync::Arc};
+
+use futures_lite::future::block_on;
+use tracing::{info, warn};
+
+use crate::child::{self, ServiceProcessHandle};
+
+/// A boxed background task.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Something that runs background tasks to completion, without the caller waiting on them.
+pub trait TaskSpawner: Debug + Send + Sync {
+    /// Start running the task in the background.
+    fn spawn_task(&self, task: Task);
+}
+
+impl<T: TaskSpawner + ?Sized> TaskSpawner for Arc<T> {
+    fn spawn_task(&self, task: Task) {
+        (**self).spawn_task(task)
+    }
+}
+
+/// Runs every task on a thread of its own. This needs no runtime at all, but is only worth it
+/// for few, long-lived tasks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadSpawner;
+
+impl TaskSpawner for ThreadSpawner {
+    fn spawn_task(&self, task: Task) {
+        if let Err(e) = std::thread::Builder::new()
+            .name("suss-task".to_owned())
+            .spawn(move || block_on(task))
+        {
+            warn!("Couldn't spawn thread for background task - {}", e);
+        }
+    }
+}
+
+/// Runs tasks on a tokio runtime.
+#[cfg(feature = "tokio-spawner")]
+#[derive(Debug, Clone)]
+pub struct TokioSpawner(tokio::runtime::Handle);
+
+#[cfg(feature = "tokio-spawner")]
+impl TokioSpawner {
+    /// Run tasks on the runtime this is called from, if any.
+    pub fn current() -> Option<Self> {
+        tokio::runtime::Handle::try_current().ok().map(Self)
+    }
+
+    /// Run tasks on the runtime of this handle.
+    pub fn new(handle: tokio::runtime::Handle) -> Self {
+        Self(handle)
+    }
+}
+
+#[cfg(feature = "tokio-spawner")]
+impl TaskSpawner for TokioSpawner {
+    fn spawn_task(&self, task: Task) {
+        drop(self.0.spawn(task));
+    }
+}
+
+/// Runs tasks on an executor, like the `smol::Executor` (which is the same type).
+#[cfg(feature = "smol-spawner")]
+#[derive(Debug, Clone)]
+pub struct ExecutorSpawner(Arc<async_executor::Executor<'static>>);
+
+#[cfg(feature = "smol-spawner")]
+impl ExecutorSpawner {
+    /// Run tasks on this executor. Something still needs to drive the executor - see
+    /// [`async_executor::Executor::run`].
+    pub fn new(executor: Arc<async_executor::Executor<'static>>) -> Self {
+        Self(executor)
+    }
+}
+
+#[cfg(feature = "smol-spawner")]
+impl TaskSpawner for ExecutorSpawner {
+    fn spawn_task(&self, task: Task) {
+        self.0.spawn(task).detach();
+    }
+}
+
+/// Runs tasks on the global executor of `async-global-executor` - which `async-std` uses too.
+#[cfg(feature = "global-executor-spawner")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GlobalExecutorSpawner;
+
+#[cfg(feature = "global-executor-spawner")]
+impl TaskSpawner for GlobalExecutorSpawner {
+    fn spawn_task(&self, task: Task) {
+        async_global_executor::spawn(task).detach();
+    }
+}
+
+/// Spawn connection handlers with the task spawner, for the `spawner` argument of
+/// [`crate::serve::serve_connections`] and friends.
+pub fn connection_spawner<F>(spawner: &dyn TaskSpawner) -> impl FnMut(F) + '_
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    move |connection| spawner.spawn_task(Box::pin(connection))
+}
+
+/// Wait on the process of a started service in a background task, so it doesn't linger as a
+/// zombie once it exits.
+pub(crate) fn reap_child(spawner: &dyn TaskSpawner, mut child: impl ServiceProcessHandle) {
+    spawner.spawn_task(Box::pin(async move {
+        let process = child::describe(&child);
+        match blocking::unblock(move || child.wait()).await {
+            Ok(status) => info!("Service process {} exited - {}", process, status),
+            Err(e) => warn!("Couldn't wait on service process {} - {}", process, e),
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        process::Command,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    use super::{connection_spawner, reap_child, TaskSpawner, ThreadSpawner};
+
+    #[test]
+    pub fn thread_spawner_test() {
+        let spawner: Arc<dyn TaskSpawner> = Arc::new(ThreadSpawner);
+        let ran = Arc::new(AtomicUsize::new(0));
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let mut spawn = connection_spawner(&spawner);
+        for _ in 0..3 {
+            let ran = ran.clone();
+            let done_tx = done_tx.clone();
+            spawn(async move {
+                ran.fetch_add(1, Ordering::AcqRel);
+                done_tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..3 {
+            done_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+        assert_eq!(ran.load(Ordering::Acquire), 3);
+
+        // Reaped children don't linger as zombies.
+        let child = Command::new("true").spawn().unwrap();
+        let pid = child.id();
+        reap_child(&spawner, child);
+        let started = Instant::now();
+        while std::path::Path::new(&format!("/proc/{}", pid)).exists() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -0,0 +1,268 @@
+//! Keeping clients from hammering a service that keeps failing to start - see [`StartThrottle`]
+//! and [`crate::ReifiedService::with_start_throttle`].
+//!
+//! Starts are limited by a token bucket: every start takes a token, and tokens are refilled one
+//! at a time at a fixed interval, up to the burst size. On top of that, a circuit breaker trips
+//! after a number of consecutive start failures, after which no starts are attempted at all until
+//! it has been open for a while.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::{Error, ServiceSocket};
+
+/// How often a [`crate::ReifiedService`] may start its service, and when to stop trying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StartThrottle {
+    burst: u32,
+    refill_interval: Duration,
+    failure_threshold: u32,
+    open_duration: Duration,
+}
+
+impl Default for StartThrottle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StartThrottle {
+    /// Allow bursts of 3 starts, refilled every 10 seconds, and trip the circuit breaker for 30
+    /// seconds after 5 consecutive start failures.
+    pub fn new() -> Self {
+        Self {
+            burst: 3,
+            refill_interval: Duration::from_secs(10),
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+
+    /// Allow this many starts in quick succession. A burst of 0 is treated as 1.
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        self.burst = burst.max(1);
+        self
+    }
+
+    /// Allow another start every time this much time has passed, up to the burst size.
+    pub fn with_refill_interval(mut self, refill_interval: Duration) -> Self {
+        self.refill_interval = refill_interval;
+        self
+    }
+
+    /// Trip the circuit breaker after this many consecutive start failures. A threshold of 0 is
+    /// treated as 1.
+    pub fn with_failure_threshold(mut self, failure_threshold: u32) -> Self {
+        self.failure_threshold = failure_threshold.max(1);
+        self
+    }
+
+    /// Keep the circuit breaker open this long once tripped, before allowing starts again.
+    pub fn with_open_duration(mut self, open_duration: Duration) -> Self {
+        self.open_duration = open_duration;
+        self
+    }
+
+    /// Most starts allowed in quick succession.
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+
+    /// How often another start is allowed.
+    pub fn refill_interval(&self) -> Duration {
+        self.refill_interval
+    }
+
+    /// Consecutive start failures that trip the circuit breaker.
+    pub fn failure_threshold(&self) -> u32 {
+        self.failure_threshold
+    }
+
+    /// How long the circuit breaker stays open once tripped.
+    pub fn open_duration(&self) -> Duration {
+        self.open_duration
+    }
+}
+
+/// A [`StartThrottle`] along with the starts and failures seen so far.
+#[derive(Debug)]
+pub(crate) struct Throttled {
+    throttle: StartThrottle,
+    state: Mutex<ThrottleState>,
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    tokens: u32,
+    /// When the last token was refilled - or the bucket was last full.
+    refilled_at: Instant,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Throttled {
+    pub(crate) fn new(throttle: StartThrottle) -> Self {
+        Self {
+            throttle,
+            state: Mutex::new(ThrottleState {
+                tokens: throttle.burst,
+                refilled_at: Instant::now(),
+                consecutive_failures: 0,
+                open_until: None,
+            }),
+        }
+    }
+
+    pub(crate) fn throttle(&self) -> &StartThrottle {
+        &self.throttle
+    }
+
+    /// Take the permission to start the service, or fail fast if the circuit breaker is open or
+    /// there have been too many starts lately.
+    pub(crate) fn acquire(&self, socket: &ServiceSocket) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        if let Some(open_until) = state.open_until {
+            if now < open_until {
+                return Err(Error::CircuitOpen {
+                    socket: socket.clone(),
+                    retry_after: open_until - now,
+                });
+            }
+        }
+        if state.tokens < self.throttle.burst {
+            let elapsed = now.duration_since(state.refilled_at);
+            let refilled = match self.throttle.refill_interval.as_nanos() {
+                0 => u128::from(self.throttle.burst),
+                interval => elapsed.as_nanos() / interval,
+            };
+            let refilled = u32::try_from(refilled).unwrap_or(u32::MAX);
+            state.tokens = state
+                .tokens
+                .saturating_add(refilled)
+                .min(self.throttle.burst);
+            state.refilled_at = match state.tokens == self.throttle.burst {
+                true => now,
+                false => state.refilled_at + self.throttle.refill_interval * refilled,
+            };
+        }
+        if state.tokens == 0 {
+            return Err(Error::StartThrottled {
+                socket: socket.clone(),
+                retry_after: (state.refilled_at + self.throttle.refill_interval)
+                    .saturating_duration_since(now),
+            });
+        }
+        if state.tokens == self.throttle.burst {
+            state.refilled_at = now;
+        }
+        state.tokens -= 1;
+        Ok(())
+    }
+
+    /// Record the service as running, closing the circuit breaker.
+    pub(crate) fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.consecutive_failures = 0;
+        state.open_until = None;
+    }
+
+    /// Record a failed start, tripping the circuit breaker once there have been too many in a
+    /// row.
+    pub(crate) fn failed(&self, socket: &ServiceSocket) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+        if state.consecutive_failures >= self.throttle.failure_threshold {
+            warn!(
+                "Service @ {} failed to start {} times in a row - not starting it again for {}",
+                socket,
+                state.consecutive_failures,
+                humantime::format_duration(self.throttle.open_duration)
+            );
+            state.open_until = Some(Instant::now() + self.throttle.open_duration);
+        }
+    }
+}
+
+/// Whether an error means the service was started, but failed to come up - as opposed to, say,
+/// connecting to the wrong service.
+pub(crate) fn is_start_failure(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::LivenessSocketFailed { .. }
+            | Error::SpawnFailed { .. }
+            | Error::SpawnExited { .. }
+            | Error::LivenessTimeout { .. }
+            | Error::StartupFailed { .. }
+            | Error::PostLivenessFailed { .. }
+            | Error::BindFailed { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, path::Path, time::Duration};
+
+    use super::{StartThrottle, Throttled};
+    use crate::{Error, ServiceSocket};
+
+    #[test]
+    pub fn start_throttle_test() {
+        let socket = ServiceSocket::new(OsStr::new("throttled.sock"), Path::new("/tmp"));
+
+        // Tokens run out after the burst, and come back after the refill interval.
+        let throttled = Throttled::new(
+            StartThrottle::new()
+                .with_burst(2)
+                .with_refill_interval(Duration::from_millis(200)),
+        );
+        throttled.acquire(&socket).unwrap();
+        throttled.acquire(&socket).unwrap();
+        assert!(matches!(
+            throttled.acquire(&socket),
+            Err(Error::StartThrottled { retry_after, .. }) if retry_after <= Duration::from_millis(200)
+        ));
+        std::thread::sleep(Duration::from_millis(250));
+        throttled.acquire(&socket).unwrap();
+        assert!(throttled.acquire(&socket).is_err());
+
+        // The circuit breaker trips after consecutive failures only.
+        let throttled = Throttled::new(
+            StartThrottle::new()
+                .with_burst(10)
+                .with_failure_threshold(2)
+                .with_open_duration(Duration::from_millis(200)),
+        );
+        throttled.failed(&socket);
+        throttled.succeeded();
+        throttled.failed(&socket);
+        throttled.acquire(&socket).unwrap();
+        throttled.failed(&socket);
+        assert!(matches!(
+            throttled.acquire(&socket),
+            Err(Error::CircuitOpen { .. })
+        ));
+        std::thread::sleep(Duration::from_millis(250));
+        throttled.acquire(&socket).unwrap();
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
@@ -25,7 +25,11 @@ pub async fn sleep(time: Duration) {
 #[cfg(all(feature = "tokio", not(feature = "async-std")))]
 /// Sleep for the alloted period of time. Uses async runtime environments where possible.
 pub async fn sleep(time: Duration) {
-    tokio_sleep(time).await
+    // Tokio timers panic outside of a tokio runtime.
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => tokio_sleep(time).await,
+        Err(_) => std_sleep(time).await,
+    }
 }
 
 #[cfg(not(any(feature = "tokio", feature = "async-std")))]
@@ -0,0 +1,276 @@
+//! Other ways for connections to reach a service than its unix socket - see [`Transport`] and
+//! [`crate::Service::transport`].
+//!
+//! Servers always bind their unix socket in the base context directory. A transport makes that
+//! socket reachable some other way as well - [`TcpTransport`] accepts TCP connections and relays
+//! them to it - and clients of the service connect that way instead. This is for deployments
+//! where clients and servers share the base context directory, but not the sockets in it - like
+//! containers on different hosts sharing a network filesystem.
+//!
+//! Connections made over a transport are handed to the [`UnixSocketInterface`] as if they were
+//! unix streams, which works as the socket interfaces only read, write and shut them down. Peer
+//! credentials can't be read from them, so services checking
+//! [`crate::Service::trusted_owner_uid`] can't be used over other transports, and servers see
+//! connections relayed from other transports as coming from themselves.
+
+use std::{
+    fmt::Debug,
+    io::{self, ErrorKind},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::{fd::OwnedFd, unix::net::UnixStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tracing::{debug, info, warn};
+
+use crate::{socket_shims::UnixSocketInterface, IoResult, ServiceSocket};
+
+/// Way of reaching the server of a service.
+pub trait Transport: Debug + Send + Sync {
+    /// Connect to the server of the service, producing the connected stream socket. This blocks.
+    fn connect(&self, service_socket: &ServiceSocket) -> IoResult<OwnedFd>;
+
+    /// In a server that has bound the unix socket of the service, make it reachable over this
+    /// transport too, for as long as the returned [`Exposure`] is kept. This blocks.
+    fn expose(&self, service_socket: &ServiceSocket) -> IoResult<Exposure>;
+}
+
+/// Keeps the server of a service reachable over a transport - see [`Transport::expose`]. Dropping
+/// it stops that.
+#[must_use = "the service stops being reachable over the transport when this is dropped"]
+pub struct Exposure {
+    on_drop: Option<Box<dyn FnOnce() + Send>>,
+}
+
+impl Exposure {
+    /// Nothing to stop.
+    pub fn none() -> Self {
+        Self { on_drop: None }
+    }
+
+    /// Stop with the given function.
+    pub fn on_drop(on_drop: impl FnOnce() + Send + 'static) -> Self {
+        Self {
+            on_drop: Some(Box::new(on_drop)),
+        }
+    }
+}
+
+impl Debug for Exposure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Exposure").finish_non_exhaustive()
+    }
+}
+
+impl Drop for Exposure {
+    fn drop(&mut self) {
+        if let Some(on_drop) = self.on_drop.take() {
+            on_drop();
+        }
+    }
+}
+
+/// The unix socket of the service itself - what services without a transport use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnixTransport;
+
+impl Transport for UnixTransport {
+    fn connect(&self, service_socket: &ServiceSocket) -> IoResult<OwnedFd> {
+        UnixStream::connect(&service_socket.path).map(OwnedFd::from)
+    }
+
+    fn expose(&self, _service_socket: &ServiceSocket) -> IoResult<Exposure> {
+        Ok(Exposure::none())
+    }
+}
+
+/// TCP, on a port the server binds at the given address - `"127.0.0.1:0"` for any free port on
+/// the loopback interface. The address actually bound is recorded in the
+/// [`ServiceSocket::tcp_registry_path`] file next to the service socket, for clients to connect
+/// to.
+///
+/// Connections aren't authenticated or encrypted, so only bind addresses that untrusted hosts
+/// can't reach.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpTransport {
+    bind_address: String,
+}
+
+impl TcpTransport {
+    /// How often the relay checks whether it should stop, while no connections come in.
+    const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    /// Bind the given address - anything [`std::net::ToSocketAddrs`] can resolve.
+    pub fn new(bind_address: impl Into<String>) -> Self {
+        Self {
+            bind_address: bind_address.into(),
+        }
+    }
+
+    /// The address servers bind.
+    pub fn bind_address(&self) -> &str {
+        &self.bind_address
+    }
+
+    /// The address the server of the service is listening on, as recorded by it.
+    pub fn registered_address(service_socket: &ServiceSocket) -> IoResult<SocketAddr> {
+        let registered = std::fs::read_to_string(service_socket.tcp_registry_path())?;
+        registered.trim().parse().map_err(|e| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "invalid address {:?} in TCP port registry - {}",
+                    registered, e
+                ),
+            )
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self, service_socket: &ServiceSocket) -> IoResult<OwnedFd> {
+        let address = Self::registered_address(service_socket)?;
+        debug!("Connecting to {} over TCP @ {}", service_socket, address);
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        Ok(stream.into())
+    }
+
+    fn expose(&self, service_socket: &ServiceSocket) -> IoResult<Exposure> {
+        let listener = TcpListener::bind(&self.bind_address)?;
+        let address = listener.local_addr()?;
+        // Checking whether to stop needs the accept loop to wake up now and again.
+        listener.set_nonblocking(true)?;
+        let registry_path = service_socket.tcp_registry_path();
+        let mut staging_path = registry_path.clone().into_os_string();
+        staging_path.push(".tmp");
+        std::fs::write(&staging_path, format!("{address}\n"))?;
+        std::fs::rename(&staging_path, &registry_path)?;
+        info!(
+            "Relaying TCP connections @ {} to {}",
+            address, service_socket
+        );
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let socket_path = service_socket.path.clone();
+        let relay_stop = stop.clone();
+        std::thread::Builder::new()
+            .name("suss-tcp-relay".to_owned())
+            .spawn(move || {
+                while !relay_stop.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((tcp, peer)) => {
+                            debug!("Relaying TCP connection from {}", peer);
+                            let socket_path = socket_path.clone();
+                            std::thread::spawn(move || {
+                                let relayed = tcp
+                                    .set_nonblocking(false)
+                                    .and_then(|()| relay(tcp, UnixStream::connect(socket_path)?));
+                                if let Err(e) = relayed {
+                                    warn!("Relaying TCP connection from {} failed - {}", peer, e);
+                                }
+                            });
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                            std::thread::sleep(Self::ACCEPT_POLL_INTERVAL)
+                        }
+                        Err(e) => {
+                            warn!("Couldn't accept TCP connection - {}", e);
+                            std::thread::sleep(Self::ACCEPT_POLL_INTERVAL)
+                        }
+                    }
+                }
+            })?;
+        Ok(Exposure::on_drop(move || {
+            stop.store(true, Ordering::Relaxed);
+            let _ = std::fs::remove_file(registry_path);
+        }))
+    }
+}
+
+/// Copy data both ways between the two streams until both directions are done.
+fn relay(tcp: TcpStream, unix: UnixStream) -> IoResult<()> {
+    let (mut tcp_read, mut unix_write) = (tcp.try_clone()?, unix.try_clone()?);
+    let inbound = std::thread::spawn(move || {
+        let copied = io::copy(&mut tcp_read, &mut unix_write);
+        let _ = unix_write.shutdown(Shutdown::Write);
+        copied
+    });
+    let (mut unix_read, mut tcp_write) = (unix, tcp);
+    let outbound = io::copy(&mut unix_read, &mut tcp_write);
+    let _ = tcp_write.shutdown(Shutdown::Write);
+    let inbound = inbound
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("relay thread panicked")));
+    inbound.and(outbound).map(drop)
+}
+
+/// Connect to the service over the transport, as a stream of the socket interface.
+pub(crate) async fn connect<U: UnixSocketInterface>(
+    transport: Arc<dyn Transport>,
+    service_socket: &ServiceSocket,
+) -> IoResult<U::UnixStream> {
+    let service_socket = service_socket.clone();
+    let socket = blocking::unblock(move || transport.connect(&service_socket)).await?;
+    U::unix_stream_from_std(UnixStream::from(socket)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::{UnixListener, UnixStream},
+    };
+
+    use super::{TcpTransport, Transport};
+    use crate::{ContextDir, ServiceSocket};
+
+    #[test]
+    pub fn tcp_relay_test() {
+        let context = ContextDir::temp_for_tests().unwrap();
+        let socket = ServiceSocket::new("tcp.sock".as_ref(), &context);
+        let listener = UnixListener::bind(&socket.path).unwrap();
+        let transport = TcpTransport::new("127.0.0.1:0");
+        let exposure = transport.expose(&socket).unwrap();
+        assert!(TcpTransport::registered_address(&socket)
+            .unwrap()
+            .ip()
+            .is_loopback());
+
+        let mut client = UnixStream::from(transport.connect(&socket).unwrap());
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut ping = [0; 4];
+        server.read_exact(&mut ping).unwrap();
+        assert_eq!(&ping, b"ping");
+        server.write_all(b"pong").unwrap();
+        drop(server);
+        let mut pong = Vec::new();
+        client.read_to_end(&mut pong).unwrap();
+        assert_eq!(pong, b"pong");
+
+        drop(exposure);
+        assert!(!socket.tcp_registry_path().exists());
+        assert!(transport.connect(&socket).is_err());
+    }
+}
+
+// suss - library for creating single, directory namespaced unix socket servers in a network
+// Copyright (C) 2022  Matti Bryce <mattibryce@protonmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published
+// by the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.